@@ -108,3 +108,14 @@ pub enum vm_cap_type {
     VM_CAP_ENABLE_INVPCID,
     VM_CAP_BPT_EXIT,
 }
+
+#[repr(i32)]
+#[allow(non_camel_case_types, unused)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, TryFromPrimitive)]
+pub enum vm_suspend_how {
+    VM_SUSPEND_NONE,
+    VM_SUSPEND_RESET,
+    VM_SUSPEND_POWEROFF,
+    VM_SUSPEND_HALT,
+    VM_SUSPEND_TRIPLEFAULT,
+}
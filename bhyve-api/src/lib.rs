@@ -7,6 +7,12 @@ pub use ioctls::*;
 pub use structs::*;
 
 pub const VM_MAXCPU: usize = 32;
+/// Size of the VM name buffer in vm_create_req, including its NUL terminator
+pub const VM_MAX_NAMELEN: usize = 128;
+
+/// Version of these bindings, for reporting which interface consumers were
+/// built against.
+pub const API_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub const VMM_PATH_PREFIX: &str = "/dev/vmm";
 pub const VMM_CTL_PATH: &str = "/dev/vmmctl";
@@ -81,6 +81,7 @@ pub union vm_exit_payload {
     pub msr: vm_rwmsr,
     pub vmx: vm_exit_vmx,
     pub svm: vm_exit_svm,
+    pub suspended: vm_exit_suspended,
     // sized to zero entire union
     empty: [u64; 6],
 }
@@ -124,6 +125,12 @@ pub struct vm_exit_svm {
     pub exitinfo2: u64,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct vm_exit_suspended {
+    pub how: c_int,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct vm_exit_msr {
@@ -262,6 +269,37 @@ pub struct vm_vcpu_reset {
     pub kind: u32,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_suspend {
+    // how values defined in vm_suspend_how
+    pub how: c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_hpet_cap {
+    /// Low 32 bits of the HPET General Capabilities and ID register
+    pub capabilities: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_activate_cpu {
+    /// vCPU to act upon, or -1 for all vCPUs
+    pub vcpuid: c_int,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct vm_cpu_topology {
+    pub sockets: u16,
+    pub cores: u16,
+    pub threads: u16,
+    /// Must be zero, as the kernel does not support altering maxcpus
+    pub maxcpus: u16,
+}
+
 // bit definitions for vm_run_state`state
 pub const VRS_INIT: u32 = 1 << 0;
 pub const VRS_RUN: u32 = 1 << 1;
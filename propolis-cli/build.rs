@@ -0,0 +1,35 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Run git with `args`, yielding its (trimmed) output if it succeeds
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn main() {
+    // Embed the commit this binary was built from, if it can be determined
+    let commit = git(&["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+
+    // Rebuild when HEAD moves, either to another ref or by a commit to the
+    // current one.  Git resolves the paths, as they differ for worktrees, and
+    // those absent (outside of a checkout, or for packed refs) are skipped.
+    println!("cargo:rerun-if-changed=build.rs");
+    let mut paths = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    paths.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for path in paths.iter() {
+        if let Some(p) = git(&["rev-parse", "--git-path", path]) {
+            if Path::new(&p).exists() {
+                println!("cargo:rerun-if-changed={}", p);
+            }
+        }
+    }
+}
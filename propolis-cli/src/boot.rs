@@ -0,0 +1,712 @@
+//! Direct-kernel boot: load a kernel image into guest memory and prepare a
+//! vCPU to enter it in 64-bit mode, per the Linux x86 64-bit boot protocol.
+//!
+//! Both raw images and bzImages are accepted.  For the latter, the real-mode
+//! setup code is skipped, with its header copied into boot_params instead.
+//!
+//! ELF kernels bearing a PVH entry point note may instead be entered in 32-bit
+//! protected mode, with an hvm_start_info structure describing the machine.
+
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+use propolis::common::GuestAddr;
+use propolis::vcpu::VcpuHdl;
+use propolis::vmm::MemCtx;
+
+use propolis::bhyve_api;
+use propolis::bhyve_api::vm_reg_name::*;
+
+// Boot structures placed in low memory, below any sane kernel load address
+const GDT_ADDR: u64 = 0x500;
+const BOOT_PARAMS_ADDR: u64 = 0x7000;
+const CMDLINE_ADDR: u64 = 0x8000;
+const CMDLINE_MAX: usize = 0x1000;
+const PML4_ADDR: u64 = 0x9000;
+const PDPT_ADDR: u64 = 0xa000;
+const PD_ADDR: u64 = 0xb000;
+const BOOT_AREA_END: u64 = PD_ADDR + 4 * 0x1000;
+
+// Selectors expected by the boot protocol (__BOOT_CS and __BOOT_DS)
+const BOOT_CS: u64 = 0x10;
+const BOOT_DS: u64 = 0x18;
+
+// Selector for the 32-bit code segment used by PVH entry
+const PVH_CS: u64 = 0x8;
+
+const GDT: [u64; 4] = [
+    0,
+    // 32-bit code: present, execute/read, 4k granularity
+    0x00cf_9b00_0000_ffff,
+    // 64-bit code: present, execute/read, long mode, 4k granularity
+    0x00af_9b00_0000_ffff,
+    // data: present, read/write, 32-bit, 4k granularity
+    0x00cf_9300_0000_ffff,
+];
+// Access rights (in the VMCS/VMCB format) for the descriptors above
+const CODE_ACCESS: u32 = 0xa09b;
+const CODE32_ACCESS: u32 = 0xc09b;
+const DATA_ACCESS: u32 = 0xc093;
+
+const PTE_P: u64 = 1 << 0;
+const PTE_RW: u64 = 1 << 1;
+const PTE_PS: u64 = 1 << 7;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_ET: u64 = 1 << 4;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+// Offsets within boot_params (the "zero page")
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_SETUP_SECTS: usize = 0x1f1;
+const BP_HDR_JUMP_LEN: usize = 0x201;
+const BP_HDR_MAGIC: usize = 0x202;
+const BP_VERSION: usize = 0x206;
+const BP_TYPE_OF_LOADER: usize = 0x210;
+const BP_RAMDISK_IMAGE: usize = 0x218;
+const BP_RAMDISK_SIZE: usize = 0x21c;
+const BP_CMD_LINE_PTR: usize = 0x228;
+const BP_INITRD_ADDR_MAX: usize = 0x22c;
+const BP_XLOADFLAGS: usize = 0x236;
+const BP_CMDLINE_SIZE: usize = 0x238;
+const BP_E820_TABLE: usize = 0x2d0;
+const BP_SIZE: usize = 0x1000;
+
+const HDR_MAGIC: &[u8; 4] = b"HdrS";
+// xloadflags (and the 64-bit entry point) arrived in protocol 2.12
+const HDR_MIN_VERSION: u16 = 0x020c;
+const XLF_KERNEL_64: u16 = 1 << 0;
+// Offset of the 64-bit entry point from the start of the protected-mode code
+const ENTRY_64_OFFSET: u64 = 0x200;
+
+const E820_RAM: u32 = 1;
+const LEGACY_HOLE_START: u64 = 0xa0000;
+const LEGACY_HOLE_END: u64 = 0x100000;
+
+// hvm_start_info (version 1) and the structures it points to, which are packed
+// into the page otherwise used for boot_params
+const PVH_START_INFO_MAGIC: u32 = 0x336e_c578;
+const PVH_START_INFO_VERSION: u32 = 1;
+const PVH_START_INFO_SIZE: usize = 56;
+const PVH_MODLIST_ENTRY_SIZE: usize = 32;
+const PVH_MEMMAP_ENTRY_SIZE: usize = 24;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+const ELF64_EHDR_SIZE: usize = 64;
+const ELF64_PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+/// A kernel image, as loaded into guest memory
+pub struct Kernel {
+    /// Setup header of a bzImage, destined for boot_params
+    setup_header: Option<Vec<u8>>,
+    /// Default 64-bit entry point
+    pub entry: u64,
+    /// End of the loaded image
+    end: u64,
+}
+
+fn read_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+fn read_u32(data: &[u8], off: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&data[off..(off + 4)]);
+    u32::from_le_bytes(buf)
+}
+fn read_u64(data: &[u8], off: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&data[off..(off + 8)]);
+    u64::from_le_bytes(buf)
+}
+fn put_u32(data: &mut [u8], off: usize, val: u32) {
+    data[off..(off + 4)].copy_from_slice(&val.to_le_bytes());
+}
+fn put_u64(data: &mut [u8], off: usize, val: u64) {
+    data[off..(off + 8)].copy_from_slice(&val.to_le_bytes());
+}
+
+/// Usable regions of guest memory, as (address, length) pairs
+fn memory_map(lowmem: u64) -> [(u64, u64); 2] {
+    [
+        (0, LEGACY_HOLE_START),
+        (LEGACY_HOLE_END, lowmem.saturating_sub(LEGACY_HOLE_END)),
+    ]
+}
+
+/// Write the NUL-terminated `cmdline` into guest memory at CMDLINE_ADDR.
+fn load_cmdline(mem: &MemCtx, cmdline: &str, max: usize) -> Result<()> {
+    if cmdline.len() > max {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cmdline exceeds {} bytes", max),
+        ));
+    }
+    let mut buf = cmdline.as_bytes().to_vec();
+    buf.push(0);
+    if mem.write_from(GuestAddr(CMDLINE_ADDR), &buf, buf.len())
+        != Some(buf.len())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot write cmdline",
+        ));
+    }
+    Ok(())
+}
+
+/// Load the initrd at `path` into the highest page-aligned location below
+/// `top`, yielding its address and size.  It must not extend below `floor`.
+fn load_initrd(
+    mem: &MemCtx,
+    path: &str,
+    top: u64,
+    floor: u64,
+) -> Result<(u64, u64)> {
+    let data = std::fs::read(path)?;
+    let addr = top.saturating_sub(data.len() as u64) & !0xfff;
+    if addr < floor
+        || mem.write_from(GuestAddr(addr), &data, data.len())
+            != Some(data.len())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "initrd {} ({:x} bytes) does not fit in memory",
+                path,
+                data.len()
+            ),
+        ));
+    }
+    Ok((addr, data.len() as u64))
+}
+
+/// Load the segments of a 64-bit ELF image at their physical addresses,
+/// returning the PVH entry point and the end of the loaded image.
+fn load_elf(mem: &MemCtx, data: &[u8]) -> Result<(u64, u64)> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg);
+    let phdrs = elf_phdrs(data).ok_or_else(|| invalid("malformed ELF"))?;
+
+    let mut entry = None;
+    let mut end = 0;
+    for ph in phdrs.iter() {
+        match ph.kind {
+            PT_LOAD => {
+                let (range, seg_end) = load_bounds(ph, data.len())?;
+                let file = &data[range];
+                let zeroed = ph.memsz.saturating_sub(ph.filesz);
+                let bss = GuestAddr(ph.paddr + ph.filesz as u64);
+                if mem.write_from(GuestAddr(ph.paddr), file, file.len())
+                    != Some(file.len())
+                    || !mem.write_bytes(bss, 0, zeroed)
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "ELF segment at {:x} does not fit in memory",
+                            ph.paddr
+                        ),
+                    ));
+                }
+                end = end.max(seg_end);
+            }
+            PT_NOTE => {
+                let notes = ph
+                    .offset
+                    .checked_add(ph.filesz)
+                    .and_then(|end| data.get(ph.offset..end))
+                    .ok_or_else(|| invalid("malformed ELF"))?;
+                entry = entry.or_else(|| pvh_entry(notes));
+            }
+            _ => {}
+        }
+    }
+    let entry = entry.ok_or_else(|| invalid("ELF lacks a PVH entry note"))?;
+    Ok((entry, end))
+}
+
+/// Check that the PT_LOAD segment `ph` lies within an image of `len` bytes,
+/// and is not loaded over the boot structures (including hvm_start_info).
+/// Returns the range of its contents within the image, and its end address.
+fn load_bounds(ph: &Phdr, len: usize) -> Result<(Range<usize>, u64)> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg);
+    let file_end = ph
+        .offset
+        .checked_add(ph.filesz)
+        .filter(|end| *end <= len)
+        .ok_or_else(|| invalid("malformed ELF"))?;
+    let mem_len = ph.memsz.max(ph.filesz) as u64;
+    let mem_end = ph
+        .paddr
+        .checked_add(mem_len)
+        .ok_or_else(|| invalid("ELF segment exceeds address space"))?;
+    if mem_len != 0 && ph.paddr < BOOT_AREA_END && mem_end > GDT_ADDR {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "ELF segment at {:x} overlaps boot structures ({:x}-{:x})",
+                ph.paddr, GDT_ADDR, BOOT_AREA_END
+            ),
+        ));
+    }
+    Ok((ph.offset..file_end, mem_end))
+}
+
+struct Phdr {
+    kind: u32,
+    offset: usize,
+    paddr: u64,
+    filesz: usize,
+    memsz: usize,
+}
+
+/// Program headers of a little-endian, x86_64 ELF64 image
+fn elf_phdrs(data: &[u8]) -> Option<Vec<Phdr>> {
+    if data.len() < ELF64_EHDR_SIZE
+        || &data[0..4] != ELF_MAGIC
+        || data[4] != ELFCLASS64
+        || data[5] != ELFDATA2LSB
+        || read_u16(data, 18) != EM_X86_64
+    {
+        return None;
+    }
+    let phoff = read_u64(data, 32) as usize;
+    let phentsize = read_u16(data, 54) as usize;
+    let phnum = read_u16(data, 56) as usize;
+    if phentsize < ELF64_PHDR_SIZE {
+        return None;
+    }
+    (0..phnum)
+        .map(|n| {
+            let off = phoff.checked_add(n * phentsize)?;
+            let ph = data.get(off..(off + ELF64_PHDR_SIZE))?;
+            Some(Phdr {
+                kind: read_u32(ph, 0),
+                offset: read_u64(ph, 8) as usize,
+                paddr: read_u64(ph, 24),
+                filesz: read_u64(ph, 32) as usize,
+                memsz: read_u64(ph, 40) as usize,
+            })
+        })
+        .collect()
+}
+
+/// Find the XEN_ELFNOTE_PHYS32_ENTRY note among `notes`
+fn pvh_entry(mut notes: &[u8]) -> Option<u64> {
+    let align = |n: usize| (n + 3) & !3;
+    while notes.len() >= 12 {
+        let namesz = read_u32(notes, 0) as usize;
+        let descsz = read_u32(notes, 4) as usize;
+        let kind = read_u32(notes, 8);
+        let desc_off = 12 + align(namesz);
+        let next = desc_off + align(descsz);
+        let name = notes.get(12..(12 + namesz))?;
+        let desc = notes.get(desc_off..(desc_off + descsz))?;
+        if name == b"Xen\0" && kind == XEN_ELFNOTE_PHYS32_ENTRY {
+            return match descsz {
+                4 => Some(read_u32(desc, 0) as u64),
+                8 => Some(read_u64(desc, 0)),
+                _ => None,
+            };
+        }
+        notes = notes.get(next..)?;
+    }
+    None
+}
+
+/// If `data` is a bzImage, return the offset of its protected-mode code and
+/// its setup header.
+fn parse_bzimage(data: &[u8]) -> Result<Option<(usize, &[u8])>> {
+    if data.len() < BP_SIZE
+        || &data[BP_HDR_MAGIC..(BP_HDR_MAGIC + 4)] != HDR_MAGIC
+    {
+        return Ok(None);
+    }
+    let invalid = |msg: &str| Err(Error::new(ErrorKind::InvalidData, msg));
+
+    let version = read_u16(data, BP_VERSION);
+    if version < HDR_MIN_VERSION {
+        return invalid("bzImage boot protocol too old");
+    }
+    if read_u16(data, BP_XLOADFLAGS) & XLF_KERNEL_64 == 0 {
+        return invalid("bzImage lacks a 64-bit entry point");
+    }
+    let setup_sects = match data[BP_SETUP_SECTS] {
+        0 => 4,
+        n => n as usize,
+    };
+    let code_off = (setup_sects + 1) * 512;
+    let hdr_end = BP_HDR_MAGIC + data[BP_HDR_JUMP_LEN] as usize;
+    if code_off >= data.len() || hdr_end > BP_SIZE {
+        return invalid("malformed bzImage");
+    }
+    Ok(Some((code_off, &data[BP_SETUP_SECTS..hdr_end])))
+}
+
+fn write_u64(mem: &MemCtx, addr: u64, val: u64) -> Result<()> {
+    if mem.write(GuestAddr(addr), &val) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot write boot structure at {:x}", addr),
+        ))
+    }
+}
+
+/// Copy the kernel image at `path` into guest memory at `load_addr`.
+///
+/// For a bzImage, only the protected-mode portion is loaded.
+pub fn load_kernel(mem: &MemCtx, path: &str, load_addr: u64) -> Result<Kernel> {
+    if load_addr < BOOT_AREA_END {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("kernel load_addr must be at or above {:x}", BOOT_AREA_END),
+        ));
+    }
+    let file = std::fs::read(path)?;
+    let (data, setup_header, entry) = match parse_bzimage(&file)? {
+        Some((off, hdr)) => {
+            (&file[off..], Some(hdr.to_vec()), load_addr + ENTRY_64_OFFSET)
+        }
+        None => (&file[..], None, load_addr),
+    };
+    match mem.write_from(GuestAddr(load_addr), data, data.len()) {
+        Some(n) if n == data.len() => Ok(Kernel {
+            setup_header,
+            entry,
+            end: load_addr + data.len() as u64,
+        }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "kernel {} ({:x} bytes) does not fit in memory at {:x}",
+                path,
+                data.len(),
+                load_addr
+            ),
+        )),
+    }
+}
+
+/// Populate the boot_params page for `kernel`, with an e820 map describing the
+/// `lowmem` bytes of guest memory, and the command line and initrd (if any).
+///
+/// The initrd is placed as high in memory as the kernel permits.
+pub fn setup_boot_params(
+    mem: &MemCtx,
+    kernel: &Kernel,
+    lowmem: u64,
+    cmdline: Option<&str>,
+    initrd: Option<&str>,
+) -> Result<()> {
+    let mut bp = vec![0u8; BP_SIZE];
+    let mut initrd_max = u32::MAX as u64;
+    let mut cmdline_max = CMDLINE_MAX - 1;
+    if let Some(hdr) = kernel.setup_header.as_ref() {
+        bp[BP_SETUP_SECTS..(BP_SETUP_SECTS + hdr.len())].copy_from_slice(hdr);
+        initrd_max = read_u32(&bp, BP_INITRD_ADDR_MAX) as u64;
+        cmdline_max = cmdline_max.min(read_u32(&bp, BP_CMDLINE_SIZE) as usize);
+    }
+    // An undefined loader, as far as the kernel is concerned
+    bp[BP_TYPE_OF_LOADER] = 0xff;
+
+    let e820 = memory_map(lowmem);
+    for (n, (addr, len)) in e820.iter().enumerate() {
+        let off = BP_E820_TABLE + n * 20;
+        put_u64(&mut bp, off, *addr);
+        put_u64(&mut bp, off + 8, *len);
+        put_u32(&mut bp, off + 16, E820_RAM);
+    }
+    bp[BP_E820_ENTRIES] = e820.len() as u8;
+
+    if let Some(cmdline) = cmdline {
+        load_cmdline(mem, cmdline, cmdline_max)?;
+        put_u32(&mut bp, BP_CMD_LINE_PTR, CMDLINE_ADDR as u32);
+    }
+
+    if let Some(path) = initrd {
+        let top = lowmem.min(initrd_max + 1);
+        let (addr, len) = load_initrd(mem, path, top, kernel.end)?;
+        put_u32(&mut bp, BP_RAMDISK_IMAGE, addr as u32);
+        put_u32(&mut bp, BP_RAMDISK_SIZE, len as u32);
+    }
+
+    if mem.write_from(GuestAddr(BOOT_PARAMS_ADDR), &bp, bp.len())
+        != Some(bp.len())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot write boot_params",
+        ));
+    }
+    Ok(())
+}
+
+/// Load the ELF kernel at `path` for PVH boot, along with its command line and
+/// initrd (if any), and place `vcpu` at its entry point in 32-bit protected
+/// mode, with %ebx pointing to the hvm_start_info structure.
+pub fn setup_pvh(
+    mem: &MemCtx,
+    vcpu: &mut VcpuHdl,
+    path: &str,
+    lowmem: u64,
+    cmdline: Option<&str>,
+    initrd: Option<&str>,
+) -> Result<()> {
+    let (entry, end) = load_elf(mem, &std::fs::read(path)?)?;
+    if end > lowmem.min(u32::MAX as u64) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("kernel {} does not fit in memory", path),
+        ));
+    }
+
+    let start_info = BOOT_PARAMS_ADDR;
+    let modlist_off = PVH_START_INFO_SIZE;
+    let memmap_off = modlist_off + PVH_MODLIST_ENTRY_SIZE;
+    let mut si = vec![0u8; BP_SIZE];
+    put_u32(&mut si, 0, PVH_START_INFO_MAGIC);
+    put_u32(&mut si, 4, PVH_START_INFO_VERSION);
+
+    if let Some(cmdline) = cmdline {
+        load_cmdline(mem, cmdline, CMDLINE_MAX - 1)?;
+        put_u64(&mut si, 24, CMDLINE_ADDR);
+    }
+    if let Some(path) = initrd {
+        let top = lowmem.min(u32::MAX as u64 + 1);
+        let (addr, len) = load_initrd(mem, path, top, end)?;
+        put_u64(&mut si, modlist_off, addr);
+        put_u64(&mut si, modlist_off + 8, len);
+        put_u32(&mut si, 12, 1);
+        put_u64(&mut si, 16, start_info + modlist_off as u64);
+    }
+
+    let memmap = memory_map(lowmem);
+    for (n, (addr, len)) in memmap.iter().enumerate() {
+        let off = memmap_off + n * PVH_MEMMAP_ENTRY_SIZE;
+        put_u64(&mut si, off, *addr);
+        put_u64(&mut si, off + 8, *len);
+        put_u32(&mut si, off + 16, E820_RAM);
+    }
+    put_u64(&mut si, 40, start_info + memmap_off as u64);
+    put_u32(&mut si, 48, memmap.len() as u32);
+
+    if mem.write_from(GuestAddr(start_info), &si, si.len()) != Some(si.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot write hvm_start_info",
+        ));
+    }
+
+    write_gdt(mem, vcpu)?;
+    let code = bhyve_api::seg_desc {
+        base: 0,
+        limit: 0xffff_ffff,
+        access: CODE32_ACCESS,
+    };
+    vcpu.set_segreg(VM_REG_GUEST_CS, &code)?;
+    vcpu.set_reg(VM_REG_GUEST_CS, PVH_CS)?;
+    set_data_segs(vcpu)?;
+
+    vcpu.set_reg(VM_REG_GUEST_CR4, 0)?;
+    vcpu.set_reg(VM_REG_GUEST_EFER, 0)?;
+    vcpu.set_reg(VM_REG_GUEST_CR0, CR0_PE | CR0_ET)?;
+
+    vcpu.set_reg(VM_REG_GUEST_RFLAGS, 0x2)?;
+    vcpu.set_reg(VM_REG_GUEST_RBX, start_info)?;
+    vcpu.set_reg(VM_REG_GUEST_RIP, entry)?;
+    Ok(())
+}
+
+fn write_gdt(mem: &MemCtx, vcpu: &mut VcpuHdl) -> Result<()> {
+    for (n, desc) in GDT.iter().enumerate() {
+        write_u64(mem, GDT_ADDR + n as u64 * 8, *desc)?;
+    }
+    let gdtr = bhyve_api::seg_desc {
+        base: GDT_ADDR,
+        limit: (GDT.len() * 8 - 1) as u32,
+        access: 0,
+    };
+    vcpu.set_segreg(VM_REG_GUEST_GDTR, &gdtr)
+}
+
+fn set_data_segs(vcpu: &mut VcpuHdl) -> Result<()> {
+    let data = bhyve_api::seg_desc {
+        base: 0,
+        limit: 0xffff_ffff,
+        access: DATA_ACCESS,
+    };
+    for seg in [VM_REG_GUEST_DS, VM_REG_GUEST_ES, VM_REG_GUEST_SS].iter() {
+        vcpu.set_segreg(*seg, &data)?;
+        vcpu.set_reg(*seg, BOOT_DS)?;
+    }
+    Ok(())
+}
+
+/// Set up the GDT and identity-mapped page tables (covering the low 4GiB),
+/// then place `vcpu` in 64-bit mode at `entry`.
+///
+/// As the protocol specifies, %rsi points to the boot_params structure, which
+/// is expected to be populated by [setup_boot_params].
+pub fn setup_long_mode(
+    mem: &MemCtx,
+    vcpu: &mut VcpuHdl,
+    entry: u64,
+) -> Result<()> {
+    write_gdt(mem, vcpu)?;
+
+    // 2MiB pages throughout, with one PD for each GiB
+    write_u64(mem, PML4_ADDR, PDPT_ADDR | PTE_P | PTE_RW)?;
+    for pdpte in 0..4u64 {
+        let pd = PD_ADDR + pdpte * 0x1000;
+        write_u64(mem, PDPT_ADDR + pdpte * 8, pd | PTE_P | PTE_RW)?;
+        for pde in 0..512u64 {
+            let addr = ((pdpte << 9) | pde) << 21;
+            write_u64(mem, pd + pde * 8, addr | PTE_P | PTE_RW | PTE_PS)?;
+        }
+    }
+
+    let code = bhyve_api::seg_desc {
+        base: 0,
+        limit: 0xffff_ffff,
+        access: CODE_ACCESS,
+    };
+    vcpu.set_segreg(VM_REG_GUEST_CS, &code)?;
+    vcpu.set_reg(VM_REG_GUEST_CS, BOOT_CS)?;
+    set_data_segs(vcpu)?;
+
+    vcpu.set_reg(VM_REG_GUEST_CR3, PML4_ADDR)?;
+    vcpu.set_reg(VM_REG_GUEST_CR4, CR4_PAE)?;
+    vcpu.set_reg(VM_REG_GUEST_EFER, EFER_LME | EFER_LMA)?;
+    vcpu.set_reg(VM_REG_GUEST_CR0, CR0_PE | CR0_ET | CR0_PG)?;
+
+    // Interrupts disabled, with only the always-set bit
+    vcpu.set_reg(VM_REG_GUEST_RFLAGS, 0x2)?;
+    vcpu.set_reg(VM_REG_GUEST_RSI, BOOT_PARAMS_ADDR)?;
+    vcpu.set_reg(VM_REG_GUEST_RIP, entry)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bzimage_header() {
+        let mut img = vec![0u8; 0x4000];
+        assert!(parse_bzimage(&img).unwrap().is_none());
+
+        img[BP_SETUP_SECTS] = 0x1d;
+        img[BP_HDR_JUMP_LEN] = 0x6a;
+        img[BP_HDR_MAGIC..(BP_HDR_MAGIC + 4)].copy_from_slice(HDR_MAGIC);
+        img[BP_VERSION..(BP_VERSION + 2)].copy_from_slice(&[0x0b, 0x02]);
+        assert!(parse_bzimage(&img).is_err(), "protocol 2.11 rejected");
+
+        img[BP_VERSION..(BP_VERSION + 2)].copy_from_slice(&[0x0f, 0x02]);
+        assert!(parse_bzimage(&img).is_err(), "32-bit only kernel rejected");
+
+        img[BP_XLOADFLAGS] = XLF_KERNEL_64 as u8;
+        let (off, hdr) = parse_bzimage(&img).unwrap().unwrap();
+        assert_eq!(off, 0x1e * 512);
+        assert_eq!(hdr.len(), 0x26c - BP_SETUP_SECTS);
+        assert_eq!(hdr[0], 0x1d);
+    }
+
+    #[test]
+    fn pvh_note() {
+        let note = |name: &[u8], kind: u32, desc: &[u8]| {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&kind.to_le_bytes());
+            buf.extend_from_slice(name);
+            buf.resize((buf.len() + 3) & !3, 0);
+            buf.extend_from_slice(desc);
+            buf.resize((buf.len() + 3) & !3, 0);
+            buf
+        };
+        let mut notes = note(b"GNU\0", 3, &[1, 2, 3, 4, 5, 6]);
+        notes.extend(note(b"Xen\0", 6, b"linux\0"));
+        assert_eq!(pvh_entry(&notes), None);
+
+        notes.extend(note(
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &0x100_1000u64.to_le_bytes(),
+        ));
+        assert_eq!(pvh_entry(&notes), Some(0x100_1000));
+
+        let short = note(b"Xen\0", XEN_ELFNOTE_PHYS32_ENTRY, &[0, 0x10, 0, 1]);
+        assert_eq!(pvh_entry(&short), Some(0x100_1000));
+        assert_eq!(pvh_entry(&short[..short.len() - 2]), None);
+    }
+
+    #[test]
+    fn elf_headers() {
+        let mut img = vec![0u8; 0x200];
+        assert!(elf_phdrs(&img).is_none());
+
+        img[0..4].copy_from_slice(ELF_MAGIC);
+        img[4] = ELFCLASS64;
+        img[5] = ELFDATA2LSB;
+        img[18] = EM_X86_64 as u8;
+        put_u64(&mut img, 32, 0x40);
+        img[54] = ELF64_PHDR_SIZE as u8;
+        img[56] = 2;
+
+        let ph = 0x40 + ELF64_PHDR_SIZE;
+        put_u32(&mut img, ph, PT_LOAD);
+        put_u64(&mut img, ph + 8, 0x100);
+        put_u64(&mut img, ph + 24, 0x100_0000);
+        put_u64(&mut img, ph + 32, 0x80);
+        put_u64(&mut img, ph + 40, 0x1000);
+
+        let phdrs = elf_phdrs(&img).unwrap();
+        assert_eq!(phdrs.len(), 2);
+        assert_eq!(phdrs[0].kind, 0);
+        assert_eq!(phdrs[1].kind, PT_LOAD);
+        assert_eq!(phdrs[1].offset, 0x100);
+        assert_eq!(phdrs[1].paddr, 0x100_0000);
+        assert_eq!((phdrs[1].filesz, phdrs[1].memsz), (0x80, 0x1000));
+
+        img[56] = 9;
+        assert!(elf_phdrs(&img).is_none(), "headers beyond image");
+        img[56] = 2;
+        img[4] = 1;
+        assert!(elf_phdrs(&img).is_none(), "32-bit ELF");
+    }
+
+    #[test]
+    fn elf_load_bounds() {
+        let seg = |offset: usize, paddr: u64, filesz: usize, memsz: usize| {
+            Phdr { kind: PT_LOAD, offset, paddr, filesz, memsz }
+        };
+        assert_eq!(
+            load_bounds(&seg(0x100, 0x100_0000, 0x80, 0x1000), 0x200).unwrap(),
+            (0x100..0x180, 0x100_1000)
+        );
+
+        // Contents beyond the image, or wrapping around
+        assert!(load_bounds(&seg(0x100, 0x100_0000, 0x101, 0), 0x200).is_err());
+        assert!(load_bounds(&seg(usize::MAX, 0x100_0000, 2, 0), 0x200).is_err());
+        assert!(load_bounds(&seg(0, u64::MAX - 0x10, 0, 0x100), 0x200).is_err());
+
+        // Segments over the boot structures, such as hvm_start_info
+        assert!(
+            load_bounds(&seg(0, BOOT_PARAMS_ADDR, 0x10, 0x10), 0x200).is_err()
+        );
+        assert!(load_bounds(&seg(0, 0, 0x10, 0x600), 0x200).is_err());
+        assert!(load_bounds(&seg(0, BOOT_AREA_END - 1, 0, 1), 0x200).is_err());
+        assert!(load_bounds(&seg(0, 0, 0x10, GDT_ADDR as usize), 0x200).is_ok());
+        assert!(load_bounds(&seg(0, BOOT_AREA_END, 0x10, 0x10), 0x200).is_ok());
+    }
+}
@@ -3,7 +3,9 @@ use std::str::FromStr;
 
 use serde_derive::Deserialize;
 
+use crate::hw::chipset::q35::MAX_ROOT_PORTS;
 use crate::hw::pci;
+use crate::vmm::CpuTopology;
 
 #[derive(Deserialize, Debug)]
 struct Top {
@@ -11,14 +13,110 @@ struct Top {
 
     #[serde(default, rename = "dev")]
     devices: BTreeMap<String, Device>,
+
+    #[serde(default)]
+    boot: Boot,
+
+    #[serde(default)]
+    console: Console,
 }
 
 #[derive(Deserialize, Debug)]
 struct Main {
     name: String,
     cpus: u8,
-    bootrom: String,
+    /// Cores in each socket, and threads in each core, of the vCPUs.  The
+    /// vCPUs fill as many sockets as are required.
+    cores: Option<u8>,
+    threads: Option<u8>,
+    bootrom: Option<String>,
     memory: usize,
+
+    /// System UUID and serial number, as presented to the guest via SMBIOS
+    uuid: Option<String>,
+    serial: Option<String>,
+
+    #[serde(default)]
+    chipset: ChipsetKind,
+
+    /// PCIe root ports (q35 only).  The device behind root port `n` is
+    /// attached at bus `n + 1` of its `pci-path`.
+    #[serde(default)]
+    root_ports: u8,
+
+    /// Provide ACPI tables to firmware, rather than relying on its own
+    #[serde(default)]
+    acpi_tables: bool,
+
+    /// Attach a pvpanic device, so guest panics are reported to the host
+    #[serde(default)]
+    pvpanic: bool,
+
+    /// Offer the S3 (suspend-to-RAM) sleep state to the guest
+    #[serde(default)]
+    s3: bool,
+
+    /// Devices (by name) in the order firmware should attempt to boot them
+    #[serde(default)]
+    boot_order: Vec<String>,
+}
+
+/// Chipset presented to the guest
+#[derive(Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChipsetKind {
+    #[default]
+    I440fx,
+    Q35,
+}
+
+#[derive(Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BootKind {
+    #[default]
+    Firmware,
+    Kernel,
+    Pvh,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Boot {
+    #[serde(default)]
+    mode: BootKind,
+    kernel: Option<String>,
+    initrd: Option<String>,
+    cmdline: Option<String>,
+    load_addr: Option<u64>,
+    entry: Option<u64>,
+}
+
+/// Serial console (COM1) socket options
+#[derive(Deserialize, Debug, Default, Copy, Clone)]
+pub struct Console {
+    /// Bytes of recent output replayed to each newly connected client
+    #[serde(default)]
+    pub scrollback: usize,
+    /// Read-only viewers accepted alongside the read-write client
+    #[serde(default)]
+    pub viewers: usize,
+}
+
+/// How the guest is to be booted
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BootMode {
+    /// Start at the reset vector of the bootrom
+    Firmware,
+    /// Load a kernel image directly into guest memory and jump to its entry
+    /// (which, absent an override, is determined by the image)
+    Kernel {
+        image: String,
+        initrd: Option<String>,
+        cmdline: Option<String>,
+        load_addr: u64,
+        entry: Option<u64>,
+    },
+    /// Load an ELF kernel and enter it at its PVH entry point
+    Pvh { image: String, initrd: Option<String>, cmdline: Option<String> },
 }
 
 #[derive(Deserialize, Debug)]
@@ -39,11 +137,47 @@ impl Config {
     pub fn get_cpus(&self) -> u8 {
         self.inner.main.cpus
     }
+    pub fn get_topology(&self) -> CpuTopology {
+        // Validated at parse time
+        cpu_topology(&self.inner).unwrap()
+    }
     pub fn get_mem(&self) -> usize {
         self.inner.main.memory
     }
-    pub fn get_bootrom(&self) -> &String {
-        &self.inner.main.bootrom
+    pub fn get_bootrom(&self) -> Option<&String> {
+        self.inner.main.bootrom.as_ref()
+    }
+    pub fn get_boot(&self) -> BootMode {
+        // Validated at parse time
+        boot_mode(&self.inner).unwrap()
+    }
+    pub fn get_boot_order(&self) -> &[String] {
+        &self.inner.main.boot_order
+    }
+    pub fn get_uuid(&self) -> Option<[u8; 16]> {
+        // Validated at parse time
+        self.inner.main.uuid.as_ref().map(|u| parse_uuid(u).unwrap())
+    }
+    pub fn get_serial(&self) -> Option<&String> {
+        self.inner.main.serial.as_ref()
+    }
+    pub fn get_chipset(&self) -> ChipsetKind {
+        self.inner.main.chipset
+    }
+    pub fn get_root_ports(&self) -> u8 {
+        self.inner.main.root_ports
+    }
+    pub fn get_acpi_tables(&self) -> bool {
+        self.inner.main.acpi_tables
+    }
+    pub fn get_pvpanic(&self) -> bool {
+        self.inner.main.pvpanic
+    }
+    pub fn get_s3(&self) -> bool {
+        self.inner.main.s3
+    }
+    pub fn get_console(&self) -> Console {
+        self.inner.console
     }
     pub fn devs(&self) -> IterDevs {
         IterDevs { inner: self.inner.devices.iter() }
@@ -60,9 +194,121 @@ impl<'a> Iterator for IterDevs<'a> {
     }
 }
 
+fn boot_mode(top: &Top) -> Result<BootMode, &'static str> {
+    let boot = &top.boot;
+    match boot.mode {
+        BootKind::Firmware => {
+            if top.main.bootrom.is_none() {
+                return Err("firmware boot requires a bootrom");
+            }
+            Ok(BootMode::Firmware)
+        }
+        BootKind::Kernel => {
+            let image =
+                boot.kernel.as_ref().ok_or("kernel boot requires kernel")?;
+            let load_addr =
+                boot.load_addr.ok_or("kernel boot requires load_addr")?;
+            Ok(BootMode::Kernel {
+                image: image.clone(),
+                initrd: boot.initrd.clone(),
+                cmdline: boot.cmdline.clone(),
+                load_addr,
+                entry: boot.entry,
+            })
+        }
+        BootKind::Pvh => {
+            let image =
+                boot.kernel.as_ref().ok_or("pvh boot requires kernel")?;
+            Ok(BootMode::Pvh {
+                image: image.clone(),
+                initrd: boot.initrd.clone(),
+                cmdline: boot.cmdline.clone(),
+            })
+        }
+    }
+}
+
+fn check_chipset(top: &Top) -> Result<(), &'static str> {
+    if top.main.chipset != ChipsetKind::I440fx
+        && top.devices.values().any(|d| d.driver == "piix3-ide")
+    {
+        return Err("piix3-ide requires the i440fx chipset");
+    }
+    if top.main.chipset != ChipsetKind::Q35 && top.main.root_ports != 0 {
+        return Err("root_ports requires the q35 chipset");
+    }
+    if top.main.root_ports > MAX_ROOT_PORTS {
+        return Err("too many root_ports");
+    }
+    for dev in top.devices.values().filter(|d| d.driver.starts_with("pci-")) {
+        let bdf = match dev.options.get("pci-path").and_then(|p| p.as_str()) {
+            Some(path) => parse_bdf(path).ok_or("malformed pci-path")?,
+            None => return Err("pci device lacks a pci-path"),
+        };
+        // Behind root port `n` lies bus `n + 1`, with a single slot
+        if bdf.bus() != 0 && (bdf.bus() > top.main.root_ports || bdf.dev() != 0)
+        {
+            return Err("pci-path not behind a root port");
+        }
+    }
+    // Guests learn of S3 only through the tables generated by Propolis
+    if top.main.s3 && !top.main.acpi_tables {
+        return Err("s3 requires acpi_tables");
+    }
+    // Resuming from S3 is done by the firmware, via its wake vector
+    if top.main.s3 && top.boot.mode != BootKind::Firmware {
+        return Err("s3 requires firmware boot");
+    }
+    Ok(())
+}
+
+fn cpu_topology(top: &Top) -> Result<CpuTopology, String> {
+    let cpus = top.main.cpus as u32;
+    let cores = top.main.cores.unwrap_or(1);
+    let threads = top.main.threads.unwrap_or(1);
+    let per_socket = cores as u32 * threads as u32;
+    let sockets = cpus.checked_div(per_socket).unwrap_or(0);
+    if sockets * per_socket != cpus {
+        return Err("cpus must be a multiple of cores * threads".to_string());
+    }
+    CpuTopology::new(sockets as u8, cores, threads)
+        .map_err(|e| format!("invalid cpu topology: {}", e))
+}
+
+fn check_boot_order(top: &Top) -> Result<(), String> {
+    for name in top.main.boot_order.iter() {
+        if !top.devices.contains_key(name) {
+            return Err(format!("boot_order device {} not defined", name));
+        }
+    }
+    Ok(())
+}
+
 pub fn parse(path: &str) -> Config {
     let file_data = std::fs::read(path).unwrap();
     let top = toml::from_slice::<Top>(&file_data).unwrap();
+    if let Err(e) = boot_mode(&top) {
+        eprintln!("invalid config {}: {}", path, e);
+        std::process::exit(libc::EXIT_FAILURE);
+    }
+    if let Err(e) = check_chipset(&top) {
+        eprintln!("invalid config {}: {}", path, e);
+        std::process::exit(libc::EXIT_FAILURE);
+    }
+    if let Err(e) = cpu_topology(&top) {
+        eprintln!("invalid config {}: {}", path, e);
+        std::process::exit(libc::EXIT_FAILURE);
+    }
+    if let Err(e) = check_boot_order(&top) {
+        eprintln!("invalid config {}: {}", path, e);
+        std::process::exit(libc::EXIT_FAILURE);
+    }
+    if let Some(uuid) = top.main.uuid.as_ref() {
+        if parse_uuid(uuid).is_none() {
+            eprintln!("invalid config {}: malformed uuid {}", path, uuid);
+            std::process::exit(libc::EXIT_FAILURE);
+        }
+    }
     Config { inner: top }
 }
 
@@ -82,3 +328,220 @@ pub fn parse_bdf(v: &str) -> Option<pci::BDF> {
         None
     }
 }
+
+/// Parse a MAC address in the colon-separated hex form (`02:08:20:ab:cd:ef`)
+pub fn parse_mac(v: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut fields = v.split(':');
+    for b in mac.iter_mut() {
+        let f = fields.next()?;
+        if f.is_empty() || f.len() > 2 {
+            return None;
+        }
+        *b = u8::from_str_radix(f, 16).ok()?;
+    }
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+/// Parse a UUID in its hyphenated hex form
+/// (`01234567-89ab-cdef-0123-456789abcdef`) into RFC 4122 byte order
+pub fn parse_uuid(v: &str) -> Option<[u8; 16]> {
+    let groups: Vec<&str> = v.split('-').collect();
+    let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    if lens != [8, 4, 4, 4, 12] {
+        return None;
+    }
+    let hex = groups.concat();
+    // from_str_radix() would also accept a sign, such as in "+f"
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut uuid = [0u8; 16];
+    for (n, b) in uuid.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(n * 2..n * 2 + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn boot_for(boot: &str) -> Result<BootMode, &'static str> {
+        let data = format!(
+            "[main]\nname = \"test\"\ncpus = 1\nmemory = 512\n{}",
+            boot
+        );
+        let top = toml::from_str::<Top>(&data).unwrap();
+        boot_mode(&top)
+    }
+
+    #[test]
+    fn boot_validate() {
+        assert_eq!(boot_for("bootrom = \"/rom\""), Ok(BootMode::Firmware));
+        assert!(boot_for("").is_err(), "firmware boot without bootrom");
+
+        assert_eq!(
+            boot_for("[boot]\nmode = \"kernel\"\nkernel = \"/k\"\nload_addr = 0x100000"),
+            Ok(BootMode::Kernel {
+                image: "/k".to_string(),
+                initrd: None,
+                cmdline: None,
+                load_addr: 0x100000,
+                entry: None
+            })
+        );
+        assert_eq!(
+            boot_for("[boot]\nmode = \"kernel\"\nkernel = \"/k\"\nload_addr = 0x100000\nentry = 0x100200"),
+            Ok(BootMode::Kernel {
+                image: "/k".to_string(),
+                initrd: None,
+                cmdline: None,
+                load_addr: 0x100000,
+                entry: Some(0x100200)
+            })
+        );
+        assert_eq!(
+            boot_for("[boot]\nmode = \"kernel\"\nkernel = \"/k\"\ninitrd = \"/i\"\ncmdline = \"console=ttyS0\"\nload_addr = 0x1000000"),
+            Ok(BootMode::Kernel {
+                image: "/k".to_string(),
+                initrd: Some("/i".to_string()),
+                cmdline: Some("console=ttyS0".to_string()),
+                load_addr: 0x1000000,
+                entry: None
+            })
+        );
+        assert!(boot_for("[boot]\nmode = \"kernel\"\nload_addr = 0x100000")
+            .is_err());
+        assert!(boot_for("[boot]\nmode = \"kernel\"\nkernel = \"/k\"").is_err());
+
+        assert_eq!(
+            boot_for("[boot]\nmode = \"pvh\"\nkernel = \"/k\""),
+            Ok(BootMode::Pvh {
+                image: "/k".to_string(),
+                initrd: None,
+                cmdline: None
+            })
+        );
+        assert!(boot_for("[boot]\nmode = \"pvh\"").is_err());
+    }
+
+    #[test]
+    fn boot_order_names() {
+        let parse = |extra: &str| {
+            let data = format!(
+                "[main]\nname = \"test\"\ncpus = 1\nmemory = 512\n{}\n\
+                [dev.block0]\ndriver = \"pci-virtio-block\"\n",
+                extra
+            );
+            toml::from_str::<Top>(&data).unwrap()
+        };
+        assert!(check_boot_order(&parse("")).is_ok());
+        assert!(check_boot_order(&parse("boot_order = [\"block0\"]")).is_ok());
+        assert!(check_boot_order(&parse("boot_order = [\"block1\"]")).is_err());
+    }
+
+    #[test]
+    fn chipset_select() {
+        let parse = |extra: &str| {
+            let data = format!(
+                "[main]\nname = \"test\"\ncpus = 1\nmemory = 512\n{}\n\
+                [dev.ide]\ndriver = \"piix3-ide\"\n",
+                extra
+            );
+            toml::from_str::<Top>(&data).unwrap()
+        };
+        let top = parse("");
+        assert_eq!(top.main.chipset, ChipsetKind::I440fx);
+        assert!(check_chipset(&top).is_ok());
+        assert!(check_chipset(&parse("acpi_tables = true")).is_ok());
+        assert!(check_chipset(&parse("s3 = true")).is_err());
+        assert!(check_chipset(&parse("acpi_tables = true\ns3 = true")).is_ok());
+        assert!(check_chipset(&parse(
+            "acpi_tables = true\ns3 = true\n[boot]\nmode = \"kernel\""
+        ))
+        .is_err());
+
+        let top = parse("chipset = \"q35\"");
+        assert_eq!(top.main.chipset, ChipsetKind::Q35);
+        assert!(check_chipset(&top).is_err(), "no IDE on q35");
+        assert!(check_chipset(&parse("root_ports = 1")).is_err());
+
+        let parse_q35 = |extra: &str, path: &str| {
+            let data = format!(
+                "[main]\nname = \"test\"\ncpus = 1\nmemory = 512\n\
+                chipset = \"q35\"\n{}\n\
+                [dev.net]\ndriver = \"pci-virtio-viona\"\npci-path = \"{}\"\n",
+                extra, path
+            );
+            toml::from_str::<Top>(&data).unwrap()
+        };
+        assert!(
+            check_chipset(&parse_q35("acpi_tables = true", "0.5.0")).is_ok()
+        );
+        assert!(check_chipset(&parse_q35("root_ports = 7", "0.5.0")).is_err());
+        assert!(check_chipset(&parse_q35("", "1.0.0")).is_err());
+        assert!(check_chipset(&parse_q35("root_ports = 2", "2.0.3")).is_ok());
+        assert!(check_chipset(&parse_q35("root_ports = 2", "2.1.0")).is_err());
+        assert!(check_chipset(&parse_q35("root_ports = 2", "3.0.0")).is_err());
+    }
+
+    #[test]
+    fn topology_select() {
+        let topo = |extra: &str| {
+            let data =
+                format!("[main]\nname = \"test\"\nmemory = 512\n{}", extra);
+            cpu_topology(&toml::from_str::<Top>(&data).unwrap())
+        };
+        assert_eq!(topo("cpus = 4"), Ok(CpuTopology::flat(4)));
+        assert_eq!(
+            topo("cpus = 8\ncores = 2\nthreads = 2"),
+            Ok(CpuTopology::new(2, 2, 2).unwrap())
+        );
+        assert_eq!(
+            topo("cpus = 4\ncores = 4"),
+            Ok(CpuTopology::new(1, 4, 1).unwrap())
+        );
+        assert!(topo("cpus = 6\ncores = 4").is_err());
+        assert!(topo("cpus = 6\ncores = 3").is_err(), "cores not a power of 2");
+        assert!(topo("cpus = 4\nthreads = 0").is_err());
+        assert!(topo("cpus = 0").is_err());
+        assert!(topo("cpus = 64").is_err());
+    }
+
+    #[test]
+    fn mac_parse() {
+        assert_eq!(
+            parse_mac("02:08:20:ab:cd:ef"),
+            Some([0x02, 0x08, 0x20, 0xab, 0xcd, 0xef])
+        );
+        assert_eq!(
+            parse_mac("2:8:20:AB:CD:EF"),
+            parse_mac("02:08:20:ab:cd:ef")
+        );
+        assert_eq!(parse_mac("02:08:20:ab:cd"), None);
+        assert_eq!(parse_mac("02:08:20:ab:cd:ef:01"), None);
+        assert_eq!(parse_mac("02:08:20:ab::ef"), None);
+        assert_eq!(parse_mac("02:08:20:ab:cd:1ef"), None);
+        assert_eq!(parse_mac("02-08-20-ab-cd-ef"), None);
+    }
+
+    #[test]
+    fn uuid_parse() {
+        assert_eq!(
+            parse_uuid("01234567-89ab-cdef-0123-456789ABCDEF"),
+            Some([
+                0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x01, 0x23,
+                0x45, 0x67, 0x89, 0xab, 0xcd, 0xef
+            ])
+        );
+        assert_eq!(parse_uuid("0123456789abcdef0123456789abcdef"), None);
+        assert_eq!(parse_uuid("01234567-89ab-cdef-0123-456789abcde"), None);
+        assert_eq!(parse_uuid("01234567-89ab-cdef-0123-456789abcdeg"), None);
+        assert_eq!(parse_uuid("01234567-89ab-cdef-0123-45678+abcdef"), None);
+        assert_eq!(parse_uuid("01234567-89ab-cdef-0123-4567+fabcdef"), None);
+    }
+}
@@ -1,28 +1,55 @@
+extern crate flate2;
 extern crate pico_args;
 extern crate propolis;
 extern crate serde;
 extern crate serde_derive;
 extern crate toml;
 
-use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Result};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
 
 use propolis::chardev::{Sink, Source};
 use propolis::dispatch::*;
+use propolis::exits::SuspendReason;
 use propolis::hw::chipset::Chipset;
-use propolis::vmm::{Builder, Machine, MachineCtx, Prot};
+use propolis::hw::rtc::Rtc;
+use propolis::hw::Lifecycle;
+use propolis::vmm::{Builder, Machine, MachineCtx};
 use propolis::*;
 
+mod boot;
 mod config;
+mod rom;
 
-const PAGE_OFFSET: u64 = 0xfff;
 // Arbitrary ROM limit for now
 const MAX_ROM_SIZE: usize = 0x20_0000;
 
+/// Initiator name used for iSCSI sessions, unless otherwise configured
+const DEFAULT_INITIATOR_IQN: &str = "iqn.2021-01.org.propolis:initiator";
+
+fn print_version() {
+    println!(
+        "propolis-cli {} (commit {}, bhyve_api {})",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_COMMIT"),
+        bhyve_api::API_VERSION,
+    );
+    match vmm::check_api() {
+        Ok(()) => println!("vmm: supported"),
+        Err(e) => println!("vmm: unavailable ({})", e),
+    }
+}
+
 fn parse_args() -> config::Config {
-    let args = pico_args::Arguments::from_env();
+    let mut args = pico_args::Arguments::from_env();
+    if args.contains(["-V", "--version"]) {
+        print_version();
+        std::process::exit(0);
+    }
     if let Some(cpath) = args.free().ok().map(|mut f| f.pop()).flatten() {
         config::parse(&cpath)
     } else {
@@ -31,76 +58,552 @@ fn parse_args() -> config::Config {
     }
 }
 
-fn build_vm(name: &str, max_cpu: u8, lowmem: usize) -> Result<Arc<Machine>> {
+fn build_vm(
+    name: &str,
+    topology: vmm::CpuTopology,
+    lowmem: usize,
+) -> Result<Arc<Machine>> {
     let vm = Builder::new(name, true)?
-        .max_cpus(max_cpu)?
-        .add_mem_region(0, lowmem, Prot::ALL, "lowmem")?
-        .add_rom_region(
-            0x1_0000_0000 - MAX_ROM_SIZE,
-            MAX_ROM_SIZE,
-            Prot::READ | Prot::EXEC,
-            "bootrom",
-        )?
-        .add_mmio_region(0xc0000000_usize, 0x20000000_usize, "dev32")?
-        .add_mmio_region(0xe0000000_usize, 0x10000000_usize, "pcicfg")?
-        .add_mmio_region(
-            vmm::MAX_SYSMEM,
-            vmm::MAX_PHYSMEM - vmm::MAX_SYSMEM,
-            "dev64",
-        )?
+        .max_cpus(topology.cpus())?
+        .cpu_topology(topology)?
+        .add_layout(&vmm::standard_layout(lowmem, MAX_ROM_SIZE))?
         .finalize()?;
     Ok(vm)
 }
 
-fn open_bootrom(path: &str) -> Result<(File, usize)> {
-    let fp = File::open(path)?;
-    let len = fp.metadata()?.len();
-    if len & PAGE_OFFSET != 0 {
-        Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "rom {} length {:x} not aligned to {:x}",
-                path,
-                len,
-                PAGE_OFFSET + 1
-            ),
-        ))
-    } else {
-        Ok((fp, len as usize))
+/// Block sizes requested for a virtio-block device, if any
+fn block_topology(
+    dev: &config::Device,
+) -> std::result::Result<Option<hw::virtio::block::BlockTopology>, &'static str>
+{
+    let get = |key| match dev.options.get(key) {
+        None => Ok(None),
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .map(Some)
+            .ok_or("block size must be an integer"),
+    };
+    let logical = get("logical_block_size")?;
+    let physical = get("physical_block_size")?;
+    if logical.is_none() && physical.is_none() {
+        return Ok(None);
+    }
+    let logical = logical.unwrap_or(512);
+    let physical = physical.unwrap_or(logical);
+    hw::virtio::block::BlockTopology::new(logical, physical).map(Some)
+}
+
+fn block_queues(dev: &config::Device) -> std::result::Result<u16, String> {
+    use hw::virtio::block::MAX_QUEUES;
+
+    match dev.options.get("num_queues") {
+        None => Ok(1),
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u16::try_from(v).ok())
+            .filter(|n| *n > 0 && *n <= MAX_QUEUES)
+            .ok_or_else(|| {
+                format!(
+                    "num_queues must be an integer between 1 and {}",
+                    MAX_QUEUES
+                )
+            }),
+    }
+}
+
+/// Entropy source and rate limit (in bytes/sec) for a virtio-rng device
+fn rng_config(
+    dev: &config::Device,
+) -> std::result::Result<(hw::virtio::rng::RngSource, Option<u64>), &'static str>
+{
+    use hw::virtio::rng::RngSource;
+
+    let source = match dev.options.get("source") {
+        None => RngSource::Path("/dev/random".into()),
+        Some(v) => match v.as_str().ok_or("source must be a string")? {
+            "getrandom" => RngSource::GetRandom,
+            path => RngSource::Path(path.into()),
+        },
+    };
+    let rate = match dev.options.get("rate") {
+        None => None,
+        Some(v) => Some(
+            v.as_integer()
+                .and_then(|v| u64::try_from(v).ok())
+                .filter(|r| *r > 0)
+                .ok_or("rate must be a positive integer")?,
+        ),
+    };
+    Ok((source, rate))
+}
+
+/// Guest CID and port mappings for a virtio-vsock device
+fn vsock_config(
+    dev: &config::Device,
+) -> std::result::Result<hw::virtio::vsock::VsockConfig, &'static str> {
+    let guest_cid = dev
+        .options
+        .get("guest_cid")
+        .and_then(|v| v.as_integer())
+        .and_then(|v| u64::try_from(v).ok())
+        .ok_or("guest_cid must be an integer")?;
+    let ports = |key| -> std::result::Result<_, &'static str> {
+        let mut map = BTreeMap::new();
+        let table = match dev.options.get(key) {
+            None => return Ok(map),
+            Some(v) => v.as_table().ok_or("port mappings must be a table")?,
+        };
+        for (port, path) in table.iter() {
+            let port =
+                port.parse::<u32>().map_err(|_| "ports must be integers")?;
+            let path = path.as_str().ok_or("socket paths must be strings")?;
+            map.insert(port, path.into());
+        }
+        Ok(map)
+    };
+    Ok(hw::virtio::vsock::VsockConfig {
+        guest_cid,
+        host_ports: ports("host_ports")?,
+        guest_ports: ports("guest_ports")?,
+    })
+}
+
+/// Open the backend described by the `format` (and related) options, with
+/// `workers` threads to service its requests.
+fn block_backend<R: block::BlockReq>(
+    opts: &BTreeMap<String, toml::Value>,
+    name: String,
+    workers: usize,
+    disp: &Dispatcher,
+) -> Result<Arc<dyn block::BlockDev<R>>> {
+    let format = opts.get("format").and_then(|v| v.as_str()).unwrap_or("raw");
+    let disk_path = || {
+        opts.get("disk").and_then(|v| v.as_str()).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "disk path required")
+        })
+    };
+    match format {
+        "raw" => {
+            let bdev = block::PlainBdev::create(disk_path()?)?;
+            Arc::clone(&bdev).start_dispatch_workers(name, workers, disp);
+            Ok(bdev)
+        }
+        "qcow2" => {
+            let bdev = block::QcowBdev::create(disk_path()?)?;
+            Arc::clone(&bdev).start_dispatch_workers(name, workers, disp);
+            Ok(bdev)
+        }
+        "iscsi" => {
+            let cfg = iscsi_config(opts)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let bdev = block::IscsiBdev::create(cfg)?;
+            Arc::clone(&bdev).start_dispatch_workers(name, workers, disp);
+            Ok(bdev)
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported disk format {}", format),
+        )),
+    }
+}
+
+fn iscsi_config(
+    opts: &BTreeMap<String, toml::Value>,
+) -> std::result::Result<block::IscsiConfig, &'static str> {
+    let get_str = |key| opts.get(key).and_then(|v| v.as_str());
+
+    let portal = get_str("portal").ok_or("iscsi portal required")?;
+    let target = get_str("target").ok_or("iscsi target required")?;
+    let lun = match opts.get("lun") {
+        None => 0,
+        Some(v) => v
+            .as_integer()
+            .and_then(|v| u16::try_from(v).ok())
+            .ok_or("lun must be an integer")?,
+    };
+    let chap = match (get_str("chap_name"), get_str("chap_secret")) {
+        (None, None) => None,
+        (Some(name), Some(secret)) => {
+            Some((name.to_string(), secret.to_string()))
+        }
+        _ => return Err("chap_name and chap_secret must be set together"),
+    };
+    Ok(block::IscsiConfig {
+        // Default to the well-known iSCSI port
+        portal: match portal.contains(':') {
+            true => portal.to_string(),
+            false => format!("{}:3260", portal),
+        },
+        target_iqn: target.to_string(),
+        initiator_iqn: get_str("initiator")
+            .unwrap_or(DEFAULT_INITIATOR_IQN)
+            .to_string(),
+        lun,
+        chap,
+        timeout: block::IscsiConfig::DEFAULT_TIMEOUT,
+    })
+}
+
+/// Whether a drive is a CD-ROM (rather than a disk), and its backend
+type StorageDrive<R> = (bool, Arc<dyn block::BlockDev<R>>);
+
+/// Open the drive configured by the `key` table of `dev`, if present.  Its
+/// `type` is "disk" (the default) or "cdrom".
+fn storage_drive<R: block::BlockReq>(
+    name: &str,
+    dev: &config::Device,
+    key: &str,
+    disp: &Dispatcher,
+) -> Result<Option<StorageDrive<R>>> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+
+    let opts = match dev.options.get(key) {
+        Some(v) => v
+            .clone()
+            .try_into::<BTreeMap<String, toml::Value>>()
+            .map_err(|_| invalid(format!("{} must be a table", key)))?,
+        None => return Ok(None),
+    };
+    let cdrom = match opts.get("type").and_then(|v| v.as_str()) {
+        None | Some("disk") => false,
+        Some("cdrom") => true,
+        Some(t) => return Err(invalid(format!("unknown {} type {}", key, t))),
+    };
+    let bdev =
+        block_backend(&opts, format!("bdev-{}-{} thread", name, key), 1, disp)
+            .map_err(|e| {
+                invalid(format!("cannot open disk for {}: {}", key, e))
+            })?;
+    Ok(Some((cdrom, bdev)))
+}
+
+/// Drives for the ports of an AHCI controller, each configured by a `portN`
+/// table.
+fn ahci_drives(
+    name: &str,
+    dev: &config::Device,
+    disp: &Dispatcher,
+) -> Result<Vec<Option<hw::ahci::AhciDrive>>> {
+    let mut drives = Vec::new();
+    for n in 0..hw::ahci::MAX_PORTS {
+        let drive = match storage_drive(name, dev, &format!("port{}", n), disp)?
+        {
+            Some((true, bdev)) => hw::ahci::AhciDrive::Cdrom(bdev),
+            Some((false, bdev)) => hw::ahci::AhciDrive::Disk(bdev),
+            None => continue,
+        };
+        drives.resize_with(n + 1, || None);
+        drives[n] = Some(drive);
+    }
+    Ok(drives)
+}
+
+/// Drives for the IDE channels, configured by `primary_master` (and so on)
+/// tables.
+fn ide_drives(
+    name: &str,
+    dev: &config::Device,
+    disp: &Dispatcher,
+) -> Result<Vec<Option<hw::ide::IdeDrive>>> {
+    let keys = [
+        "primary_master",
+        "primary_slave",
+        "secondary_master",
+        "secondary_slave",
+    ];
+    let mut drives = Vec::new();
+    for key in keys.iter() {
+        drives.push(match storage_drive(name, dev, key, disp)? {
+            Some((true, bdev)) => Some(hw::ide::IdeDrive::Cdrom(bdev)),
+            Some((false, bdev)) => Some(hw::ide::IdeDrive::Disk(bdev)),
+            None => None,
+        });
+    }
+    Ok(drives)
+}
+
+fn viona_config(
+    dev: &config::Device,
+) -> std::result::Result<hw::virtio::viona::VionaConfig, String> {
+    use hw::virtio::viona::{VionaConfig, MIN_MTU};
+
+    let mut cfg = VionaConfig::default();
+    if let Some(v) = dev.options.get("mtu") {
+        let mtu = v
+            .as_integer()
+            .and_then(|v| u16::try_from(v).ok())
+            .filter(|mtu| *mtu >= MIN_MTU)
+            .ok_or_else(|| {
+                format!(
+                    "mtu must be an integer between {} and {}",
+                    MIN_MTU,
+                    u16::MAX
+                )
+            })?;
+        cfg.mtu = Some(mtu);
+    }
+    let toggle = |key, val: &mut bool| -> std::result::Result<(), &str> {
+        match dev.options.get(key) {
+            None => Ok(()),
+            Some(v) => {
+                *val = v.as_bool().ok_or("offload toggles must be booleans")?;
+                Ok(())
+            }
+        }
+    };
+    toggle("csum", &mut cfg.csum)?;
+    toggle("tso", &mut cfg.tso)?;
+    toggle("mrg_rxbuf", &mut cfg.mrg_rxbuf)?;
+    Ok(cfg)
+}
+
+#[derive(Default)]
+struct Requests {
+    /// SIGUSR1, which stands in for the power button of a sleeping instance
+    power_button: bool,
+    /// SIGUSR2, requesting that the instance be paused (or resumed)
+    pause_toggle: bool,
+    /// The instance has stopped, so no further requests will be acted upon
+    done: bool,
+}
+
+/// Requests made of the instance from outside of it, and a condvar signalled
+/// as they arrive.
+#[derive(Default)]
+struct Control {
+    reqs: Mutex<Requests>,
+    cv: Condvar,
+}
+impl Control {
+    /// Block the signals used for requests in the calling thread (and so in
+    /// any it subsequently spawns), and spawn a thread to receive them.
+    ///
+    /// This must be called before any other threads are spawned.
+    fn start(power_button: bool) -> Arc<Self> {
+        let this = Arc::new(Self::default());
+        let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        // Safety: the set is initialized by sigemptyset before use
+        let set = unsafe {
+            libc::sigemptyset(set.as_mut_ptr());
+            let mut set = set.assume_init();
+            libc::sigaddset(&mut set, libc::SIGUSR2);
+            if power_button {
+                libc::sigaddset(&mut set, libc::SIGUSR1);
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            set
+        };
+
+        let ctl = Arc::clone(&this);
+        std::thread::spawn(move || loop {
+            let mut sig = 0;
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                continue;
+            }
+            let mut reqs = ctl.reqs.lock().unwrap();
+            match sig {
+                libc::SIGUSR1 => reqs.power_button = true,
+                libc::SIGUSR2 => reqs.pause_toggle = true,
+                _ => continue,
+            }
+            ctl.cv.notify_all();
+        });
+        this
+    }
+    fn finish(&self) {
+        self.reqs.lock().unwrap().done = true;
+        self.cv.notify_all();
+    }
+}
+
+/// The devices of an instance which partake in its lifecycle: the chipset
+/// (and so everything on its PCI bus), along with those outside of it.
+struct InstanceDevs<'a> {
+    chipset: &'a dyn Chipset,
+    others: Vec<Arc<dyn Lifecycle>>,
+}
+impl Lifecycle for InstanceDevs<'_> {
+    fn reset(&self, ctx: &DispCtx) {
+        self.chipset.reset(ctx);
+        self.others.iter().for_each(|dev| dev.reset(ctx));
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        self.chipset.wake(ctx);
+        self.others.iter().for_each(|dev| dev.wake(ctx));
+    }
+    fn pause(&self, ctx: &DispCtx) {
+        self.chipset.pause(ctx);
+        self.others.iter().for_each(|dev| dev.pause(ctx));
+    }
+    fn resume(&self, ctx: &DispCtx) {
+        self.chipset.resume(ctx);
+        self.others.iter().for_each(|dev| dev.resume(ctx));
+    }
+}
+
+/// Pause or resume the instance as requested via SIGUSR2, until it stops.
+fn pause_control(dispatch: &Dispatcher, devs: &dyn Lifecycle, ctl: &Control) {
+    let mut paused = false;
+    loop {
+        let reqs = ctl.reqs.lock().unwrap();
+        let mut reqs =
+            ctl.cv.wait_while(reqs, |r| !r.pause_toggle && !r.done).unwrap();
+        if reqs.done {
+            return;
+        }
+        reqs.pause_toggle = false;
+        drop(reqs);
+
+        let res = if paused {
+            dispatch.resume(|ctx| devs.resume(ctx))
+        } else {
+            dispatch.pause(|ctx| devs.pause(ctx))
+        };
+        let action = if paused { "resume" } else { "pause" };
+        match res {
+            Ok(()) => {
+                paused = !paused;
+                println!("instance {}d", action);
+            }
+            Err(e) => println!("failed to {} instance: {}", action, e),
+        }
+    }
+}
+
+/// Wait for an event to wake the instance from S3: the RTC alarm firing, or a
+/// press of the power button.
+fn wait_for_wake(vm: &Machine, ctl: &Control) {
+    let hdl = vm.get_hdl();
+    // The alarm cannot be changed by the sleeping guest, so its deadline can
+    // be established up front.
+    let deadline = match Rtc::alarm_delay(&hdl) {
+        Ok(delay) => delay.map(|d| Instant::now() + d),
+        Err(e) => {
+            println!("failed to check RTC alarm: {}", e);
+            None
+        }
+    };
+
+    let mut reqs = ctl.reqs.lock().unwrap();
+    reqs.power_button = false;
+    loop {
+        if std::mem::replace(&mut reqs.power_button, false) {
+            println!("woken by power button");
+            return;
+        }
+        reqs = match deadline {
+            Some(when) => {
+                let now = Instant::now();
+                if now >= when {
+                    println!("woken by RTC alarm");
+                    return;
+                }
+                ctl.cv.wait_timeout(reqs, when - now).unwrap().0
+            }
+            None => ctl.cv.wait(reqs).unwrap(),
+        };
+    }
+}
+
+/// Prepare the vCPUs (and guest memory, for a direct kernel boot) to start the
+/// instance from power-on, and spawn the vCPU threads.
+fn boot_vcpus(
+    vm: &Machine,
+    mctx: &MachineCtx,
+    dispatch: &Dispatcher,
+    config: &config::Config,
+    lowmem: usize,
+) {
+    let cpus = config.get_cpus();
+
+    // Spin up non-boot CPUs prior to vCPU 0
+    // They will simply block until INIT/SIPI is received
+    for n in 1..cpus {
+        let mut next_vcpu = vm.vcpu(n as i32);
+        next_vcpu.set_default_capabs().unwrap();
+        next_vcpu.reboot_state().unwrap();
+        next_vcpu.activate().unwrap();
+        dispatch.spawn_vcpu(next_vcpu, propolis::vcpu_run_loop).unwrap();
+    }
+
+    let mut vcpu0 = vm.vcpu(0);
+
+    vcpu0.set_default_capabs().unwrap();
+    vcpu0.reboot_state().unwrap();
+    vcpu0.activate().unwrap();
+    vcpu0.set_run_state(bhyve_api::VRS_RUN).unwrap();
+    match config.get_boot() {
+        config::BootMode::Firmware => {
+            vcpu0
+                .set_reg(bhyve_api::vm_reg_name::VM_REG_GUEST_RIP, 0xfff0)
+                .unwrap();
+        }
+        config::BootMode::Kernel {
+            image,
+            initrd,
+            cmdline,
+            load_addr,
+            entry,
+        } => {
+            let mem = mctx.memctx();
+            let kernel = boot::load_kernel(&mem, &image, load_addr).unwrap();
+            boot::setup_boot_params(
+                &mem,
+                &kernel,
+                lowmem as u64,
+                cmdline.as_deref(),
+                initrd.as_deref(),
+            )
+            .unwrap();
+            let entry = entry.unwrap_or(kernel.entry);
+            boot::setup_long_mode(&mem, &mut vcpu0, entry).unwrap();
+        }
+        config::BootMode::Pvh { image, initrd, cmdline } => {
+            let mem = mctx.memctx();
+            boot::setup_pvh(
+                &mem,
+                &mut vcpu0,
+                &image,
+                lowmem as u64,
+                cmdline.as_deref(),
+                initrd.as_deref(),
+            )
+            .unwrap();
+        }
     }
+
+    dispatch.spawn_vcpu(vcpu0, propolis::vcpu_run_loop).unwrap();
 }
 
 fn main() {
     let config = parse_args();
+    let ctl = Control::start(config.get_s3());
 
     let vm_name = config.get_name();
     let lowmem: usize = config.get_mem() * 1024 * 1024;
     let cpus = config.get_cpus();
+    let topology = config.get_topology();
 
-    let vm = build_vm(vm_name, cpus, lowmem).unwrap();
+    let vm = build_vm(vm_name, topology, lowmem).unwrap();
     println!("vm {} created", vm_name);
 
-    let (mut romfp, rom_len) = open_bootrom(config.get_bootrom()).unwrap();
-    vm.populate_rom("bootrom", |ptr, region_len| {
-        if region_len < rom_len {
-            return Err(Error::new(ErrorKind::InvalidData, "rom too long"));
-        }
-        let offset = region_len - rom_len;
-        unsafe {
-            let write_ptr = ptr.as_ptr().add(offset);
-            let buf = std::slice::from_raw_parts_mut(write_ptr, rom_len);
-            match romfp.read(buf) {
-                Ok(n) if n == rom_len => Ok(()),
-                Ok(_) => {
-                    // TODO: handle short read
-                    Ok(())
-                }
-                Err(e) => Err(e),
+    if let Some(rom_path) = config.get_bootrom() {
+        let rom = rom::RomImage::open(rom_path).unwrap();
+        let rom_len = rom.size();
+        vm.populate_rom("bootrom", |ptr, region_len| {
+            if region_len < rom_len {
+                return Err(Error::new(ErrorKind::InvalidData, "rom too long"));
             }
-        }
-    })
-    .unwrap();
-    drop(romfp);
+            let offset = region_len - rom_len;
+            unsafe {
+                let write_ptr = ptr.as_ptr().add(offset);
+                let buf = std::slice::from_raw_parts_mut(write_ptr, rom_len);
+                rom.read_into(buf)
+            }
+        })
+        .unwrap();
+    }
 
     vm.initalize_rtc(lowmem).unwrap();
 
@@ -108,25 +611,53 @@ fn main() {
     let mut dispatch = Dispatcher::new(mctx.clone());
     dispatch.spawn_events().unwrap();
 
-    let com1_sock = chardev::UDSock::bind(Path::new("./ttya")).unwrap();
+    let console = config.get_console();
+    let com1_sock = chardev::UDSock::bind_with_scrollback(
+        Path::new("./ttya"),
+        console.scrollback,
+    )
+    .unwrap();
+    com1_sock.set_max_viewers(console.viewers);
     dispatch.with_ctx(|ctx| {
         com1_sock.listen(ctx);
     });
 
-    let chipset = mctx.with_pio(|pio| {
-        hw::chipset::i440fx::I440Fx::create(vm.get_hdl(), pio, |lpc| {
-            lpc.config_uarts(|com1, com2, com3, com4| {
-                com1_sock.attach_sink(Arc::clone(com1) as Arc<dyn Sink>);
-                com1_sock.attach_source(Arc::clone(com1) as Arc<dyn Source>);
-                com1.source_set_autodiscard(false);
-
-                // XXX: plumb up com2-4, but until then, just auto-discard
-                com2.source_set_autodiscard(true);
-                com3.source_set_autodiscard(true);
-                com4.source_set_autodiscard(true);
-            })
-        })
-    });
+    let cfg_uarts = |com1: &Arc<hw::uart::LpcUart>,
+                     com2: &Arc<hw::uart::LpcUart>,
+                     com3: &Arc<hw::uart::LpcUart>,
+                     com4: &Arc<hw::uart::LpcUart>| {
+        com1_sock.attach_sink(Arc::clone(com1) as Arc<dyn Sink>);
+        com1_sock.attach_source(Arc::clone(com1) as Arc<dyn Source>);
+        com1.source_set_autodiscard(false);
+
+        // XXX: plumb up com2-4, but until then, just auto-discard
+        com2.source_set_autodiscard(true);
+        com3.source_set_autodiscard(true);
+        com4.source_set_autodiscard(true);
+    };
+    // The i440fx is kept separately, as only it offers the PIIX3 IDE function
+    let (chipset, i440fx): (Arc<dyn Chipset>, _) =
+        mctx.with_pio(|pio| match config.get_chipset() {
+            config::ChipsetKind::I440fx => {
+                let i440fx = hw::chipset::i440fx::I440Fx::create(
+                    vm.get_hdl(),
+                    pio,
+                    config.get_acpi_tables(),
+                    |lpc| lpc.config_uarts(cfg_uarts),
+                );
+                (Arc::clone(&i440fx) as Arc<dyn Chipset>, Some(i440fx))
+            }
+            config::ChipsetKind::Q35 => {
+                let q35 = hw::chipset::q35::Q35::create(
+                    vm.get_hdl(),
+                    pio,
+                    config.get_root_ports(),
+                    config.get_acpi_tables(),
+                    |lpc| lpc.config_uarts(cfg_uarts),
+                );
+                (q35 as Arc<dyn Chipset>, None)
+            }
+        });
 
     let _dbg = mctx.with_pio(|pio| {
         let debug = std::fs::File::create("debug.out").unwrap();
@@ -137,6 +668,22 @@ fn main() {
         )
     });
 
+    let _pvpanic = config.get_pvpanic().then(|| {
+        let pvpanic = mctx.with_pio(hw::qemu::pvpanic::QemuPvpanic::create);
+        pvpanic.on_event(|events| {
+            use hw::qemu::pvpanic::PanicEvent;
+            if events.contains(PanicEvent::PANICKED) {
+                eprintln!("guest reported a kernel panic");
+            }
+            if events.contains(PanicEvent::CRASH_LOADED) {
+                eprintln!("guest loaded a crash kernel");
+            }
+        });
+        pvpanic
+    });
+
+    let mut boot_devs = BTreeMap::new();
+    let mut pci_devs = Vec::new();
     for (name, dev) in config.devs() {
         let driver = &dev.driver as &str;
         let bdf = if driver.starts_with("pci-") {
@@ -146,39 +693,160 @@ fn main() {
         } else {
             None
         };
-        match driver {
+        let pci_dev = match driver {
             "pci-virtio-block" => {
-                let disk_path =
-                    dev.options.get("disk").unwrap().as_str().unwrap();
-
-                let plain: Arc<block::PlainBdev<hw::virtio::block::Request>> =
-                    block::PlainBdev::create(disk_path).unwrap();
-
-                let vioblk = hw::virtio::VirtioBlock::create(
-                    0x100,
-                    Arc::clone(&plain)
-                        as Arc<dyn block::BlockDev<hw::virtio::block::Request>>,
+                let topo = block_topology(dev).unwrap_or_else(|e| {
+                    eprintln!("invalid block sizes for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let num_queues = block_queues(dev).unwrap_or_else(|e| {
+                    eprintln!("invalid queue count for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let bdev = block_backend(
+                    &dev.options,
+                    format!("bdev-{} thread", name),
+                    num_queues as usize,
+                    &dispatch,
+                )
+                .unwrap_or_else(|e| {
+                    eprintln!("cannot open disk for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let vioblk = hw::virtio::VirtioBlock::create_multiqueue(
+                    0x100, num_queues, bdev, topo,
                 );
-                chipset.pci_attach(bdf.unwrap(), vioblk);
-
-                plain
-                    .start_dispatch(format!("bdev-{} thread", name), &dispatch);
+                boot_devs.insert(
+                    name.as_str(),
+                    hw::qemu::bootorder::BootDevice::VirtioBlock(bdf.unwrap()),
+                );
+                Some(vioblk)
+            }
+            "pci-ahci" => {
+                let drives =
+                    ahci_drives(name, dev, &dispatch).unwrap_or_else(|e| {
+                        eprintln!("cannot configure {}: {}", name, e);
+                        std::process::exit(libc::EXIT_FAILURE);
+                    });
+                let ahci = hw::ahci::AhciCtrl::create(drives);
+                Some(ahci)
+            }
+            "piix3-ide" => {
+                let drives =
+                    ide_drives(name, dev, &dispatch).unwrap_or_else(|e| {
+                        eprintln!("cannot configure {}: {}", name, e);
+                        std::process::exit(libc::EXIT_FAILURE);
+                    });
+                // Validated at parse time
+                let i440fx = i440fx.as_ref().unwrap();
+                mctx.with_pio(|pio| i440fx.attach_ide(pio, drives));
+                None
+            }
+            "pci-virtio-net" => {
+                let tap_path =
+                    dev.options.get("tap").unwrap().as_str().unwrap();
+                let mac = dev
+                    .options
+                    .get("mac")
+                    .and_then(|v| v.as_str())
+                    .and_then(config::parse_mac)
+                    .unwrap_or_else(|| {
+                        eprintln!("{} requires a valid mac address", name);
+                        std::process::exit(libc::EXIT_FAILURE);
+                    });
+
+                let tap = hw::virtio::net::TapDev::open(tap_path).unwrap();
+                let net = hw::virtio::net::VirtioNet::create(tap, mac, 0x100);
+                Some(net)
+            }
+            "pci-virtio-9p" => {
+                let tag = dev.options.get("tag").unwrap().as_str().unwrap();
+                let path = dev.options.get("path").unwrap().as_str().unwrap();
+                let read_only = dev
+                    .options
+                    .get("read_only")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+
+                let p9 = hw::virtio::p9fs::VirtioP9::create(
+                    tag, path, read_only, 0x100,
+                )
+                .unwrap();
+                Some(p9)
+            }
+            "pci-virtio-rng" => {
+                let (source, rate) = rng_config(dev).unwrap_or_else(|e| {
+                    eprintln!("invalid options for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let rng = hw::virtio::rng::VirtioRng::create(
+                    &source, rate, 0x100, &dispatch,
+                )
+                .unwrap();
+                Some(rng)
+            }
+            "pci-virtio-vsock" => {
+                let cfg = vsock_config(dev).unwrap_or_else(|e| {
+                    eprintln!("invalid options for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let vsock = hw::virtio::vsock::VirtioVsock::create(
+                    cfg, 0x100, &dispatch,
+                )
+                .unwrap();
+                Some(vsock)
+            }
+            "pci-i6300esb" => {
+                use hw::i6300esb::{I6300Esb, WdtAction};
+                let action = match dev.options.get("action") {
+                    None => Some(WdtAction::Reset),
+                    Some(v) => match v.as_str() {
+                        Some("reset") => Some(WdtAction::Reset),
+                        Some("poweroff") => Some(WdtAction::PowerOff),
+                        Some("none") => Some(WdtAction::None),
+                        _ => None,
+                    },
+                };
+                let action = action.unwrap_or_else(|| {
+                    eprintln!(
+                        "invalid options for {}: action must be one of \
+                        \"reset\", \"poweroff\", or \"none\"",
+                        name
+                    );
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+                let wdt = I6300Esb::create(action, &dispatch);
+                wdt.with_inner(|inner: &I6300Esb| {
+                    inner.on_expire(move |_ctx| {
+                        eprintln!("watchdog expired, action: {:?}", action);
+                    })
+                });
+                Some(wdt)
             }
             "pci-virtio-viona" => {
                 let vnic_name =
                     dev.options.get("vnic").unwrap().as_str().unwrap();
 
+                let viona_cfg = viona_config(dev).unwrap_or_else(|e| {
+                    eprintln!("invalid options for {}: {}", name, e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                });
+
                 let hdl = vm.get_hdl();
-                let viona = hw::virtio::viona::VirtioViona::create(
-                    vnic_name, 0x100, &hdl,
+                let viona = hw::virtio::viona::VirtioViona::create_with_config(
+                    vnic_name, 0x100, &hdl, viona_cfg,
                 )
                 .unwrap();
-                chipset.pci_attach(bdf.unwrap(), viona);
+                Some(viona)
             }
             _ => {
                 eprintln!("unrecognized driver: {}", name);
                 std::process::exit(libc::EXIT_FAILURE);
             }
+        };
+        if let Some(dev) = pci_dev {
+            chipset.pci_attach(bdf.unwrap(), Arc::clone(&dev) as Arc<_>);
+            pci_devs.push((name.as_str(), dev));
         }
     }
 
@@ -197,33 +865,128 @@ fn main() {
         .unwrap();
     ramfb.attach(&mut fwcfg);
 
-    let fwcfg_dev = fwcfg.finalize();
-
-    mctx.with_pio(|pio| fwcfg_dev.attach(pio));
-
-    // Spin up non-boot CPUs prior to vCPU 0
-    // They will simply block until INIT/SIPI is received
-    for n in 1..cpus {
-        let mut next_vcpu = vm.vcpu(n as i32);
-        next_vcpu.set_default_capabs().unwrap();
-        next_vcpu.reboot_state().unwrap();
-        next_vcpu.activate().unwrap();
-        dispatch.spawn_vcpu(next_vcpu, propolis::vcpu_run_loop).unwrap();
+    let uuid = config.get_uuid().unwrap_or_default();
+    if config.get_uuid().is_some() {
+        fwcfg
+            .add_legacy(
+                hw::qemu::fwcfg::LegacyId::Uuid,
+                hw::qemu::fwcfg::FixedItem::new_raw(uuid.to_vec()),
+            )
+            .unwrap();
+    }
+    let smbios =
+        hw::qemu::smbios::Smbios::new(&hw::qemu::smbios::SmbiosParams {
+            uuid,
+            serial: config.get_serial().cloned(),
+            mem_size: lowmem as u64,
+            topology,
+        });
+    smbios.attach(&mut fwcfg).unwrap();
+
+    if config.get_acpi_tables() {
+        let chipset = match config.get_chipset() {
+            config::ChipsetKind::I440fx => hw::qemu::acpi::AcpiChipset::I440fx,
+            config::ChipsetKind::Q35 => hw::qemu::acpi::AcpiChipset::Q35,
+        };
+        let acpi = hw::qemu::acpi::Acpi::new(&hw::qemu::acpi::AcpiParams {
+            chipset,
+            topology,
+            // Only COM1 is connected (to the console socket)
+            uarts: hw::chipset::i440fx::LPC_UARTS[..1].to_vec(),
+            hpet_cap: vm.get_hdl().hpet_capabilities().ok(),
+            pvpanic: config.get_pvpanic(),
+            s3: config.get_s3(),
+        });
+        acpi.attach(&mut fwcfg).unwrap();
+    }
+    hw::qemu::acpi::attach_system_states(&mut fwcfg, config.get_s3()).unwrap();
+
+    let mut boot_order = hw::qemu::bootorder::BootOrder::new();
+    for name in config.get_boot_order() {
+        let dev = match boot_devs.get(name.as_str()) {
+            Some(dev) => dev,
+            None => {
+                eprintln!("boot_order device {} is not bootable", name);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        };
+        if boot_order.push(dev).is_none() {
+            eprintln!("boot_order device {} is not on the root bus", name);
+            std::process::exit(libc::EXIT_FAILURE);
+        }
     }
+    boot_order.attach(&mut fwcfg).unwrap();
 
-    let mut vcpu0 = vm.vcpu(0);
+    let fwcfg_dev = fwcfg.finalize();
 
-    vcpu0.set_default_capabs().unwrap();
-    vcpu0.reboot_state().unwrap();
-    vcpu0.activate().unwrap();
-    vcpu0.set_run_state(bhyve_api::VRS_RUN).unwrap();
-    vcpu0.set_reg(bhyve_api::vm_reg_name::VM_REG_GUEST_RIP, 0xfff0).unwrap();
+    mctx.with_pio(|pio| fwcfg_dev.attach(pio));
 
     // Wait until someone connects to ttya
     com1_sock.wait_for_connect();
 
-    dispatch.spawn_vcpu(vcpu0, propolis::vcpu_run_loop).unwrap();
+    let devs = InstanceDevs {
+        chipset: chipset.as_ref(),
+        others: vec![fwcfg_dev as Arc<dyn Lifecycle>, ramfb],
+    };
+    std::thread::scope(|s| {
+        s.spawn(|| pause_control(&dispatch, &devs, &ctl));
+        run_instance(&vm, &mctx, &dispatch, &devs, &ctl, &config, lowmem);
+        ctl.finish();
+    });
+    for (name, dev) in pci_devs.iter() {
+        print_intr_counts(name, &dev.intr_counts());
+    }
 
-    dispatch.join();
+    dispatch.shutdown();
     drop(vm);
 }
+
+/// Report the interrupts raised by a PCI device over the life of the instance
+fn print_intr_counts(name: &str, counts: &hw::pci::IntrCounts) {
+    let msix: Vec<String> = counts
+        .msix
+        .iter()
+        .map(|v| format!("{}/{}", v.delivered, v.deferred))
+        .collect();
+    println!(
+        "{} interrupts: intx {}, msix (delivered/deferred) [{}]",
+        name,
+        counts.intx,
+        msix.join(" ")
+    );
+}
+
+/// Boot the instance, and see it through any resets (or sleeps) until it is
+/// stopped.
+fn run_instance(
+    vm: &Machine,
+    mctx: &MachineCtx,
+    dispatch: &Dispatcher,
+    devs: &dyn Lifecycle,
+    ctl: &Control,
+    config: &config::Config,
+    lowmem: usize,
+) {
+    // Guest-initiated resets (and triple faults) restart the instance in place,
+    // and it is woken from sleep by way of the firmware, while any other
+    // suspension ends it.
+    loop {
+        boot_vcpus(vm, mctx, dispatch, config, lowmem);
+        match dispatch.wait_vcpus() {
+            Some(reason) if reason.is_reset() => {
+                println!("instance reset ({:?})", reason);
+                dispatch.reset(|ctx| devs.reset(ctx)).unwrap();
+            }
+            Some(SuspendReason::Sleep) => {
+                println!("instance sleeping (S3)");
+                wait_for_wake(vm, ctl);
+                dispatch.wake(|ctx| devs.wake(ctx)).unwrap();
+            }
+            Some(reason) => {
+                println!("instance stopped ({:?})", reason);
+                break;
+            }
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+use flate2::read::GzDecoder;
+
+const PAGE_OFFSET: u64 = 0xfff;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A bootrom image, which may be gzip-compressed on disk.
+pub struct RomImage {
+    reader: Box<dyn Read>,
+    size: usize,
+}
+impl RomImage {
+    /// Open the image at `path`, detecting gzip compression by its magic.
+    ///
+    /// The (decompressed) image size must be page-aligned.
+    pub fn open(path: &str) -> Result<Self> {
+        let mut fp = File::open(path)?;
+        let file_len = fp.metadata()?.len();
+
+        let mut magic = [0u8; 2];
+        let is_gzip = file_len > 2
+            && fp.read_exact(&mut magic).is_ok()
+            && magic == GZIP_MAGIC;
+
+        let (reader, len) = if is_gzip {
+            // The gzip trailer ends with the uncompressed size (mod 2^32),
+            // which is ample for ROM images.  The decompressed stream is
+            // checked against it when read.
+            let mut isize = [0u8; 4];
+            fp.seek(SeekFrom::End(-4))?;
+            fp.read_exact(&mut isize)?;
+            fp.seek(SeekFrom::Start(0))?;
+            let len = u32::from_le_bytes(isize) as u64;
+            let dec = GzDecoder::new(BufReader::new(fp));
+            (Box::new(dec) as Box<dyn Read>, len)
+        } else {
+            fp.seek(SeekFrom::Start(0))?;
+            (Box::new(fp) as Box<dyn Read>, file_len)
+        };
+
+        if len & PAGE_OFFSET != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "rom {} length {:x} not aligned to {:x}",
+                    path,
+                    len,
+                    PAGE_OFFSET + 1
+                ),
+            ));
+        }
+        Ok(Self { reader, size: len as usize })
+    }
+
+    /// Size of the image contents, after any decompression
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read the full image into `buf`, which must match its size.
+    pub fn read_into(mut self, buf: &mut [u8]) -> Result<()> {
+        assert_eq!(buf.len(), self.size);
+        self.reader.read_exact(buf)?;
+
+        // Ensure the stream does not hold more than was advertised
+        let mut extra = [0u8; 1];
+        if self.reader.read(&mut extra)? != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "rom contents exceed expected length",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use propolis::util::tempfs::TempFile;
+    use std::io::Write;
+
+    fn path(file: &TempFile) -> &str {
+        file.path().to_str().unwrap()
+    }
+
+    fn rom_data() -> Vec<u8> {
+        (0..0x3000u32).map(|n| (n % 251) as u8).collect()
+    }
+
+    fn load(path: &str) -> Result<Vec<u8>> {
+        let rom = RomImage::open(path)?;
+        let mut buf = vec![0u8; rom.size()];
+        rom.read_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn gzip_roundtrip() {
+        let data = rom_data();
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&data).unwrap();
+        let compressed = enc.finish().unwrap();
+        assert!(compressed.len() as u64 & PAGE_OFFSET != 0);
+
+        let file = TempFile::new("rom-gz", &compressed);
+        assert_eq!(load(path(&file)).unwrap(), data);
+    }
+
+    #[test]
+    fn raw_unchanged() {
+        let data = rom_data();
+        let file = TempFile::new("rom-raw", &data);
+        assert_eq!(load(path(&file)).unwrap(), data);
+
+        let file = TempFile::new("rom-short", &data[..0x2ff0]);
+        assert!(RomImage::open(path(&file)).is_err());
+    }
+
+    #[test]
+    fn gzip_bad_length() {
+        // Decompressed size not page-aligned
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(&rom_data()[..0x2ff0]).unwrap();
+        let file = TempFile::new("rom-gz-short", &enc.finish().unwrap());
+        assert!(RomImage::open(path(&file)).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::VecDeque;
+use std::fs::{metadata, File, OpenOptions};
+use std::io::Result;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::Condvar;
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::dispatch::{DispCtx, Dispatcher};
+
+use libc::{c_void, pread, pwrite};
+
+mod iscsi;
+mod qcow;
+pub use iscsi::{IscsiBdev, IscsiConfig};
+pub use qcow::QcowBdev;
+
+/// Size of the scratch buffer used when writing zeroes to the backing file
+const ZERO_BUF_SZ: usize = 64 * 1024;
+
+#[derive(Copy, Clone, Debug)]
+pub enum BlockOp {
+    Read,
+    Write,
+    /// Zero `len` bytes at the request offset.  No buffers are transferred.
+    /// If `may_unmap` is set, the backend is permitted (but not required) to
+    /// deallocate the range, provided it subsequently reads as zeroes.
+    WriteZeroes {
+        len: usize,
+        may_unmap: bool,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum BlockResult {
+    Success,
+    Failure,
+    Unsupported,
+}
+
+pub trait BlockReq: Send + Sync + 'static {
+    fn oper(&self) -> BlockOp;
+    fn offset(&self) -> usize;
+    fn next_buf(&mut self) -> Option<GuestRegion>;
+    /// Buffer in host memory to transfer the data of a `Read` or `Write`
+    /// through, in place of the guest regions yielded by `next_buf()`, for
+    /// devices which move data by PIO rather than DMA.
+    fn host_buf(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    fn complete(self, res: BlockResult, ctx: &DispCtx);
+}
+
+#[derive(Debug)]
+pub struct BlockInquiry {
+    /// Device size in blocks (see below)
+    pub total_size: u64,
+    /// Size (in bytes) per block
+    pub block_size: u32,
+    pub writable: bool,
+}
+
+pub trait BlockDev<R: BlockReq>: Send + Sync + 'static {
+    fn enqueue(&self, req: R);
+    fn inquire(&self) -> BlockInquiry;
+}
+
+/// Requests awaiting processing by the worker(s) of a backend
+struct ReqQueue<R> {
+    reqs: Mutex<VecDeque<R>>,
+    cond: Condvar,
+}
+impl<R: BlockReq> ReqQueue<R> {
+    fn new() -> Self {
+        Self { reqs: Mutex::new(VecDeque::new()), cond: Condvar::new() }
+    }
+    fn push(&self, req: R) {
+        self.reqs.lock().unwrap().push_back(req);
+        self.cond.notify_all();
+    }
+    /// Wait for the next request, or `None` if woken to quiesce with no
+    /// remaining work.
+    fn next(&self, ctx: &DispCtx) -> Option<R> {
+        let reqs = self.reqs.lock().unwrap();
+        let mut reqs = self
+            .cond
+            .wait_while(reqs, |r| r.is_empty() && !ctx.should_exit())
+            .unwrap();
+        reqs.pop_front()
+    }
+    fn wake_all(&self) {
+        // Take the lock so the wake-up cannot slip in between a worker
+        // checking its exit condition and waiting on the condvar.
+        let _guard = self.reqs.lock().unwrap();
+        self.cond.notify_all();
+    }
+}
+
+/// Backend whose requests are processed by workers spawned on the dispatcher
+trait QueuedBdev<R: BlockReq>: Send + Sync + 'static {
+    fn queue(&self) -> &ReqQueue<R>;
+    fn process(&self, req: &mut R, ctx: &DispCtx) -> BlockResult;
+}
+
+/// Spawn `count` workers on `disp` to process the requests queued for `bdev`
+fn start_workers<R, B>(
+    bdev: Arc<B>,
+    name: String,
+    count: usize,
+    disp: &Dispatcher,
+) where
+    R: BlockReq,
+    B: QueuedBdev<R>,
+{
+    assert!(count > 0);
+    let qbdev = Arc::clone(&bdev);
+    disp.on_quiesce(move || qbdev.queue().wake_all());
+    for n in 0..count {
+        let wname = match count {
+            1 => name.clone(),
+            _ => format!("{} {}", name, n),
+        };
+        disp.spawn(wname, Arc::clone(&bdev), |dctx, bdev| {
+            while let Some(mut req) = bdev.queue().next(&dctx) {
+                let _active = dctx.device_activity();
+                let res = bdev.process(&mut req, &dctx);
+                req.complete(res, &dctx);
+            }
+        })
+        .unwrap();
+    }
+}
+
+pub struct PlainBdev<R: BlockReq> {
+    fp: File,
+    fd: RawFd,
+    is_ro: bool,
+    is_raw: bool,
+    block_size: usize,
+    sectors: usize,
+    reqs: ReqQueue<R>,
+}
+impl<R: BlockReq> PlainBdev<R> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let p: &Path = path.as_ref();
+
+        let meta = metadata(p)?;
+        let is_ro = meta.permissions().readonly();
+
+        let fp = OpenOptions::new().read(true).write(!is_ro).open(p)?;
+        let is_raw = fp.metadata()?.file_type().is_char_device();
+        let fd = fp.as_raw_fd();
+
+        let mut this = Self {
+            fp,
+            fd,
+            is_ro,
+            block_size: 512,
+            sectors: 0,
+            is_raw,
+            reqs: ReqQueue::new(),
+        };
+        this.raw_init();
+
+        Ok(Arc::new(this))
+    }
+    fn raw_init(&mut self) {
+        // TODO: query block size, write cache, discard, etc
+        assert!(!self.is_raw);
+        let len = self.fp.metadata().unwrap().len() as usize;
+        self.sectors = len / self.block_size;
+    }
+    fn process_read(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        let mut offset = req.offset();
+        if let Some(buf) = req.host_buf() {
+            return match self.fp.read_exact_at(buf, offset as u64) {
+                Ok(()) => BlockResult::Success,
+                Err(_) => BlockResult::Failure,
+            };
+        }
+
+        let mem = ctx.mctx.memctx();
+        while let Some(buf) = req.next_buf() {
+            if let Some(rbuf) = mem.raw_writable(&buf) {
+                let nread = unsafe {
+                    pread(self.fd, rbuf as *mut c_void, buf.1, offset as i64)
+                };
+                if nread == -1 {
+                    // XXX: error reporting
+                    return BlockResult::Failure;
+                }
+                assert_eq!(nread as usize, buf.1);
+                offset += buf.1;
+            } else {
+                // XXX: report bad addr
+                return BlockResult::Failure;
+            }
+        }
+        BlockResult::Success
+    }
+    fn process_write(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        let mut offset = req.offset();
+        if let Some(buf) = req.host_buf() {
+            return match self.fp.write_all_at(buf, offset as u64) {
+                Ok(()) => BlockResult::Success,
+                Err(_) => BlockResult::Failure,
+            };
+        }
+
+        let mem = ctx.mctx.memctx();
+        while let Some(buf) = req.next_buf() {
+            if let Some(wbuf) = mem.raw_readable(&buf) {
+                let nwritten = unsafe {
+                    pwrite(self.fd, wbuf as *const c_void, buf.1, offset as i64)
+                };
+                if nwritten == -1 {
+                    // XXX: error reporting
+                    return BlockResult::Failure;
+                }
+                assert_eq!(nwritten as usize, buf.1);
+                offset += buf.1;
+            } else {
+                // XXX: report bad addr
+                return BlockResult::Failure;
+            }
+        }
+        BlockResult::Success
+    }
+    fn write_zeroes(&self, offset: usize, len: usize) -> BlockResult {
+        if self.is_ro {
+            return BlockResult::Failure;
+        }
+        let dev_size = self.sectors * self.block_size;
+        match offset.checked_add(len) {
+            Some(end) if end <= dev_size => {}
+            _ => return BlockResult::Failure,
+        }
+
+        let zeroes = vec![0u8; usize::min(len, ZERO_BUF_SZ)];
+        let mut done = 0;
+        while done < len {
+            let chunk = usize::min(len - done, zeroes.len());
+            let nwritten = unsafe {
+                pwrite(
+                    self.fd,
+                    zeroes.as_ptr() as *const c_void,
+                    chunk,
+                    (offset + done) as i64,
+                )
+            };
+            if nwritten <= 0 {
+                // XXX: error reporting
+                return BlockResult::Failure;
+            }
+            done += nwritten as usize;
+        }
+        BlockResult::Success
+    }
+    pub fn start_dispatch(self: Arc<Self>, name: String, disp: &Dispatcher) {
+        self.start_dispatch_workers(name, 1, disp)
+    }
+    /// Start `count` workers, allowing up to that many requests to be
+    /// processed in parallel.  The workers are named `name` suffixed with
+    /// their index.
+    pub fn start_dispatch_workers(
+        self: Arc<Self>,
+        name: String,
+        count: usize,
+        disp: &Dispatcher,
+    ) {
+        start_workers(self, name, count, disp)
+    }
+}
+
+impl<R: BlockReq> QueuedBdev<R> for PlainBdev<R> {
+    fn queue(&self) -> &ReqQueue<R> {
+        &self.reqs
+    }
+    fn process(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        match req.oper() {
+            BlockOp::Read => self.process_read(req, ctx),
+            BlockOp::Write => self.process_write(req, ctx),
+            BlockOp::WriteZeroes { len, may_unmap: _ } => {
+                // Deallocation is merely permitted, so always zero
+                self.write_zeroes(req.offset(), len)
+            }
+        }
+    }
+}
+
+impl<R: BlockReq> BlockDev<R> for PlainBdev<R> {
+    fn enqueue(&self, req: R) {
+        self.reqs.push(req);
+    }
+
+    fn inquire(&self) -> BlockInquiry {
+        BlockInquiry {
+            total_size: self.sectors as u64,
+            block_size: self.block_size as u32,
+            writable: !self.is_ro,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::util::tempfs::TempFile;
+
+    struct NoReq {}
+    impl BlockReq for NoReq {
+        fn oper(&self) -> BlockOp {
+            BlockOp::Read
+        }
+        fn offset(&self) -> usize {
+            0
+        }
+        fn next_buf(&mut self) -> Option<GuestRegion> {
+            None
+        }
+        fn complete(self, _res: BlockResult, _ctx: &DispCtx) {}
+    }
+
+    #[test]
+    fn write_zeroes() {
+        let file = TempFile::new("write-zeroes", &[0xaau8; 8192]);
+        let bdev: Arc<PlainBdev<NoReq>> =
+            PlainBdev::create(&file.path()).unwrap();
+
+        assert!(matches!(bdev.write_zeroes(1024, 2048), BlockResult::Success));
+        let data = file.contents();
+        assert_eq!(data.len(), 8192);
+        assert!(data[..1024].iter().all(|b| *b == 0xaa));
+        assert!(data[1024..3072].iter().all(|b| *b == 0));
+        assert!(data[3072..].iter().all(|b| *b == 0xaa));
+
+        // Requests past the end of the device are refused outright
+        assert!(matches!(bdev.write_zeroes(7680, 1024), BlockResult::Failure));
+        assert!(matches!(
+            bdev.write_zeroes(usize::MAX - 511, 1024),
+            BlockResult::Failure
+        ));
+        assert!(file.contents()[7680..].iter().all(|b| *b == 0xaa));
+    }
+}
@@ -0,0 +1,680 @@
+//! Backend for disk images in the qcow2 format.
+//!
+//! Clusters are allocated, at the end of the image, as they are first written.
+//! Images which use backing files, compression, encryption, or internal
+//! snapshots are refused.
+
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::*;
+
+/// "QFI\xfb"
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const QCOW_V2_HDR_LEN: usize = 72;
+const QCOW_V3_HDR_LEN: usize = 104;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+
+/// Host offset of the (L2 table or data) cluster in an L1 or L2 entry
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeroes (v3 only)
+const QCOW_OFLAG_ZERO: u64 = 1 << 0;
+const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+/// Only 16-bit refcounts (the default) are supported
+const REFCOUNT_ORDER: u32 = 4;
+
+/// Number of L2 tables cached in memory
+const L2_CACHE_TABLES: usize = 16;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("qcow2: {}", msg))
+}
+
+fn be32(buf: &[u8], off: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&buf[off..(off + 4)]);
+    u32::from_be_bytes(raw)
+}
+fn be64(buf: &[u8], off: usize) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&buf[off..(off + 8)]);
+    u64::from_be_bytes(raw)
+}
+
+struct Header {
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_offset: u64,
+    reft_offset: u64,
+    reft_clusters: u32,
+}
+impl Header {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < QCOW_V2_HDR_LEN || be32(buf, 0) != QCOW_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        let version = be32(buf, 4);
+        match version {
+            2 => {}
+            3 => {
+                if buf.len() < QCOW_V3_HDR_LEN {
+                    return Err(invalid("truncated header"));
+                }
+                if be64(buf, 72) != 0 {
+                    return Err(invalid("unsupported incompatible features"));
+                }
+                if be32(buf, 96) != REFCOUNT_ORDER {
+                    return Err(invalid("unsupported refcount width"));
+                }
+            }
+            _ => return Err(invalid("unsupported version")),
+        }
+        if be64(buf, 8) != 0 {
+            return Err(invalid("backing files are not supported"));
+        }
+        if be32(buf, 32) != 0 {
+            return Err(invalid("encryption is not supported"));
+        }
+        if be32(buf, 60) != 0 {
+            return Err(invalid("internal snapshots are not supported"));
+        }
+
+        let hdr = Self {
+            version,
+            cluster_bits: be32(buf, 20),
+            size: be64(buf, 24),
+            l1_size: be32(buf, 36),
+            l1_offset: be64(buf, 40),
+            reft_offset: be64(buf, 48),
+            reft_clusters: be32(buf, 56),
+        };
+        if hdr.cluster_bits < MIN_CLUSTER_BITS
+            || hdr.cluster_bits > MAX_CLUSTER_BITS
+        {
+            return Err(invalid("bad cluster size"));
+        }
+        // Each L1 entry covers an L2 table worth of clusters
+        let l2_bits = hdr.cluster_bits - 3;
+        let l1_span = 1u64 << (hdr.cluster_bits + l2_bits);
+        let l1_needed = hdr
+            .size
+            .checked_add(l1_span - 1)
+            .ok_or_else(|| invalid("bad image size"))?
+            >> (hdr.cluster_bits + l2_bits);
+        if (hdr.l1_size as u64) < l1_needed {
+            return Err(invalid("L1 table too small for image size"));
+        }
+        if hdr.reft_clusters == 0 {
+            return Err(invalid("missing refcount table"));
+        }
+        Ok(hdr)
+    }
+
+    /// Check that the L1 and refcount tables lie within an image of
+    /// `file_len` bytes, so their (header-specified) sizes cannot cause an
+    /// outsized allocation.
+    fn check_tables(&self, file_len: u64) -> Result<()> {
+        let within = |off: u64, len: Option<u64>| {
+            len.and_then(|len| off.checked_add(len))
+                .is_some_and(|end| end <= file_len)
+        };
+        let l1_len = (self.l1_size as u64).checked_mul(8);
+        if !within(self.l1_offset, l1_len) {
+            return Err(invalid("L1 table beyond end of image"));
+        }
+        let reft_len =
+            (self.reft_clusters as u64).checked_mul(1 << self.cluster_bits);
+        if !within(self.reft_offset, reft_len) {
+            return Err(invalid("refcount table beyond end of image"));
+        }
+        Ok(())
+    }
+}
+
+struct Meta {
+    l1: Vec<u64>,
+    reft: Vec<u64>,
+    /// L2 tables, keyed by host offset, with the most recently used last
+    l2_cache: Vec<(u64, Vec<u64>)>,
+    /// Host offset at which the next cluster will be allocated
+    next_free: u64,
+}
+
+struct QcowImage {
+    fp: File,
+    writable: bool,
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_offset: u64,
+    reft_offset: u64,
+    meta: Mutex<Meta>,
+}
+impl QcowImage {
+    fn open(path: &Path) -> Result<Self> {
+        let writable = !metadata(path)?.permissions().readonly();
+        let fp = OpenOptions::new().read(true).write(writable).open(path)?;
+
+        let mut buf = [0u8; QCOW_V3_HDR_LEN];
+        let file_len = fp.metadata()?.len();
+        let hdr_len = usize::min(buf.len(), file_len as usize);
+        fp.read_exact_at(&mut buf[..hdr_len], 0)?;
+        let hdr = Header::parse(&buf[..hdr_len])?;
+        hdr.check_tables(file_len)?;
+
+        let cluster_size = 1u64 << hdr.cluster_bits;
+        let mut this = Self {
+            fp,
+            writable,
+            version: hdr.version,
+            cluster_bits: hdr.cluster_bits,
+            size: hdr.size,
+            l1_offset: hdr.l1_offset,
+            reft_offset: hdr.reft_offset,
+            meta: Mutex::new(Meta {
+                l1: Vec::new(),
+                reft: Vec::new(),
+                l2_cache: Vec::with_capacity(L2_CACHE_TABLES),
+                next_free: (file_len + cluster_size - 1) & !(cluster_size - 1),
+            }),
+        };
+        let l1 = this.read_table(hdr.l1_offset, hdr.l1_size as usize)?;
+        let reft = this.read_table(
+            hdr.reft_offset,
+            (hdr.reft_clusters as usize) << (hdr.cluster_bits - 3),
+        )?;
+        let meta = this.meta.get_mut().unwrap();
+        meta.l1 = l1;
+        meta.reft = reft;
+        Ok(this)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+    /// Indices into the L1 and L2 tables for guest offset `off`
+    fn indices(&self, off: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        let l2_idx = (off >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        let l1_idx = off >> (self.cluster_bits + l2_bits);
+        (l1_idx as usize, l2_idx as usize)
+    }
+
+    fn read_table(&self, off: u64, count: usize) -> Result<Vec<u64>> {
+        let mut buf = vec![0u8; count * 8];
+        self.fp.read_exact_at(&mut buf, off)?;
+        Ok((0..count).map(|n| be64(&buf, n * 8)).collect())
+    }
+    fn write_entry(&self, off: u64, val: u64) -> Result<()> {
+        self.fp.write_all_at(&val.to_be_bytes(), off)
+    }
+
+    fn l2_table<'a>(
+        &self,
+        meta: &'a mut Meta,
+        l2_off: u64,
+    ) -> Result<&'a mut Vec<u64>> {
+        let cache = &mut meta.l2_cache;
+        if let Some(pos) = cache.iter().position(|(off, _)| *off == l2_off) {
+            let ent = cache.remove(pos);
+            cache.push(ent);
+        } else {
+            let table =
+                self.read_table(l2_off, 1 << (self.cluster_bits - 3))?;
+            if cache.len() == L2_CACHE_TABLES {
+                cache.remove(0);
+            }
+            cache.push((l2_off, table));
+        }
+        Ok(&mut cache.last_mut().unwrap().1)
+    }
+
+    /// Host offset of the data cluster for guest offset `off`, if one is
+    /// allocated and not marked as reading zeroes.
+    fn lookup(&self, meta: &mut Meta, off: u64) -> Result<Option<u64>> {
+        let (l1_idx, l2_idx) = self.indices(off);
+        let l2_off = meta.l1[l1_idx] & ENTRY_OFFSET_MASK;
+        if l2_off == 0 {
+            return Ok(None);
+        }
+        let ent = self.l2_table(meta, l2_off)?[l2_idx];
+        if ent & QCOW_OFLAG_COMPRESSED != 0 {
+            return Err(invalid("compressed clusters are not supported"));
+        }
+        if self.version >= 3 && ent & QCOW_OFLAG_ZERO != 0 {
+            return Ok(None);
+        }
+        match ent & ENTRY_OFFSET_MASK {
+            0 => Ok(None),
+            host => Ok(Some(host)),
+        }
+    }
+
+    /// Host offset of the data cluster for guest offset `off`, allocating it
+    /// (and its L2 table) if necessary.
+    fn map_for_write(&self, meta: &mut Meta, off: u64) -> Result<u64> {
+        let (l1_idx, l2_idx) = self.indices(off);
+        let mut l2_off = meta.l1[l1_idx] & ENTRY_OFFSET_MASK;
+        if l2_off == 0 {
+            l2_off = self.alloc_cluster(meta)?;
+            meta.l1[l1_idx] = l2_off | QCOW_OFLAG_COPIED;
+            self.write_entry(
+                self.l1_offset + l1_idx as u64 * 8,
+                meta.l1[l1_idx],
+            )?;
+        }
+
+        let ent = self.l2_table(meta, l2_off)?[l2_idx];
+        if ent & QCOW_OFLAG_COMPRESSED != 0 {
+            return Err(invalid("compressed clusters are not supported"));
+        }
+        let zeroed = self.version >= 3 && ent & QCOW_OFLAG_ZERO != 0;
+        let host = match ent & ENTRY_OFFSET_MASK {
+            0 => self.alloc_cluster(meta)?,
+            host if zeroed => {
+                // Preallocated cluster which must be cleared before the flag
+                let zeroes = vec![0u8; self.cluster_size() as usize];
+                self.fp.write_all_at(&zeroes, host)?;
+                host
+            }
+            host => return Ok(host),
+        };
+
+        let ent = host | QCOW_OFLAG_COPIED;
+        self.l2_table(meta, l2_off)?[l2_idx] = ent;
+        self.write_entry(l2_off + l2_idx as u64 * 8, ent)?;
+        Ok(host)
+    }
+
+    /// Allocate a (zeroed) cluster at the end of the image
+    fn alloc_cluster(&self, meta: &mut Meta) -> Result<u64> {
+        let off = self.extend(meta)?;
+        self.incr_refcount(meta, off)?;
+        Ok(off)
+    }
+    fn extend(&self, meta: &mut Meta) -> Result<u64> {
+        let off = meta.next_free;
+        meta.next_free += self.cluster_size();
+        self.fp.set_len(meta.next_free)?;
+        Ok(off)
+    }
+    fn incr_refcount(&self, meta: &mut Meta, off: u64) -> Result<()> {
+        // 16-bit refcounts, filling each refcount block
+        let per_block = self.cluster_size() / 2;
+        let cluster = off >> self.cluster_bits;
+        let reft_idx = (cluster / per_block) as usize;
+        if reft_idx >= meta.reft.len() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "qcow2: refcount table full",
+            ));
+        }
+
+        let mut block = meta.reft[reft_idx] & REFT_OFFSET_MASK;
+        if block == 0 {
+            // The new refcount block must itself be counted
+            block = self.extend(meta)?;
+            meta.reft[reft_idx] = block;
+            self.write_entry(self.reft_offset + reft_idx as u64 * 8, block)?;
+            self.incr_refcount(meta, block)?;
+        }
+
+        let ent_off = block + (cluster % per_block) * 2;
+        let mut raw = [0u8; 2];
+        self.fp.read_exact_at(&mut raw, ent_off)?;
+        let count = u16::from_be_bytes(raw)
+            .checked_add(1)
+            .ok_or_else(|| invalid("refcount overflow"))?;
+        self.fp.write_all_at(&count.to_be_bytes(), ent_off)
+    }
+
+    fn check_range(&self, offset: u64, len: usize) -> Result<()> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "access beyond end of image",
+            )),
+        }
+    }
+    /// Split an access of `len` bytes at `offset` at cluster boundaries,
+    /// calling `f` with the offset (into the access) and length of each piece.
+    fn for_clusters<F>(&self, offset: u64, len: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(usize, usize) -> Result<()>,
+    {
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < len {
+            let within = (offset + done as u64) & (cluster_size - 1);
+            let chunk =
+                usize::min((cluster_size - within) as usize, len - done);
+            f(done, chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.check_range(offset, buf.len())?;
+        let cmask = self.cluster_size() - 1;
+        self.for_clusters(offset, buf.len(), |done, chunk| {
+            let off = offset + done as u64;
+            let host = self.lookup(&mut self.meta.lock().unwrap(), off)?;
+            let dst = &mut buf[done..(done + chunk)];
+            match host {
+                Some(host) => self.fp.read_exact_at(dst, host + (off & cmask)),
+                None => {
+                    for b in dst.iter_mut() {
+                        *b = 0;
+                    }
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        if !self.writable {
+            return Err(Error::new(ErrorKind::PermissionDenied, "read-only"));
+        }
+        self.check_range(offset, buf.len())?;
+        let cmask = self.cluster_size() - 1;
+        self.for_clusters(offset, buf.len(), |done, chunk| {
+            let off = offset + done as u64;
+            let host =
+                self.map_for_write(&mut self.meta.lock().unwrap(), off)?;
+            self.fp
+                .write_all_at(&buf[done..(done + chunk)], host + (off & cmask))
+        })
+    }
+
+    /// Zero `len` bytes at `offset`.  Unallocated clusters already read as
+    /// zeroes, and are left so.
+    fn write_zeroes(&self, offset: u64, len: usize) -> Result<()> {
+        if !self.writable {
+            return Err(Error::new(ErrorKind::PermissionDenied, "read-only"));
+        }
+        self.check_range(offset, len)?;
+        let cmask = self.cluster_size() - 1;
+        let zeroes = vec![0u8; self.cluster_size() as usize];
+        self.for_clusters(offset, len, |done, chunk| {
+            let off = offset + done as u64;
+            match self.lookup(&mut self.meta.lock().unwrap(), off)? {
+                Some(host) => {
+                    self.fp.write_all_at(&zeroes[..chunk], host + (off & cmask))
+                }
+                None => Ok(()),
+            }
+        })
+    }
+}
+
+pub struct QcowBdev<R: BlockReq> {
+    img: QcowImage,
+    reqs: ReqQueue<R>,
+}
+impl<R: BlockReq> QcowBdev<R> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Arc<Self>> {
+        let img = QcowImage::open(path.as_ref())?;
+        Ok(Arc::new(Self { img, reqs: ReqQueue::new() }))
+    }
+    /// Start `count` workers, allowing up to that many requests to be
+    /// processed in parallel.
+    pub fn start_dispatch_workers(
+        self: Arc<Self>,
+        name: String,
+        count: usize,
+        disp: &Dispatcher,
+    ) {
+        start_workers(self, name, count, disp)
+    }
+
+    fn process_read(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        let mut offset = req.offset() as u64;
+        if let Some(buf) = req.host_buf() {
+            return match self.img.read_at(buf, offset) {
+                Ok(()) => BlockResult::Success,
+                Err(_) => BlockResult::Failure,
+            };
+        }
+        let mem = ctx.mctx.memctx();
+
+        // Guest memory may be modified concurrently, so the image is accessed
+        // through a bounce buffer rather than a slice formed over the mapping.
+        let mut data = Vec::new();
+        while let Some(buf) = req.next_buf() {
+            data.resize(buf.1, 0);
+            if self.img.read_at(&mut data, offset).is_err() {
+                return BlockResult::Failure;
+            }
+            if mem.write_from(buf.0, &data, buf.1) != Some(buf.1) {
+                return BlockResult::Failure;
+            }
+            offset += buf.1 as u64;
+        }
+        BlockResult::Success
+    }
+    fn process_write(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        let mut offset = req.offset() as u64;
+        if let Some(buf) = req.host_buf() {
+            return match self.img.write_at(buf, offset) {
+                Ok(()) => BlockResult::Success,
+                Err(_) => BlockResult::Failure,
+            };
+        }
+        let mem = ctx.mctx.memctx();
+
+        let mut data = Vec::new();
+        while let Some(buf) = req.next_buf() {
+            data.resize(buf.1, 0);
+            if mem.read_into(buf.0, &mut data, buf.1) != Some(buf.1) {
+                return BlockResult::Failure;
+            }
+            if self.img.write_at(&data, offset).is_err() {
+                return BlockResult::Failure;
+            }
+            offset += buf.1 as u64;
+        }
+        BlockResult::Success
+    }
+}
+
+impl<R: BlockReq> QueuedBdev<R> for QcowBdev<R> {
+    fn queue(&self) -> &ReqQueue<R> {
+        &self.reqs
+    }
+    fn process(&self, req: &mut R, ctx: &DispCtx) -> BlockResult {
+        match req.oper() {
+            BlockOp::Read => self.process_read(req, ctx),
+            BlockOp::Write => self.process_write(req, ctx),
+            BlockOp::WriteZeroes { len, may_unmap: _ } => {
+                match self.img.write_zeroes(req.offset() as u64, len) {
+                    Ok(()) => BlockResult::Success,
+                    Err(_) => BlockResult::Failure,
+                }
+            }
+        }
+    }
+}
+
+impl<R: BlockReq> BlockDev<R> for QcowBdev<R> {
+    fn enqueue(&self, req: R) {
+        self.reqs.push(req);
+    }
+
+    fn inquire(&self) -> BlockInquiry {
+        BlockInquiry {
+            total_size: self.img.size / 512,
+            block_size: 512,
+            writable: self.img.writable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::util::tempfs::TempFile;
+
+    const CLUSTER: u64 = 0x1_0000;
+
+    /// Empty v3 image, with 64KiB clusters for the header, refcount table,
+    /// refcount block, and L1 table.
+    fn empty_image(size: u64) -> Vec<u8> {
+        let mut img = vec![0u8; 4 * CLUSTER as usize];
+        let put32 = |img: &mut Vec<u8>, off: usize, v: u32| {
+            img[off..(off + 4)].copy_from_slice(&v.to_be_bytes())
+        };
+        let put64 = |img: &mut Vec<u8>, off: usize, v: u64| {
+            img[off..(off + 8)].copy_from_slice(&v.to_be_bytes())
+        };
+        put32(&mut img, 0, QCOW_MAGIC);
+        put32(&mut img, 4, 3);
+        put32(&mut img, 20, 16);
+        put64(&mut img, 24, size);
+        put32(&mut img, 36, 1);
+        put64(&mut img, 40, 3 * CLUSTER);
+        put64(&mut img, 48, CLUSTER);
+        put32(&mut img, 56, 1);
+        put32(&mut img, 96, REFCOUNT_ORDER);
+        put32(&mut img, 100, QCOW_V3_HDR_LEN as u32);
+        put64(&mut img, CLUSTER as usize, 2 * CLUSTER);
+        for n in 0..4 {
+            img[(2 * CLUSTER) as usize + n * 2 + 1] = 1;
+        }
+        img
+    }
+
+    fn refcount(file: &TempFile, cluster: u64) -> u16 {
+        let data = file.contents();
+        let off = (2 * CLUSTER + cluster * 2) as usize;
+        u16::from_be_bytes([data[off], data[off + 1]])
+    }
+
+    #[test]
+    fn read_write() {
+        let file = TempFile::new("qcow-rw", &empty_image(0x40_0000));
+        let img = QcowImage::open(&file.path()).unwrap();
+
+        let mut buf = vec![0xffu8; 0x2000];
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // Straddle the first two clusters
+        let data: Vec<u8> = (0..0x200u32).map(|n| n as u8).collect();
+        img.write_at(&data, CLUSTER - 0x100).unwrap();
+        let mut buf = vec![0u8; 0x400];
+        img.read_at(&mut buf, CLUSTER - 0x200).unwrap();
+        assert!(buf[..0x100].iter().all(|b| *b == 0));
+        assert_eq!(&buf[0x100..0x300], &data[..]);
+        assert!(buf[0x300..].iter().all(|b| *b == 0));
+
+        // One new L2 table and two data clusters, each counted once
+        let len = std::fs::metadata(&file.path()).unwrap().len();
+        assert_eq!(len, 7 * CLUSTER);
+        for cluster in 0..7 {
+            assert_eq!(refcount(&file, cluster), 1);
+        }
+
+        // Data persists when the image is reopened
+        drop(img);
+        let img = QcowImage::open(&file.path()).unwrap();
+        let mut buf = vec![0u8; 0x200];
+        img.read_at(&mut buf, CLUSTER - 0x100).unwrap();
+        assert_eq!(buf, data);
+
+        assert!(img.write_at(&data, 0x40_0000 - 0x100).is_err());
+        assert!(img.read_at(&mut buf, u64::MAX - 0x10).is_err());
+    }
+
+    #[test]
+    fn write_zeroes() {
+        let file = TempFile::new("qcow-zero", &empty_image(0x40_0000));
+        let img = QcowImage::open(&file.path()).unwrap();
+
+        img.write_at(&[0xaa; 0x1000], 0).unwrap();
+        img.write_zeroes(0x400, 0x400).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        img.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..0x400].iter().all(|b| *b == 0xaa));
+        assert!(buf[0x400..0x800].iter().all(|b| *b == 0));
+        assert!(buf[0x800..].iter().all(|b| *b == 0xaa));
+
+        // Nothing is allocated to zero unallocated clusters
+        let len = std::fs::metadata(&file.path()).unwrap().len();
+        img.write_zeroes(CLUSTER, 0x10_0000).unwrap();
+        assert_eq!(std::fs::metadata(&file.path()).unwrap().len(), len);
+    }
+
+    #[test]
+    fn refcount_block_alloc() {
+        // Without a refcount block, the first allocation must add one
+        let mut data = empty_image(0x40_0000);
+        data[(CLUSTER as usize)..(CLUSTER as usize + 8)].fill(0);
+        let file = TempFile::new("qcow-refblock", &data);
+        let img = QcowImage::open(&file.path()).unwrap();
+
+        img.write_at(&[0x55; 0x10], 0).unwrap();
+        drop(img);
+        let data = file.contents();
+        // L2 table at cluster 4, its refcount block at 5, and data at 6
+        assert_eq!(be64(&data, CLUSTER as usize), 5 * CLUSTER);
+        let count = |n: usize| {
+            let off = 5 * CLUSTER as usize + n * 2;
+            u16::from_be_bytes([data[off], data[off + 1]])
+        };
+        assert_eq!(count(4), 1);
+        assert_eq!(count(5), 1);
+        assert_eq!(count(6), 1);
+        assert_eq!(&data[(6 * CLUSTER as usize)..][..0x10], &[0x55; 0x10]);
+    }
+
+    #[test]
+    fn header_validate() {
+        let good = empty_image(0x40_0000);
+        assert!(Header::parse(&good).is_ok());
+
+        let with = |off: usize, val: &[u8]| {
+            let mut img = good.clone();
+            img[off..(off + val.len())].copy_from_slice(val);
+            Header::parse(&img[..QCOW_V3_HDR_LEN])
+        };
+        assert!(with(0, b"QFI\0").is_err(), "bad magic");
+        assert!(with(4, &4u32.to_be_bytes()).is_err(), "version");
+        assert!(with(15, &[1]).is_err(), "backing file");
+        assert!(with(20, &8u32.to_be_bytes()).is_err(), "cluster size");
+        assert!(with(35, &[1]).is_err(), "encryption");
+        assert!(with(39, &[0]).is_err(), "L1 size");
+        assert!(with(63, &[1]).is_err(), "snapshots");
+        assert!(with(79, &[1]).is_err(), "dirty");
+        assert!(with(99, &[5]).is_err(), "refcount order");
+        assert!(Header::parse(&good[..QCOW_V2_HDR_LEN]).is_err());
+
+        let huge = with(24, &u64::MAX.to_be_bytes()).err().unwrap();
+        assert_eq!(huge.kind(), ErrorKind::InvalidData, "image size");
+
+        // Tables must lie within the image
+        let len = good.len() as u64;
+        let hdr = Header::parse(&good).unwrap();
+        assert!(hdr.check_tables(len).is_ok());
+        assert!(hdr.check_tables(3 * CLUSTER + 4).is_err(), "L1 past end");
+        let big_l1 =
+            Header { l1_size: u32::MAX, ..Header::parse(&good).unwrap() };
+        assert!(big_l1.check_tables(len).is_err(), "L1 size");
+        let big_reft =
+            Header { reft_clusters: u32::MAX, ..Header::parse(&good).unwrap() };
+        assert!(big_reft.check_tables(len).is_err(), "refcount size");
+    }
+}
@@ -24,6 +24,10 @@ struct Socks {
     server: UnixListener,
     client: Option<UnixStream>,
     client_token_fd: Option<Token>,
+    /// Listener registration retained (while a client is connected) so that
+    /// additional connections can be accepted as read-only viewers
+    viewer_listen: Option<Token>,
+    max_viewers: usize,
 }
 struct SinkDriver {
     sink: Option<Arc<dyn Sink>>,
@@ -32,6 +36,61 @@ struct SinkDriver {
 struct SourceDriver {
     source: Option<Arc<dyn Source>>,
     buf: VecDeque<u8>,
+    connected: bool,
+    scrollback: Scrollback,
+    replay: VecDeque<u8>,
+    viewers: Vec<Viewer>,
+}
+
+/// Minimum output held for a viewer before it is deemed unable to keep up.
+/// (A viewer may always fall as far behind as the scrollback it is sent.)
+const VIEWER_PENDING_MIN: usize = 4096;
+
+/// Read-only viewer, and the output yet to be written to it
+struct Viewer {
+    sock: UnixStream,
+    pending: VecDeque<u8>,
+}
+impl Viewer {
+    /// Write as much of the pending output as the (non-blocking) socket will
+    /// take, returning `false` if the viewer has gone away.
+    fn flush(&mut self) -> bool {
+        while !self.pending.is_empty() {
+            let (front, _back) = self.pending.as_slices();
+            match self.sock.write(front) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_e) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Bounded history of output sent (or destined) to clients
+struct Scrollback {
+    buf: VecDeque<u8>,
+    max: usize,
+}
+impl Scrollback {
+    fn new(max: usize) -> Self {
+        Self { buf: VecDeque::with_capacity(max), max }
+    }
+    fn enabled(&self) -> bool {
+        self.max != 0
+    }
+    fn push(&mut self, b: u8) {
+        if !self.enabled() {
+            return;
+        }
+        if self.buf.len() == self.max {
+            self.buf.pop_front();
+        }
+        self.buf.push_back(b);
+    }
 }
 
 impl SinkDriver {
@@ -41,9 +100,73 @@ impl SinkDriver {
     }
 }
 impl SourceDriver {
-    fn new(bufsz: usize) -> Self {
+    fn new(bufsz: usize, scrollback: usize) -> Self {
         assert!(bufsz > 0);
-        Self { source: None, buf: VecDeque::with_capacity(bufsz) }
+        Self {
+            source: None,
+            buf: VecDeque::with_capacity(bufsz),
+            connected: false,
+            scrollback: Scrollback::new(scrollback),
+            replay: VecDeque::new(),
+            viewers: Vec::new(),
+        }
+    }
+
+    /// Record a byte of output as delivered: retaining it in the scrollback
+    /// and queuing it for any viewers, to be written by `flush_viewers`.
+    fn record(&mut self, b: u8) {
+        self.scrollback.push(b);
+        for viewer in self.viewers.iter_mut() {
+            viewer.pending.push_back(b);
+        }
+    }
+
+    /// Write out the output queued for viewers.  Viewers which cannot keep up
+    /// (or have gone away) are dropped, rather than stalling output to the
+    /// read-write client.
+    fn flush_viewers(&mut self) {
+        let max = usize::max(self.scrollback.max, VIEWER_PENDING_MIN);
+        self.viewers.retain_mut(|v| v.flush() && v.pending.len() <= max);
+    }
+
+    /// Add a read-only viewer, queuing the scrollback to be sent to it
+    fn add_viewer(&mut self, viewer: UnixStream) -> Result<()> {
+        viewer.set_nonblocking(true)?;
+        let pending = self.scrollback.buf.clone();
+        self.viewers.push(Viewer { sock: viewer, pending });
+        self.flush_viewers();
+        Ok(())
+    }
+
+    /// Queue up the scrollback for replay to a newly connected client
+    fn client_connected(&mut self) {
+        self.connected = true;
+        self.replay = self.scrollback.buf.iter().copied().collect();
+    }
+    fn client_gone(&mut self) {
+        self.connected = false;
+        self.replay.clear();
+        if self.scrollback.enabled() {
+            // Any output not yet sent is retained (in order) in the scrollback,
+            // ahead of whatever the source produces while disconnected.
+            while let Some(b) = self.buf.pop_front() {
+                self.record(b);
+            }
+            self.drive();
+        }
+    }
+
+    /// Next byte to be written to the client, with any replay coming first
+    fn peek_output(&self) -> Option<u8> {
+        self.replay.front().or_else(|| self.buf.front()).copied()
+    }
+    /// Consume the byte returned by `peek_output`, once it has been written
+    fn consume_output(&mut self) {
+        if self.replay.pop_front().is_none() {
+            if let Some(b) = self.buf.pop_front() {
+                self.record(b);
+            }
+        }
     }
 }
 
@@ -95,18 +218,30 @@ impl BufDriver for SinkDriver {
 
 impl BufDriver for SourceDriver {
     fn drive(&mut self) {
-        if let Some(source) = self.source.as_ref() {
-            while self.buf.len() < self.buf.capacity() {
-                if let Some(b) = source.source_read() {
-                    self.buf.push_back(b);
-                } else {
-                    break;
+        if let Some(source) = self.source.clone() {
+            if !self.connected && self.scrollback.enabled() {
+                // With no client to deliver to, keep the source flowing into
+                // the scrollback so it is not blocked on a console connection.
+                while let Some(b) = source.source_read() {
+                    self.record(b);
+                }
+            } else {
+                while self.buf.len() < self.buf.capacity() {
+                    if let Some(b) = source.source_read() {
+                        self.buf.push_back(b);
+                    } else {
+                        break;
+                    }
                 }
             }
         }
+        // Pass along whatever has been delivered since the last pass
+        self.flush_viewers();
     }
     fn buffer_state(&self) -> BufState {
-        if self.buf.is_empty() {
+        if !self.replay.is_empty() {
+            BufState::ProcessRequired
+        } else if self.buf.is_empty() {
             BufState::Steady
         } else if self.buf.len() != self.buf.capacity() {
             BufState::ProcessCapable
@@ -131,6 +266,17 @@ impl SelfArc for UDSock {
 }
 impl UDSock {
     pub fn bind(path: &Path) -> Result<Arc<Self>> {
+        Self::bind_with_scrollback(path, 0)
+    }
+    /// Bind a socket which retains up to `scrollback` bytes of recent output
+    /// from its source, replaying them to each newly connected client.
+    ///
+    /// While no client is connected, output from the source is consumed into
+    /// the scrollback, rather than being held back until a client arrives.
+    pub fn bind_with_scrollback(
+        path: &Path,
+        scrollback: usize,
+    ) -> Result<Arc<Self>> {
         let sock = match UnixListener::bind(path) {
             Ok(sock) => sock,
             Err(e) => {
@@ -149,9 +295,11 @@ impl UDSock {
                 server: sock,
                 client: None,
                 client_token_fd: None,
+                viewer_listen: None,
+                max_viewers: 0,
             }),
             sink_driver: Mutex::new(SinkDriver::new(16)),
-            source_driver: Mutex::new(SourceDriver::new(16)),
+            source_driver: Mutex::new(SourceDriver::new(16, scrollback)),
             cv: Condvar::new(),
             sa_cell: SelfArcCell::new(),
         });
@@ -159,6 +307,15 @@ impl UDSock {
         Ok(this)
     }
 
+    /// Accept up to `max` additional connections, made while a client is
+    /// connected, as read-only viewers of the source output.
+    ///
+    /// Viewers are sent the scrollback (if any) upon connection.  Input from
+    /// them is ignored, and those which fall behind the output are dropped.
+    pub fn set_max_viewers(&self, max: usize) {
+        self.socks.lock().unwrap().max_viewers = max;
+    }
+
     pub fn attach_sink(&self, sink: Arc<dyn Sink>) {
         let mut state = self.sink_driver.lock().unwrap();
 
@@ -332,7 +489,7 @@ impl UDSock {
         if revents.contains(FdEvents::POLLOUT) && !close_client {
             let mut source = self.source_driver.lock().unwrap();
             while source.buffer_state() != BufState::Steady {
-                buf[0] = source.buf.pop_front().unwrap();
+                buf[0] = source.peek_output().unwrap();
                 if match client.write(&buf) {
                     Ok(0) => true,
                     Err(e) if e.kind() == ErrorKind::WouldBlock => true,
@@ -342,10 +499,10 @@ impl UDSock {
                     }
                     Ok(_n) => false,
                 } {
-                    // failed the write, put the data back
-                    source.buf.push_front(buf[0]);
+                    // failed the write, leave the data in place
                     break;
                 }
+                source.consume_output();
             }
         }
         if close_client {
@@ -359,8 +516,13 @@ impl UDSock {
         if let Some(token) = socks.client_token_fd {
             ctx.event.fd_deregister(token);
         }
+        if let Some(token) = socks.viewer_listen.take() {
+            ctx.event.fd_deregister(token);
+        }
         socks.client = None;
+        socks.client_token_fd = None;
         socks.state = SockState::ClientGone;
+        self.source_driver.lock().unwrap().client_gone();
         self.do_listen(socks, ctx);
     }
 }
@@ -373,9 +535,14 @@ impl EventTarget for UDSock {
             SockState::Listen(listen_tok) => {
                 match socks.server.accept() {
                     Ok((client, _addr)) => {
-                        ctx.event.fd_deregister(listen_tok);
+                        if socks.max_viewers != 0 {
+                            socks.viewer_listen = Some(listen_tok);
+                        } else {
+                            ctx.event.fd_deregister(listen_tok);
+                        }
                         socks.client = Some(client);
                         socks.state = SockState::Connected;
+                        self.source_driver.lock().unwrap().client_connected();
                         self.config_notifications(
                             &mut socks,
                             &mut self.sink_driver.lock().unwrap(),
@@ -389,6 +556,15 @@ impl EventTarget for UDSock {
                     }
                 }
             }
+            SockState::Connected if Some(ev.token) == socks.viewer_listen => {
+                if let Ok((viewer, _addr)) = socks.server.accept() {
+                    let mut source = self.source_driver.lock().unwrap();
+                    if source.viewers.len() < socks.max_viewers {
+                        // A viewer which cannot take the scrollback is dropped
+                        let _ = source.add_viewer(viewer);
+                    }
+                }
+            }
             SockState::Connected => match ev.res {
                 Resource::Fd(cfd, revents) => {
                     assert_eq!(cfd, socks.client.as_ref().unwrap().as_raw_fd());
@@ -408,3 +584,138 @@ impl EventTarget for UDSock {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chardev::Notifier;
+
+    struct TestSource(Mutex<VecDeque<u8>>);
+    impl TestSource {
+        fn new(data: &[u8]) -> Arc<Self> {
+            Arc::new(Self(Mutex::new(data.iter().copied().collect())))
+        }
+        fn feed(&self, data: &[u8]) {
+            self.0.lock().unwrap().extend(data.iter());
+        }
+    }
+    impl Source for TestSource {
+        fn source_read(&self) -> Option<u8> {
+            self.0.lock().unwrap().pop_front()
+        }
+        fn source_discard(&self, _count: usize) -> usize {
+            0
+        }
+        fn source_set_autodiscard(&self, _active: bool) {}
+        fn source_set_notifier(&self, _f: Notifier) {}
+    }
+
+    fn drain(drv: &mut SourceDriver) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            drv.drive();
+            match drv.peek_output() {
+                Some(b) => {
+                    out.push(b);
+                    drv.consume_output();
+                }
+                None => break,
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn scrollback_late_connect() {
+        let src = TestSource::new(b"early boot output");
+        let mut drv = SourceDriver::new(4, 10);
+        drv.source = Some(src.clone() as Arc<dyn Source>);
+
+        // With no client, the source is consumed into the (bounded) scrollback
+        drv.drive();
+        assert!(src.0.lock().unwrap().is_empty());
+        assert_eq!(drv.buffer_state(), BufState::Steady);
+
+        drv.client_connected();
+        assert_eq!(drv.buffer_state(), BufState::ProcessRequired);
+        src.feed(b"!!");
+        assert_eq!(drain(&mut drv), b"oot output!!");
+
+        // A subsequent client sees that which the first was sent
+        drv.client_gone();
+        src.feed(b"?");
+        drv.client_connected();
+        assert_eq!(drain(&mut drv), b"t output!!?");
+    }
+
+    #[test]
+    fn scrollback_disabled() {
+        let src = TestSource::new(b"early boot output");
+        let mut drv = SourceDriver::new(4, 0);
+        drv.source = Some(src.clone() as Arc<dyn Source>);
+
+        // Output is held in the source, pending a client
+        drv.drive();
+        assert_eq!(src.0.lock().unwrap().len(), 13);
+        drv.client_connected();
+        assert_eq!(drain(&mut drv), b"early boot output");
+    }
+
+    #[test]
+    fn scrollback_viewers() {
+        let src = TestSource::new(b"boot");
+        let mut drv = SourceDriver::new(4, 6);
+        drv.source = Some(src.clone() as Arc<dyn Source>);
+        drv.drive();
+
+        let (early, mut early_peer) = UnixStream::pair().unwrap();
+        drv.add_viewer(early).unwrap();
+
+        drv.client_connected();
+        src.feed(b"ing up");
+        assert_eq!(drain(&mut drv), b"booting up");
+
+        let mut buf = [0u8; 16];
+        let n = early_peer.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"booting up");
+
+        // A late viewer receives only what remains in the scrollback
+        let (late, mut late_peer) = UnixStream::pair().unwrap();
+        drv.add_viewer(late).unwrap();
+
+        // Viewers which have gone away are dropped
+        drop(early_peer);
+        src.feed(b"!");
+        assert_eq!(drain(&mut drv), b"!");
+        assert_eq!(drv.viewers.len(), 1);
+
+        let n = late_peer.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"ing up!");
+    }
+
+    #[test]
+    fn viewer_large_scrollback() {
+        // More scrollback than the socket can take in one go
+        const LEN: usize = 1024 * 1024;
+        let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+        let src = TestSource::new(&data);
+        let mut drv = SourceDriver::new(4, LEN);
+        drv.source = Some(src.clone() as Arc<dyn Source>);
+        drv.drive();
+
+        let (viewer, mut peer) = UnixStream::pair().unwrap();
+        drv.add_viewer(viewer).unwrap();
+        assert_eq!(drv.viewers.len(), 1);
+        assert!(!drv.viewers[0].pending.is_empty());
+
+        let mut seen = Vec::with_capacity(LEN);
+        let mut buf = vec![0u8; 64 * 1024];
+        while seen.len() < LEN {
+            drv.flush_viewers();
+            let n = peer.read(&mut buf).unwrap();
+            seen.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(seen, data);
+        assert_eq!(drv.viewers.len(), 1);
+    }
+}
@@ -285,9 +285,9 @@ impl RWOp<'_, '_> {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct GuestAddr(pub u64);
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct GuestRegion(pub GuestAddr, pub usize);
 
 impl Add<usize> for GuestAddr {
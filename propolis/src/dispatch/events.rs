@@ -247,7 +247,7 @@ impl EventDispatch {
         self.notify();
     }
 
-    fn notify(&self) {
+    pub(super) fn notify(&self) {
         if !self.notified.fetch_or(true, Ordering::SeqCst) {
             self.eport.send(1, NOTIFY_TOKEN).unwrap();
         }
@@ -266,6 +266,7 @@ impl EventDispatch {
         }
         let event = res.unwrap();
 
+        let _active = ctx.device_activity();
         let state = self.tracker.lock().unwrap();
         Tracker::dispatch_event(state, Token(event.data), &event.obj, ctx);
     }
@@ -300,7 +301,7 @@ impl EventCtx {
 }
 
 pub fn event_loop(edisp: Arc<EventDispatch>, dctx: DispCtx) {
-    loop {
+    while !dctx.should_exit() {
         edisp.process_events(&dctx)
     }
 }
@@ -1,7 +1,8 @@
-use std::io::Result;
-use std::sync::{Arc, Mutex};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{Builder, JoinHandle};
 
+use crate::exits::SuspendReason;
 use crate::vcpu::VcpuHdl;
 use crate::vmm::MachineCtx;
 
@@ -10,11 +11,258 @@ pub mod events;
 
 use events::{EventCtx, EventDispatch};
 
+/// Lifecycle phases of a [`Dispatcher`].
+///
+/// Shutdown proceeds through these in order:
+///
+/// 1. `Draining`: No new work (vCPU or device threads) may be spawned.
+/// 2. `HaltVcpus`: The instance is suspended, so the vCPUs stop running guest
+///    code, and the vCPU threads are joined.  Devices remain fully functional
+///    during this phase, so any in-flight emulation can complete.
+/// 3. `QuiesceDevices`: Device threads (including event dispatch) are told to
+///    exit via their registered quiesce hooks, and are then joined.
+/// 4. `Released`: The quiesce hooks, and any device references they hold, are
+///    dropped.  Nothing under dispatch is left referring to the devices.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Phase {
+    Running,
+    Draining,
+    HaltVcpus,
+    QuiesceDevices,
+    Released,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TaskClass {
+    Vcpu,
+    Device,
+}
+impl TaskClass {
+    /// Phase at which tasks of this class are expected to exit
+    fn exit_phase(&self) -> Phase {
+        match self {
+            TaskClass::Vcpu => Phase::HaltVcpus,
+            TaskClass::Device => Phase::QuiesceDevices,
+        }
+    }
+}
+
+/// Accounting of vCPU threads held out of guest context by a pause
+#[derive(Default)]
+struct PauseState {
+    paused: bool,
+    /// vCPU threads which have not yet exited
+    live: usize,
+    /// vCPU threads parked, waiting for the pause to end
+    parked: usize,
+    /// Device activity under way (see [`DispCtx::device_activity`])
+    dev_active: usize,
+    /// RTC time (in seconds since the epoch) when the instance was paused
+    rtc_time: u64,
+}
+
+struct Lifecycle {
+    phase: Mutex<Phase>,
+    cv: Condvar,
+    suspended: Mutex<Option<SuspendReason>>,
+    pause: Mutex<PauseState>,
+    pause_cv: Condvar,
+}
+impl Lifecycle {
+    fn new() -> Self {
+        Self {
+            phase: Mutex::new(Phase::Running),
+            cv: Condvar::new(),
+            suspended: Mutex::new(None),
+            pause: Mutex::new(PauseState::default()),
+            pause_cv: Condvar::new(),
+        }
+    }
+    fn phase(&self) -> Phase {
+        *self.phase.lock().unwrap()
+    }
+    fn advance(&self, next: Phase) {
+        let mut phase = self.phase.lock().unwrap();
+        assert!(next > *phase);
+        *phase = next;
+        self.cv.notify_all();
+    }
+    fn wait_for(&self, target: Phase) {
+        let phase = self.phase.lock().unwrap();
+        let _guard = self.cv.wait_while(phase, |p| *p < target).unwrap();
+    }
+
+    fn vcpu_started(&self) {
+        self.pause.lock().unwrap().live += 1;
+    }
+    fn vcpu_exited(&self) {
+        self.pause.lock().unwrap().live -= 1;
+        self.pause_cv.notify_all();
+    }
+    /// Mark the instance as paused, failing if it already is, or if it has no
+    /// vCPUs running (as while it is being reset or is asleep) to be paused.
+    fn begin_pause(&self) -> std::result::Result<(), &'static str> {
+        let mut state = self.pause.lock().unwrap();
+        if state.paused {
+            return Err("instance already paused");
+        }
+        if state.live == 0 {
+            return Err("instance has no vCPUs running");
+        }
+        state.paused = true;
+        Ok(())
+    }
+    /// Wait for every remaining vCPU thread to park, and for any device
+    /// activity under way to finish.
+    fn wait_parked(&self) {
+        let state = self.pause.lock().unwrap();
+        let _guard = self
+            .pause_cv
+            .wait_while(state, |s| s.parked < s.live || s.dev_active > 0)
+            .unwrap();
+    }
+    /// Wait for the instance to be resumed, should it be paused.
+    fn wait_unpaused(&self) {
+        let state = self.pause.lock().unwrap();
+        let _guard = self.pause_cv.wait_while(state, |s| s.paused).unwrap();
+    }
+    fn dev_enter(&self) {
+        let state = self.pause.lock().unwrap();
+        let mut state = self.pause_cv.wait_while(state, |s| s.paused).unwrap();
+        state.dev_active += 1;
+    }
+    fn dev_exit(&self) {
+        self.pause.lock().unwrap().dev_active -= 1;
+        self.pause_cv.notify_all();
+    }
+    /// Release any parked vCPU threads, returning if the instance was paused.
+    fn end_pause(&self) -> bool {
+        let mut state = self.pause.lock().unwrap();
+        let was_paused = std::mem::replace(&mut state.paused, false);
+        self.pause_cv.notify_all();
+        was_paused
+    }
+    fn park(&self) {
+        let mut state = self.pause.lock().unwrap();
+        state.parked += 1;
+        self.pause_cv.notify_all();
+        state = self.pause_cv.wait_while(state, |s| s.paused).unwrap();
+        state.parked -= 1;
+    }
+}
+
+type QuiesceHook = Box<dyn Fn() + Send>;
+
+#[derive(Default)]
+struct Tasks {
+    vcpus: Vec<(String, JoinHandle<()>)>,
+    devices: Vec<(String, JoinHandle<()>)>,
+    quiesce: Vec<QuiesceHook>,
+}
+
+/// Bookkeeping for spawned threads, independent of the machine they act upon,
+/// which carries out the ordered shutdown.
+struct TaskSet {
+    lifecycle: Arc<Lifecycle>,
+    tasks: Mutex<Tasks>,
+}
+impl TaskSet {
+    fn new() -> Self {
+        Self {
+            lifecycle: Arc::new(Lifecycle::new()),
+            tasks: Mutex::new(Tasks::default()),
+        }
+    }
+
+    fn spawn<F>(&self, class: TaskClass, name: String, func: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Holding the task lock while checking the phase ensures that anything
+        // spawned is visible to a concurrent shutdown.
+        let mut tasks = self.tasks.lock().unwrap();
+        if self.lifecycle.phase() != Phase::Running {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "dispatcher is shutting down",
+            ));
+        }
+        // Live vCPU threads are counted, so a pause knows how many to await
+        if class == TaskClass::Vcpu {
+            self.lifecycle.vcpu_started();
+        }
+        let lifecycle = Arc::clone(&self.lifecycle);
+        let res = Builder::new().name(name.clone()).spawn(move || {
+            func();
+            if class == TaskClass::Vcpu {
+                lifecycle.vcpu_exited();
+            }
+        });
+        let hdl = match res {
+            Ok(hdl) => hdl,
+            Err(e) => {
+                if class == TaskClass::Vcpu {
+                    self.lifecycle.vcpu_exited();
+                }
+                return Err(e);
+            }
+        };
+        match class {
+            TaskClass::Vcpu => tasks.vcpus.push((name, hdl)),
+            TaskClass::Device => tasks.devices.push((name, hdl)),
+        }
+        Ok(())
+    }
+    fn on_quiesce(&self, hook: QuiesceHook) {
+        self.tasks.lock().unwrap().quiesce.push(hook);
+    }
+
+    fn join_vcpus(&self) {
+        let vcpus = std::mem::take(&mut self.tasks.lock().unwrap().vcpus);
+        for (_name, joinhdl) in vcpus {
+            joinhdl.join().unwrap()
+        }
+    }
+    fn vcpus_running(&self) -> bool {
+        !self.tasks.lock().unwrap().vcpus.is_empty()
+    }
+    fn join_devices(&self) {
+        let devices = std::mem::take(&mut self.tasks.lock().unwrap().devices);
+        for (_name, joinhdl) in devices {
+            joinhdl.join().unwrap()
+        }
+    }
+
+    fn shutdown<F>(&self, halt_vcpus: F)
+    where
+        F: FnOnce(),
+    {
+        if self.lifecycle.phase() != Phase::Running {
+            return;
+        }
+        self.lifecycle.advance(Phase::Draining);
+
+        self.lifecycle.advance(Phase::HaltVcpus);
+        halt_vcpus();
+        self.join_vcpus();
+
+        self.lifecycle.advance(Phase::QuiesceDevices);
+        let hooks = std::mem::take(&mut self.tasks.lock().unwrap().quiesce);
+        for hook in hooks.iter() {
+            hook();
+        }
+        self.join_devices();
+
+        drop(hooks);
+        self.lifecycle.advance(Phase::Released);
+    }
+}
+
 pub struct Dispatcher {
     mctx: MachineCtx,
     event_dispatch: Arc<EventDispatch>,
-    event_thread: Option<JoinHandle<()>>,
-    tasks: Mutex<Vec<(String, JoinHandle<()>)>>,
+    events_spawned: bool,
+    tasks: TaskSet,
 }
 
 impl Dispatcher {
@@ -22,24 +270,28 @@ impl Dispatcher {
         Self {
             mctx,
             event_dispatch: Arc::new(EventDispatch::new()),
-            event_thread: None,
-            tasks: Mutex::new(Vec::new()),
+            events_spawned: false,
+            tasks: TaskSet::new(),
         }
     }
 
     pub fn spawn_events(&mut self) -> Result<()> {
-        if self.event_thread.is_some() {
+        if self.events_spawned {
             // XXX: better error handling
             panic!();
         }
-        let ctx = DispCtx::new(self.mctx.clone(), self.event_dispatch.clone());
+        let ctx = self.ctx(TaskClass::Device);
         let edisp = Arc::clone(&self.event_dispatch);
-        let hdl = Builder::new().name("event-dispatch".to_string()).spawn(
+        self.tasks.spawn(
+            TaskClass::Device,
+            "event-dispatch".to_string(),
             move || {
                 events::event_loop(edisp, ctx);
             },
         )?;
-        self.event_thread = Some(hdl);
+        let edisp = Arc::clone(&self.event_dispatch);
+        self.tasks.on_quiesce(Box::new(move || edisp.notify()));
+        self.events_spawned = true;
         Ok(())
     }
 
@@ -52,57 +304,403 @@ impl Dispatcher {
     where
         D: Send + 'static,
     {
-        let ctx = DispCtx::new(self.mctx.clone(), self.event_dispatch.clone());
-        let hdl = Builder::new().name(name.clone()).spawn(move || {
+        let ctx = self.ctx(TaskClass::Device);
+        self.tasks.spawn(TaskClass::Device, name, move || {
             func(ctx, data);
-        })?;
-        self.tasks.lock().unwrap().push((name, hdl));
-        Ok(())
+        })
     }
     pub fn spawn_vcpu(
         &self,
         vcpu: VcpuHdl,
         func: fn(DispCtx, VcpuHdl),
     ) -> Result<()> {
-        let ctx = DispCtx::new(self.mctx.clone(), self.event_dispatch.clone());
+        let ctx = self.ctx(TaskClass::Vcpu);
         let name = format!("vcpu-{}", vcpu.cpuid());
-        let hdl = Builder::new().name(name.clone()).spawn(move || {
+        self.tasks.spawn(TaskClass::Vcpu, name, move || {
             func(ctx, vcpu);
+        })
+    }
+    /// Register a hook to be called when device threads are being quiesced.
+    ///
+    /// Threads spawned via [`Dispatcher::spawn`] which block on some condition
+    /// other than [`DispCtx::wait_exit`] should use this to wake themselves,
+    /// so they can observe [`DispCtx::should_exit`].  The hook is dropped once
+    /// all device threads have been joined.
+    pub fn on_quiesce<F>(&self, hook: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        self.tasks.on_quiesce(Box::new(hook));
+    }
+    /// Wait for the vCPU threads to exit of their own accord (such as when the
+    /// guest powers itself off), then perform an orderly shutdown.
+    pub fn join(&self) {
+        self.tasks.join_vcpus();
+        self.shutdown();
+    }
+    /// Wait for the vCPU threads to exit of their own accord, without shutting
+    /// down the rest of dispatch.
+    ///
+    /// Returns the reason the instance was suspended, if one was reported by
+    /// the vCPUs via [`DispCtx::report_suspend`].
+    pub fn wait_vcpus(&self) -> Option<SuspendReason> {
+        self.tasks.join_vcpus();
+        *self.tasks.lifecycle.suspended.lock().unwrap()
+    }
+    /// Reset the instance, once its vCPUs have exited (see
+    /// [`Dispatcher::wait_vcpus`]), so that it can be booted again.
+    ///
+    /// The in-kernel state of the instance is reinitialized, after which
+    /// `reset_devs` is called to return the emulated devices to their
+    /// power-on state.  Device threads are left running throughout.  It is up
+    /// to the caller to prepare and spawn the vCPUs anew.
+    pub fn reset<F>(&self, reset_devs: F) -> Result<()>
+    where
+        F: FnOnce(&DispCtx),
+    {
+        self.reinit()?;
+        self.with_ctx(reset_devs);
+        Ok(())
+    }
+    /// Wake the instance from sleep (S3), once its vCPUs have exited.
+    ///
+    /// As with [`Dispatcher::reset`], the in-kernel state of the instance is
+    /// reinitialized, but the emulated devices retain their state: `wake_devs`
+    /// is called to notify them of the wake-up.  The vCPUs are then expected
+    /// to be started at the reset vector, for the firmware to carry out the
+    /// resume.
+    pub fn wake<F>(&self, wake_devs: F) -> Result<()>
+    where
+        F: FnOnce(&DispCtx),
+    {
+        self.reinit()?;
+        self.with_ctx(wake_devs);
+        Ok(())
+    }
+    fn reinit(&self) -> Result<()> {
+        if self.phase() != Phase::Running {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "dispatcher is shutting down",
+            ));
+        }
+        if self.tasks.vcpus_running() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "vCPUs must exit before reset",
+            ));
+        }
+        // A pause which caught the vCPUs on their way out holds the instance
+        // as it is until resumed.
+        self.tasks.lifecycle.wait_unpaused();
+        self.mctx.with_hdl(|hdl| hdl.reinit())?;
+        *self.tasks.lifecycle.suspended.lock().unwrap() = None;
+        Ok(())
+    }
+    /// Pause the instance, stopping its vCPUs until [`Dispatcher::resume`].
+    ///
+    /// Once every vCPU has been parked outside of guest context, and device
+    /// activity gated by [`DispCtx::device_activity`] has drained,
+    /// `pause_devs` is called so that the emulated devices can quiesce any
+    /// other activity which proceeds independently of the guest (such as
+    /// timers).
+    ///
+    /// This fails if the instance has no vCPUs running, as while it is asleep
+    /// or between a reset and the vCPUs being started anew.
+    pub fn pause<F>(&self, pause_devs: F) -> Result<()>
+    where
+        F: FnOnce(&DispCtx),
+    {
+        if self.phase() != Phase::Running {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "dispatcher is shutting down",
+            ));
+        }
+        let lifecycle = &self.tasks.lifecycle;
+        if let Err(msg) = lifecycle.begin_pause() {
+            return Err(Error::new(ErrorKind::Other, msg));
+        }
+        if let Err(e) = self.mctx.with_hdl(|hdl| hdl.pause()) {
+            lifecycle.end_pause();
+            return Err(e.into());
+        }
+        lifecycle.wait_parked();
+
+        // Note the time, so it can be wound back when resuming
+        let rtc_time = self.mctx.with_hdl(|hdl| hdl.rtc_gettime())?;
+        lifecycle.pause.lock().unwrap().rtc_time = rtc_time;
+
+        self.with_ctx(pause_devs);
+        Ok(())
+    }
+    /// Resume an instance stopped by [`Dispatcher::pause`].
+    ///
+    /// The emulated devices are resumed by `resume_devs`, and the RTC is set
+    /// back to the time at which the instance was paused, so the guest does
+    /// not observe the pause as a jump in the time of day.  The TSC (and the
+    /// in-kernel timers derived from it) continue to advance while paused, as
+    /// the kernel offers no interface through which to adjust them.
+    pub fn resume<F>(&self, resume_devs: F) -> Result<()>
+    where
+        F: FnOnce(&DispCtx),
+    {
+        let lifecycle = &self.tasks.lifecycle;
+        if !lifecycle.pause.lock().unwrap().paused {
+            return Err(Error::new(ErrorKind::Other, "instance not paused"));
+        }
+        self.with_ctx(resume_devs);
+
+        let rtc_time = lifecycle.pause.lock().unwrap().rtc_time;
+        self.mctx.with_hdl(|hdl| {
+            hdl.rtc_settime(rtc_time)?;
+            hdl.resume()
         })?;
-        self.tasks.lock().unwrap().push((name, hdl));
+        lifecycle.end_pause();
         Ok(())
     }
-    pub fn join(&self) {
-        let mut tasks = self.tasks.lock().unwrap();
-        for (_name, joinhdl) in tasks.drain(..) {
-            joinhdl.join().unwrap()
+    /// Tear down all dispatch activity in the order described by [`Phase`].
+    pub fn shutdown(&self) {
+        // Parked vCPUs must be released in order to observe the suspension
+        if self.tasks.lifecycle.end_pause() {
+            if let Err(e) = self.mctx.with_hdl(|hdl| hdl.resume()) {
+                println!("failed to resume vCPUs: {}", e);
+            }
         }
+        self.tasks.shutdown(|| {
+            let res = self.mctx.with_hdl(|hdl| {
+                hdl.suspend(bhyve_api::vm_suspend_how::VM_SUSPEND_POWEROFF)
+            });
+            if let Err(e) = res {
+                println!("failed to suspend instance: {}", e);
+            }
+        });
+    }
+    pub fn phase(&self) -> Phase {
+        self.tasks.lifecycle.phase()
     }
     pub fn with_ctx<F>(&self, f: F)
     where
         F: FnOnce(&DispCtx),
     {
-        let ctx = DispCtx::new(self.mctx.clone(), self.event_dispatch.clone());
+        let ctx = self.ctx(TaskClass::Device);
         f(&ctx)
     }
+
+    fn ctx(&self, class: TaskClass) -> DispCtx {
+        DispCtx::new(
+            self.mctx.clone(),
+            self.event_dispatch.clone(),
+            self.tasks.lifecycle.clone(),
+            class,
+        )
+    }
 }
 
 pub struct DispCtx {
     pub mctx: MachineCtx,
     pub vcpu: Option<VcpuHdl>,
     pub event: EventCtx,
+    lifecycle: Arc<Lifecycle>,
+    class: TaskClass,
 }
 
 impl DispCtx {
-    fn new(mctx: MachineCtx, edisp: Arc<EventDispatch>) -> DispCtx {
-        DispCtx { mctx, vcpu: None, event: EventCtx::new(edisp) }
-    }
-
-    fn for_vcpu(
+    fn new(
         mctx: MachineCtx,
         edisp: Arc<EventDispatch>,
-        cpu: VcpuHdl,
+        lifecycle: Arc<Lifecycle>,
+        class: TaskClass,
     ) -> DispCtx {
-        Self { mctx, vcpu: Some(cpu), event: EventCtx::new(edisp) }
+        DispCtx {
+            mctx,
+            vcpu: None,
+            event: EventCtx::new(edisp),
+            lifecycle,
+            class,
+        }
+    }
+
+    /// Has the thread owning this context been asked to exit?
+    pub fn should_exit(&self) -> bool {
+        self.lifecycle.phase() >= self.class.exit_phase()
+    }
+    /// Block until the thread owning this context is asked to exit.
+    pub fn wait_exit(&self) {
+        self.lifecycle.wait_for(self.class.exit_phase())
+    }
+    /// Record the reason the instance was suspended, as observed by a vCPU
+    /// (or by the device which requested the suspension).
+    ///
+    /// Every vCPU observes the same suspension, so only the first report is
+    /// kept until the instance is reset.
+    pub fn report_suspend(&self, reason: SuspendReason) {
+        let mut suspended = self.lifecycle.suspended.lock().unwrap();
+        if suspended.is_none() {
+            *suspended = Some(reason);
+        }
+    }
+    /// Block the (vCPU) thread owning this context for as long as the
+    /// instance is paused.
+    pub fn park(&self) {
+        self.lifecycle.park()
+    }
+    /// Mark the start of device activity which proceeds independently of the
+    /// vCPUs, such as processing queued requests or handling host I/O, for as
+    /// long as the returned guard is held.
+    ///
+    /// While the instance is paused, this blocks until it is resumed.  Pausing
+    /// waits for any outstanding guards to be dropped, so none of this activity
+    /// is under way (or can begin) while the instance is paused.
+    pub fn device_activity(&self) -> ActivityGuard<'_> {
+        self.lifecycle.dev_enter();
+        ActivityGuard(&self.lifecycle)
+    }
+}
+
+/// Device activity under way, as started by [`DispCtx::device_activity`]
+pub struct ActivityGuard<'a>(&'a Lifecycle);
+impl Drop for ActivityGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dev_exit();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn log_push(log: &Arc<Mutex<Vec<String>>>, ent: &str) {
+        log.lock().unwrap().push(ent.to_string());
+    }
+
+    #[test]
+    fn shutdown_order() {
+        let tasks = TaskSet::new();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // Stub vCPUs "run" until the instance is halted, signalled here by
+        // the sending side of the channel being dropped.
+        let (halt_tx, halt_rx) = mpsc::channel::<()>();
+        let halt_rx = Arc::new(Mutex::new(halt_rx));
+        for n in 0..2 {
+            let rx = Arc::clone(&halt_rx);
+            let log = Arc::clone(&log);
+            tasks
+                .spawn(TaskClass::Vcpu, format!("vcpu-{}", n), move || {
+                    let _ = rx.lock().unwrap().recv();
+                    log_push(&log, "vcpu");
+                })
+                .unwrap();
+        }
+        // Likewise, the stub device waits to be woken by its quiesce hook,
+        // which also holds a stand-in for a device reference.
+        let (wake_tx, wake_rx) = mpsc::channel::<()>();
+        let dlog = Arc::clone(&log);
+        tasks
+            .spawn(TaskClass::Device, "dev".to_string(), move || {
+                let _ = wake_rx.recv();
+                log_push(&dlog, "device");
+            })
+            .unwrap();
+
+        let dev_ref = Arc::new(());
+        let hook_ref = Arc::clone(&dev_ref);
+        let hook_tx = Mutex::new(Some(wake_tx));
+        let hlog = Arc::clone(&log);
+        tasks.on_quiesce(Box::new(move || {
+            let _held = &hook_ref;
+            log_push(&hlog, "quiesce");
+            drop(hook_tx.lock().unwrap().take());
+        }));
+
+        let halt_log = Arc::clone(&log);
+        let lc = Arc::clone(&tasks.lifecycle);
+        tasks.shutdown(move || {
+            assert_eq!(lc.phase(), Phase::HaltVcpus);
+            log_push(&halt_log, "halt");
+            drop(halt_tx);
+        });
+
+        assert_eq!(tasks.lifecycle.phase(), Phase::Released);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["halt", "vcpu", "vcpu", "quiesce", "device"]
+        );
+        assert_eq!(Arc::strong_count(&dev_ref), 1, "device ref not released");
+
+        // No new work once shut down
+        assert!(tasks
+            .spawn(TaskClass::Device, "late".to_string(), || {})
+            .is_err());
+    }
+
+    #[test]
+    fn pause_parks_vcpus() {
+        let tasks = TaskSet::new();
+        let lc = Arc::clone(&tasks.lifecycle);
+        assert!(lc.begin_pause().is_err(), "no vCPUs to pause");
+
+        // One stub vCPU parks, while the other exits of its own accord
+        let (go_tx, go_rx) = mpsc::channel::<()>();
+        let (run_tx, run_rx) = mpsc::channel::<()>();
+        let plc = Arc::clone(&lc);
+        tasks
+            .spawn(TaskClass::Vcpu, "vcpu-0".to_string(), move || {
+                go_rx.recv().unwrap();
+                plc.park();
+                run_tx.send(()).unwrap();
+            })
+            .unwrap();
+        tasks.spawn(TaskClass::Vcpu, "vcpu-1".to_string(), || {}).unwrap();
+        assert!(lc.begin_pause().is_ok());
+        assert!(lc.begin_pause().is_err(), "already paused");
+        go_tx.send(()).unwrap();
+
+        lc.wait_parked();
+        assert_eq!(lc.pause.lock().unwrap().parked, 1);
+        assert!(run_rx.try_recv().is_err(), "vCPU ran while paused");
+
+        assert!(lc.end_pause());
+        run_rx.recv().unwrap();
+        tasks.join_vcpus();
+        assert_eq!(lc.pause.lock().unwrap().live, 0);
+        assert!(!lc.end_pause());
+    }
+
+    #[test]
+    fn pause_gates_devices() {
+        let tasks = TaskSet::new();
+        let lc = Arc::clone(&tasks.lifecycle);
+        let (exit_tx, exit_rx) = mpsc::channel::<()>();
+        tasks
+            .spawn(TaskClass::Vcpu, "vcpu-0".to_string(), move || {
+                let _ = exit_rx.recv();
+            })
+            .unwrap();
+
+        // Pausing waits out the activity already under way...
+        lc.dev_enter();
+        assert!(lc.begin_pause().is_ok());
+        let (busy_tx, busy_rx) = mpsc::channel::<()>();
+        let dlc = Arc::clone(&lc);
+        tasks
+            .spawn(TaskClass::Device, "dev".to_string(), move || {
+                dlc.dev_exit();
+                // ...and holds off any more until resumed
+                dlc.dev_enter();
+                busy_tx.send(()).unwrap();
+                dlc.dev_exit();
+            })
+            .unwrap();
+        drop(exit_tx);
+        lc.wait_parked();
+        assert_eq!(lc.pause.lock().unwrap().dev_active, 0);
+        assert!(busy_rx.try_recv().is_err(), "device ran while paused");
+
+        assert!(lc.end_pause());
+        busy_rx.recv().unwrap();
+        tasks.join_vcpus();
     }
 }
@@ -1,8 +1,10 @@
 use std::convert::TryFrom;
 use std::os::raw::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use bhyve_api::{
     vm_entry, vm_entry_cmds, vm_entry_payload, vm_exit, vm_exitcode,
+    vm_suspend_how,
 };
 
 pub struct VmExit {
@@ -50,6 +52,41 @@ pub enum MmioReq {
     Write(MmioWriteReq),
 }
 
+/// Reason the instance was suspended, as reported to each of its vCPUs
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SuspendReason {
+    /// Guest requested a reset (via the reset control register, 8042, etc)
+    Reset,
+    /// Guest requested power-off, or the host stopped the instance
+    PowerOff,
+    Halt,
+    /// A vCPU encountered a triple fault
+    TripleFault,
+    /// Guest entered the S3 (suspend-to-RAM) sleep state.  This is reported
+    /// by the chipset, rather than the kernel, which sees only a halt.
+    Sleep,
+    Unknown(i32),
+}
+impl SuspendReason {
+    /// Should the instance be reset (rather than stopped) for this reason?
+    pub fn is_reset(&self) -> bool {
+        matches!(self, SuspendReason::Reset | SuspendReason::TripleFault)
+    }
+}
+impl From<i32> for SuspendReason {
+    fn from(how: i32) -> Self {
+        match vm_suspend_how::try_from(how) {
+            Ok(vm_suspend_how::VM_SUSPEND_RESET) => SuspendReason::Reset,
+            Ok(vm_suspend_how::VM_SUSPEND_POWEROFF) => SuspendReason::PowerOff,
+            Ok(vm_suspend_how::VM_SUSPEND_HALT) => SuspendReason::Halt,
+            Ok(vm_suspend_how::VM_SUSPEND_TRIPLEFAULT) => {
+                SuspendReason::TripleFault
+            }
+            _ => SuspendReason::Unknown(how),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum VmExitKind {
     Bogus,
@@ -57,6 +94,10 @@ pub enum VmExitKind {
     Mmio(MmioReq),
     Rdmsr(u32),
     Wrmsr(u32, u64),
+    Hlt,
+    Suspended(SuspendReason),
+    /// The vCPU has been held out of guest context (via `VM_SUSPEND_CPU`)
+    Debug,
     Unknown(i32),
 }
 impl From<&vm_exit> for VmExitKind {
@@ -99,11 +140,102 @@ impl From<&vm_exit> for VmExitKind {
                     }))
                 }
             }
+            vm_exitcode::VM_EXITCODE_HLT => VmExitKind::Hlt,
+            vm_exitcode::VM_EXITCODE_SUSPENDED => {
+                let suspended = unsafe { &exit.u.suspended };
+                VmExitKind::Suspended(SuspendReason::from(suspended.how))
+            }
+            vm_exitcode::VM_EXITCODE_DEBUG => VmExitKind::Debug,
             c => VmExitKind::Unknown(c as i32),
         }
     }
 }
 
+/// Reason for a VM exit, without any of its accompanying details
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ExitReason {
+    Bogus,
+    Inout,
+    Mmio,
+    Rdmsr,
+    Wrmsr,
+    Hlt,
+    Suspended,
+    Debug,
+    Unknown,
+}
+impl ExitReason {
+    const COUNT: usize = 9;
+    const ALL: [ExitReason; ExitReason::COUNT] = [
+        ExitReason::Bogus,
+        ExitReason::Inout,
+        ExitReason::Mmio,
+        ExitReason::Rdmsr,
+        ExitReason::Wrmsr,
+        ExitReason::Hlt,
+        ExitReason::Suspended,
+        ExitReason::Debug,
+        ExitReason::Unknown,
+    ];
+}
+impl From<&VmExitKind> for ExitReason {
+    fn from(kind: &VmExitKind) -> Self {
+        match kind {
+            VmExitKind::Bogus => ExitReason::Bogus,
+            VmExitKind::Inout(_) => ExitReason::Inout,
+            VmExitKind::Mmio(_) => ExitReason::Mmio,
+            VmExitKind::Rdmsr(_) => ExitReason::Rdmsr,
+            VmExitKind::Wrmsr(_, _) => ExitReason::Wrmsr,
+            VmExitKind::Hlt => ExitReason::Hlt,
+            VmExitKind::Suspended(_) => ExitReason::Suspended,
+            VmExitKind::Debug => ExitReason::Debug,
+            VmExitKind::Unknown(_) => ExitReason::Unknown,
+        }
+    }
+}
+
+/// Running count of VM exits, by reason, for a single vCPU
+#[derive(Default)]
+pub struct ExitStats {
+    counts: [AtomicU64; ExitReason::COUNT],
+}
+impl ExitStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn record(&self, reason: ExitReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn snapshot(&self) -> ExitCounts {
+        let mut counts = ExitCounts::default();
+        for (dst, src) in counts.0.iter_mut().zip(self.counts.iter()) {
+            *dst = src.load(Ordering::Relaxed);
+        }
+        counts
+    }
+}
+
+/// Point-in-time VM exit counts, by reason
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExitCounts([u64; ExitReason::COUNT]);
+impl ExitCounts {
+    pub fn get(&self, reason: ExitReason) -> u64 {
+        self.0[reason as usize]
+    }
+    pub fn total(&self) -> u64 {
+        self.0.iter().sum()
+    }
+    /// Accumulate the counts from `other` into these
+    pub fn merge(&mut self, other: &ExitCounts) {
+        for (dst, src) in self.0.iter_mut().zip(other.0.iter()) {
+            *dst += src;
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (ExitReason, u64)> + '_ {
+        ExitReason::ALL.iter().map(move |r| (*r, self.get(*r)))
+    }
+}
+
 pub enum InoutRes {
     In(IoPort, u32),
     Out(IoPort),
@@ -173,3 +305,62 @@ impl VmEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_counting() {
+        let port = || IoPort { port: 0x80, bytes: 1 };
+        let exits = [
+            VmExitKind::Inout(InoutReq::Out(port(), 0)),
+            VmExitKind::Inout(InoutReq::In(port())),
+            VmExitKind::Mmio(MmioReq::Read(MmioReadReq { addr: 0, bytes: 4 })),
+            VmExitKind::Bogus,
+            VmExitKind::Inout(InoutReq::Out(port(), 1)),
+            VmExitKind::Rdmsr(0x10),
+            VmExitKind::Unknown(99),
+        ];
+        let cpu0 = ExitStats::new();
+        for exit in exits.iter() {
+            cpu0.record(ExitReason::from(exit));
+        }
+        let snap0 = cpu0.snapshot();
+        assert_eq!(snap0.get(ExitReason::Inout), 3);
+        assert_eq!(snap0.get(ExitReason::Mmio), 1);
+        assert_eq!(snap0.get(ExitReason::Bogus), 1);
+        assert_eq!(snap0.get(ExitReason::Rdmsr), 1);
+        assert_eq!(snap0.get(ExitReason::Wrmsr), 0);
+        assert_eq!(snap0.get(ExitReason::Unknown), 1);
+        assert_eq!(snap0.total(), exits.len() as u64);
+
+        let cpu1 = ExitStats::new();
+        cpu1.record(ExitReason::Mmio);
+        cpu1.record(ExitReason::Suspended);
+
+        let mut all = ExitCounts::default();
+        all.merge(&snap0);
+        all.merge(&cpu1.snapshot());
+        assert_eq!(all.get(ExitReason::Mmio), 2);
+        assert_eq!(all.get(ExitReason::Suspended), 1);
+        assert_eq!(all.total(), 9);
+        assert_eq!(all.iter().map(|(_r, c)| c).sum::<u64>(), 9);
+    }
+
+    #[test]
+    fn suspend_reason() {
+        let reset = vm_suspend_how::VM_SUSPEND_RESET as i32;
+        let tf = vm_suspend_how::VM_SUSPEND_TRIPLEFAULT as i32;
+        let off = vm_suspend_how::VM_SUSPEND_POWEROFF as i32;
+        assert_eq!(SuspendReason::from(reset), SuspendReason::Reset);
+        assert_eq!(SuspendReason::from(tf), SuspendReason::TripleFault);
+        assert_eq!(SuspendReason::from(off), SuspendReason::PowerOff);
+        assert_eq!(SuspendReason::from(42), SuspendReason::Unknown(42));
+
+        assert!(SuspendReason::Reset.is_reset());
+        assert!(SuspendReason::TripleFault.is_reset());
+        assert!(!SuspendReason::PowerOff.is_reset());
+        assert!(!SuspendReason::Halt.is_reset());
+    }
+}
@@ -0,0 +1,65 @@
+// HBA capabilities (CAP)
+pub const CAP_S64A: u32 = 1 << 31;
+pub const CAP_SAM: u32 = 1 << 18;
+pub const CAP_ISS_GEN1: u32 = 1 << 20;
+pub const CAP_NCS_SHIFT: u32 = 8;
+
+// Global HBA control (GHC)
+pub const GHC_HR: u32 = 1 << 0;
+pub const GHC_IE: u32 = 1 << 1;
+pub const GHC_AE: u32 = 1 << 31;
+
+/// AHCI 1.3
+pub const AHCI_VERSION: u32 = 0x0001_0300;
+
+pub const MAX_PORTS: usize = 32;
+pub const CMD_SLOTS: u32 = 32;
+
+// Port command and status (PxCMD)
+pub const PXCMD_ST: u32 = 1 << 0;
+pub const PXCMD_SUD: u32 = 1 << 1;
+pub const PXCMD_POD: u32 = 1 << 2;
+pub const PXCMD_CLO: u32 = 1 << 3;
+pub const PXCMD_FRE: u32 = 1 << 4;
+pub const PXCMD_FR: u32 = 1 << 14;
+pub const PXCMD_CR: u32 = 1 << 15;
+pub const PXCMD_ATAPI: u32 = 1 << 24;
+pub const PXCMD_ICC_MASK: u32 = 0xf << 28;
+pub const PXCMD_WRITABLE: u32 =
+    PXCMD_ST | PXCMD_FRE | PXCMD_ATAPI | PXCMD_ICC_MASK;
+
+// Port interrupt status (PxIS) and enable (PxIE)
+pub const PXIS_DHRS: u32 = 1 << 0;
+pub const PXIS_PSS: u32 = 1 << 1;
+pub const PXIS_TFES: u32 = 1 << 30;
+pub const PXIE_MASK: u32 = 0xfdc0_00ff;
+
+// Port SATA control (PxSCTL) and status (PxSSTS)
+pub const PXSCTL_DET_MASK: u32 = 0xf;
+pub const PXSCTL_DET_COMRESET: u32 = 1;
+/// Device present and communication established, at Gen1 speed, active
+pub const PXSSTS_ONLINE: u32 = 0x113;
+
+pub const SIG_ATA: u32 = 0x0000_0101;
+pub const SIG_ATAPI: u32 = 0xeb14_0101;
+pub const SIG_NONE: u32 = 0xffff_ffff;
+
+// FIS types
+pub const FIS_TYPE_H2D: u8 = 0x27;
+pub const FIS_TYPE_D2H: u8 = 0x34;
+pub const FIS_TYPE_PIO_SETUP: u8 = 0x5f;
+pub const FIS_H2D_CMD: u8 = 0x80;
+pub const FIS_INTR: u8 = 0x40;
+pub const FIS_DIR_D2H: u8 = 0x20;
+
+// Received FIS area offsets
+pub const RFIS_PIO_SETUP: u64 = 0x20;
+pub const RFIS_D2H: u64 = 0x40;
+
+// Command list and table layout
+pub const CMD_HDR_SZ: u64 = 32;
+pub const CMD_HDR_ATAPI: u16 = 1 << 5;
+pub const CMD_TBL_ACMD: u64 = 0x40;
+pub const CMD_TBL_PRDT: u64 = 0x80;
+pub const PRD_SZ: u64 = 16;
+pub const PRD_DBC_MASK: u32 = 0x3f_ffff;
@@ -0,0 +1,1058 @@
+//! ICH9-style AHCI (SATA) controller, with ports holding disks or ATAPI
+//! CD-ROMs backed by the block layer.
+
+use std::sync::{Arc, Mutex};
+
+use crate::block::*;
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci;
+use crate::util::regmap::{Flags, RegMap};
+use crate::util::self_arc::*;
+use crate::vmm::MemCtx;
+
+use lazy_static::lazy_static;
+
+mod bits;
+
+use crate::hw::ata::{self, bits::*, Packet, Sense, CD_SECTOR_SZ};
+use bits::*;
+
+pub use bits::MAX_PORTS;
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEV_ICH9_AHCI: u16 = 0x2922;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROGIF_AHCI: u8 = 0x01;
+
+/// ICH9 implements 6 ports, which is how many are offered unless more drives
+/// are attached.
+const ICH9_PORTS: usize = 6;
+
+const ABAR_SIZE: usize = 0x2000;
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+
+/// A drive attached to an AHCI port
+pub enum AhciDrive {
+    Disk(Arc<dyn BlockDev<Request>>),
+    Cdrom(Arc<dyn BlockDev<Request>>),
+}
+impl AhciDrive {
+    fn bdev(&self) -> &Arc<dyn BlockDev<Request>> {
+        match self {
+            AhciDrive::Disk(bdev) | AhciDrive::Cdrom(bdev) => bdev,
+        }
+    }
+    fn is_cdrom(&self) -> bool {
+        matches!(self, AhciDrive::Cdrom(_))
+    }
+}
+
+struct HbaState {
+    ghc: u32,
+    is: u32,
+    intx_en: bool,
+    lintr_pin: Option<pci::INTxPin>,
+}
+
+/// Global HBA state, shared by the ports for raising interrupts.
+///
+/// When both are required, a port lock is always taken before the HBA lock.
+struct Hba {
+    state: Mutex<HbaState>,
+}
+impl Hba {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(HbaState {
+                ghc: GHC_AE,
+                is: 0,
+                intx_en: false,
+                lintr_pin: None,
+            }),
+        }
+    }
+    /// Record a pending interrupt for `port`.  Once set, the bit in IS is
+    /// only cleared by the guest.
+    fn port_intr(&self, port: usize, pending: bool) {
+        let mut state = self.state.lock().unwrap();
+        if pending {
+            state.is |= 1 << port;
+        }
+        Self::update_pin(&state);
+    }
+    fn update_pin(state: &HbaState) {
+        if let Some(pin) = state.lintr_pin.as_ref() {
+            if state.intx_en && state.ghc & GHC_IE != 0 && state.is != 0 {
+                pin.assert();
+            } else {
+                pin.deassert();
+            }
+        }
+    }
+}
+
+struct PortState {
+    clb: u64,
+    fb: u64,
+    is: u32,
+    ie: u32,
+    cmd: u32,
+    tfd: u32,
+    sig: u32,
+    ssts: u32,
+    sctl: u32,
+    serr: u32,
+    sact: u32,
+    ci: u32,
+    /// Slots with requests outstanding to the backend
+    busy: u32,
+    /// Bumped on port reset, so stale completions can be discarded
+    gen: u64,
+    /// Sense data from the last failed packet command
+    sense: Sense,
+}
+
+/// Outcome of a command from the command list
+enum CmdResult {
+    Done { status: u8, error: u8, xfer: usize },
+    Queued,
+}
+impl CmdResult {
+    fn ok(xfer: usize) -> Self {
+        CmdResult::Done { status: ATA_SR_DRDY | ATA_SR_DSC, error: 0, xfer }
+    }
+    fn abort() -> Self {
+        CmdResult::Done {
+            status: ATA_SR_DRDY | ATA_SR_ERR,
+            error: ATA_ER_ABRT,
+            xfer: 0,
+        }
+    }
+    fn check(sense: Sense) -> Self {
+        CmdResult::Done {
+            status: ATA_SR_DRDY | ATA_SR_ERR,
+            error: sense.key << 4,
+            xfer: 0,
+        }
+    }
+}
+
+/// Command header and table for a slot in the command list
+struct Cmd {
+    slot: u32,
+    atapi: bool,
+    fis: [u8; 20],
+    acmd: [u8; 16],
+    prdt: Vec<GuestRegion>,
+}
+impl Cmd {
+    fn read(mem: &MemCtx, clb: u64, slot: u32) -> Option<Self> {
+        let hdr: [u32; 4] =
+            mem.read(GuestAddr(clb + slot as u64 * CMD_HDR_SZ))?;
+        let flags = hdr[0] as u16;
+        let prdtl = (hdr[0] >> 16) as u64;
+        let ctba = (hdr[3] as u64) << 32 | (hdr[2] & !0x7f) as u64;
+
+        let fis: [u8; 20] = mem.read(GuestAddr(ctba))?;
+        let acmd: [u8; 16] = mem.read(GuestAddr(ctba + CMD_TBL_ACMD))?;
+        let mut prdt = Vec::with_capacity(prdtl as usize);
+        for n in 0..prdtl {
+            let prd: [u32; 4] =
+                mem.read(GuestAddr(ctba + CMD_TBL_PRDT + n * PRD_SZ))?;
+            let dba = (prd[1] as u64) << 32 | (prd[0] & !0x1) as u64;
+            let dbc = (prd[3] & PRD_DBC_MASK) as usize + 1;
+            prdt.push(GuestRegion(GuestAddr(dba), dbc));
+        }
+        Some(Self { slot, atapi: flags & CMD_HDR_ATAPI != 0, fis, acmd, prdt })
+    }
+    fn command(&self) -> u8 {
+        self.fis[2]
+    }
+    fn lba28(&self) -> u64 {
+        (self.fis[7] as u64 & 0xf) << 24
+            | (self.fis[6] as u64) << 16
+            | (self.fis[5] as u64) << 8
+            | self.fis[4] as u64
+    }
+    fn lba48(&self) -> u64 {
+        (self.fis[10] as u64) << 40
+            | (self.fis[9] as u64) << 32
+            | (self.fis[8] as u64) << 24
+            | (self.fis[6] as u64) << 16
+            | (self.fis[5] as u64) << 8
+            | self.fis[4] as u64
+    }
+    /// Sector count, where zero stands for the maximum
+    fn count(&self, ext: bool) -> u64 {
+        match (ext, self.fis[12], self.fis[13]) {
+            (false, 0, _) => 256,
+            (false, lo, _) => lo as u64,
+            (true, 0, 0) => 65536,
+            (true, lo, hi) => (hi as u64) << 8 | lo as u64,
+        }
+    }
+}
+
+pub struct Port {
+    num: usize,
+    hba: Arc<Hba>,
+    drive: Option<AhciDrive>,
+    state: Mutex<PortState>,
+    sa_cell: SelfArcCell<Self>,
+}
+impl Port {
+    fn new(num: usize, hba: Arc<Hba>, drive: Option<AhciDrive>) -> Arc<Self> {
+        let state = Self::initial_state(drive.as_ref(), 0);
+        let mut this = Arc::new(Self {
+            num,
+            hba,
+            drive,
+            state: Mutex::new(state),
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
+        this
+    }
+    fn initial_state(drive: Option<&AhciDrive>, gen: u64) -> PortState {
+        let mut state = PortState {
+            clb: 0,
+            fb: 0,
+            is: 0,
+            ie: 0,
+            cmd: PXCMD_SUD | PXCMD_POD,
+            tfd: 0x7f,
+            sig: SIG_NONE,
+            ssts: 0,
+            sctl: 0,
+            serr: 0,
+            sact: 0,
+            ci: 0,
+            busy: 0,
+            gen,
+            sense: Sense::default(),
+        };
+        if let Some(drive) = drive {
+            state.tfd = (ATA_SR_DRDY | ATA_SR_DSC) as u32;
+            state.ssts = PXSSTS_ONLINE;
+            if drive.is_cdrom() {
+                state.sig = SIG_ATAPI;
+                state.cmd |= PXCMD_ATAPI;
+            } else {
+                state.sig = SIG_ATA;
+            }
+        }
+        state
+    }
+    fn reset(&self, state: &mut PortState) {
+        *state = Self::initial_state(self.drive.as_ref(), state.gen + 1);
+    }
+
+    fn reg_read(&self, id: &PortReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        let val = match id {
+            PortReg::Clb => state.clb as u32,
+            PortReg::Clbu => (state.clb >> 32) as u32,
+            PortReg::Fb => state.fb as u32,
+            PortReg::Fbu => (state.fb >> 32) as u32,
+            PortReg::Is => state.is,
+            PortReg::Ie => state.ie,
+            PortReg::Cmd => state.cmd,
+            PortReg::Tfd => state.tfd,
+            PortReg::Sig => state.sig,
+            PortReg::Ssts => state.ssts,
+            PortReg::Sctl => state.sctl,
+            PortReg::Serr => state.serr,
+            PortReg::Sact => state.sact,
+            PortReg::Ci => state.ci,
+            PortReg::Sntf | PortReg::Fbs | PortReg::Reserved => 0,
+        };
+        ro.write_u32(val);
+    }
+    fn reg_write(&self, id: &PortReg, wo: &mut WriteOp, ctx: &DispCtx) {
+        let val = wo.read_u32();
+        let mut state = self.state.lock().unwrap();
+        match id {
+            PortReg::Clb => {
+                state.clb = (state.clb & !0xffff_ffff) | (val & !0x3ff) as u64
+            }
+            PortReg::Clbu => {
+                state.clb = (state.clb & 0xffff_ffff) | (val as u64) << 32
+            }
+            PortReg::Fb => {
+                state.fb = (state.fb & !0xffff_ffff) | (val & !0xff) as u64
+            }
+            PortReg::Fbu => {
+                state.fb = (state.fb & 0xffff_ffff) | (val as u64) << 32
+            }
+            PortReg::Is => {
+                state.is &= !val;
+                self.update_intr(&state);
+            }
+            PortReg::Ie => {
+                state.ie = val & PXIE_MASK;
+                self.update_intr(&state);
+            }
+            PortReg::Cmd => self.cmd_write(&mut state, val, ctx),
+            PortReg::Sctl => {
+                let det_old = state.sctl & PXSCTL_DET_MASK;
+                state.sctl = val;
+                match val & PXSCTL_DET_MASK {
+                    PXSCTL_DET_COMRESET => {
+                        // Hold the link down for the duration of the reset
+                        self.reset(&mut state);
+                        state.sctl = val;
+                        state.ssts = 0;
+                        state.tfd = 0x7f;
+                    }
+                    0 if det_old == PXSCTL_DET_COMRESET => {
+                        let cmd = state.cmd;
+                        self.reset(&mut state);
+                        state.sctl = val;
+                        state.cmd = cmd & !(PXCMD_ST | PXCMD_CR);
+                        self.post_signature(&state, &ctx.mctx.memctx());
+                    }
+                    _ => {}
+                }
+            }
+            PortReg::Serr => state.serr &= !val,
+            PortReg::Sact => {
+                if state.cmd & PXCMD_ST != 0 {
+                    state.sact |= val;
+                }
+            }
+            PortReg::Ci => {
+                if state.cmd & PXCMD_ST != 0 {
+                    state.ci |= val;
+                    self.process_cmds(&mut state, &ctx.mctx.memctx());
+                }
+            }
+            PortReg::Tfd
+            | PortReg::Sig
+            | PortReg::Ssts
+            | PortReg::Sntf
+            | PortReg::Fbs
+            | PortReg::Reserved => {}
+        }
+    }
+    fn cmd_write(&self, state: &mut PortState, val: u32, ctx: &DispCtx) {
+        let old = state.cmd;
+        let mut cmd = (old & !PXCMD_WRITABLE) | (val & PXCMD_WRITABLE);
+        // Interface state changes complete immediately
+        cmd &= !PXCMD_ICC_MASK;
+
+        if val & PXCMD_CLO != 0 {
+            state.tfd &= !((ATA_SR_BSY | ATA_SR_DRQ) as u32);
+        }
+        if cmd & PXCMD_FRE != 0 {
+            cmd |= PXCMD_FR;
+            if old & PXCMD_FRE == 0 {
+                self.post_signature(state, &ctx.mctx.memctx());
+            }
+        } else {
+            cmd &= !PXCMD_FR;
+        }
+        state.cmd = cmd;
+
+        if cmd & PXCMD_ST != 0 {
+            state.cmd |= PXCMD_CR;
+            if old & PXCMD_ST == 0 {
+                self.process_cmds(state, &ctx.mctx.memctx());
+            }
+        } else if old & PXCMD_ST != 0 {
+            // Abandon any outstanding commands
+            state.cmd &= !PXCMD_CR;
+            state.ci = 0;
+            state.sact = 0;
+            state.busy = 0;
+            state.gen += 1;
+        }
+    }
+
+    fn update_intr(&self, state: &PortState) {
+        self.hba.port_intr(self.num, state.is & state.ie != 0);
+    }
+    fn write_fis(
+        &self,
+        state: &PortState,
+        mem: &MemCtx,
+        off: u64,
+        fis: &[u8; 20],
+    ) {
+        if state.cmd & PXCMD_FRE != 0 {
+            let _ = mem.write(GuestAddr(state.fb + off), fis);
+        }
+    }
+    /// Deliver the device signature, as is done following a reset
+    fn post_signature(&self, state: &PortState, mem: &MemCtx) {
+        if self.drive.is_none() {
+            return;
+        }
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_D2H;
+        fis[2] = state.tfd as u8;
+        fis[4] = (state.sig >> 8) as u8;
+        fis[5] = (state.sig >> 16) as u8;
+        fis[6] = (state.sig >> 24) as u8;
+        fis[12] = state.sig as u8;
+        self.write_fis(state, mem, RFIS_D2H, &fis);
+    }
+    fn post_pio_setup(&self, state: &mut PortState, mem: &MemCtx, len: usize) {
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_PIO_SETUP;
+        fis[1] = FIS_DIR_D2H;
+        fis[2] = ATA_SR_DRDY | ATA_SR_DSC | ATA_SR_DRQ;
+        fis[15] = ATA_SR_DRDY | ATA_SR_DSC;
+        fis[16..18].copy_from_slice(&(len as u16).to_le_bytes());
+        self.write_fis(state, mem, RFIS_PIO_SETUP, &fis);
+        state.is |= PXIS_PSS;
+    }
+
+    fn process_cmds(&self, state: &mut PortState, mem: &MemCtx) {
+        let mut pending = state.ci & !state.busy;
+        while pending != 0 {
+            let slot = pending.trailing_zeros();
+            pending &= !(1 << slot);
+
+            let res = match Cmd::read(mem, state.clb, slot) {
+                Some(cmd) => self.process_cmd(state, &cmd, mem),
+                None => CmdResult::abort(),
+            };
+            match res {
+                CmdResult::Queued => state.busy |= 1 << slot,
+                CmdResult::Done { status, error, xfer } => {
+                    self.finish_cmd(state, mem, slot, status, error, xfer)
+                }
+            }
+        }
+    }
+    fn process_cmd(
+        &self,
+        state: &mut PortState,
+        cmd: &Cmd,
+        mem: &MemCtx,
+    ) -> CmdResult {
+        let drive = match self.drive.as_ref() {
+            Some(d) => d,
+            None => return CmdResult::abort(),
+        };
+        if cmd.fis[0] != FIS_TYPE_H2D || cmd.fis[1] & FIS_H2D_CMD == 0 {
+            return CmdResult::abort();
+        }
+        let info = drive.bdev().inquire();
+        let bytes = info.total_size * info.block_size as u64;
+
+        if drive.is_cdrom() {
+            return match cmd.command() {
+                ATA_CMD_IDENTIFY_PACKET => {
+                    let data = ata::identify_cdrom(&self.serial());
+                    self.post_pio_setup(state, mem, data.len());
+                    CmdResult::ok(ata::prdt_copy_out(mem, &cmd.prdt, &data))
+                }
+                ATA_CMD_PACKET if cmd.atapi => {
+                    self.process_packet(state, cmd, mem, bytes / CD_SECTOR_SZ)
+                }
+                ATA_CMD_SET_FEATURES
+                | ATA_CMD_IDLE_IMMED
+                | ATA_CMD_STANDBY_IMMED => CmdResult::ok(0),
+                // Commands for disks are rejected, with the signature left
+                // for the guest to find an ATAPI device instead.
+                _ => CmdResult::abort(),
+            };
+        }
+
+        let sector_sz = u64::max(info.block_size as u64, ata::SECTOR_SZ);
+        let sectors = bytes / sector_sz;
+        let (op, lba, count) = match cmd.command() {
+            ATA_CMD_READ_DMA | ATA_CMD_READ_SECTORS => {
+                (BlockOp::Read, cmd.lba28(), cmd.count(false))
+            }
+            ATA_CMD_READ_DMA_EXT | ATA_CMD_READ_SECTORS_EXT => {
+                (BlockOp::Read, cmd.lba48(), cmd.count(true))
+            }
+            ATA_CMD_WRITE_DMA | ATA_CMD_WRITE_SECTORS => {
+                (BlockOp::Write, cmd.lba28(), cmd.count(false))
+            }
+            ATA_CMD_WRITE_DMA_EXT | ATA_CMD_WRITE_SECTORS_EXT => {
+                (BlockOp::Write, cmd.lba48(), cmd.count(true))
+            }
+            ATA_CMD_IDENTIFY => {
+                let data = ata::identify_disk(
+                    &self.serial(),
+                    sectors,
+                    sector_sz as u32,
+                );
+                self.post_pio_setup(state, mem, data.len());
+                return CmdResult::ok(ata::prdt_copy_out(
+                    mem, &cmd.prdt, &data,
+                ));
+            }
+            ATA_CMD_FLUSH_CACHE
+            | ATA_CMD_FLUSH_CACHE_EXT
+            | ATA_CMD_SET_FEATURES
+            | ATA_CMD_SET_MULTIPLE
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_IDLE_IMMED
+            | ATA_CMD_STANDBY_IMMED => return CmdResult::ok(0),
+            _ => return CmdResult::abort(),
+        };
+        if lba + count > sectors {
+            return CmdResult::abort();
+        }
+        if matches!(op, BlockOp::Write) && !info.writable {
+            return CmdResult::abort();
+        }
+        self.submit(state, cmd, op, lba * sector_sz, count * sector_sz)
+    }
+    fn process_packet(
+        &self,
+        state: &mut PortState,
+        cmd: &Cmd,
+        mem: &MemCtx,
+        sectors: u64,
+    ) -> CmdResult {
+        match ata::atapi_packet(&cmd.acmd, sectors, &mut state.sense) {
+            Packet::Data(data) => {
+                if !data.is_empty() {
+                    self.post_pio_setup(state, mem, data.len());
+                }
+                CmdResult::ok(ata::prdt_copy_out(mem, &cmd.prdt, &data))
+            }
+            Packet::Read { count: 0, .. } => CmdResult::ok(0),
+            Packet::Read { lba, count } => self.submit(
+                state,
+                cmd,
+                BlockOp::Read,
+                lba * CD_SECTOR_SZ,
+                count as u64 * CD_SECTOR_SZ,
+            ),
+            Packet::Error(sense) => {
+                state.sense = sense;
+                CmdResult::check(sense)
+            }
+        }
+    }
+    /// Queue a transfer of `len` bytes at `off` to the backend
+    fn submit(
+        &self,
+        state: &PortState,
+        cmd: &Cmd,
+        op: BlockOp,
+        off: u64,
+        len: u64,
+    ) -> CmdResult {
+        let bufs = match ata::prdt_covering(&cmd.prdt, len as usize) {
+            Some(bufs) => bufs,
+            None => return CmdResult::abort(),
+        };
+        let req = Request {
+            op,
+            off: off as usize,
+            bufs,
+            idx: 0,
+            xfer: len as usize,
+            port: self.self_arc(),
+            slot: cmd.slot,
+            gen: state.gen,
+        };
+        self.drive.as_ref().unwrap().bdev().enqueue(req);
+        CmdResult::Queued
+    }
+    fn finish_cmd(
+        &self,
+        state: &mut PortState,
+        mem: &MemCtx,
+        slot: u32,
+        status: u8,
+        error: u8,
+        xfer: usize,
+    ) {
+        let prdbc = GuestAddr(state.clb + slot as u64 * CMD_HDR_SZ + 4);
+        let _ = mem.write(prdbc, &(xfer as u32));
+
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_D2H;
+        fis[1] = FIS_INTR;
+        fis[2] = status;
+        fis[3] = error;
+        self.write_fis(state, mem, RFIS_D2H, &fis);
+
+        state.tfd = (error as u32) << 8 | status as u32;
+        state.is |= PXIS_DHRS;
+        if status & ATA_SR_ERR != 0 {
+            state.is |= PXIS_TFES;
+        }
+        state.ci &= !(1 << slot);
+        state.busy &= !(1 << slot);
+        self.update_intr(state);
+    }
+    fn complete(
+        &self,
+        slot: u32,
+        gen: u64,
+        xfer: usize,
+        res: BlockResult,
+        mem: &MemCtx,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        if state.gen != gen || state.busy & (1 << slot) == 0 {
+            // The port was reset while the request was outstanding
+            return;
+        }
+        let cdrom = self.drive.as_ref().map(AhciDrive::is_cdrom).unwrap();
+        let res = match (res, cdrom) {
+            (BlockResult::Success, _) => CmdResult::ok(xfer),
+            (_, true) => {
+                let sense = Sense::new(SENSE_MEDIUM_ERROR, ASC_READ_ERROR);
+                state.sense = sense;
+                CmdResult::check(sense)
+            }
+            (BlockResult::Failure, false) => CmdResult::Done {
+                status: ATA_SR_DRDY | ATA_SR_ERR,
+                error: ATA_ER_UNC,
+                xfer: 0,
+            },
+            (BlockResult::Unsupported, false) => CmdResult::abort(),
+        };
+        if let CmdResult::Done { status, error, xfer } = res {
+            self.finish_cmd(&mut state, mem, slot, status, error, xfer);
+        }
+    }
+    fn serial(&self) -> String {
+        format!("PROPOLIS-AHCI-{}", self.num)
+    }
+}
+impl SelfArc for Port {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
+}
+
+pub struct AhciCtrl {
+    hba: Arc<Hba>,
+    ports: Vec<Arc<Port>>,
+}
+impl AhciCtrl {
+    /// Create a controller with a port for each entry in `drives` (and at
+    /// least as many as ICH9 offers), populated with any drive present.
+    pub fn create(drives: Vec<Option<AhciDrive>>) -> Arc<pci::DeviceInst> {
+        assert!(drives.len() <= MAX_PORTS);
+
+        let hba = Arc::new(Hba::new());
+        let mut drives = drives;
+        while drives.len() < ICH9_PORTS {
+            drives.push(None);
+        }
+        let ports = drives
+            .into_iter()
+            .enumerate()
+            .map(|(n, drive)| Port::new(n, Arc::clone(&hba), drive))
+            .collect();
+
+        pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: DEV_ICH9_AHCI,
+            class: pci::bits::CLASS_STORAGE,
+            subclass: SUBCLASS_SATA,
+            prog_if: PROGIF_AHCI,
+            ..Default::default()
+        })
+        .add_lintr()
+        .add_bar_mmio(pci::BarN::BAR5, ABAR_SIZE as u32)
+        .finish(Arc::new(Self { hba, ports }))
+    }
+
+    fn hba_read(&self, id: &HbaReg, ro: &mut ReadOp) {
+        let state = self.hba.state.lock().unwrap();
+        let nports = self.ports.len() as u32;
+        let val = match id {
+            HbaReg::Cap => {
+                CAP_S64A
+                    | CAP_SAM
+                    | CAP_ISS_GEN1
+                    | (CMD_SLOTS - 1) << CAP_NCS_SHIFT
+                    | (nports - 1)
+            }
+            HbaReg::Ghc => state.ghc,
+            HbaReg::Is => state.is,
+            HbaReg::Pi => match nports {
+                32 => u32::MAX,
+                n => (1 << n) - 1,
+            },
+            HbaReg::Vs => AHCI_VERSION,
+            _ => 0,
+        };
+        ro.write_u32(val);
+    }
+    fn hba_write(&self, id: &HbaReg, wo: &mut WriteOp) {
+        let val = wo.read_u32();
+        match id {
+            HbaReg::Ghc => {
+                if val & GHC_HR != 0 {
+                    self.reset_hba();
+                    return;
+                }
+                let mut state = self.hba.state.lock().unwrap();
+                state.ghc = GHC_AE | (val & GHC_IE);
+                Hba::update_pin(&state);
+            }
+            HbaReg::Is => {
+                self.hba.state.lock().unwrap().is &= !val;
+                // Ports with interrupts still pending will raise them anew
+                for port in self.ports.iter() {
+                    let state = port.state.lock().unwrap();
+                    port.update_intr(&state);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn reset_hba(&self) {
+        for port in self.ports.iter() {
+            let mut state = port.state.lock().unwrap();
+            port.reset(&mut state);
+        }
+        let mut state = self.hba.state.lock().unwrap();
+        state.ghc = GHC_AE;
+        state.is = 0;
+        Hba::update_pin(&state);
+    }
+}
+impl pci::Device for AhciCtrl {
+    fn reset(&self, _ctx: &DispCtx) {
+        self.reset_hba();
+    }
+    fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
+        assert_eq!(bar, pci::BarN::BAR5);
+        ABAR_MAP.process(&mut rwo, |id, rwo| match (id, rwo) {
+            (AbarReg::Hba(reg), RWOp::Read(ro)) => self.hba_read(reg, ro),
+            (AbarReg::Hba(reg), RWOp::Write(wo)) => self.hba_write(reg, wo),
+            (AbarReg::Port(n, reg), rwo) => match (self.ports.get(*n), rwo) {
+                (Some(port), RWOp::Read(ro)) => port.reg_read(reg, ro),
+                (Some(port), RWOp::Write(wo)) => port.reg_write(reg, wo, ctx),
+                (None, RWOp::Read(ro)) => ro.fill(0),
+                (None, RWOp::Write(_)) => {}
+            },
+            (AbarReg::Reserved, RWOp::Read(ro)) => ro.fill(0),
+            (AbarReg::Reserved, RWOp::Write(_)) => {}
+        });
+    }
+    fn attach(
+        &self,
+        lintr_pin: Option<pci::INTxPin>,
+        msix_hdl: Option<pci::MsixHdl>,
+    ) {
+        assert!(lintr_pin.is_some());
+        assert!(msix_hdl.is_none());
+        self.hba.state.lock().unwrap().lintr_pin = lintr_pin;
+    }
+    fn interrupt_mode_change(&self, mode: pci::IntrMode) {
+        let mut state = self.hba.state.lock().unwrap();
+        state.intx_en = mode == pci::IntrMode::INTxPin;
+        Hba::update_pin(&state);
+    }
+}
+
+/// Block request issued on behalf of a command slot of a port
+pub struct Request {
+    op: BlockOp,
+    off: usize,
+    bufs: Vec<GuestRegion>,
+    idx: usize,
+    xfer: usize,
+    port: Arc<Port>,
+    slot: u32,
+    gen: u64,
+}
+impl BlockReq for Request {
+    fn oper(&self) -> BlockOp {
+        self.op
+    }
+    fn offset(&self) -> usize {
+        self.off
+    }
+    fn next_buf(&mut self) -> Option<GuestRegion> {
+        let buf = self.bufs.get(self.idx)?;
+        self.idx += 1;
+        Some(GuestRegion(buf.0, buf.1))
+    }
+    fn complete(self, res: BlockResult, ctx: &DispCtx) {
+        let mem = ctx.mctx.memctx();
+        self.port.complete(self.slot, self.gen, self.xfer, res, &mem);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum HbaReg {
+    Cap,
+    Ghc,
+    Is,
+    Pi,
+    Vs,
+    CccCtl,
+    CccPorts,
+    EmLoc,
+    EmCtl,
+    Cap2,
+    Bohc,
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum PortReg {
+    Clb,
+    Clbu,
+    Fb,
+    Fbu,
+    Is,
+    Ie,
+    Cmd,
+    Tfd,
+    Sig,
+    Ssts,
+    Sctl,
+    Serr,
+    Sact,
+    Ci,
+    Sntf,
+    Fbs,
+    Reserved,
+}
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum AbarReg {
+    Hba(HbaReg),
+    Port(usize, PortReg),
+    Reserved,
+}
+lazy_static! {
+    static ref ABAR_MAP: RegMap<AbarReg> = {
+        let mut map = RegMap::new(ABAR_SIZE);
+        let hba = [
+            HbaReg::Cap,
+            HbaReg::Ghc,
+            HbaReg::Is,
+            HbaReg::Pi,
+            HbaReg::Vs,
+            HbaReg::CccCtl,
+            HbaReg::CccPorts,
+            HbaReg::EmLoc,
+            HbaReg::EmCtl,
+            HbaReg::Cap2,
+            HbaReg::Bohc,
+        ];
+        for (n, reg) in hba.iter().enumerate() {
+            map.define(n * 4, 4, AbarReg::Hba(*reg));
+        }
+        let hba_end = hba.len() * 4;
+        map.define_with_flags(
+            hba_end,
+            PORT_BASE - hba_end,
+            AbarReg::Reserved,
+            Flags::PASSTHRU,
+        );
+
+        let port = [
+            (0x00, PortReg::Clb),
+            (0x04, PortReg::Clbu),
+            (0x08, PortReg::Fb),
+            (0x0c, PortReg::Fbu),
+            (0x10, PortReg::Is),
+            (0x14, PortReg::Ie),
+            (0x18, PortReg::Cmd),
+            (0x1c, PortReg::Reserved),
+            (0x20, PortReg::Tfd),
+            (0x24, PortReg::Sig),
+            (0x28, PortReg::Ssts),
+            (0x2c, PortReg::Sctl),
+            (0x30, PortReg::Serr),
+            (0x34, PortReg::Sact),
+            (0x38, PortReg::Ci),
+            (0x3c, PortReg::Sntf),
+            (0x40, PortReg::Fbs),
+        ];
+        for n in 0..MAX_PORTS {
+            let base = PORT_BASE + n * PORT_SIZE;
+            for (off, reg) in port.iter() {
+                map.define(base + off, 4, AbarReg::Port(n, *reg));
+            }
+            map.define_with_flags(
+                base + 0x44,
+                PORT_SIZE - 0x44,
+                AbarReg::Port(n, PortReg::Reserved),
+                Flags::PASSTHRU,
+            );
+        }
+        let ports_end = PORT_BASE + MAX_PORTS * PORT_SIZE;
+        map.define_with_flags(
+            ports_end,
+            ABAR_SIZE - ports_end,
+            AbarReg::Reserved,
+            Flags::PASSTHRU,
+        );
+        map
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmm::TestMem;
+
+    /// Disk which holds on to its requests, for the test to complete
+    struct HeldBdev(Mutex<Vec<Request>>);
+    impl BlockDev<Request> for HeldBdev {
+        fn enqueue(&self, req: Request) {
+            self.0.lock().unwrap().push(req);
+        }
+        fn inquire(&self) -> BlockInquiry {
+            BlockInquiry { total_size: 0x100, block_size: 512, writable: true }
+        }
+    }
+
+    const CLB: u64 = 0x1000;
+    const FB: u64 = 0x2000;
+    const CTBA: u64 = 0x3000;
+
+    /// Place a command in `slot`, with a scatter-gather list of `prdt`
+    fn put_cmd(mem: &MemCtx, slot: u32, fis: &[u8; 20], prdt: &[(u64, u32)]) {
+        let ctba = CTBA + slot as u64 * 0x100;
+        let hdr: [u32; 4] = [
+            5 | (prdt.len() as u32) << 16,
+            0,
+            ctba as u32,
+            (ctba >> 32) as u32,
+        ];
+        assert!(mem.write(GuestAddr(CLB + slot as u64 * CMD_HDR_SZ), &hdr));
+        assert!(mem.write(GuestAddr(ctba), fis));
+        for (n, (dba, len)) in prdt.iter().enumerate() {
+            let prd: [u32; 4] = [*dba as u32, (*dba >> 32) as u32, 0, len - 1];
+            let addr = ctba + CMD_TBL_PRDT + n as u64 * PRD_SZ;
+            assert!(mem.write(GuestAddr(addr), &prd));
+        }
+    }
+
+    fn h2d(cmd: u8, lba: u64, count: u16) -> [u8; 20] {
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_H2D;
+        fis[1] = FIS_H2D_CMD;
+        fis[2] = cmd;
+        let lba = lba.to_le_bytes();
+        fis[4..7].copy_from_slice(&lba[0..3]);
+        fis[7] = 0x40;
+        fis[8..11].copy_from_slice(&lba[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+        fis
+    }
+
+    #[test]
+    fn cmd_list_read() {
+        let tmem = TestMem::new(0x10000);
+        let mem = tmem.memctx();
+        let bdev = Arc::new(HeldBdev(Mutex::new(Vec::new())));
+        let hba = Arc::new(Hba::new());
+        let port = Port::new(
+            0,
+            hba,
+            Some(AhciDrive::Disk(
+                Arc::clone(&bdev) as Arc<dyn BlockDev<Request>>
+            )),
+        );
+        let mut state = port.state.lock().unwrap();
+        state.clb = CLB;
+        state.fb = FB;
+        state.cmd |= PXCMD_FRE | PXCMD_ST;
+
+        // IDENTIFY is answered directly, through the PRDT
+        put_cmd(&mem, 0, &h2d(ATA_CMD_IDENTIFY, 0, 0), &[(0x8000, 0x200)]);
+        state.ci = 1 << 0;
+        port.process_cmds(&mut state, &mem);
+        assert_eq!(state.ci, 0);
+        assert_eq!(state.is & (PXIS_PSS | PXIS_DHRS), PXIS_PSS | PXIS_DHRS);
+        let prdbc: u32 = mem.read(GuestAddr(CLB + 4)).unwrap();
+        assert_eq!(prdbc, 512);
+        let id: [u16; 256] = mem.read(GuestAddr(0x8000)).unwrap();
+        // 48-bit sector count
+        assert_eq!(id[100], 0x100);
+        let pio: [u8; 20] = mem.read(GuestAddr(FB + RFIS_PIO_SETUP)).unwrap();
+        assert_eq!(pio[0], FIS_TYPE_PIO_SETUP);
+        assert_eq!(u16::from_le_bytes([pio[16], pio[17]]), 512);
+
+        // A READ DMA EXT of 3 sectors from LBA 5, split across two PRDs
+        state.is = 0;
+        let prdt = [(0x9000, 0x400), (0xa000, 0x200)];
+        put_cmd(&mem, 2, &h2d(ATA_CMD_READ_DMA_EXT, 5, 3), &prdt);
+        state.ci = 1 << 2;
+        port.process_cmds(&mut state, &mem);
+        assert_eq!(state.busy, 1 << 2);
+        assert_eq!(state.ci, 1 << 2);
+
+        let mut req = bdev.0.lock().unwrap().pop().unwrap();
+        assert!(matches!(req.oper(), BlockOp::Read));
+        assert_eq!(req.offset(), 5 * 512);
+        let bufs: Vec<_> = std::iter::from_fn(|| req.next_buf())
+            .map(|r| (r.0 .0, r.1))
+            .collect();
+        assert_eq!(bufs, [(0x9000, 0x400), (0xa000, 0x200)]);
+
+        // Completion is reported in the command header and received FIS
+        let (slot, gen, xfer) = (req.slot, req.gen, req.xfer);
+        drop(state);
+        port.complete(slot, gen, xfer, BlockResult::Success, &mem);
+        let state = port.state.lock().unwrap();
+        assert_eq!(state.ci, 0);
+        assert_eq!(state.busy, 0);
+        assert_ne!(state.is & PXIS_DHRS, 0);
+        let prdbc: u32 = mem.read(GuestAddr(CLB + 2 * CMD_HDR_SZ + 4)).unwrap();
+        assert_eq!(prdbc, 3 * 512);
+        let d2h: [u8; 20] = mem.read(GuestAddr(FB + RFIS_D2H)).unwrap();
+        assert_eq!(d2h[0], FIS_TYPE_D2H);
+        assert_eq!(d2h[2], ATA_SR_DRDY | ATA_SR_DSC);
+    }
+
+    #[test]
+    fn abar_layout() {
+        assert_eq!(ABAR_MAP.total_len(), ABAR_SIZE);
+
+        let mut found = Vec::new();
+        let mut buf = [0u8; 4];
+        for off in [0x04, 0x100 + 0x38, 0x180 + 0x18, 0x1080 + 0x28].iter() {
+            let mut ro = ReadOp::new_buf(*off, &mut buf);
+            ABAR_MAP.process(&mut RWOp::Read(&mut ro), |id, _| found.push(*id));
+        }
+        assert_eq!(
+            found,
+            vec![
+                AbarReg::Hba(HbaReg::Ghc),
+                AbarReg::Port(0, PortReg::Ci),
+                AbarReg::Port(1, PortReg::Cmd),
+                AbarReg::Port(31, PortReg::Ssts),
+            ]
+        );
+    }
+
+    #[test]
+    fn port_signature() {
+        let hba = Arc::new(Hba::new());
+        let empty = Port::new(0, Arc::clone(&hba), None);
+        let state = empty.state.lock().unwrap();
+        assert_eq!(state.sig, SIG_NONE);
+        assert_eq!(state.ssts, 0);
+        drop(state);
+
+        struct StubBdev;
+        impl BlockDev<Request> for StubBdev {
+            fn enqueue(&self, _req: Request) {}
+            fn inquire(&self) -> BlockInquiry {
+                BlockInquiry {
+                    total_size: 0x1000,
+                    block_size: 512,
+                    writable: false,
+                }
+            }
+        }
+        let cd = Port::new(1, hba, Some(AhciDrive::Cdrom(Arc::new(StubBdev))));
+        let state = cd.state.lock().unwrap();
+        assert_eq!(state.sig, SIG_ATAPI);
+        assert_eq!(state.ssts, PXSSTS_ONLINE);
+        assert_ne!(state.cmd & PXCMD_ATAPI, 0);
+    }
+}
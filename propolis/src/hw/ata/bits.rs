@@ -0,0 +1,52 @@
+// ATA status and error
+pub const ATA_SR_BSY: u8 = 0x80;
+pub const ATA_SR_DRDY: u8 = 0x40;
+pub const ATA_SR_DSC: u8 = 0x10;
+pub const ATA_SR_DRQ: u8 = 0x08;
+pub const ATA_SR_ERR: u8 = 0x01;
+pub const ATA_ER_ABRT: u8 = 0x04;
+pub const ATA_ER_UNC: u8 = 0x40;
+
+// ATA commands
+pub const ATA_CMD_DEVICE_RESET: u8 = 0x08;
+pub const ATA_CMD_READ_SECTORS: u8 = 0x20;
+pub const ATA_CMD_READ_SECTORS_EXT: u8 = 0x24;
+pub const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+pub const ATA_CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+pub const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_CMD_EXEC_DIAG: u8 = 0x90;
+pub const ATA_CMD_INIT_DEV_PARAMS: u8 = 0x91;
+pub const ATA_CMD_PACKET: u8 = 0xa0;
+pub const ATA_CMD_IDENTIFY_PACKET: u8 = 0xa1;
+pub const ATA_CMD_SET_MULTIPLE: u8 = 0xc6;
+pub const ATA_CMD_READ_DMA: u8 = 0xc8;
+pub const ATA_CMD_WRITE_DMA: u8 = 0xca;
+pub const ATA_CMD_STANDBY_IMMED: u8 = 0xe0;
+pub const ATA_CMD_IDLE_IMMED: u8 = 0xe1;
+pub const ATA_CMD_FLUSH_CACHE: u8 = 0xe7;
+pub const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+pub const ATA_CMD_IDENTIFY: u8 = 0xec;
+pub const ATA_CMD_SET_FEATURES: u8 = 0xef;
+
+// SCSI (MMC) commands accepted by ATAPI devices
+pub const SCSI_TEST_UNIT_READY: u8 = 0x00;
+pub const SCSI_REQUEST_SENSE: u8 = 0x03;
+pub const SCSI_INQUIRY: u8 = 0x12;
+pub const SCSI_START_STOP_UNIT: u8 = 0x1b;
+pub const SCSI_PREVENT_ALLOW: u8 = 0x1e;
+pub const SCSI_READ_CAPACITY: u8 = 0x25;
+pub const SCSI_READ_10: u8 = 0x28;
+pub const SCSI_READ_TOC: u8 = 0x43;
+pub const SCSI_GET_CONFIGURATION: u8 = 0x46;
+pub const SCSI_GET_EVENT_STATUS: u8 = 0x4a;
+pub const SCSI_MODE_SENSE_10: u8 = 0x5a;
+pub const SCSI_READ_12: u8 = 0xa8;
+
+// Sense keys and additional sense codes
+pub const SENSE_MEDIUM_ERROR: u8 = 0x3;
+pub const SENSE_ILLEGAL_REQUEST: u8 = 0x5;
+pub const ASC_READ_ERROR: u8 = 0x11;
+pub const ASC_INVALID_OPCODE: u8 = 0x20;
+pub const ASC_LBA_OUT_OF_RANGE: u8 = 0x21;
+pub const ASC_INVALID_FIELD: u8 = 0x24;
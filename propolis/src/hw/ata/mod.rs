@@ -0,0 +1,462 @@
+//! IDENTIFY data, ATAPI packet command handling, and scatter-gather list
+//! helpers, common to the disks and CD-ROMs of the AHCI and IDE controllers.
+
+pub mod bits;
+
+use crate::common::GuestRegion;
+use crate::vmm::MemCtx;
+
+use bits::*;
+
+/// Sector size of ATA disks
+pub const SECTOR_SZ: u64 = 512;
+/// Sector size of CD-ROM media
+pub const CD_SECTOR_SZ: u64 = 2048;
+
+const MODEL_DISK: &str = "Propolis SATA Disk";
+const MODEL_CDROM: &str = "Propolis SATA CD-ROM";
+const FW_REV: &str = "1.0";
+
+/// Sense data (key, additional sense code, and qualifier) of a failed packet
+/// command
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+impl Sense {
+    pub fn new(key: u8, asc: u8) -> Self {
+        Self { key, asc, ascq: 0 }
+    }
+    fn fixed(&self) -> [u8; 18] {
+        let mut buf = [0u8; 18];
+        buf[0] = 0x70;
+        buf[2] = self.key;
+        // Additional sense length
+        buf[7] = 10;
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        buf
+    }
+}
+
+struct IdentifyData([u16; 256]);
+impl IdentifyData {
+    fn new() -> Self {
+        Self([0; 256])
+    }
+    /// Strings are space-padded, with the first of each pair of characters in
+    /// the upper byte of a word.
+    fn set_str(&mut self, word: usize, words: usize, val: &str) {
+        let mut bytes = val.bytes().chain(std::iter::repeat(b' '));
+        for w in self.0[word..(word + words)].iter_mut() {
+            let hi = bytes.next().unwrap();
+            let lo = bytes.next().unwrap();
+            *w = (hi as u16) << 8 | lo as u16;
+        }
+    }
+    fn set_u32(&mut self, word: usize, val: u32) {
+        self.0[word] = val as u16;
+        self.0[word + 1] = (val >> 16) as u16;
+    }
+    fn set_u64(&mut self, word: usize, val: u64) {
+        self.set_u32(word, val as u32);
+        self.set_u32(word + 2, (val >> 32) as u32);
+    }
+    fn set_common(&mut self, serial: &str, model: &str) {
+        self.set_str(10, 10, serial);
+        self.set_str(23, 4, FW_REV);
+        self.set_str(27, 20, model);
+        // LBA and DMA supported
+        self.0[49] = 1 << 9 | 1 << 8;
+        // Words 64-70 and 88 are valid
+        self.0[53] = 0x0006;
+        // Multiword DMA modes 0-2, and PIO modes 3-4
+        self.0[63] = 0x0007;
+        self.0[64] = 0x0003;
+        self.0[65] = 120;
+        self.0[66] = 120;
+        self.0[67] = 120;
+        self.0[68] = 120;
+        // SATA Gen1
+        self.0[76] = 1 << 1;
+        // UDMA modes 0-5, with mode 5 selected
+        self.0[88] = 0x203f;
+    }
+    /// Serialize, with the integrity word (255) set so the data sums to zero
+    fn finish(mut self) -> [u8; 512] {
+        self.0[255] = 0x00a5;
+        let mut buf = [0u8; 512];
+        for (n, w) in self.0.iter().enumerate() {
+            buf[(n * 2)..(n * 2 + 2)].copy_from_slice(&w.to_le_bytes());
+        }
+        let sum = buf[..511].iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+        buf[511] = sum.wrapping_neg();
+        buf
+    }
+}
+
+/// IDENTIFY DEVICE data for a disk of `sectors` sectors, each `sector_sz`
+/// bytes.
+pub fn identify_disk(serial: &str, sectors: u64, sector_sz: u32) -> [u8; 512] {
+    let mut id = IdentifyData::new();
+    id.set_common(serial, MODEL_DISK);
+
+    // Non-removable ATA device, with a nominal CHS geometry for the curious
+    id.0[0] = 0x0040;
+    id.0[1] = u64::min(sectors / (16 * 63), 16383) as u16;
+    id.0[3] = 16;
+    id.0[6] = 63;
+    // READ/WRITE MULTIPLE is not supported
+    id.0[47] = 0x8000;
+    id.set_u32(60, u64::min(sectors, 0x0fff_ffff) as u32);
+
+    // ATA8-ACS, with 48-bit addressing and cache flushes
+    id.0[80] = 0x01f0;
+    id.0[82] = 1 << 14;
+    id.0[83] = 1 << 14 | 1 << 13 | 1 << 12 | 1 << 10;
+    id.0[84] = 1 << 14;
+    id.0[85] = 1 << 14;
+    id.0[86] = 1 << 13 | 1 << 12 | 1 << 10;
+    id.0[87] = 1 << 14;
+    id.set_u64(100, sectors);
+    if sector_sz != 512 {
+        // Logical sectors larger than 512 bytes, sized in words
+        id.0[106] = 1 << 14 | 1 << 12;
+        id.set_u32(117, sector_sz / 2);
+    }
+    // Non-rotating media
+    id.0[217] = 1;
+    id.finish()
+}
+
+/// IDENTIFY PACKET DEVICE data for a CD-ROM
+pub fn identify_cdrom(serial: &str) -> [u8; 512] {
+    let mut id = IdentifyData::new();
+    id.set_common(serial, MODEL_CDROM);
+
+    // ATAPI CD-ROM device, removable, 12-byte packets, DRQ within 50us
+    id.0[0] = 0x8000 | 0x05 << 8 | 1 << 7 | 1 << 6;
+    id.0[80] = 0x01f0;
+    id.finish()
+}
+
+/// Outcome of a packet command
+#[derive(Debug, Eq, PartialEq)]
+pub enum Packet {
+    /// Data to transfer to the host
+    Data(Vec<u8>),
+    /// Read `count` sectors starting at `lba`
+    Read {
+        lba: u64,
+        count: u32,
+    },
+    Error(Sense),
+}
+
+/// Regions of the scatter-gather list `prdt` covering the first `len` bytes,
+/// if it is that large
+pub fn prdt_covering(
+    prdt: &[GuestRegion],
+    len: usize,
+) -> Option<Vec<GuestRegion>> {
+    let mut left = len;
+    let mut res = Vec::new();
+    for region in prdt.iter() {
+        if left == 0 {
+            break;
+        }
+        let sz = usize::min(region.1, left);
+        res.push(GuestRegion(region.0, sz));
+        left -= sz;
+    }
+    match left {
+        0 => Some(res),
+        _ => None,
+    }
+}
+
+/// Copy `data` into the scatter-gather list `prdt`, returning the number of
+/// bytes written
+pub fn prdt_copy_out(mem: &MemCtx, prdt: &[GuestRegion], data: &[u8]) -> usize {
+    let mut done = 0;
+    for region in prdt.iter() {
+        if done == data.len() {
+            break;
+        }
+        let sz = usize::min(region.1, data.len() - done);
+        match mem.write_from(region.0, &data[done..], sz) {
+            Some(n) if n == sz => done += sz,
+            _ => break,
+        }
+    }
+    done
+}
+
+fn be16(buf: &[u8]) -> u16 {
+    u16::from_be_bytes([buf[0], buf[1]])
+}
+fn be32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Data truncated to the allocation length of the command
+fn reply(mut data: Vec<u8>, alloc_len: usize) -> Packet {
+    data.truncate(alloc_len);
+    Packet::Data(data)
+}
+
+/// Sector address in minute/second/frame form, offset by the 2-second
+/// pre-gap.
+fn lba_to_msf(lba: u64) -> [u8; 4] {
+    let frames = lba + 150;
+    [
+        0,
+        (frames / (75 * 60)) as u8,
+        (frames / 75 % 60) as u8,
+        (frames % 75) as u8,
+    ]
+}
+
+/// Handle the packet command in `cdb` for a CD-ROM of `sectors` sectors,
+/// with `sense` holding the outcome of the last failed command.
+pub fn atapi_packet(cdb: &[u8; 16], sectors: u64, sense: &mut Sense) -> Packet {
+    let invalid_field =
+        Packet::Error(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD));
+    match cdb[0] {
+        SCSI_TEST_UNIT_READY | SCSI_START_STOP_UNIT | SCSI_PREVENT_ALLOW => {
+            Packet::Data(Vec::new())
+        }
+        SCSI_REQUEST_SENSE => {
+            let data = sense.fixed().to_vec();
+            *sense = Sense::default();
+            reply(data, cdb[4] as usize)
+        }
+        SCSI_INQUIRY => {
+            if cdb[1] & 0x1 != 0 {
+                // No vital product data pages
+                return invalid_field;
+            }
+            let mut data = vec![0u8; 36];
+            // CD-ROM, removable media, responding per SPC-3
+            data[0] = 0x05;
+            data[1] = 0x80;
+            data[2] = 0x05;
+            data[3] = 0x02;
+            data[4] = 31;
+            data[8..16].copy_from_slice(b"PROPOLIS");
+            data[16..32].copy_from_slice(b"VIRTUAL CD-ROM  ");
+            data[32..36].copy_from_slice(b"1.0 ");
+            reply(data, cdb[4] as usize)
+        }
+        SCSI_READ_CAPACITY => {
+            let last = sectors.saturating_sub(1).min(u32::MAX as u64) as u32;
+            let mut data = last.to_be_bytes().to_vec();
+            data.extend_from_slice(&(CD_SECTOR_SZ as u32).to_be_bytes());
+            Packet::Data(data)
+        }
+        SCSI_READ_10 | SCSI_READ_12 => {
+            let lba = be32(&cdb[2..6]) as u64;
+            let count = match cdb[0] {
+                SCSI_READ_10 => be16(&cdb[7..9]) as u32,
+                _ => be32(&cdb[6..10]),
+            };
+            if lba + count as u64 > sectors {
+                return Packet::Error(Sense::new(
+                    SENSE_ILLEGAL_REQUEST,
+                    ASC_LBA_OUT_OF_RANGE,
+                ));
+            }
+            Packet::Read { lba, count }
+        }
+        SCSI_READ_TOC => {
+            let msf = cdb[1] & 0x2 != 0;
+            let addr = |lba: u64| match msf {
+                true => lba_to_msf(lba),
+                false => (lba as u32).to_be_bytes(),
+            };
+            let alloc_len = be16(&cdb[7..9]) as usize;
+            let mut data = vec![0u8; 4];
+            match cdb[2] & 0xf {
+                0 => {
+                    // A single data track, followed by the lead-out
+                    data[2] = 1;
+                    data[3] = 1;
+                    for (track, lba) in [(1u8, 0), (0xaa, sectors)].iter() {
+                        data.extend_from_slice(&[0, 0x14, *track, 0]);
+                        data.extend_from_slice(&addr(*lba));
+                    }
+                }
+                1 => {
+                    // Session info: the first track of the only session
+                    data[2] = 1;
+                    data[3] = 1;
+                    data.extend_from_slice(&[0, 0x14, 1, 0]);
+                    data.extend_from_slice(&addr(0));
+                }
+                _ => return invalid_field,
+            }
+            let len = (data.len() - 2) as u16;
+            data[0..2].copy_from_slice(&len.to_be_bytes());
+            reply(data, alloc_len)
+        }
+        SCSI_GET_CONFIGURATION => {
+            // Only the feature header, naming CD-ROM as the current profile
+            let mut data = vec![0u8; 8];
+            data[3] = 4;
+            data[7] = 0x08;
+            reply(data, be16(&cdb[7..9]) as usize)
+        }
+        SCSI_GET_EVENT_STATUS => {
+            if cdb[1] & 0x1 == 0 {
+                // Asynchronous notification is not supported
+                return invalid_field;
+            }
+            // Media class: no change, with media present
+            let data = vec![0, 6, 0x04, 0x10, 0, 0x02, 0, 0];
+            reply(data, be16(&cdb[7..9]) as usize)
+        }
+        SCSI_MODE_SENSE_10 => {
+            let page = cdb[2] & 0x3f;
+            if page != 0x2a && page != 0x3f {
+                return invalid_field;
+            }
+            // Header, then the capabilities page (advertising nothing)
+            let mut data = vec![0u8; 8 + 22];
+            data[1] = (data.len() - 2) as u8;
+            data[8] = 0x2a;
+            data[9] = 20;
+            reply(data, be16(&cdb[7..9]) as usize)
+        }
+        _ => {
+            Packet::Error(Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_OPCODE))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::GuestAddr;
+    use crate::vmm::TestMem;
+
+    fn word(data: &[u8], n: usize) -> u16 {
+        u16::from_le_bytes([data[n * 2], data[n * 2 + 1]])
+    }
+
+    #[test]
+    fn identify() {
+        let data = identify_disk("SER1", 0x1_2345_6789, 512);
+        assert_eq!(data.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0);
+        assert_eq!(data[510], 0xa5);
+        // Strings are byte-swapped within each word
+        assert_eq!(&data[20..26], b"ES1R  ");
+        assert_eq!(word(&data, 60), 0xffff);
+        assert_eq!(word(&data, 61), 0x0fff);
+        assert_eq!(word(&data, 100), 0x6789);
+        assert_eq!(word(&data, 101), 0x2345);
+        assert_eq!(word(&data, 102), 0x0001);
+        assert_eq!(word(&data, 106), 0);
+
+        let data = identify_disk("SER1", 0x1000, 4096);
+        assert_eq!(word(&data, 106), 0x5000);
+        assert_eq!(word(&data, 117), 2048);
+
+        let data = identify_cdrom("SER2");
+        assert_eq!(data.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0);
+        assert_eq!(word(&data, 0), 0x85c0);
+    }
+
+    #[test]
+    fn prdt() {
+        let prdt = [
+            GuestRegion(GuestAddr(0x1000), 0x200),
+            GuestRegion(GuestAddr(0x4000), 0x1000),
+        ];
+        assert_eq!(
+            prdt_covering(&prdt, 0x400),
+            Some(vec![
+                GuestRegion(GuestAddr(0x1000), 0x200),
+                GuestRegion(GuestAddr(0x4000), 0x200),
+            ])
+        );
+        assert_eq!(prdt_covering(&prdt, 0x1200), Some(prdt.to_vec()));
+        assert_eq!(prdt_covering(&prdt, 0x1201), None);
+
+        let mem = TestMem::new(0x8000);
+        let data: Vec<u8> = (0..0x300).map(|n| n as u8).collect();
+        assert_eq!(prdt_copy_out(&mem.memctx(), &prdt, &data), 0x300);
+        let mut buf = [0u8; 0x100];
+        mem.memctx().read_into(GuestAddr(0x4000), &mut buf, 0x100);
+        assert_eq!(buf[..], data[0x200..]);
+
+        // Stops short at the end of guest memory
+        let past = [GuestRegion(GuestAddr(0x7f00), 0x200)];
+        assert_eq!(prdt_copy_out(&mem.memctx(), &past, &data), 0);
+    }
+
+    #[test]
+    fn packets() {
+        let mut sense = Sense::default();
+        let mut cdb = [0u8; 16];
+
+        cdb[0] = SCSI_READ_CAPACITY;
+        assert_eq!(
+            atapi_packet(&cdb, 1000, &mut sense),
+            Packet::Data(vec![0, 0, 0x03, 0xe7, 0, 0, 0x08, 0])
+        );
+
+        cdb = [0; 16];
+        cdb[0] = SCSI_READ_10;
+        cdb[5] = 10;
+        cdb[8] = 4;
+        assert_eq!(
+            atapi_packet(&cdb, 1000, &mut sense),
+            Packet::Read { lba: 10, count: 4 }
+        );
+        cdb[3] = 1;
+        assert_eq!(
+            atapi_packet(&cdb, 1000, &mut sense),
+            Packet::Error(Sense::new(
+                SENSE_ILLEGAL_REQUEST,
+                ASC_LBA_OUT_OF_RANGE
+            ))
+        );
+
+        cdb = [0; 16];
+        cdb[0] = SCSI_READ_TOC;
+        cdb[1] = 0x2;
+        cdb[8] = 0xff;
+        match atapi_packet(&cdb, 75 * 60, &mut sense) {
+            Packet::Data(data) => {
+                assert_eq!(data.len(), 20);
+                assert_eq!(&data[0..4], &[0, 18, 1, 1]);
+                // Lead-out at 1:02:00
+                assert_eq!(&data[14..20], &[0xaa, 0, 0, 1, 2, 0]);
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+
+        sense = Sense::new(SENSE_MEDIUM_ERROR, ASC_READ_ERROR);
+        cdb = [0; 16];
+        cdb[0] = SCSI_REQUEST_SENSE;
+        cdb[4] = 18;
+        match atapi_packet(&cdb, 1000, &mut sense) {
+            Packet::Data(data) => {
+                assert_eq!(data[2], SENSE_MEDIUM_ERROR);
+                assert_eq!(data[12], ASC_READ_ERROR);
+            }
+            p => panic!("unexpected {:?}", p),
+        }
+        assert_eq!(sense, Sense::default());
+
+        cdb = [0; 16];
+        cdb[0] = 0xff;
+        assert!(matches!(
+            atapi_packet(&cdb, 1000, &mut sense),
+            Packet::Error(Sense { key: SENSE_ILLEGAL_REQUEST, .. })
+        ));
+    }
+}
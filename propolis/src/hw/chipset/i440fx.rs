@@ -1,13 +1,18 @@
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
-use super::{BarPlacer, Chipset};
+use super::{place_bars, Chipset};
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::exits::SuspendReason;
+use crate::hw::ide::{IdeDrive, Piix3Ide};
 use crate::hw::pci::{self, INTxPinID, PioCfgDecoder, BDF};
 use crate::hw::ps2ctrl::PS2Ctrl;
+use crate::hw::rtc::Rtc;
 use crate::hw::uart::{self, LpcUart};
+use crate::hw::Lifecycle;
 use crate::intr_pins::{IntrPin, LegacyPIC, LegacyPin};
+use crate::mmio::MmioDevice;
 use crate::pio::{PioBus, PioDev};
 use crate::util::regmap::RegMap;
 use crate::util::self_arc::*;
@@ -24,13 +29,17 @@ pub struct I440Fx {
 
     lnk_pins: [Arc<LNKPin>; 4],
     sci_pin: Arc<LNKPin>,
+    rst_ctrl: RstCtrl,
 
     sa_cell: SelfArcCell<Self>,
 }
 impl I440Fx {
+    /// Create the chipset.  With `acpi_mode` set, the PM block starts out in
+    /// ACPI mode, as expected by the tables generated by Propolis.
     pub fn create(
         hdl: Arc<VmmHdl>,
         pio: &PioBus,
+        acpi_mode: bool,
         cfg_lpc: impl FnOnce(&Piix3Lpc),
     ) -> Arc<Self> {
         let pic = LegacyPIC::new(Arc::clone(&hdl));
@@ -50,6 +59,7 @@ impl I440Fx {
                 Arc::new(LNKPin::new()),
             ],
             sci_pin,
+            rst_ctrl: RstCtrl::new(),
 
             sa_cell: SelfArcCell::new(),
         });
@@ -57,7 +67,7 @@ impl I440Fx {
 
         let hbdev = Piix4HostBridge::create();
         let lpcdev = Piix3Lpc::create(Arc::downgrade(&this), &this.pic, pio);
-        let pmdev = Piix3PM::create(hdl.as_ref(), pio);
+        let pmdev = Piix3PM::create(hdl.as_ref(), pio, acpi_mode);
 
         lpcdev.with_inner(cfg_lpc);
 
@@ -68,49 +78,34 @@ impl I440Fx {
         this
     }
 
+    /// Attach the PIIX3 IDE function (at 0:1.1), holding `drives` in the
+    /// order: primary master, primary slave, secondary master, and secondary
+    /// slave.
+    pub fn attach_ide(&self, pio: &PioBus, drives: Vec<Option<IdeDrive>>) {
+        let ide = Piix3Ide::create(&self.pic, pio, drives);
+        self.pci_attach(BDF::new(0, 1, 1), ide);
+    }
+
     fn set_lnk_route(&self, idx: usize, irq: Option<u8>) {
         assert!(idx <= 3);
         self.lnk_pins[idx].reassign(irq.and_then(|i| self.pic.pin_handle(i)));
     }
 
     fn route_lintr(&self, bdf: &BDF) -> (INTxPinID, Arc<dyn IntrPin>) {
-        let intx_pin = match (bdf.func() + 1) % 4 {
-            1 => INTxPinID::INTA,
-            2 => INTxPinID::INTB,
-            3 => INTxPinID::INTC,
-            4 => INTxPinID::INTD,
-            _ => panic!(),
-        };
-        // D->A->B->C starting at 0:0.0
-        let pin_route = (bdf.dev() + intx_pin as u8 + 2) % 4;
-        (
-            intx_pin,
-            Arc::clone(&self.lnk_pins[pin_route as usize]) as Arc<dyn IntrPin>,
-        )
+        super::route_lintr(bdf, |slot, pin| {
+            // D->A->B->C starting at 0:0.0
+            let pin_route = (slot + pin as u8 + 2) % 4;
+            Arc::clone(&self.lnk_pins[pin_route as usize])
+        })
     }
     fn place_bars(&self) {
         let bus = self.pci_bus.lock().unwrap();
-
-        let mut bar_placer = BarPlacer::new();
-        bar_placer.add_avail_pio(0xc000, 0x4000);
-        bar_placer.add_avail_mmio(0xe0000000, 0x10000000);
-
-        for (slot, func, dev) in bus.iter() {
-            dev.bar_for_each(&mut |bar, def| {
-                bar_placer.add_bar((slot, func, bar), def);
-            });
-        }
-        let remain = bar_placer.place(|(slot, func, bar), addr| {
-            println!(
-                "placing {:?} @ {:x} for 0:{:x}:{:x}",
-                bar, addr, slot, func
-            );
-            let dev = bus.device_at(slot, func).unwrap();
-            dev.bar_place(bar, addr as u64);
-        });
-        if let Some((pio, mmio)) = remain {
-            panic!("Unfulfilled BAR allocations! pio:{} mmio:{}", pio, mmio);
-        }
+        place_bars(
+            &bus,
+            0,
+            Some((0xc000, 0x4000)),
+            Some((0xe000_0000, 0x1000_0000)),
+        );
     }
 }
 impl Chipset for I440Fx {
@@ -131,9 +126,36 @@ impl Chipset for I440Fx {
         self.place_bars();
     }
 }
+impl Lifecycle for I440Fx {
+    fn reset(&self, ctx: &DispCtx) {
+        self.pci_cfg.reset();
+        self.rst_ctrl.reset();
+        for dev in bus_devices(&self.pci_bus) {
+            dev.reset(ctx);
+        }
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.wake(ctx);
+        }
+    }
+    fn pause(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.pause(ctx);
+        }
+    }
+    fn resume(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.resume(ctx);
+        }
+    }
+}
 impl PioDev for I440Fx {
     fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
         match port {
+            pci::PORT_PCI_CONFIG_ADDR if RstCtrl::is_target(&rwo) => {
+                self.rst_ctrl.service(rwo, ctx);
+            }
             pci::PORT_PCI_CONFIG_ADDR => {
                 self.pci_cfg.service_addr(rwo);
             }
@@ -178,7 +200,81 @@ impl SelfArc for I440Fx {
     }
 }
 
-struct LNKPin {
+/// Devices on the bus, in slot and function order.
+///
+/// Lifecycle events for the devices may touch the PIO/MMIO buses, so they are
+/// carried out on this snapshot, rather than with the PCI bus lock held.
+pub(super) fn bus_devices(
+    bus: &Mutex<pci::Bus>,
+) -> Vec<Arc<dyn pci::Endpoint>> {
+    bus.lock().unwrap().iter().map(|(_, _, dev)| Arc::clone(dev)).collect()
+}
+
+/// Port of the Reset Control Register
+pub(crate) const PORT_RST_CTRL: u16 = 0xcf9;
+
+bitflags! {
+    #[derive(Default)]
+    struct RstCtrlReg: u8 {
+        const SYS_RST = 1 << 1;
+        const RST_CPU = 1 << 2;
+        const FULL_RST = 1 << 3;
+    }
+}
+
+/// Value written to the Reset Control Register to reset the system
+pub(crate) const RST_CTRL_RESET: u8 =
+    RstCtrlReg::SYS_RST.bits() | RstCtrlReg::RST_CPU.bits();
+
+/// Reset Control Register, through which the guest can request a reset of
+/// the instance.  It resides within the PCI configuration address port range,
+/// so accesses to it are directed here by the chipset.
+pub(super) struct RstCtrl {
+    reg: Mutex<RstCtrlReg>,
+}
+impl RstCtrl {
+    pub(super) fn new() -> Self {
+        Self { reg: Mutex::new(RstCtrlReg::empty()) }
+    }
+    /// Is an access to the PCI configuration address port actually aimed at
+    /// the Reset Control Register?
+    pub(super) fn is_target(rwo: &RWOp) -> bool {
+        let off = (PORT_RST_CTRL - pci::PORT_PCI_CONFIG_ADDR) as usize;
+        rwo.offset() == off && rwo.len() == 1
+    }
+    pub(super) fn service(&self, rwo: RWOp, ctx: &DispCtx) {
+        let mut reg = self.reg.lock().unwrap();
+        match rwo {
+            RWOp::Read(ro) => ro.write_u8(reg.bits()),
+            RWOp::Write(wo) => {
+                let val = RstCtrlReg::from_bits_truncate(wo.read_u8());
+                // RST_CPU triggers the reset, and does not latch
+                *reg = val - RstCtrlReg::RST_CPU;
+                if val.contains(RstCtrlReg::RST_CPU) {
+                    suspend_instance(
+                        bhyve_api::vm_suspend_how::VM_SUSPEND_RESET,
+                        ctx,
+                    );
+                }
+            }
+        }
+    }
+    pub(super) fn reset(&self) {
+        *self.reg.lock().unwrap() = RstCtrlReg::empty();
+    }
+}
+
+/// Suspend the instance on behalf of the guest, leaving it to whoever is
+/// waiting on the vCPUs to act upon the suspension.
+fn suspend_instance(how: bhyve_api::vm_suspend_how, ctx: &DispCtx) {
+    let res = ctx.mctx.with_hdl(|hdl| hdl.suspend(how));
+    if let Err(e) = res {
+        println!("failed to suspend instance ({:?}): {}", how, e);
+    }
+}
+
+/// Interrupt link (such as a PIRQ) which may be routed to an ISA IRQ
+pub(super) struct LNKPin {
     inner: Mutex<LNKPinInner>,
 }
 struct LNKPinInner {
@@ -186,10 +282,10 @@ struct LNKPinInner {
     pin: Option<LegacyPin>,
 }
 impl LNKPin {
-    fn new() -> Self {
+    pub(super) fn new() -> Self {
         Self { inner: Mutex::new(LNKPinInner { asserted: false, pin: None }) }
     }
-    fn reassign(&self, new_pin: Option<LegacyPin>) {
+    pub(super) fn reassign(&self, new_pin: Option<LegacyPin>) {
         let mut inner = self.inner.lock().unwrap();
         if let Some(old_pin) = inner.pin.as_ref() {
             if inner.asserted {
@@ -232,16 +328,16 @@ impl IntrPin for LNKPin {
     }
 }
 
-const PIR_OFFSET: usize = 0x60;
+pub(crate) const PIR_OFFSET: usize = 0x60;
 const PIR_LEN: usize = 4;
 const PIR_END: usize = PIR_OFFSET + PIR_LEN;
 
-const PIR_MASK_DISABLE: u8 = 0x80;
-const PIR_MASK_IRQ: u8 = 0x0f;
+pub(super) const PIR_MASK_DISABLE: u8 = 0x80;
+pub(super) const PIR_MASK_IRQ: u8 = 0x0f;
 
-const SCI_IRQ: u8 = 0x9;
+pub(crate) const SCI_IRQ: u8 = 0x9;
 
-fn valid_pir_irq(irq: u8) -> bool {
+pub(super) fn valid_pir_irq(irq: u8) -> bool {
     // Existing ACPI tables allow 3-7, 9-12, 14-15
     matches!(irq, 3..=7 | 9..=12 | 14 | 15)
 }
@@ -260,87 +356,55 @@ impl Piix4HostBridge {
 }
 impl pci::Device for Piix4HostBridge {}
 
-const COM1_PORT: u16 = 0x3f8;
-const COM2_PORT: u16 = 0x2f8;
-const COM3_PORT: u16 = 0x3e8;
-const COM4_PORT: u16 = 0x2e8;
-const COM1_IRQ: u8 = 4;
-const COM2_IRQ: u8 = 3;
-const COM3_IRQ: u8 = 4;
-const COM4_IRQ: u8 = 3;
+pub(crate) const COM1_PORT: u16 = 0x3f8;
+pub(crate) const COM2_PORT: u16 = 0x2f8;
+pub(crate) const COM3_PORT: u16 = 0x3e8;
+pub(crate) const COM4_PORT: u16 = 0x2e8;
+pub(crate) const COM1_IRQ: u8 = 4;
+pub(crate) const COM2_IRQ: u8 = 3;
+pub(crate) const COM3_IRQ: u8 = 4;
+pub(crate) const COM4_IRQ: u8 = 3;
+
+/// IO port and IRQ of each of the COM1-4 UARTs behind the LPC bridge
+pub const LPC_UARTS: [(u16, u8); 4] = [
+    (COM1_PORT, COM1_IRQ),
+    (COM2_PORT, COM2_IRQ),
+    (COM3_PORT, COM3_IRQ),
+    (COM4_PORT, COM4_IRQ),
+];
 
 const PORT_FAST_A20: u16 = 0x92;
 const LEN_FAST_A20: u16 = 1;
 const PORT_POST_CODE: u16 = 0x80;
 const LEN_POST_CODE: u16 = 1;
 
-pub struct Piix3Lpc {
-    reg_pir: Mutex<[u8; PIR_LEN]>,
-    post_code: AtomicU8,
-    uart_com1: Arc<LpcUart>,
-    uart_com2: Arc<LpcUart>,
-    uart_com3: Arc<LpcUart>,
-    uart_com4: Arc<LpcUart>,
+/// ISA devices which sit behind the LPC bridge: the COM1-4 UARTs, the PS/2
+/// controller, and the fast-A20 and POST code ports.
+pub(super) struct LpcDevs {
+    uarts: [Arc<LpcUart>; 4],
     ps2_ctrl: Arc<PS2Ctrl>,
-    chipset: Weak<I440Fx>,
+    post_code: AtomicU8,
 }
-impl Piix3Lpc {
-    pub fn create(
-        chipset: Weak<I440Fx>,
-        pic: &LegacyPIC,
-        pio_bus: &PioBus,
-    ) -> Arc<pci::DeviceInst> {
-        let com1 = LpcUart::new(pic.pin_handle(COM1_IRQ).unwrap());
-        let com2 = LpcUart::new(pic.pin_handle(COM2_IRQ).unwrap());
-        let com3 = LpcUart::new(pic.pin_handle(COM3_IRQ).unwrap());
-        let com4 = LpcUart::new(pic.pin_handle(COM4_IRQ).unwrap());
-
-        pio_bus
-            .register(
-                COM1_PORT,
-                uart::REGISTER_LEN as u16,
-                Arc::downgrade(&com1) as Weak<dyn PioDev>,
-                0,
-            )
-            .unwrap();
-        pio_bus
-            .register(
-                COM2_PORT,
-                uart::REGISTER_LEN as u16,
-                Arc::downgrade(&com2) as Weak<dyn PioDev>,
-                0,
-            )
-            .unwrap();
-        pio_bus
-            .register(
-                COM3_PORT,
-                uart::REGISTER_LEN as u16,
-                Arc::downgrade(&com2) as Weak<dyn PioDev>,
-                0,
-            )
-            .unwrap();
-        pio_bus
-            .register(
-                COM4_PORT,
-                uart::REGISTER_LEN as u16,
-                Arc::downgrade(&com2) as Weak<dyn PioDev>,
-                0,
-            )
-            .unwrap();
+impl LpcDevs {
+    pub(super) fn create(pic: &LegacyPIC, pio_bus: &PioBus) -> Arc<Self> {
+        let uarts = LPC_UARTS
+            .map(|(_port, irq)| LpcUart::new(pic.pin_handle(irq).unwrap()));
+        for (uart, (port, _irq)) in uarts.iter().zip(LPC_UARTS.iter()) {
+            pio_bus
+                .register(
+                    *port,
+                    uart::REGISTER_LEN as u16,
+                    Arc::downgrade(uart) as Weak<dyn PioDev>,
+                    0,
+                )
+                .unwrap();
+        }
 
         let ps2_ctrl = PS2Ctrl::create();
         ps2_ctrl.attach(pio_bus, pic);
 
-        let this = Arc::new(Self {
-            reg_pir: Mutex::new([0u8; PIR_LEN]),
-            post_code: AtomicU8::new(0),
-            uart_com1: com1,
-            uart_com2: com2,
-            uart_com3: com3,
-            uart_com4: com4,
-            ps2_ctrl,
-            chipset,
-        });
+        let this =
+            Arc::new(Self { uarts, ps2_ctrl, post_code: AtomicU8::new(0) });
 
         pio_bus
             .register(
@@ -358,6 +422,43 @@ impl Piix3Lpc {
                 0,
             )
             .unwrap();
+        this
+    }
+
+    pub(super) fn config_uarts<F>(&self, f: F)
+    where
+        F: FnOnce(&Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>),
+    {
+        let [com1, com2, com3, com4] = &self.uarts;
+        f(com1, com2, com3, com4);
+    }
+}
+impl Lifecycle for LpcDevs {
+    fn reset(&self, ctx: &DispCtx) {
+        for uart in self.uarts.iter() {
+            uart.reset(ctx);
+        }
+        self.ps2_ctrl.reset(ctx);
+        self.post_code.store(0, Ordering::SeqCst);
+    }
+}
+
+pub struct Piix3Lpc {
+    reg_pir: Mutex<[u8; PIR_LEN]>,
+    devs: Arc<LpcDevs>,
+    chipset: Weak<I440Fx>,
+}
+impl Piix3Lpc {
+    pub fn create(
+        chipset: Weak<I440Fx>,
+        pic: &LegacyPIC,
+        pio_bus: &PioBus,
+    ) -> Arc<pci::DeviceInst> {
+        let this = Arc::new(Self {
+            reg_pir: Mutex::new([0u8; PIR_LEN]),
+            devs: LpcDevs::create(pic, pio_bus),
+            chipset,
+        });
 
         pci::Builder::new(pci::Ident {
             vendor_id: 0x8086,
@@ -374,7 +475,7 @@ impl Piix3Lpc {
     where
         F: FnOnce(&Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>),
     {
-        f(&self.uart_com1, &self.uart_com2, &self.uart_com3, &self.uart_com4);
+        self.devs.config_uarts(f);
     }
 
     fn write_pir(&self, idx: usize, val: u8) {
@@ -397,6 +498,12 @@ impl Piix3Lpc {
     }
 }
 impl pci::Device for Piix3Lpc {
+    fn reset(&self, ctx: &DispCtx) {
+        for idx in 0..PIR_LEN {
+            self.write_pir(idx, 0);
+        }
+        self.devs.reset(ctx);
+    }
     fn cfg_rw(&self, region: u8, rwo: RWOp) {
         assert_eq!(region as usize, PIR_OFFSET);
         assert!(rwo.offset() + rwo.len() <= PIR_END - PIR_OFFSET);
@@ -416,7 +523,7 @@ impl pci::Device for Piix3Lpc {
         }
     }
 }
-impl PioDev for Piix3Lpc {
+impl PioDev for LpcDevs {
     fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, _ctx: &DispCtx) {
         match port {
             PORT_FAST_A20 => {
@@ -447,8 +554,10 @@ impl PioDev for Piix3Lpc {
 const PMCFG_OFFSET: usize = 0x40;
 const PMCFG_LEN: usize = 0x98;
 
-const PMBASE_DEFAULT: u16 = 0xb000;
-const PMBASE_LEN: u16 = 0x40;
+pub(crate) const PMBASE_DEFAULT: u16 = 0xb000;
+pub(crate) const PMBASE_LEN: u16 = 0x40;
+/// Offset of the PM timer within the PM register block
+pub(crate) const PM_TMR_OFF: u16 = 0x8;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum PmCfg {
@@ -570,6 +679,7 @@ bitflags! {
     #[derive(Default)]
     struct PmSts: u16 {
         const PWRBTN_STS = 1 << 8;
+        const WAK_STS = 1 << 15;
     }
 }
 bitflags! {
@@ -588,45 +698,118 @@ bitflags! {
     }
 }
 
+/// SUS_TYP values requesting soft-off (S5): zero, as in the DSDT generated by
+/// Propolis (and QEMU), and five, as in the tables used with bhyve.
+const SUS_TYP_S5: [u16; 2] = [0, 5];
+/// SUS_TYP value requesting suspend-to-RAM (S3), as established by QEMU (and
+/// the firmware built for it).
+const SUS_TYP_S3: u16 = 1;
+
 struct PMRegs {
     pm_base: u16,
     pm_status: PmSts,
     pm_ena: PmEn,
     pm_ctrl: PmCntrl,
+    /// Run in ACPI mode from power-on, with SCI_EN set (and held)
+    acpi_mode: bool,
 }
-impl Default for PMRegs {
-    fn default() -> Self {
+impl PMRegs {
+    fn new(acpi_mode: bool) -> Self {
+        let pm_ctrl = match acpi_mode {
+            true => PmCntrl::SCI_EN,
+            false => PmCntrl::empty(),
+        };
         Self {
             pm_base: PMBASE_DEFAULT,
             pm_status: PmSts::empty(),
             pm_ena: PmEn::empty(),
-            pm_ctrl: PmCntrl::empty(),
+            pm_ctrl,
+            acpi_mode,
         }
     }
 }
 
-pub struct Piix3PM {
-    regs: Mutex<PMRegs>,
-    sa_cell: SelfArcCell<Self>,
+/// ACPI PM1 register block (with the in-kernel PM timer) at PMBASE
+pub(super) struct PmIo {
+    regs: Arc<Mutex<PMRegs>>,
+    // The PIO bus holds only a weak reference to the PM register block
+    _pm_io: Arc<MmioDevice<PmReg>>,
 }
-impl Piix3PM {
-    pub fn create(hdl: &VmmHdl, pio: &PioBus) -> Arc<pci::DeviceInst> {
-        let regs = PMRegs::default();
-        let mut this = Arc::new(Self {
-            regs: Mutex::new(regs),
-            sa_cell: SelfArcCell::new(),
+impl PmIo {
+    /// Attach the PM register block to `pio`.
+    ///
+    /// With `acpi_mode` set, the block starts out in ACPI mode (SCI_EN set),
+    /// as the tables generated by Propolis offer no SMI command port through
+    /// which the guest could make the switch itself.
+    pub(super) fn attach(hdl: &VmmHdl, pio: &PioBus, acpi_mode: bool) -> Self {
+        let regs = Arc::new(Mutex::new(PMRegs::new(acpi_mode)));
+        let io_regs = Arc::clone(&regs);
+        let pm_io = MmioDevice::new(&PM_REGS, move |id, rwo, ctx| {
+            let mut regs = io_regs.lock().unwrap();
+            match rwo {
+                RWOp::Read(ro) => regs.pmreg_read(id, ro),
+                RWOp::Write(wo) => regs.pmreg_write(id, wo, ctx),
+            }
         });
-        SelfArc::self_arc_init(&mut this);
 
         // XXX: static registration for now
         pio.register(
             PMBASE_DEFAULT,
             PMBASE_LEN,
-            Arc::downgrade(&this) as Weak<dyn PioDev>,
+            Arc::downgrade(&pm_io) as Weak<dyn PioDev>,
             0,
         )
         .unwrap();
-        hdl.pmtmr_locate(PMBASE_DEFAULT + 0x8).unwrap();
+        hdl.pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFF).unwrap();
+
+        Self { regs, _pm_io: pm_io }
+    }
+    pub(super) fn pm_base(&self) -> u16 {
+        self.regs.lock().unwrap().pm_base
+    }
+    pub(super) fn reset(&self, ctx: &DispCtx) {
+        let mut regs = self.regs.lock().unwrap();
+        *regs = PMRegs::new(regs.acpi_mode);
+        drop(regs);
+        Self::locate_pmtmr(ctx);
+    }
+    pub(super) fn wake(&self, ctx: &DispCtx) {
+        self.regs.lock().unwrap().pm_status.insert(PmSts::WAK_STS);
+        Self::locate_pmtmr(ctx);
+
+        // Direct the firmware down its S3 resume path
+        let res = ctx.mctx.with_hdl(Rtc::set_s3_resume);
+        if let Err(e) = res {
+            println!("failed to flag S3 resume: {}", e);
+        }
+    }
+    fn locate_pmtmr(ctx: &DispCtx) {
+        // The in-kernel PM timer is recreated when the instance is
+        // reinitialized, and must be located again.
+        let res = ctx
+            .mctx
+            .with_hdl(|hdl| hdl.pmtmr_locate(PMBASE_DEFAULT + PM_TMR_OFF));
+        if let Err(e) = res {
+            println!("failed to locate PM timer: {}", e);
+        }
+    }
+}
+
+pub struct Piix3PM {
+    pm: PmIo,
+    sa_cell: SelfArcCell<Self>,
+}
+impl Piix3PM {
+    pub fn create(
+        hdl: &VmmHdl,
+        pio: &PioBus,
+        acpi_mode: bool,
+    ) -> Arc<pci::DeviceInst> {
+        let mut this = Arc::new(Self {
+            pm: PmIo::attach(hdl, pio, acpi_mode),
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
 
         pci::Builder::new(pci::Ident {
             vendor_id: 0x8086,
@@ -645,10 +828,8 @@ impl Piix3PM {
                 ro.write_u8(0x1);
             }
             PmCfg::PmBase => {
-                let regs = self.regs.lock().unwrap();
-
                 // LSB hardwired to 1 to indicate PMBase in IO space
-                ro.write_u32(regs.pm_base as u32 | 0x1);
+                ro.write_u32(self.pm.pm_base() as u32 | 0x1);
             }
             _ => {
                 // XXX: report everything else as zeroed
@@ -660,17 +841,18 @@ impl Piix3PM {
         // XXX: ignore writes for now
         println!("ignored PM cfg write to {:?}", id);
     }
+}
+impl PMRegs {
     fn pmreg_read(&self, id: &PmReg, ro: &mut ReadOp) {
-        let regs = self.regs.lock().unwrap();
         match id {
             PmReg::PmSts => {
-                ro.write_u16(regs.pm_status.bits());
+                ro.write_u16(self.pm_status.bits());
             }
             PmReg::PmEn => {
-                ro.write_u16(regs.pm_ena.bits());
+                ro.write_u16(self.pm_ena.bits());
             }
             PmReg::PmCntrl => {
-                ro.write_u16(regs.pm_ctrl.bits());
+                ro.write_u16(self.pm_ctrl.bits());
             }
 
             PmReg::PmTmr
@@ -695,28 +877,44 @@ impl Piix3PM {
             }
         }
     }
-    fn pmreg_write(&self, id: &PmReg, wo: &mut WriteOp) {
-        let mut regs = self.regs.lock().unwrap();
+    fn pmreg_write(&mut self, id: &PmReg, wo: &mut WriteOp, ctx: &DispCtx) {
         match id {
             PmReg::PmSts => {
                 let val = PmSts::from_bits_truncate(wo.read_u16());
                 // status bits are W1C
-                regs.pm_status.remove(val);
+                self.pm_status.remove(val);
             }
             PmReg::PmEn => {
-                regs.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
+                self.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
             }
             PmReg::PmCntrl => {
-                regs.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
-                if regs.pm_ctrl.contains(PmCntrl::SUS_EN) {
+                self.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
+                if self.acpi_mode {
+                    self.pm_ctrl.insert(PmCntrl::SCI_EN);
+                }
+                if self.pm_ctrl.contains(PmCntrl::SUS_EN) {
                     // SUS_EN is write-only and should always read 0
-                    regs.pm_ctrl.remove(PmCntrl::SUS_EN);
-
-                    let suspend_type = (regs.pm_ctrl & PmCntrl::SUS_TYP).bits();
-                    if suspend_type == 0 {
-                        // 0b000 corresponds to soft-off
-                        // XXX: initiate power-off
-                        eprintln!("poweroff");
+                    self.pm_ctrl.remove(PmCntrl::SUS_EN);
+
+                    let suspend_type =
+                        (self.pm_ctrl & PmCntrl::SUS_TYP).bits() >> 10;
+                    if SUS_TYP_S5.contains(&suspend_type) {
+                        suspend_instance(
+                            bhyve_api::vm_suspend_how::VM_SUSPEND_POWEROFF,
+                            ctx,
+                        );
+                    } else if suspend_type == SUS_TYP_S3 {
+                        // Only the vCPUs are halted, with device state kept
+                        // for the wake-up.  The kernel sees nothing more than
+                        // a halt, so the sleep is reported separately.
+                        self.pm_status.remove(PmSts::WAK_STS);
+                        ctx.report_suspend(SuspendReason::Sleep);
+                        suspend_instance(
+                            bhyve_api::vm_suspend_how::VM_SUSPEND_HALT,
+                            ctx,
+                        );
+                    } else {
+                        println!("unsupported sleep type {}", suspend_type);
                     }
                 }
             }
@@ -740,6 +938,12 @@ impl Piix3PM {
     }
 }
 impl pci::Device for Piix3PM {
+    fn reset(&self, ctx: &DispCtx) {
+        self.pm.reset(ctx);
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        self.pm.wake(ctx);
+    }
     fn cfg_rw(&self, region: u8, mut rwo: RWOp) {
         assert_eq!(region as usize, PMCFG_OFFSET);
 
@@ -749,14 +953,6 @@ impl pci::Device for Piix3PM {
         })
     }
 }
-impl PioDev for Piix3PM {
-    fn pio_rw(&self, _port: u16, _ident: usize, mut rwo: RWOp, _ctx: &DispCtx) {
-        PM_REGS.process(&mut rwo, |id, rwo| match rwo {
-            RWOp::Read(ro) => self.pmreg_read(id, ro),
-            RWOp::Write(wo) => self.pmreg_write(id, wo),
-        });
-    }
-}
 impl SelfArc for Piix3PM {
     fn self_arc_cell(&self) -> &SelfArcCell<Self> {
         &self.sa_cell
@@ -1,15 +1,83 @@
 use std::sync::Arc;
 
 use crate::dispatch::DispCtx;
-use crate::hw::pci::{BarDefine, Endpoint, BDF};
+use crate::hw::pci::{self, BarDefine, Endpoint, INTxPinID, BDF};
+use crate::hw::Lifecycle;
+use crate::intr_pins::IntrPin;
 
 pub mod i440fx;
+mod pcie;
+pub mod q35;
 
-pub trait Chipset {
+/// A chipset, and the PCI bus it hosts.  Resetting the chipset also resets
+/// all of the devices attached to that bus.
+pub trait Chipset: Lifecycle {
     fn pci_attach(&self, bdf: BDF, dev: Arc<dyn Endpoint>);
     fn pci_finalize(&self, ctx: &DispCtx);
 }
 
+/// INTx pin used by the function at `bdf`: INTA-INTD in turn, by function
+/// number.
+fn intx_pin(bdf: &BDF) -> INTxPinID {
+    match bdf.func() % 4 {
+        0 => INTxPinID::INTA,
+        1 => INTxPinID::INTB,
+        2 => INTxPinID::INTC,
+        _ => INTxPinID::INTD,
+    }
+}
+
+/// Route the INTx pin of the function at `bdf` to the pin chosen by `route`,
+/// given the slot of the function and its INTx pin.
+fn route_lintr<P: IntrPin + 'static>(
+    bdf: &BDF,
+    route: impl FnOnce(u8, INTxPinID) -> Arc<P>,
+) -> (INTxPinID, Arc<dyn IntrPin>) {
+    let pin = intx_pin(bdf);
+    (pin, route(bdf.dev(), pin) as Arc<dyn IntrPin>)
+}
+
+/// Place the BARs of the devices on bus `busnum` within the PIO and MMIO
+/// windows (as base and length) provided.  BARs of a type for which no window
+/// is provided are left unplaced.
+fn place_bars(
+    bus: &pci::Bus,
+    busnum: u8,
+    pio: Option<(u16, u16)>,
+    mmio: Option<(u32, u32)>,
+) {
+    let mut bar_placer = BarPlacer::new();
+    if let Some((port, len)) = pio {
+        bar_placer.add_avail_pio(port, len);
+    }
+    if let Some((addr, len)) = mmio {
+        bar_placer.add_avail_mmio(addr, len);
+    }
+
+    for (slot, func, dev) in bus.iter() {
+        dev.bar_for_each(&mut |bar, def| {
+            let wanted = match def {
+                BarDefine::Pio(_) => pio.is_some(),
+                _ => mmio.is_some(),
+            };
+            if wanted {
+                bar_placer.add_bar((slot, func, bar), def);
+            }
+        });
+    }
+    let remain = bar_placer.place(|(slot, func, bar), addr| {
+        println!(
+            "placing {:?} @ {:x} for {}:{:x}:{:x}",
+            bar, addr, busnum, slot, func
+        );
+        let dev = bus.device_at(slot, func).unwrap();
+        dev.bar_place(bar, addr as u64);
+    });
+    if let Some((pio, mmio)) = remain {
+        panic!("Unfulfilled BAR allocations! pio:{} mmio:{}", pio, mmio);
+    }
+}
+
 pub(self) struct BarPlacer<T> {
     pio_bars: Vec<(T, usize)>,
     mmio_bars: Vec<(T, usize)>,
@@ -55,11 +123,9 @@ impl<T: Copy + Sized> BarPlacer<T> {
         self.mmio_avail = Some((addr as usize, len as usize));
     }
     pub fn place(self, mut cb: impl FnMut(T, usize)) -> Option<(usize, usize)> {
-        assert!(self.pio_avail.is_some());
-        assert!(self.mmio_avail.is_some());
-
-        let (pio_start, pio_len) = self.pio_avail.unwrap();
-        let (mmio_start, mmio_len) = self.mmio_avail.unwrap();
+        // A missing region leaves any BARs of that type unfulfilled
+        let (pio_start, pio_len) = self.pio_avail.unwrap_or((0, 0));
+        let (mmio_start, mmio_len) = self.mmio_avail.unwrap_or((0, 0));
 
         let pio_remain =
             Self::simple_placement(self.pio_bars, pio_start, pio_len, &mut cb);
@@ -121,3 +187,27 @@ fn align_to(addr: usize, align: usize) -> (usize, usize) {
         (fixed, fixed - addr)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn intx_pins() {
+        let pins: Vec<INTxPinID> =
+            (0..8).map(|func| intx_pin(&BDF::new(0, 3, func))).collect();
+        assert_eq!(
+            pins,
+            [
+                INTxPinID::INTA,
+                INTxPinID::INTB,
+                INTxPinID::INTC,
+                INTxPinID::INTD,
+                INTxPinID::INTA,
+                INTxPinID::INTB,
+                INTxPinID::INTC,
+                INTxPinID::INTD,
+            ]
+        );
+    }
+}
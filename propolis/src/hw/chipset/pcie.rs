@@ -0,0 +1,401 @@
+//! PCI Express root ports, each leading to a secondary bus with room for a
+//! single device (of up to eight functions).
+//!
+//! The ports present a type 1 (PCI-to-PCI bridge) configuration header and a
+//! PCI Express capability, without hotplug or error reporting.  The IO and
+//! memory windows of the port are sized to fit the BARs of the device behind
+//! it, and are placed along with the BARs of bus 0 as if they were BARs of
+//! the port itself.
+
+use std::sync::{Arc, Mutex};
+
+use super::i440fx::bus_devices;
+use super::place_bars;
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci::bits::LEN_CFG;
+use crate::hw::pci::{self, BarDefine, BarN, INTxPinID, BDF};
+use crate::hw::Lifecycle;
+use crate::intr_pins::IntrPin;
+
+use byteorder::{ByteOrder, LE};
+use lazy_static::lazy_static;
+
+/// Slot on bus 0 holding the root ports, one per function
+pub(super) const ROOT_PORT_SLOT: u8 = 0x1c;
+/// Maximum number of root ports, as offered by the ICH9
+pub const MAX_ROOT_PORTS: u8 = 6;
+
+// ICH9 root port 1, with each subsequent port 2 above the last
+const DEVICE_ID_BASE: u16 = 0x2940;
+
+const OFF_VENDOR_ID: usize = 0x00;
+const OFF_DEVICE_ID: usize = 0x02;
+const OFF_COMMAND: usize = 0x04;
+const OFF_STATUS: usize = 0x06;
+const OFF_CLASS: usize = 0x09;
+const OFF_HEADER_TYPE: usize = 0x0e;
+const OFF_PRIMARY_BUS: usize = 0x18;
+const OFF_SECONDARY_BUS: usize = 0x19;
+const OFF_SUBORDINATE_BUS: usize = 0x1a;
+const OFF_SEC_LATENCY: usize = 0x1b;
+const OFF_IO_BASE: usize = 0x1c;
+const OFF_IO_LIMIT: usize = 0x1d;
+const OFF_MEM_BASE: usize = 0x20;
+const OFF_MEM_LIMIT: usize = 0x22;
+const OFF_CAP_PTR: usize = 0x34;
+const OFF_INTR_LINE: usize = 0x3c;
+const OFF_BRIDGE_CTL: usize = 0x3e;
+
+const STATUS_CAP_LIST: u16 = 1 << 4;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const HEADER_TYPE_MULTIFUNC: u8 = 0x80;
+
+// PCI Express capability, and the registers within it used here
+const OFF_PCIE_CAP: usize = 0x40;
+const CAP_ID_PCIE: u8 = 0x10;
+const PCIE_CAP_V2_ROOT_SLOT: u16 = 0x2 | (0x4 << 4) | (1 << 8);
+const OFF_DEV_CAP: usize = OFF_PCIE_CAP + 0x04;
+const OFF_DEV_CTL: usize = OFF_PCIE_CAP + 0x08;
+const OFF_LINK_CAP: usize = OFF_PCIE_CAP + 0x0c;
+const OFF_LINK_CTL: usize = OFF_PCIE_CAP + 0x10;
+const OFF_LINK_STATUS: usize = OFF_PCIE_CAP + 0x12;
+const OFF_SLOT_CAP: usize = OFF_PCIE_CAP + 0x14;
+const OFF_SLOT_CTL: usize = OFF_PCIE_CAP + 0x18;
+const OFF_SLOT_STATUS: usize = OFF_PCIE_CAP + 0x1a;
+const OFF_ROOT_CTL: usize = OFF_PCIE_CAP + 0x1c;
+const OFF_DEV_CTL2: usize = OFF_PCIE_CAP + 0x28;
+const OFF_LINK_CAP2: usize = OFF_PCIE_CAP + 0x2c;
+const OFF_LINK_CTL2: usize = OFF_PCIE_CAP + 0x30;
+
+const DEV_CAP_RBER: u32 = 1 << 15;
+// x1 link at 2.5GT/s, able to report the data link layer as active
+const LINK_SPEED_WIDTH: u16 = 0x1 | (0x1 << 4);
+const LINK_CAP_DLLLA: u32 = 1 << 20;
+const LINK_STATUS_DLLLA: u16 = 1 << 13;
+const LINK_CAP2_SPEEDS: u32 = 1 << 1;
+const SLOT_STATUS_PRESENT: u16 = 1 << 6;
+
+// Windows are no smaller than their alignment: 4K for IO and 1M for memory
+const IO_WINDOW_MIN: usize = 0x1000;
+const MEM_WINDOW_MIN: usize = 0x10_0000;
+
+lazy_static! {
+    /// Bits of the configuration space writable by the guest
+    static ref WRITE_MASK: [u8; LEN_CFG] = {
+        let mut mask = [0u8; LEN_CFG];
+        // IO, memory, bus master, parity, SERR, and INTx disable
+        LE::write_u16(&mut mask[OFF_COMMAND..], 0x0547);
+        mask[OFF_PRIMARY_BUS] = 0xff;
+        mask[OFF_SECONDARY_BUS] = 0xff;
+        mask[OFF_SUBORDINATE_BUS] = 0xff;
+        mask[OFF_SEC_LATENCY] = 0xff;
+        // Only 16-bit IO and 32-bit (non-prefetchable) memory windows
+        mask[OFF_IO_BASE] = 0xf0;
+        mask[OFF_IO_LIMIT] = 0xf0;
+        LE::write_u16(&mut mask[OFF_MEM_BASE..], 0xfff0);
+        LE::write_u16(&mut mask[OFF_MEM_LIMIT..], 0xfff0);
+        mask[OFF_INTR_LINE] = 0xff;
+        LE::write_u16(&mut mask[OFF_BRIDGE_CTL..], 0x0fff);
+        LE::write_u16(&mut mask[OFF_DEV_CTL..], 0xffff);
+        LE::write_u16(&mut mask[OFF_LINK_CTL..], 0xffff);
+        LE::write_u16(&mut mask[OFF_SLOT_CTL..], 0xffff);
+        LE::write_u16(&mut mask[OFF_ROOT_CTL..], 0xffff);
+        LE::write_u16(&mut mask[OFF_DEV_CTL2..], 0xffff);
+        LE::write_u16(&mut mask[OFF_LINK_CTL2..], 0xffff);
+        mask
+    };
+}
+
+struct State {
+    regs: [u8; LEN_CFG],
+    /// Contents of `regs` after a reset: the defaults, along with the bus
+    /// numbers and windows as placed when the instance was built.
+    init: [u8; LEN_CFG],
+}
+
+pub(super) struct RootPort {
+    num: u8,
+    state: Mutex<State>,
+    bus: Mutex<pci::Bus>,
+}
+impl RootPort {
+    /// Create root port `num` (from 0), at function `num` of the root port
+    /// slot.  Its secondary bus is initially numbered `num + 1`.
+    pub fn create(num: u8, multifunc: bool) -> Arc<Self> {
+        assert!(num < MAX_ROOT_PORTS);
+        let mut init = [0u8; LEN_CFG];
+        LE::write_u16(&mut init[OFF_VENDOR_ID..], 0x8086);
+        LE::write_u16(
+            &mut init[OFF_DEVICE_ID..],
+            DEVICE_ID_BASE + 2 * num as u16,
+        );
+        LE::write_u16(&mut init[OFF_STATUS..], STATUS_CAP_LIST);
+        // Bridge (0x06), PCI-to-PCI (0x04)
+        init[OFF_CLASS..OFF_CLASS + 3].copy_from_slice(&[0x00, 0x04, 0x06]);
+        init[OFF_HEADER_TYPE] = match multifunc {
+            true => HEADER_TYPE_BRIDGE | HEADER_TYPE_MULTIFUNC,
+            false => HEADER_TYPE_BRIDGE,
+        };
+        init[OFF_SECONDARY_BUS] = num + 1;
+        init[OFF_SUBORDINATE_BUS] = num + 1;
+        // Both windows closed, with their base above their limit
+        init[OFF_IO_BASE] = 0xf0;
+        LE::write_u16(&mut init[OFF_MEM_BASE..], 0xfff0);
+        init[OFF_CAP_PTR] = OFF_PCIE_CAP as u8;
+
+        init[OFF_PCIE_CAP] = CAP_ID_PCIE;
+        LE::write_u16(&mut init[OFF_PCIE_CAP + 2..], PCIE_CAP_V2_ROOT_SLOT);
+        LE::write_u32(&mut init[OFF_DEV_CAP..], DEV_CAP_RBER);
+        LE::write_u32(
+            &mut init[OFF_LINK_CAP..],
+            LINK_SPEED_WIDTH as u32 | LINK_CAP_DLLLA | ((num as u32 + 1) << 24),
+        );
+        LE::write_u16(&mut init[OFF_LINK_STATUS..], LINK_SPEED_WIDTH);
+        // Physical slot number
+        LE::write_u32(&mut init[OFF_SLOT_CAP..], (num as u32 + 1) << 19);
+        LE::write_u32(&mut init[OFF_LINK_CAP2..], LINK_CAP2_SPEEDS);
+        LE::write_u16(&mut init[OFF_LINK_CTL2..], 0x1);
+
+        Arc::new(Self {
+            num,
+            state: Mutex::new(State { regs: init, init }),
+            bus: Mutex::new(pci::Bus::new()),
+        })
+    }
+
+    /// Bus number given to the secondary bus of the port when it was created,
+    /// by which devices are attached behind it.
+    pub fn initial_bus(&self) -> u8 {
+        self.num + 1
+    }
+
+    /// Attach `dev` as function `func` of the device behind the port.
+    pub fn attach_child(&self, func: u8, dev: Arc<dyn pci::Endpoint>) {
+        self.bus.lock().unwrap().attach(0, func, dev);
+
+        // With a device present, the link is up
+        let mut state = self.state.lock().unwrap();
+        let State { regs, init } = &mut *state;
+        for cfg in [regs, init].iter_mut() {
+            let status = LE::read_u16(&cfg[OFF_LINK_STATUS..]);
+            LE::write_u16(
+                &mut cfg[OFF_LINK_STATUS..],
+                status | LINK_STATUS_DLLLA,
+            );
+            LE::write_u16(&mut cfg[OFF_SLOT_STATUS..], SLOT_STATUS_PRESENT);
+        }
+    }
+
+    /// Find the device function at `bdf`, if it resides on the secondary bus
+    /// of the port, as currently programmed by the guest.
+    pub fn child_at(&self, bdf: &BDF) -> Option<Arc<dyn pci::Endpoint>> {
+        let secondary = self.state.lock().unwrap().regs[OFF_SECONDARY_BUS];
+        if secondary == 0 || bdf.bus() != secondary || bdf.dev() != 0 {
+            return None;
+        }
+        self.bus.lock().unwrap().device_at(0, bdf.func()).cloned()
+    }
+
+    /// Sizes of the IO and memory windows needed to hold the BARs of the
+    /// device behind the port (zero if none are needed).
+    fn window_sizes(bus: &pci::Bus) -> (usize, usize) {
+        let (mut pio, mut mmio) = (0, 0);
+        for (_, _, dev) in bus.iter() {
+            dev.bar_for_each(&mut |_bar, def| match def {
+                BarDefine::Pio(sz) => pio += *sz as usize,
+                BarDefine::Mmio(sz) => mmio += *sz as usize,
+                _ => {}
+            });
+        }
+        // The BARs are each a power of two in size, so they can be packed
+        // (largest first) into a window of the next power of two
+        let size = |total: usize, min: usize| match total {
+            0 => 0,
+            n => usize::max(n.next_power_of_two(), min),
+        };
+        (size(pio, IO_WINDOW_MIN), size(mmio, MEM_WINDOW_MIN))
+    }
+}
+impl pci::Endpoint for RootPort {
+    fn cfg_rw(&self, op: RWOp<'_, '_>, _ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        let off = op.offset();
+        match op {
+            RWOp::Read(ro) => {
+                let data: Vec<u8> = (off..off + ro.len())
+                    .map(|i| state.regs.get(i).copied().unwrap_or(0))
+                    .collect();
+                ro.write_bytes(&data);
+            }
+            RWOp::Write(wo) => {
+                let mut data = vec![0u8; wo.len()];
+                wo.read_bytes(&mut data);
+                for (i, val) in (off..LEN_CFG).zip(data) {
+                    let mask = WRITE_MASK[i];
+                    state.regs[i] = (state.regs[i] & !mask) | (val & mask);
+                }
+            }
+        }
+    }
+    fn attach(&self, _get_lintr: &dyn Fn() -> (INTxPinID, Arc<dyn IntrPin>)) {
+        // The port raises no interrupts of its own
+    }
+    fn bar_for_each(&self, cb: &mut dyn FnMut(BarN, &BarDefine)) {
+        let (pio, mmio) = Self::window_sizes(&self.bus.lock().unwrap());
+        if pio != 0 {
+            cb(BarN::BAR0, &BarDefine::Pio(pio as u16));
+        }
+        if mmio != 0 {
+            cb(BarN::BAR1, &BarDefine::Mmio(mmio as u32));
+        }
+    }
+    fn bar_place(&self, bar: BarN, addr: u64) {
+        let bus = self.bus.lock().unwrap();
+        let (pio, mmio) = Self::window_sizes(&bus);
+        let mut state = self.state.lock().unwrap();
+        let secondary = state.regs[OFF_SECONDARY_BUS];
+        let State { regs, init } = &mut *state;
+        match bar {
+            BarN::BAR0 => {
+                let last = addr as usize + pio - 1;
+                for cfg in [&mut *regs, &mut *init].iter_mut() {
+                    cfg[OFF_IO_BASE] = (addr >> 8) as u8 & 0xf0;
+                    cfg[OFF_IO_LIMIT] = (last >> 8) as u8 & 0xf0;
+                }
+                place_bars(
+                    &bus,
+                    secondary,
+                    Some((addr as u16, pio as u16)),
+                    None,
+                );
+            }
+            BarN::BAR1 => {
+                let last = addr as usize + mmio - 1;
+                for cfg in [&mut *regs, &mut *init].iter_mut() {
+                    LE::write_u16(
+                        &mut cfg[OFF_MEM_BASE..],
+                        (addr >> 16) as u16 & 0xfff0,
+                    );
+                    LE::write_u16(
+                        &mut cfg[OFF_MEM_LIMIT..],
+                        (last >> 16) as u16 & 0xfff0,
+                    );
+                }
+                place_bars(
+                    &bus,
+                    secondary,
+                    None,
+                    Some((addr as u32, mmio as u32)),
+                );
+            }
+            _ => panic!("unexpected window {:?}", bar),
+        }
+    }
+}
+impl Lifecycle for RootPort {
+    fn reset(&self, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        state.regs = state.init;
+        drop(state);
+        for dev in bus_devices(&self.bus) {
+            dev.reset(ctx);
+        }
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.bus) {
+            dev.wake(ctx);
+        }
+    }
+    fn pause(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.bus) {
+            dev.pause(ctx);
+        }
+    }
+    fn resume(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.bus) {
+            dev.resume(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::pci::Endpoint;
+
+    struct BarDev {
+        bars: Vec<(BarN, BarDefine)>,
+        placed: Mutex<Vec<(BarN, u64)>>,
+    }
+    impl Lifecycle for BarDev {}
+    impl pci::Endpoint for BarDev {
+        fn cfg_rw(&self, _op: RWOp<'_, '_>, _ctx: &DispCtx) {}
+        fn attach(&self, _: &dyn Fn() -> (INTxPinID, Arc<dyn IntrPin>)) {}
+        fn bar_for_each(&self, cb: &mut dyn FnMut(BarN, &BarDefine)) {
+            for (bar, def) in self.bars.iter() {
+                cb(*bar, def);
+            }
+        }
+        fn bar_place(&self, bar: BarN, addr: u64) {
+            self.placed.lock().unwrap().push((bar, addr));
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let port = RootPort::create(2, true);
+        assert_eq!(port.initial_bus(), 3);
+        let mut windows = Vec::new();
+        port.bar_for_each(&mut |bar, def| windows.push((bar, *def)));
+        assert!(windows.is_empty());
+
+        let dev = Arc::new(BarDev {
+            bars: vec![
+                (BarN::BAR0, BarDefine::Pio(0x20)),
+                (BarN::BAR1, BarDefine::Mmio(0x1000)),
+                (BarN::BAR2, BarDefine::Mmio(0x4000)),
+            ],
+            placed: Mutex::new(Vec::new()),
+        });
+        port.attach_child(1, Arc::clone(&dev) as Arc<dyn pci::Endpoint>);
+        port.bar_for_each(&mut |bar, def| windows.push((bar, *def)));
+        assert_eq!(
+            windows,
+            [
+                (BarN::BAR0, BarDefine::Pio(0x1000)),
+                (BarN::BAR1, BarDefine::Mmio(0x10_0000)),
+            ]
+        );
+
+        port.bar_place(BarN::BAR0, 0xd000);
+        port.bar_place(BarN::BAR1, 0xc010_0000);
+        let placed = dev.placed.lock().unwrap().clone();
+        assert_eq!(
+            placed,
+            [
+                (BarN::BAR0, 0xd000),
+                (BarN::BAR2, 0xc010_0000),
+                (BarN::BAR1, 0xc010_4000),
+            ]
+        );
+        let state = port.state.lock().unwrap();
+        assert_eq!(&state.regs[OFF_IO_BASE..=OFF_IO_LIMIT], [0xd0, 0xd0]);
+        assert_eq!(LE::read_u16(&state.regs[OFF_MEM_BASE..]), 0xc010);
+        assert_eq!(LE::read_u16(&state.regs[OFF_MEM_LIMIT..]), 0xc010);
+        assert_ne!(
+            LE::read_u16(&state.regs[OFF_LINK_STATUS..]) & LINK_STATUS_DLLLA,
+            0
+        );
+        assert_eq!(state.init, state.regs);
+        drop(state);
+
+        // The device is found on the secondary bus, as programmed
+        assert!(port.child_at(&BDF::new(3, 0, 1)).is_some());
+        assert!(port.child_at(&BDF::new(3, 0, 0)).is_none());
+        assert!(port.child_at(&BDF::new(3, 1, 1)).is_none());
+        port.state.lock().unwrap().regs[OFF_SECONDARY_BUS] = 5;
+        assert!(port.child_at(&BDF::new(3, 0, 1)).is_none());
+        assert!(port.child_at(&BDF::new(5, 0, 1)).is_some());
+    }
+}
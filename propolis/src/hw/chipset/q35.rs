@@ -0,0 +1,376 @@
+//! Q35 (MCH with ICH9) chipset, offering PCIe enhanced configuration access
+//! through a fixed ECAM region in addition to the legacy IO port mechanism.
+//!
+//! Devices reside on bus 0, or behind one of the PCIe root ports (in slot
+//! 0x1c), each of which leads to a secondary bus with a single slot.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use super::i440fx::{
+    bus_devices, valid_pir_irq, LNKPin, LpcDevs, PmIo, RstCtrl,
+    PIR_MASK_DISABLE, PIR_MASK_IRQ,
+};
+use super::pcie::{RootPort, ROOT_PORT_SLOT};
+use super::{place_bars, Chipset};
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci::{self, INTxPinID, PioCfgDecoder, BDF};
+use crate::hw::uart::LpcUart;
+use crate::hw::Lifecycle;
+use crate::intr_pins::{IntrPin, LegacyPIC};
+use crate::mmio::MmioDev;
+use crate::pio::{PioBus, PioDev};
+use crate::util::self_arc::*;
+use crate::vmm::VmmHdl;
+
+/// Guest-physical address of the ECAM region
+pub const ECAM_BASE: usize = 0xe000_0000;
+
+pub use super::pcie::MAX_ROOT_PORTS;
+
+pub(crate) const PIRQ_COUNT: usize = 8;
+
+// Slots at or above this are home to devices integrated in the ICH9
+pub(crate) const ICH9_FIRST_SLOT: u8 = 25;
+
+pub struct Q35 {
+    pic: Arc<LegacyPIC>,
+    pci_bus: Mutex<pci::Bus>,
+    pci_cfg: PioCfgDecoder,
+    root_ports: Vec<Arc<RootPort>>,
+
+    pirq_pins: [Arc<LNKPin>; PIRQ_COUNT],
+    rst_ctrl: RstCtrl,
+
+    sa_cell: SelfArcCell<Self>,
+}
+impl Q35 {
+    /// Create the chipset, with `root_ports` PCIe root ports (up to
+    /// [`MAX_ROOT_PORTS`]).  The device behind root port `n` is attached at
+    /// bus `n + 1`.  With `acpi_mode` set, the PM block starts out in ACPI
+    /// mode, as expected by the tables generated by Propolis.
+    pub fn create(
+        hdl: Arc<VmmHdl>,
+        pio: &PioBus,
+        root_ports: u8,
+        acpi_mode: bool,
+        cfg_lpc: impl FnOnce(&Ich9Lpc),
+    ) -> Arc<Self> {
+        assert!(root_ports <= MAX_ROOT_PORTS);
+        let pic = LegacyPIC::new(Arc::clone(&hdl));
+
+        let mut this = Arc::new(Self {
+            pic,
+            pci_bus: Mutex::new(pci::Bus::new()),
+            pci_cfg: PioCfgDecoder::new(),
+            root_ports: (0..root_ports)
+                .map(|n| RootPort::create(n, root_ports > 1))
+                .collect(),
+
+            pirq_pins: [0; PIRQ_COUNT].map(|_| Arc::new(LNKPin::new())),
+            rst_ctrl: RstCtrl::new(),
+
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
+
+        let hbdev = Q35HostBridge::create();
+        let lpcdev = Ich9Lpc::create(
+            Arc::downgrade(&this),
+            &this.pic,
+            &hdl,
+            pio,
+            acpi_mode,
+        );
+
+        lpcdev.with_inner(cfg_lpc);
+
+        this.pci_attach(BDF::new(0, 0, 0), hbdev);
+        this.pci_attach(BDF::new(0, 0x1f, 0), lpcdev);
+        for (n, port) in this.root_ports.iter().enumerate() {
+            let port = Arc::clone(port) as Arc<dyn pci::Endpoint>;
+            this.pci_attach(BDF::new(0, ROOT_PORT_SLOT, n as u8), port);
+        }
+
+        this
+    }
+
+    fn set_pirq_route(&self, idx: usize, irq: Option<u8>) {
+        assert!(idx < PIRQ_COUNT);
+        self.pirq_pins[idx].reassign(irq.and_then(|i| self.pic.pin_handle(i)));
+    }
+
+    fn route_lintr(&self, bdf: &BDF) -> (INTxPinID, Arc<dyn IntrPin>) {
+        super::route_lintr(bdf, |slot, pin| self.pirq_pin(slot, pin))
+    }
+    /// PIRQ pin to which INTx `pin` of devices in `slot` (on bus 0) is routed
+    fn pirq_pin(&self, slot: u8, pin: INTxPinID) -> Arc<LNKPin> {
+        // Integrated devices are routed to PIRQA-D, and all others to
+        // PIRQE-H, rotating through each set by slot.
+        let base = if slot >= ICH9_FIRST_SLOT { 0 } else { 4 };
+        let pin_route = base + (slot + pin as u8 - 1) % 4;
+        Arc::clone(&self.pirq_pins[pin_route as usize])
+    }
+    fn place_bars(&self) {
+        let bus = self.pci_bus.lock().unwrap();
+        place_bars(
+            &bus,
+            0,
+            Some((0xc000, 0x4000)),
+            Some((0xc000_0000, 0x2000_0000)),
+        );
+    }
+    fn cfg_rw(&self, bdf: &BDF, rwo: RWOp, ctx: &DispCtx) -> Option<()> {
+        let dev = if bdf.bus() == 0 {
+            let bus = self.pci_bus.lock().unwrap();
+            Arc::clone(bus.device_at(bdf.dev(), bdf.func())?)
+        } else {
+            self.root_ports.iter().find_map(|port| port.child_at(bdf))?
+        };
+        dev.cfg_rw(rwo, ctx);
+        Some(())
+    }
+}
+impl Chipset for Q35 {
+    fn pci_attach(&self, bdf: BDF, dev: Arc<dyn pci::Endpoint>) {
+        if bdf.bus() == 0 {
+            dev.attach(&|| self.route_lintr(&bdf));
+            let mut bus = self.pci_bus.lock().unwrap();
+            bus.attach(bdf.dev(), bdf.func(), dev);
+            return;
+        }
+
+        // Behind a root port, the device (in slot 0) keeps its INTx pin
+        // through the bridge swizzle, to be routed as if it were in the slot
+        // of the root port.
+        assert!(bdf.dev() == 0);
+        let port = self
+            .root_ports
+            .iter()
+            .find(|port| port.initial_bus() == bdf.bus())
+            .expect("no root port for bus");
+        dev.attach(&|| {
+            super::route_lintr(&bdf, |_, pin| {
+                self.pirq_pin(ROOT_PORT_SLOT, pin)
+            })
+        });
+        port.attach_child(bdf.func(), dev);
+    }
+    fn pci_finalize(&self, ctx: &DispCtx) {
+        let cfg_pio = self.self_weak() as Weak<dyn PioDev>;
+        ctx.mctx.with_pio(|pio| {
+            let cfg_pio2 = Weak::clone(&cfg_pio);
+            pio.register(pci::PORT_PCI_CONFIG_ADDR, 4, cfg_pio, 0).unwrap();
+            pio.register(pci::PORT_PCI_CONFIG_DATA, 4, cfg_pio2, 0).unwrap();
+        });
+        let cfg_mmio = self.self_weak() as Weak<dyn MmioDev>;
+        ctx.mctx.with_mmio(|mmio| {
+            mmio.register(ECAM_BASE, pci::LEN_ECAM, cfg_mmio, 0).unwrap();
+        });
+        self.place_bars();
+    }
+}
+impl Lifecycle for Q35 {
+    fn reset(&self, ctx: &DispCtx) {
+        self.pci_cfg.reset();
+        self.rst_ctrl.reset();
+        for dev in bus_devices(&self.pci_bus) {
+            dev.reset(ctx);
+        }
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.wake(ctx);
+        }
+    }
+    fn pause(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.pause(ctx);
+        }
+    }
+    fn resume(&self, ctx: &DispCtx) {
+        for dev in bus_devices(&self.pci_bus) {
+            dev.resume(ctx);
+        }
+    }
+}
+impl PioDev for Q35 {
+    fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        match port {
+            pci::PORT_PCI_CONFIG_ADDR if RstCtrl::is_target(&rwo) => {
+                self.rst_ctrl.service(rwo, ctx);
+            }
+            pci::PORT_PCI_CONFIG_ADDR => {
+                self.pci_cfg.service_addr(rwo);
+            }
+            pci::PORT_PCI_CONFIG_DATA => {
+                self.pci_cfg
+                    .service_data(rwo, |bdf, rwo| self.cfg_rw(bdf, rwo, ctx));
+            }
+            _ => {
+                panic!();
+            }
+        }
+    }
+}
+impl MmioDev for Q35 {
+    fn mmio_rw(&self, _addr: usize, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        pci::ecam_service(rwo, |bdf, rwo| self.cfg_rw(bdf, rwo, ctx));
+    }
+}
+impl SelfArc for Q35 {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
+}
+
+const PCIEXBAR_OFFSET: usize = 0x60;
+const PCIEXBAR_LEN: usize = 8;
+const PCIEXBAR_EN: u64 = 1;
+
+struct Q35HostBridge {}
+impl Q35HostBridge {
+    pub fn create() -> Arc<pci::DeviceInst> {
+        pci::Builder::new(pci::Ident {
+            vendor_id: 0x8086,
+            device_id: 0x29c0,
+            class: 0x06,
+            ..Default::default()
+        })
+        .add_custom_cfg(PCIEXBAR_OFFSET as u8, PCIEXBAR_LEN as u8)
+        .finish(Arc::new(Self {}))
+    }
+}
+impl pci::Device for Q35HostBridge {
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        assert_eq!(region as usize, PCIEXBAR_OFFSET);
+
+        match rwo {
+            RWOp::Read(ro) => {
+                // Enabled, with a length (encoded as 0) of 256 buses
+                let val = (ECAM_BASE as u64 | PCIEXBAR_EN).to_le_bytes();
+                let off = ro.offset();
+                ro.write_bytes(&val[off..(off + ro.len())]);
+            }
+            RWOp::Write(_) => {
+                // XXX: the ECAM region is fixed for now
+            }
+        }
+    }
+}
+
+const PMBASE_OFFSET: usize = 0x40;
+const PMBASE_LEN: usize = 4;
+const ACPI_CNTL_OFFSET: usize = 0x44;
+const ACPI_CNTL_LEN: usize = 1;
+// ACPI IO decode enabled, with the SCI on IRQ 9
+const ACPI_CNTL_VAL: u8 = 0x80;
+pub(crate) const PIRQA_OFFSET: usize = 0x60;
+pub(crate) const PIRQE_OFFSET: usize = 0x68;
+pub(crate) const PIRQ_LEN: usize = 4;
+
+pub struct Ich9Lpc {
+    reg_pirq: Mutex<[u8; PIRQ_COUNT]>,
+    devs: Arc<LpcDevs>,
+    pm: PmIo,
+    chipset: Weak<Q35>,
+}
+impl Ich9Lpc {
+    pub fn create(
+        chipset: Weak<Q35>,
+        pic: &LegacyPIC,
+        hdl: &VmmHdl,
+        pio_bus: &PioBus,
+        acpi_mode: bool,
+    ) -> Arc<pci::DeviceInst> {
+        let this = Arc::new(Self {
+            reg_pirq: Mutex::new([PIR_MASK_DISABLE; PIRQ_COUNT]),
+            devs: LpcDevs::create(pic, pio_bus),
+            pm: PmIo::attach(hdl, pio_bus, acpi_mode),
+            chipset,
+        });
+
+        pci::Builder::new(pci::Ident {
+            vendor_id: 0x8086,
+            device_id: 0x2918,
+            class: 0x06,
+            subclass: 0x01,
+            ..Default::default()
+        })
+        .add_custom_cfg(PMBASE_OFFSET as u8, PMBASE_LEN as u8)
+        .add_custom_cfg(ACPI_CNTL_OFFSET as u8, ACPI_CNTL_LEN as u8)
+        .add_custom_cfg(PIRQA_OFFSET as u8, PIRQ_LEN as u8)
+        .add_custom_cfg(PIRQE_OFFSET as u8, PIRQ_LEN as u8)
+        .finish(this)
+    }
+
+    pub fn config_uarts<F>(&self, f: F)
+    where
+        F: FnOnce(&Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>, &Arc<LpcUart>),
+    {
+        self.devs.config_uarts(f);
+    }
+
+    fn write_pirq(&self, idx: usize, val: u8) {
+        assert!(idx < PIRQ_COUNT);
+
+        let mut regs = self.reg_pirq.lock().unwrap();
+        if regs[idx] != val {
+            let disabled = (val & PIR_MASK_DISABLE) != 0;
+            let irq = val & PIR_MASK_IRQ;
+
+            let chipset = Weak::upgrade(&self.chipset).unwrap();
+            if !disabled && valid_pir_irq(irq) {
+                chipset.set_pirq_route(idx, Some(irq));
+            } else {
+                chipset.set_pirq_route(idx, None);
+            }
+            regs[idx] = val;
+        }
+    }
+}
+impl pci::Device for Ich9Lpc {
+    fn reset(&self, ctx: &DispCtx) {
+        for idx in 0..PIRQ_COUNT {
+            self.write_pirq(idx, PIR_MASK_DISABLE);
+        }
+        self.devs.reset(ctx);
+        self.pm.reset(ctx);
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        self.pm.wake(ctx);
+    }
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        let region = region as usize;
+        match (region, rwo) {
+            (PMBASE_OFFSET, RWOp::Read(ro)) => {
+                // LSB hardwired to 1 to indicate PMBase in IO space
+                let val = (self.pm.pm_base() as u32 | 0x1).to_le_bytes();
+                let off = ro.offset();
+                ro.write_bytes(&val[off..(off + ro.len())]);
+            }
+            (ACPI_CNTL_OFFSET, RWOp::Read(ro)) => {
+                ro.write_u8(ACPI_CNTL_VAL);
+            }
+            (PMBASE_OFFSET, RWOp::Write(_))
+            | (ACPI_CNTL_OFFSET, RWOp::Write(_)) => {
+                // XXX: the PM register block is fixed for now
+            }
+            (_, rwo) => {
+                let base = if region == PIRQA_OFFSET { 0 } else { PIRQ_LEN };
+                let off = base + rwo.offset();
+                match rwo {
+                    RWOp::Read(ro) => {
+                        let reg = self.reg_pirq.lock().unwrap();
+                        ro.write_bytes(&reg[off..(off + ro.len())]);
+                    }
+                    RWOp::Write(wo) => {
+                        for i in 0..wo.len() {
+                            self.write_pirq(off + i, wo.read_u8());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::hw::pci;
+use crate::util::regmap::RegMap;
+
+use lazy_static::lazy_static;
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEV_I6300ESB_WDT: u16 = 0x25ab;
+const SUBCLASS_OTHER: u8 = 0x80;
+
+const BAR_LEN: usize = 0x10;
+
+const ESB_CONFIG_OFFSET: u8 = 0x60;
+const ESB_CONFIG_LEN: usize = 2;
+const ESB_LOCK_OFFSET: u8 = 0x68;
+const ESB_LOCK_LEN: usize = 1;
+
+// Bits of the config register
+const ESB_WDT_INTTYPE: u16 = 0x03;
+const ESB_WDT_FREQ: u16 = 1 << 2;
+const ESB_WDT_REBOOT: u16 = 1 << 5;
+const INT_TYPE_IRQ: u16 = 0;
+
+// Bits of the lock register
+const ESB_WDT_LOCK: u8 = 1 << 0;
+const ESB_WDT_ENABLE: u8 = 1 << 1;
+
+// Bits of the reload register
+const ESB_WDT_RELOAD: u16 = 1 << 8;
+const ESB_WDT_TIMEOUT: u16 = 1 << 9;
+
+/// Sequence written to the reload register to unlock the next register write
+const ESB_UNLOCK1: u16 = 0x80;
+const ESB_UNLOCK2: u16 = 0x86;
+
+/// Preload values are 20 bits wide
+const PRELOAD_MASK: u32 = 0xf_ffff;
+/// Duration of a PCI clock tick, which drives the timer prescaler
+const TICK_NS: u64 = 30;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum BarReg {
+    Timer1,
+    Timer2,
+    IntStatus,
+    Reload,
+    Reserved,
+}
+lazy_static! {
+    static ref BAR_MAP: RegMap<BarReg> = {
+        let layout = [
+            (BarReg::Timer1, 4),
+            (BarReg::Timer2, 4),
+            (BarReg::IntStatus, 4),
+            (BarReg::Reload, 2),
+            (BarReg::Reserved, 2),
+        ];
+        RegMap::create_packed(BAR_LEN, &layout, Some(BarReg::Reserved))
+    };
+}
+
+/// Action taken when the watchdog expires
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WdtAction {
+    /// Reset the instance
+    Reset,
+    /// Power off the instance
+    PowerOff,
+    /// Take no action, beyond notifying any registered handler
+    None,
+}
+
+/// Outcome of the running timer stage expiring
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Expiry {
+    /// The first stage ran down, and the second has begun
+    Stage1,
+    /// The second stage ran down, calling for the instance to be suspended (if
+    /// at all) in the given manner
+    Fired(Option<bhyve_api::vm_suspend_how>),
+}
+
+/// Length of a timer stage with the given preload value
+fn stage_period(preload: u32, config: u16) -> Duration {
+    // The prescaler divides the clock by 2^15 (~1kHz) or 2^5 (~1MHz)
+    let shift = if config & ESB_WDT_FREQ != 0 { 5 } else { 15 };
+    Duration::from_nanos(((preload as u64) << shift) * TICK_NS)
+}
+
+struct State {
+    config: u16,
+    lock: u8,
+    /// Progress through the unlock sequence
+    unlock: u8,
+    preload: [u32; 2],
+    /// Current stage (1 or 2) of the running timer
+    stage: u8,
+    deadline: Option<Instant>,
+    /// Time left in the running stage, held while the instance is paused
+    frozen: Option<Duration>,
+    /// Set when the second stage expires, until cleared by the guest
+    timed_out: bool,
+    int_status: bool,
+    lintr_pin: Option<pci::INTxPin>,
+    expirations: u64,
+}
+impl State {
+    fn new() -> Self {
+        Self {
+            config: 0,
+            lock: 0,
+            unlock: 0,
+            preload: [PRELOAD_MASK; 2],
+            stage: 1,
+            deadline: None,
+            frozen: None,
+            timed_out: false,
+            int_status: false,
+            lintr_pin: None,
+            expirations: 0,
+        }
+    }
+    fn enabled(&self) -> bool {
+        self.lock & ESB_WDT_ENABLE != 0
+    }
+    fn start_stage(&mut self, stage: u8, now: Instant) {
+        self.stage = stage;
+        let preload = self.preload[stage as usize - 1];
+        self.deadline = Some(now + stage_period(preload, self.config));
+    }
+}
+
+type ExpireHandler = Box<dyn Fn(&DispCtx) + Send>;
+
+/// Intel 6300ESB watchdog timer.
+///
+/// Once enabled by the guest, the timer must be periodically reloaded.  Should
+/// it run down through both of its stages, the configured action is taken.
+/// Expiry is tracked by a worker spawned on the dispatcher.
+pub struct I6300Esb {
+    state: Mutex<State>,
+    cv: Condvar,
+    action: WdtAction,
+    handler: Mutex<Option<ExpireHandler>>,
+}
+impl I6300Esb {
+    pub fn create(
+        action: WdtAction,
+        disp: &Dispatcher,
+    ) -> Arc<pci::DeviceInst> {
+        let this = Arc::new(Self::new(action));
+
+        let qthis = Arc::clone(&this);
+        disp.on_quiesce(move || {
+            let _guard = qthis.state.lock().unwrap();
+            qthis.cv.notify_all();
+        });
+        disp.spawn("i6300esb".to_string(), Arc::clone(&this), Self::run)
+            .unwrap();
+
+        pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: DEV_I6300ESB_WDT,
+            class: pci::bits::CLASS_SYSTEM,
+            subclass: SUBCLASS_OTHER,
+            ..Default::default()
+        })
+        .add_lintr()
+        .add_bar_mmio(pci::BarN::BAR0, BAR_LEN as u32)
+        .add_custom_cfg(ESB_CONFIG_OFFSET, ESB_CONFIG_LEN as u8)
+        .add_custom_cfg(ESB_LOCK_OFFSET, ESB_LOCK_LEN as u8)
+        .finish(this)
+    }
+
+    fn new(action: WdtAction) -> Self {
+        Self {
+            state: Mutex::new(State::new()),
+            cv: Condvar::new(),
+            action,
+            handler: Mutex::new(None),
+        }
+    }
+
+    /// Set a callback to be invoked (prior to the configured action) each time
+    /// the watchdog expires.
+    pub fn on_expire<F>(&self, handler: F)
+    where
+        F: Fn(&DispCtx) + Send + 'static,
+    {
+        *self.handler.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Number of times the watchdog has expired
+    pub fn expirations(&self) -> u64 {
+        self.state.lock().unwrap().expirations
+    }
+
+    fn run(ctx: DispCtx, this: Arc<Self>) {
+        let mut state = this.state.lock().unwrap();
+        loop {
+            if ctx.should_exit() {
+                return;
+            }
+            let now = Instant::now();
+            state = match state.deadline {
+                None => this.cv.wait(state).unwrap(),
+                Some(deadline) if deadline > now => {
+                    this.cv.wait_timeout(state, deadline - now).unwrap().0
+                }
+                Some(_) => this.expire(state, now, &ctx),
+            };
+        }
+    }
+
+    fn expire<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        now: Instant,
+        ctx: &DispCtx,
+    ) -> MutexGuard<'a, State> {
+        let how = match self.advance(&mut state, now) {
+            Expiry::Stage1 => return state,
+            Expiry::Fired(how) => how,
+        };
+        drop(state);
+
+        if let Some(handler) = self.handler.lock().unwrap().as_ref() {
+            handler(ctx);
+        }
+        if let Some(how) = how {
+            if let Err(e) = ctx.mctx.with_hdl(|hdl| hdl.suspend(how)) {
+                eprintln!("watchdog failed to suspend instance: {}", e);
+            }
+        }
+        self.state.lock().unwrap()
+    }
+
+    /// Move the timer past the expiry of its running stage
+    fn advance(&self, state: &mut State, now: Instant) -> Expiry {
+        if state.stage == 1 {
+            if state.config & ESB_WDT_INTTYPE == INT_TYPE_IRQ {
+                state.int_status = true;
+                if let Some(pin) = state.lintr_pin.as_ref() {
+                    pin.assert();
+                }
+            }
+            state.start_stage(2, now);
+            return Expiry::Stage1;
+        }
+
+        state.expirations += 1;
+        if state.config & ESB_WDT_REBOOT != 0 {
+            // With reboots disabled, the timer runs through its stages again
+            state.start_stage(1, now);
+            return Expiry::Fired(None);
+        }
+        state.deadline = None;
+        state.timed_out = true;
+        Expiry::Fired(match self.action {
+            WdtAction::Reset => {
+                Some(bhyve_api::vm_suspend_how::VM_SUSPEND_RESET)
+            }
+            WdtAction::PowerOff => {
+                Some(bhyve_api::vm_suspend_how::VM_SUSPEND_POWEROFF)
+            }
+            WdtAction::None => None,
+        })
+    }
+
+    fn reload_write(&self, state: &mut State, val: u16) {
+        // Writes to the timer registers must be preceded by the unlock sequence
+        match (state.unlock, val) {
+            (0, ESB_UNLOCK1) => state.unlock = 1,
+            (1, ESB_UNLOCK2) => state.unlock = 2,
+            (2, _) => {
+                if val & ESB_WDT_RELOAD != 0 && state.enabled() {
+                    state.start_stage(1, Instant::now());
+                    self.cv.notify_all();
+                }
+                if val & ESB_WDT_TIMEOUT != 0 {
+                    state.timed_out = false;
+                }
+                state.unlock = 0;
+            }
+            _ => state.unlock = 0,
+        }
+    }
+
+    fn wdt_bar_rw(&self, mut rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        BAR_MAP.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                BarReg::Timer1 => ro.write_u32(state.preload[0]),
+                BarReg::Timer2 => ro.write_u32(state.preload[1]),
+                BarReg::IntStatus => ro.write_u32(state.int_status as u32),
+                BarReg::Reload => ro.write_u16(if state.timed_out {
+                    ESB_WDT_TIMEOUT
+                } else {
+                    0
+                }),
+                BarReg::Reserved => ro.fill(0),
+            },
+            RWOp::Write(wo) => match id {
+                BarReg::Timer1 | BarReg::Timer2 => {
+                    let val = wo.read_u32() & PRELOAD_MASK;
+                    if state.unlock == 2 {
+                        let idx = if *id == BarReg::Timer1 { 0 } else { 1 };
+                        state.preload[idx] = val;
+                    }
+                    state.unlock = 0;
+                }
+                BarReg::IntStatus => {
+                    // Interrupt status is write-1-to-clear
+                    if wo.read_u32() & 1 != 0 && state.int_status {
+                        state.int_status = false;
+                        if let Some(pin) = state.lintr_pin.as_ref() {
+                            pin.deassert();
+                        }
+                    }
+                }
+                BarReg::Reload => self.reload_write(&mut state, wo.read_u16()),
+                BarReg::Reserved => {}
+            },
+        });
+    }
+
+    fn freeze(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(deadline) = state.deadline.take() {
+            state.frozen =
+                Some(deadline.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    fn thaw(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(remain) = state.frozen.take() {
+            state.deadline = Some(Instant::now() + remain);
+            self.cv.notify_all();
+        }
+    }
+}
+impl pci::Device for I6300Esb {
+    fn bar_rw(&self, bar: pci::BarN, rwo: RWOp, _ctx: &DispCtx) {
+        assert_eq!(bar, pci::BarN::BAR0);
+        self.wdt_bar_rw(rwo);
+    }
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        let mut state = self.state.lock().unwrap();
+        match (region, rwo) {
+            (ESB_CONFIG_OFFSET, RWOp::Read(ro)) => {
+                let bytes = state.config.to_le_bytes();
+                ro.write_bytes(&bytes[ro.offset()..(ro.offset() + ro.len())]);
+            }
+            (ESB_CONFIG_OFFSET, RWOp::Write(wo)) => {
+                let mut bytes = state.config.to_le_bytes();
+                let off = wo.offset();
+                wo.read_bytes(&mut bytes[off..(off + wo.len())]);
+                state.config = u16::from_le_bytes(bytes);
+            }
+            (ESB_LOCK_OFFSET, RWOp::Read(ro)) => ro.write_u8(state.lock),
+            (ESB_LOCK_OFFSET, RWOp::Write(wo)) => {
+                // Once locked, the register is fixed until reset
+                if state.lock & ESB_WDT_LOCK != 0 {
+                    return;
+                }
+                state.lock = wo.read_u8();
+                if state.enabled() {
+                    state.start_stage(1, Instant::now());
+                } else {
+                    state.deadline = None;
+                }
+                self.cv.notify_all();
+            }
+            _ => panic!("unexpected cfg region {:x}", region),
+        }
+    }
+    fn reset(&self, _ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        // The timeout indication survives a reset, so the guest can tell that
+        // it was rebooted by the watchdog.
+        let fresh = State {
+            timed_out: state.timed_out,
+            lintr_pin: state.lintr_pin.take(),
+            expirations: state.expirations,
+            ..State::new()
+        };
+        *state = fresh;
+        self.cv.notify_all();
+    }
+    fn pause(&self, _ctx: &DispCtx) {
+        // Time spent paused does not count against the guest
+        self.freeze();
+    }
+    fn resume(&self, _ctx: &DispCtx) {
+        self.thaw();
+    }
+    fn attach(
+        &self,
+        lintr_pin: Option<pci::INTxPin>,
+        msix_hdl: Option<pci::MsixHdl>,
+    ) {
+        assert!(lintr_pin.is_some());
+        assert!(msix_hdl.is_none());
+        self.state.lock().unwrap().lintr_pin = lintr_pin;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::pci::{Device, Endpoint};
+    use crate::intr_pins::{IntrPin, LegacyPin};
+
+    #[test]
+    fn stage_timing() {
+        // Linux programs (heartbeat << 9) into each stage, at the 1kHz scale
+        let heartbeat = stage_period(30 << 9, 0) * 2;
+        assert!(heartbeat > Duration::from_secs(29));
+        assert!(heartbeat < Duration::from_secs(31));
+
+        // The 1MHz scale is 1024 times finer
+        assert_eq!(stage_period(1024, ESB_WDT_FREQ), stage_period(1, 0));
+        assert_eq!(stage_period(0, 0), Duration::from_nanos(0));
+    }
+
+    fn bar_write(dev: &I6300Esb, off: usize, buf: &[u8]) {
+        dev.wdt_bar_rw(RWOp::Write(&mut WriteOp::new_buf(off, buf)));
+    }
+    fn bar_read(dev: &I6300Esb, off: usize, buf: &mut [u8]) {
+        dev.wdt_bar_rw(RWOp::Read(&mut ReadOp::new_buf(off, buf)));
+    }
+    fn reload(dev: &I6300Esb, val: u16) {
+        bar_write(dev, 0xc, &val.to_le_bytes());
+    }
+    fn cfg_write(dev: &I6300Esb, region: u8, buf: &[u8]) {
+        dev.cfg_rw(region, RWOp::Write(&mut WriteOp::new_buf(0, buf)));
+    }
+    fn enable(dev: &I6300Esb) {
+        cfg_write(dev, ESB_LOCK_OFFSET, &[ESB_WDT_ENABLE]);
+    }
+    fn unlock(dev: &I6300Esb) {
+        reload(dev, ESB_UNLOCK1);
+        reload(dev, ESB_UNLOCK2);
+    }
+
+    #[test]
+    fn unlock_reload() {
+        let dev = I6300Esb::new(WdtAction::Reset);
+        enable(&dev);
+
+        // Preload writes without the unlock sequence are dropped
+        bar_write(&dev, 0x0, &0x100u32.to_le_bytes());
+        assert_eq!(dev.state.lock().unwrap().preload[0], PRELOAD_MASK);
+        // ... as are those after an incorrect sequence
+        reload(&dev, ESB_UNLOCK1);
+        reload(&dev, ESB_UNLOCK1);
+        reload(&dev, ESB_UNLOCK2);
+        bar_write(&dev, 0x0, &0x100u32.to_le_bytes());
+        assert_eq!(dev.state.lock().unwrap().preload[0], PRELOAD_MASK);
+
+        unlock(&dev);
+        bar_write(&dev, 0x0, &0x100u32.to_le_bytes());
+        unlock(&dev);
+        bar_write(&dev, 0x4, &0x200u32.to_le_bytes());
+        // Each unlock admits only a single write
+        bar_write(&dev, 0x4, &0x300u32.to_le_bytes());
+        assert_eq!(dev.state.lock().unwrap().preload, [0x100, 0x200]);
+
+        // Reload restarts the first stage, but only once unlocked
+        let now = Instant::now();
+        let mut state = dev.state.lock().unwrap();
+        assert_eq!(dev.advance(&mut state, now), Expiry::Stage1);
+        drop(state);
+        reload(&dev, ESB_WDT_RELOAD);
+        assert_eq!(dev.state.lock().unwrap().stage, 2);
+        unlock(&dev);
+        reload(&dev, ESB_WDT_RELOAD);
+        let state = dev.state.lock().unwrap();
+        assert_eq!(state.stage, 1);
+        let deadline = state.deadline.unwrap();
+        assert!(deadline >= now + stage_period(0x100, 0));
+        assert!(deadline <= Instant::now() + stage_period(0x100, 0));
+    }
+
+    #[test]
+    fn stage1_interrupt() {
+        let dev = Arc::new(I6300Esb::new(WdtAction::Reset));
+        let inst = pci::Builder::new(pci::Ident::default())
+            .add_lintr()
+            .finish(Arc::clone(&dev));
+        let pin = Arc::new(LegacyPin::detached(10));
+        inst.attach(&|| {
+            (pci::INTxPinID::INTA, Arc::clone(&pin) as Arc<dyn IntrPin>)
+        });
+        enable(&dev);
+
+        let mut state = dev.state.lock().unwrap();
+        assert_eq!(dev.advance(&mut state, Instant::now()), Expiry::Stage1);
+        drop(state);
+        assert!(pin.is_asserted());
+        let mut buf = [0u8; 4];
+        bar_read(&dev, 0x8, &mut buf);
+        assert_eq!(u32::from_le_bytes(buf), 1);
+
+        // Status is write-1-to-clear, which releases the interrupt
+        bar_write(&dev, 0x8, &0u32.to_le_bytes());
+        assert!(pin.is_asserted());
+        bar_write(&dev, 0x8, &1u32.to_le_bytes());
+        assert!(!pin.is_asserted());
+        bar_read(&dev, 0x8, &mut buf);
+        assert_eq!(u32::from_le_bytes(buf), 0);
+
+        // No interrupt is raised when another type (SMI) is configured
+        cfg_write(&dev, ESB_CONFIG_OFFSET, &2u16.to_le_bytes());
+        unlock(&dev);
+        reload(&dev, ESB_WDT_RELOAD);
+        let mut state = dev.state.lock().unwrap();
+        assert_eq!(dev.advance(&mut state, Instant::now()), Expiry::Stage1);
+        assert!(!state.int_status);
+        drop(state);
+        assert!(!pin.is_asserted());
+        assert_eq!(inst.intr_counts().intx, 1);
+    }
+
+    #[test]
+    fn expiry_actions() {
+        use bhyve_api::vm_suspend_how::*;
+
+        let cases = [
+            (WdtAction::Reset, Some(VM_SUSPEND_RESET)),
+            (WdtAction::PowerOff, Some(VM_SUSPEND_POWEROFF)),
+            (WdtAction::None, None),
+        ];
+        for (action, how) in cases.iter() {
+            let dev = I6300Esb::new(*action);
+            enable(&dev);
+            let now = Instant::now();
+            let mut state = dev.state.lock().unwrap();
+            assert_eq!(dev.advance(&mut state, now), Expiry::Stage1);
+            assert_eq!(dev.advance(&mut state, now), Expiry::Fired(*how));
+            assert_eq!(state.deadline, None);
+            drop(state);
+            assert_eq!(dev.expirations(), 1);
+
+            // The timeout is indicated until cleared by the guest
+            let mut buf = [0u8; 2];
+            bar_read(&dev, 0xc, &mut buf);
+            assert_eq!(u16::from_le_bytes(buf), ESB_WDT_TIMEOUT);
+            unlock(&dev);
+            reload(&dev, ESB_WDT_TIMEOUT);
+            bar_read(&dev, 0xc, &mut buf);
+            assert_eq!(u16::from_le_bytes(buf), 0);
+        }
+
+        // With reboots disabled, no action is taken and stage 1 restarts
+        let dev = I6300Esb::new(WdtAction::Reset);
+        cfg_write(&dev, ESB_CONFIG_OFFSET, &ESB_WDT_REBOOT.to_le_bytes());
+        enable(&dev);
+        let now = Instant::now();
+        let mut state = dev.state.lock().unwrap();
+        assert_eq!(dev.advance(&mut state, now), Expiry::Stage1);
+        assert_eq!(dev.advance(&mut state, now), Expiry::Fired(None));
+        assert_eq!(state.stage, 1);
+        assert_eq!(
+            state.deadline,
+            Some(now + stage_period(PRELOAD_MASK, ESB_WDT_REBOOT))
+        );
+        assert!(!state.timed_out);
+        assert_eq!(state.expirations, 1);
+    }
+
+    #[test]
+    fn paused_timer_frozen() {
+        let dev = I6300Esb::new(WdtAction::Reset);
+        // Nothing is held when the timer is not running
+        dev.freeze();
+        assert_eq!(dev.state.lock().unwrap().frozen, None);
+
+        enable(&dev);
+        let deadline = dev.state.lock().unwrap().deadline.unwrap();
+        dev.freeze();
+        let state = dev.state.lock().unwrap();
+        assert_eq!(state.deadline, None);
+        let remain = state.frozen.unwrap();
+        assert!(remain <= stage_period(PRELOAD_MASK, 0));
+        drop(state);
+
+        // The deadline is pushed out by the time spent paused
+        let pause = Duration::from_millis(10);
+        std::thread::sleep(pause);
+        dev.thaw();
+        let state = dev.state.lock().unwrap();
+        assert_eq!(state.frozen, None);
+        assert!(state.deadline.unwrap() >= deadline + pause);
+        assert_eq!(state.stage, 1);
+    }
+}
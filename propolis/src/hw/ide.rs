@@ -0,0 +1,1232 @@
+//! PIIX3 IDE controller, exposing disks and ATAPI CD-ROMs on its two
+//! legacy-mode channels for guests lacking drivers for anything newer.
+//!
+//! Disk sectors may be transferred by bus-master DMA, or by PIO through the
+//! data register a sector at a time, as firmware and simple boot loaders are
+//! wont to do.  CD-ROM media is only read by DMA.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::block::*;
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::ata::{self, bits::*, Packet, Sense, CD_SECTOR_SZ};
+use crate::hw::pci;
+use crate::intr_pins::{IntrPin, LegacyPIC, LegacyPin};
+use crate::pio::{PioBus, PioDev};
+use crate::util::regmap::RegMap;
+use crate::util::self_arc::*;
+use crate::vmm::MemCtx;
+
+use lazy_static::lazy_static;
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEV_PIIX3_IDE: u16 = 0x7010;
+const SUBCLASS_IDE: u8 = 0x01;
+/// Both channels fixed in legacy mode, with bus-master DMA
+const PROGIF_LEGACY_BM: u8 = 0x80;
+
+/// Command block port, control block port, and IRQ of each channel
+const CHAN_LEGACY: [(u16, u16, u8); 2] =
+    [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+const CMD_BLOCK_LEN: u16 = 8;
+const CTL_BLOCK_LEN: u16 = 1;
+const PIO_IDENT_CMD: usize = 0;
+const PIO_IDENT_CTL: usize = 1;
+
+/// Bus-master registers (in BAR4), 8 bytes for each channel
+const BM_LEN: usize = 16;
+const BM_CHAN_LEN: usize = 8;
+
+/// IDE timing registers, in which channel decode must appear enabled
+const IDETIM_OFFSET: u8 = 0x40;
+const IDETIM_LEN: usize = 4;
+const IDETIM_DECODE_EN: u8 = 0x80;
+/// Decode enabled for both channels
+const IDETIM_DEFAULT: [u8; IDETIM_LEN] =
+    [0, IDETIM_DECODE_EN, 0, IDETIM_DECODE_EN];
+
+// Command block registers
+const REG_DATA: usize = 0;
+const REG_FEATURE: usize = 1;
+const REG_COUNT: usize = 2;
+const REG_LBA_LO: usize = 3;
+const REG_LBA_MID: usize = 4;
+const REG_LBA_HI: usize = 5;
+const REG_DEVICE: usize = 6;
+const REG_COMMAND: usize = 7;
+
+const DEV_SLAVE: u8 = 1 << 4;
+const DEV_LBA: u8 = 1 << 6;
+
+const CTL_NIEN: u8 = 1 << 1;
+const CTL_SRST: u8 = 1 << 2;
+const CTL_HOB: u8 = 1 << 7;
+
+const BM_CMD_START: u8 = 1 << 0;
+const BM_CMD_TO_MEM: u8 = 1 << 3;
+const BM_STS_ACTIVE: u8 = 1 << 0;
+const BM_STS_ERR: u8 = 1 << 1;
+const BM_STS_INTR: u8 = 1 << 2;
+const BM_STS_DMA_CAP: u8 = 0x3 << 5;
+
+const PRD_EOT: u32 = 1 << 31;
+/// Bound on the PRD entries followed, guarding against unterminated tables
+const PRD_MAX: u64 = 512;
+
+// Interrupt reason (in the sector count register) for ATAPI devices
+const IR_COD: u8 = 1 << 0;
+const IR_IO: u8 = 1 << 1;
+/// Packet command data is to be transferred by DMA
+const FEAT_DMA: u8 = 1 << 0;
+const ATAPI_PACKET_LEN: usize = 12;
+
+// Geometry for CHS addressing, matching that reported by IDENTIFY
+const CHS_HEADS: u64 = 16;
+const CHS_SECTORS: u64 = 63;
+
+const STATUS_OK: u8 = ATA_SR_DRDY | ATA_SR_DSC;
+
+const SECTOR_LEN: usize = ata::SECTOR_SZ as usize;
+
+/// A drive attached to an IDE channel
+pub enum IdeDrive {
+    Disk(Arc<dyn BlockDev<Request>>),
+    Cdrom(Arc<dyn BlockDev<Request>>),
+}
+impl IdeDrive {
+    fn bdev(&self) -> &Arc<dyn BlockDev<Request>> {
+        match self {
+            IdeDrive::Disk(bdev) | IdeDrive::Cdrom(bdev) => bdev,
+        }
+    }
+    fn is_cdrom(&self) -> bool {
+        matches!(self, IdeDrive::Cdrom(_))
+    }
+}
+
+/// Data transfer of the command in progress on a channel
+enum Xfer {
+    Idle,
+    /// Data for the host to read from the data register
+    PioIn {
+        buf: Vec<u8>,
+        pos: usize,
+        atapi: bool,
+    },
+    /// Collecting the command packet for an ATAPI device
+    Packet {
+        buf: Vec<u8>,
+        dma: bool,
+    },
+    /// Awaiting bus-master DMA to transfer `len` bytes at `off`
+    Dma {
+        op: BlockOp,
+        off: u64,
+        len: u64,
+    },
+    /// Awaiting bus-master DMA of a packet command response
+    DmaData(Vec<u8>),
+    /// Sector read by PIO, for the host to read from the data register, with
+    /// `left` more to be read from `off` once it is drained
+    SectorIn {
+        buf: Vec<u8>,
+        pos: usize,
+        off: u64,
+        left: u64,
+    },
+    /// Collecting a sector written by PIO, to be stored at `off`, with `left`
+    /// more to follow it
+    SectorOut {
+        buf: Vec<u8>,
+        off: u64,
+        left: u64,
+    },
+    /// Request outstanding to the backend
+    Busy,
+    /// Request outstanding to the backend for the sector at `off` of a PIO
+    /// transfer, with `left` more to follow it
+    SectorBusy {
+        op: BlockOp,
+        off: u64,
+        left: u64,
+    },
+}
+
+struct DriveState {
+    drive: IdeDrive,
+    /// Command block registers, and their previous contents (for 48-bit
+    /// commands), indexed by register
+    tf: [u8; 8],
+    hob: [u8; 8],
+    status: u8,
+    error: u8,
+    sense: Sense,
+}
+impl DriveState {
+    fn new(drive: IdeDrive) -> Self {
+        let mut this = Self {
+            drive,
+            tf: [0; 8],
+            hob: [0; 8],
+            status: 0,
+            error: 0,
+            sense: Sense::default(),
+        };
+        this.reset();
+        this
+    }
+    /// Return to the post-reset state, presenting the device signature
+    fn reset(&mut self) {
+        self.tf = [0; 8];
+        self.hob = [0; 8];
+        self.set_signature();
+        self.error = 0x01;
+        self.status = match self.drive.is_cdrom() {
+            true => 0,
+            false => STATUS_OK,
+        };
+        self.sense = Sense::default();
+    }
+    fn set_signature(&mut self) {
+        self.tf[REG_COUNT] = 1;
+        self.tf[REG_LBA_LO] = 1;
+        if self.drive.is_cdrom() {
+            self.tf[REG_LBA_MID] = 0x14;
+            self.tf[REG_LBA_HI] = 0xeb;
+        } else {
+            self.tf[REG_LBA_MID] = 0;
+            self.tf[REG_LBA_HI] = 0;
+        }
+    }
+    /// Sector address and count of a command, or `None` if the address is
+    /// invalid
+    fn lba_count(&self, device: u8, ext: bool) -> Option<(u64, u64)> {
+        let tf = &self.tf;
+        if ext {
+            let hob = &self.hob;
+            let lba = (hob[REG_LBA_HI] as u64) << 40
+                | (hob[REG_LBA_MID] as u64) << 32
+                | (hob[REG_LBA_LO] as u64) << 24
+                | (tf[REG_LBA_HI] as u64) << 16
+                | (tf[REG_LBA_MID] as u64) << 8
+                | tf[REG_LBA_LO] as u64;
+            let count =
+                match (hob[REG_COUNT] as u64) << 8 | tf[REG_COUNT] as u64 {
+                    0 => 65536,
+                    n => n,
+                };
+            return Some((lba, count));
+        }
+        let count = match tf[REG_COUNT] {
+            0 => 256,
+            n => n as u64,
+        };
+        let lba = if device & DEV_LBA != 0 {
+            (device as u64 & 0xf) << 24
+                | (tf[REG_LBA_HI] as u64) << 16
+                | (tf[REG_LBA_MID] as u64) << 8
+                | tf[REG_LBA_LO] as u64
+        } else {
+            let cyl = (tf[REG_LBA_HI] as u64) << 8 | tf[REG_LBA_MID] as u64;
+            let head = device as u64 & 0xf;
+            let sector = tf[REG_LBA_LO] as u64;
+            if sector == 0 || sector > CHS_SECTORS {
+                return None;
+            }
+            (cyl * CHS_HEADS + head) * CHS_SECTORS + sector - 1
+        };
+        Some((lba, count))
+    }
+    fn serial(&self, chan: usize, idx: usize) -> String {
+        format!("PROPOLIS-IDE-{}-{}", chan, idx)
+    }
+}
+
+struct ChanState {
+    drives: [Option<DriveState>; 2],
+    device: u8,
+    ctl: u8,
+    intrq: bool,
+    xfer: Xfer,
+    /// Drive which issued the command in progress
+    active: usize,
+    bm_cmd: u8,
+    bm_sts: u8,
+    bm_prdt: u32,
+    /// Bumped on reset, so stale completions can be discarded
+    gen: u64,
+}
+impl ChanState {
+    fn selected(&self) -> usize {
+        (self.device & DEV_SLAVE != 0) as usize
+    }
+    fn active_drive(&mut self) -> &mut DriveState {
+        self.drives[self.active].as_mut().unwrap()
+    }
+}
+
+pub struct Channel {
+    num: usize,
+    pin: LegacyPin,
+    state: Mutex<ChanState>,
+    sa_cell: SelfArcCell<Self>,
+}
+impl Channel {
+    fn new(
+        num: usize,
+        pin: LegacyPin,
+        drives: [Option<IdeDrive>; 2],
+    ) -> Arc<Self> {
+        let [d0, d1] = drives;
+        let mut bm_sts = 0;
+        if d0.is_some() {
+            bm_sts |= 1 << 5;
+        }
+        if d1.is_some() {
+            bm_sts |= 1 << 6;
+        }
+        let mut this = Arc::new(Self {
+            num,
+            pin,
+            state: Mutex::new(ChanState {
+                drives: [d0.map(DriveState::new), d1.map(DriveState::new)],
+                device: 0,
+                ctl: 0,
+                intrq: false,
+                xfer: Xfer::Idle,
+                active: 0,
+                bm_cmd: 0,
+                bm_sts,
+                bm_prdt: 0,
+                gen: 0,
+            }),
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
+        this
+    }
+
+    fn raise_intr(&self, state: &mut ChanState) {
+        state.bm_sts |= BM_STS_INTR;
+        state.intrq = true;
+        if state.ctl & CTL_NIEN == 0 {
+            self.pin.assert();
+        }
+    }
+    /// Complete the command in progress, interrupting the host
+    fn finish(&self, state: &mut ChanState, status: u8, error: u8) {
+        let drive = state.active_drive();
+        drive.status = status;
+        drive.error = error;
+        if drive.drive.is_cdrom() {
+            drive.tf[REG_COUNT] = IR_IO | IR_COD;
+        }
+        state.xfer = Xfer::Idle;
+        self.raise_intr(state);
+    }
+    fn abort(&self, state: &mut ChanState) {
+        self.finish(state, ATA_SR_DRDY | ATA_SR_ERR, ATA_ER_ABRT);
+    }
+    fn check(&self, state: &mut ChanState, sense: Sense) {
+        state.active_drive().sense = sense;
+        self.finish(state, ATA_SR_DRDY | ATA_SR_ERR, sense.key << 4);
+    }
+    /// Reset the channel in response to a power-on (rather than software)
+    /// reset, also clearing the control and bus-master registers.
+    fn power_reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.reset(&mut state);
+        state.ctl = 0;
+        state.active = 0;
+        state.bm_cmd = 0;
+        state.bm_sts &= BM_STS_DMA_CAP;
+        state.bm_prdt = 0;
+    }
+    fn reset(&self, state: &mut ChanState) {
+        state.gen += 1;
+        state.xfer = Xfer::Idle;
+        state.device = 0;
+        state.intrq = false;
+        self.pin.deassert();
+        for drive in state.drives.iter_mut().flatten() {
+            drive.reset();
+        }
+    }
+
+    fn cmd_read(&self, state: &mut ChanState, reg: usize, ro: &mut ReadOp) {
+        if reg == REG_DATA {
+            return self.data_read(state, ro);
+        }
+        if reg == REG_DEVICE {
+            return ro.write_u8(state.device);
+        }
+        let hob = state.ctl & CTL_HOB != 0;
+        let sel = state.selected();
+        let val = match state.drives[sel].as_ref() {
+            // Registers of absent drives read as zero
+            None => 0,
+            Some(drive) => match reg {
+                REG_FEATURE => drive.error,
+                REG_COMMAND => drive.status,
+                _ if hob => drive.hob[reg],
+                _ => drive.tf[reg],
+            },
+        };
+        if reg == REG_COMMAND {
+            // Reading status acknowledges the interrupt
+            state.intrq = false;
+            self.pin.deassert();
+        }
+        ro.write_u8(val);
+    }
+    fn cmd_write(
+        &self,
+        state: &mut ChanState,
+        reg: usize,
+        wo: &mut WriteOp,
+        mem: &MemCtx,
+    ) {
+        if reg == REG_DATA {
+            return self.data_write(state, wo, mem);
+        }
+        let val = wo.read_u8();
+        match reg {
+            REG_DEVICE => state.device = val,
+            REG_COMMAND => {
+                let sel = state.selected();
+                if state.drives[sel].is_some() {
+                    state.active = sel;
+                    self.exec(state, val, mem);
+                }
+            }
+            _ => {
+                // Both drives latch writes to the command block
+                state.ctl &= !CTL_HOB;
+                for drive in state.drives.iter_mut().flatten() {
+                    drive.hob[reg] = drive.tf[reg];
+                    drive.tf[reg] = val;
+                }
+            }
+        }
+    }
+    fn ctl_read(&self, state: &ChanState, ro: &mut ReadOp) {
+        // Alternate status, without acknowledging any interrupt
+        let sel = state.selected();
+        ro.write_u8(state.drives[sel].as_ref().map(|d| d.status).unwrap_or(0));
+    }
+    fn ctl_write(&self, state: &mut ChanState, wo: &mut WriteOp) {
+        let old = state.ctl;
+        let val = wo.read_u8();
+        state.ctl = val;
+        if old & CTL_SRST != 0 && val & CTL_SRST == 0 {
+            self.reset(state);
+        }
+        if val & CTL_NIEN != 0 {
+            self.pin.deassert();
+        } else if state.intrq {
+            self.pin.assert();
+        }
+    }
+
+    fn data_read(&self, state: &mut ChanState, ro: &mut ReadOp) {
+        let mut data = vec![0u8; ro.len()];
+        let drained = match &mut state.xfer {
+            Xfer::PioIn { buf, pos, .. } | Xfer::SectorIn { buf, pos, .. } => {
+                let n = usize::min(data.len(), buf.len() - *pos);
+                data[..n].copy_from_slice(&buf[*pos..(*pos + n)]);
+                *pos += n;
+                *pos == buf.len()
+            }
+            _ => false,
+        };
+        ro.write_bytes(&data);
+        if !drained {
+            return;
+        }
+        match std::mem::replace(&mut state.xfer, Xfer::Idle) {
+            Xfer::PioIn { atapi: true, .. } => self.finish(state, STATUS_OK, 0),
+            Xfer::SectorIn { buf, off, left, .. } if left > 0 => {
+                self.sector_io(state, BlockOp::Read, off, left - 1, buf)
+            }
+            _ => state.active_drive().status = STATUS_OK,
+        }
+    }
+    fn data_write(
+        &self,
+        state: &mut ChanState,
+        wo: &mut WriteOp,
+        mem: &MemCtx,
+    ) {
+        let mut data = vec![0u8; wo.len()];
+        wo.read_bytes(&mut data);
+        let ready = match &mut state.xfer {
+            Xfer::Packet { buf, .. } => {
+                let n = usize::min(data.len(), ATAPI_PACKET_LEN - buf.len());
+                buf.extend_from_slice(&data[..n]);
+                buf.len() == ATAPI_PACKET_LEN
+            }
+            Xfer::SectorOut { buf, .. } => {
+                let n = usize::min(data.len(), SECTOR_LEN - buf.len());
+                buf.extend_from_slice(&data[..n]);
+                buf.len() == SECTOR_LEN
+            }
+            _ => false,
+        };
+        if !ready {
+            return;
+        }
+        match std::mem::replace(&mut state.xfer, Xfer::Idle) {
+            Xfer::SectorOut { buf, off, left } => {
+                self.sector_io(state, BlockOp::Write, off, left, buf)
+            }
+            xfer => {
+                state.xfer = xfer;
+                self.exec_packet(state, mem);
+            }
+        }
+    }
+
+    fn exec(&self, state: &mut ChanState, cmd: u8, mem: &MemCtx) {
+        state.active_drive().error = 0;
+        if cmd == ATA_CMD_EXEC_DIAG {
+            for drive in state.drives.iter_mut().flatten() {
+                drive.reset();
+            }
+            self.raise_intr(state);
+            return;
+        }
+        if state.active_drive().drive.is_cdrom() {
+            self.exec_atapi(state, cmd);
+        } else {
+            self.exec_ata(state, cmd, mem);
+        }
+    }
+    fn exec_ata(&self, state: &mut ChanState, cmd: u8, mem: &MemCtx) {
+        let device = state.device;
+        let (chan, idx) = (self.num, state.active);
+        let drive = state.active_drive();
+        let info = drive.drive.bdev().inquire();
+        let bytes = info.total_size * info.block_size as u64;
+        let sectors = bytes / ata::SECTOR_SZ;
+
+        let (op, ext, dma) = match cmd {
+            ATA_CMD_READ_DMA => (BlockOp::Read, false, true),
+            ATA_CMD_READ_DMA_EXT => (BlockOp::Read, true, true),
+            ATA_CMD_WRITE_DMA => (BlockOp::Write, false, true),
+            ATA_CMD_WRITE_DMA_EXT => (BlockOp::Write, true, true),
+            ATA_CMD_READ_SECTORS => (BlockOp::Read, false, false),
+            ATA_CMD_READ_SECTORS_EXT => (BlockOp::Read, true, false),
+            ATA_CMD_WRITE_SECTORS => (BlockOp::Write, false, false),
+            ATA_CMD_WRITE_SECTORS_EXT => (BlockOp::Write, true, false),
+            ATA_CMD_IDENTIFY => {
+                let data = ata::identify_disk(
+                    &drive.serial(chan, idx),
+                    sectors,
+                    ata::SECTOR_SZ as u32,
+                );
+                drive.status = STATUS_OK | ATA_SR_DRQ;
+                state.xfer =
+                    Xfer::PioIn { buf: data.to_vec(), pos: 0, atapi: false };
+                return self.raise_intr(state);
+            }
+            ATA_CMD_FLUSH_CACHE
+            | ATA_CMD_FLUSH_CACHE_EXT
+            | ATA_CMD_SET_FEATURES
+            | ATA_CMD_SET_MULTIPLE
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_IDLE_IMMED
+            | ATA_CMD_STANDBY_IMMED => return self.finish(state, STATUS_OK, 0),
+            _ => return self.abort(state),
+        };
+        let (lba, count) = match drive.lba_count(device, ext) {
+            Some((lba, count)) if lba + count <= sectors => (lba, count),
+            _ => return self.abort(state),
+        };
+        if matches!(op, BlockOp::Write) && !info.writable {
+            return self.abort(state);
+        }
+        let off = lba * ata::SECTOR_SZ;
+        match (op, dma) {
+            (_, true) => {
+                drive.status = STATUS_OK | ATA_SR_BSY;
+                state.xfer = Xfer::Dma { op, off, len: count * ata::SECTOR_SZ };
+                self.try_dma(state, mem);
+            }
+            (BlockOp::Read, false) => {
+                let buf = vec![0u8; SECTOR_LEN];
+                self.sector_io(state, op, off, count - 1, buf);
+            }
+            (_, false) => {
+                // The host writes the first sector once DRQ is set, without
+                // awaiting an interrupt.
+                drive.status = STATUS_OK | ATA_SR_DRQ;
+                state.xfer = Xfer::SectorOut {
+                    buf: Vec::with_capacity(SECTOR_LEN),
+                    off,
+                    left: count - 1,
+                };
+            }
+        }
+    }
+    fn exec_atapi(&self, state: &mut ChanState, cmd: u8) {
+        let (chan, idx) = (self.num, state.active);
+        let drive = state.active_drive();
+        match cmd {
+            ATA_CMD_IDENTIFY_PACKET => {
+                let data = ata::identify_cdrom(&drive.serial(chan, idx));
+                drive.status = STATUS_OK | ATA_SR_DRQ;
+                state.xfer =
+                    Xfer::PioIn { buf: data.to_vec(), pos: 0, atapi: false };
+                self.raise_intr(state);
+            }
+            ATA_CMD_PACKET => {
+                let dma = drive.tf[REG_FEATURE] & FEAT_DMA != 0;
+                drive.status = ATA_SR_DRDY | ATA_SR_DRQ;
+                drive.tf[REG_COUNT] = IR_COD;
+                state.xfer = Xfer::Packet {
+                    buf: Vec::with_capacity(ATAPI_PACKET_LEN),
+                    dma,
+                };
+            }
+            ATA_CMD_DEVICE_RESET => {
+                drive.reset();
+                state.xfer = Xfer::Idle;
+            }
+            ATA_CMD_SET_FEATURES
+            | ATA_CMD_IDLE_IMMED
+            | ATA_CMD_STANDBY_IMMED => self.finish(state, STATUS_OK, 0),
+            _ => {
+                // Leave the signature for the guest to find an ATAPI device
+                drive.set_signature();
+                self.abort(state);
+            }
+        }
+    }
+    fn exec_packet(&self, state: &mut ChanState, mem: &MemCtx) {
+        let dma = match std::mem::replace(&mut state.xfer, Xfer::Idle) {
+            Xfer::Packet { buf, dma } => {
+                let mut cdb = [0u8; 16];
+                cdb[..ATAPI_PACKET_LEN].copy_from_slice(&buf);
+                (cdb, dma)
+            }
+            _ => return,
+        };
+        let (cdb, dma) = dma;
+        let drive = state.active_drive();
+        let info = drive.drive.bdev().inquire();
+        let sectors = info.total_size * info.block_size as u64 / CD_SECTOR_SZ;
+
+        match ata::atapi_packet(&cdb, sectors, &mut drive.sense) {
+            Packet::Data(data) if data.is_empty() => {
+                self.finish(state, STATUS_OK, 0)
+            }
+            Packet::Data(data) if dma => {
+                drive.status = STATUS_OK | ATA_SR_BSY;
+                state.xfer = Xfer::DmaData(data);
+                self.try_dma(state, mem);
+            }
+            Packet::Data(data) => {
+                let len = data.len() as u16;
+                drive.tf[REG_LBA_MID] = len as u8;
+                drive.tf[REG_LBA_HI] = (len >> 8) as u8;
+                drive.tf[REG_COUNT] = IR_IO;
+                drive.status = ATA_SR_DRDY | ATA_SR_DRQ;
+                state.xfer = Xfer::PioIn { buf: data, pos: 0, atapi: true };
+                self.raise_intr(state);
+            }
+            Packet::Read { count: 0, .. } => self.finish(state, STATUS_OK, 0),
+            Packet::Read { lba, count } if dma => {
+                drive.status = STATUS_OK | ATA_SR_BSY;
+                state.xfer = Xfer::Dma {
+                    op: BlockOp::Read,
+                    off: lba * CD_SECTOR_SZ,
+                    len: count as u64 * CD_SECTOR_SZ,
+                };
+                self.try_dma(state, mem);
+            }
+            Packet::Read { .. } => self.check(
+                state,
+                Sense::new(SENSE_ILLEGAL_REQUEST, ASC_INVALID_FIELD),
+            ),
+            Packet::Error(sense) => self.check(state, sense),
+        }
+    }
+
+    /// Walk the PRD table, to the entry marked as the last
+    fn read_prdt(mem: &MemCtx, addr: u32) -> Option<Vec<GuestRegion>> {
+        let mut prdt = Vec::new();
+        for n in 0..PRD_MAX {
+            let prd: [u32; 2] = mem.read(GuestAddr(addr as u64 + n * 8))?;
+            let len = match prd[1] & 0xffff {
+                0 => 0x1_0000,
+                n => n as usize,
+            };
+            prdt.push(GuestRegion(GuestAddr((prd[0] & !1) as u64), len));
+            if prd[1] & PRD_EOT != 0 {
+                break;
+            }
+        }
+        Some(prdt)
+    }
+    /// Start any transfer pending for the command in progress, once the host
+    /// has started the bus master.
+    fn try_dma(&self, state: &mut ChanState, mem: &MemCtx) {
+        if state.bm_sts & BM_STS_ACTIVE == 0 {
+            return;
+        }
+        let prdt = match state.xfer {
+            Xfer::Dma { .. } | Xfer::DmaData(_) => {
+                Self::read_prdt(mem, state.bm_prdt)
+            }
+            _ => return,
+        };
+        let prdt = match prdt {
+            Some(prdt) => prdt,
+            None => {
+                state.bm_sts &= !BM_STS_ACTIVE;
+                state.bm_sts |= BM_STS_ERR;
+                return self.abort(state);
+            }
+        };
+        match std::mem::replace(&mut state.xfer, Xfer::Busy) {
+            Xfer::Dma { op, off, len } => {
+                let bufs = match ata::prdt_covering(&prdt, len as usize) {
+                    Some(bufs) => bufs,
+                    None => {
+                        state.bm_sts &= !BM_STS_ACTIVE;
+                        return self.abort(state);
+                    }
+                };
+                let req = Request {
+                    op,
+                    off: off as usize,
+                    bufs,
+                    idx: 0,
+                    chan: self.self_arc(),
+                    pio: None,
+                    gen: state.gen,
+                };
+                state.active_drive().drive.bdev().enqueue(req);
+            }
+            Xfer::DmaData(data) => {
+                ata::prdt_copy_out(mem, &prdt, &data);
+                state.bm_sts &= !BM_STS_ACTIVE;
+                self.finish(state, STATUS_OK, 0);
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Issue the request for the sector at `off` of a PIO transfer, with
+    /// `left` more to follow it, reading into or writing from `buf`.
+    fn sector_io(
+        &self,
+        state: &mut ChanState,
+        op: BlockOp,
+        off: u64,
+        left: u64,
+        buf: Vec<u8>,
+    ) {
+        state.active_drive().status = STATUS_OK | ATA_SR_BSY;
+        state.xfer = Xfer::SectorBusy { op, off, left };
+        let req = Request {
+            op,
+            off: off as usize,
+            bufs: Vec::new(),
+            idx: 0,
+            chan: self.self_arc(),
+            pio: Some(buf),
+            gen: state.gen,
+        };
+        state.active_drive().drive.bdev().enqueue(req);
+    }
+    /// Continue a PIO transfer once the request for one of its sectors is
+    /// complete.
+    fn sector_done(
+        &self,
+        state: &mut ChanState,
+        res: BlockResult,
+        (op, off, left): (BlockOp, u64, u64),
+        buf: Vec<u8>,
+    ) {
+        match res {
+            BlockResult::Success => {}
+            BlockResult::Failure => {
+                return self.finish(state, ATA_SR_DRDY | ATA_SR_ERR, ATA_ER_UNC)
+            }
+            BlockResult::Unsupported => return self.abort(state),
+        }
+        let next = off + ata::SECTOR_SZ;
+        match op {
+            BlockOp::Read => {
+                state.active_drive().status = STATUS_OK | ATA_SR_DRQ;
+                state.xfer = Xfer::SectorIn { buf, pos: 0, off: next, left };
+                self.raise_intr(state);
+            }
+            _ if left > 0 => {
+                let mut buf = buf;
+                buf.clear();
+                state.active_drive().status = STATUS_OK | ATA_SR_DRQ;
+                state.xfer = Xfer::SectorOut { buf, off: next, left: left - 1 };
+                self.raise_intr(state);
+            }
+            _ => self.finish(state, STATUS_OK, 0),
+        }
+    }
+    fn complete(&self, gen: u64, res: BlockResult, pio: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.gen != gen {
+            // The channel was reset while the request was outstanding
+            return;
+        }
+        match std::mem::replace(&mut state.xfer, Xfer::Idle) {
+            Xfer::Busy => {}
+            Xfer::SectorBusy { op, off, left } => {
+                let buf = pio.unwrap_or_default();
+                return self.sector_done(&mut state, res, (op, off, left), buf);
+            }
+            xfer => {
+                state.xfer = xfer;
+                return;
+            }
+        }
+        state.bm_sts &= !BM_STS_ACTIVE;
+        let cdrom = state.active_drive().drive.is_cdrom();
+        match (res, cdrom) {
+            (BlockResult::Success, _) => self.finish(&mut state, STATUS_OK, 0),
+            (_, true) => self.check(
+                &mut state,
+                Sense::new(SENSE_MEDIUM_ERROR, ASC_READ_ERROR),
+            ),
+            (BlockResult::Failure, false) => {
+                self.finish(&mut state, ATA_SR_DRDY | ATA_SR_ERR, ATA_ER_UNC)
+            }
+            (BlockResult::Unsupported, false) => self.abort(&mut state),
+        }
+    }
+
+    fn bm_read(&self, id: &BmReg, ro: &mut ReadOp) {
+        let state = self.state.lock().unwrap();
+        match id {
+            BmReg::Cmd(_) => ro.write_u8(state.bm_cmd),
+            BmReg::Status(_) => ro.write_u8(state.bm_sts),
+            BmReg::Prdt(_) => ro.write_u32(state.bm_prdt),
+            BmReg::Reserved => ro.fill(0),
+        }
+    }
+    fn bm_write(&self, id: &BmReg, wo: &mut WriteOp, mem: &MemCtx) {
+        let mut state = self.state.lock().unwrap();
+        match id {
+            BmReg::Cmd(_) => {
+                let old = state.bm_cmd;
+                let val = wo.read_u8() & (BM_CMD_START | BM_CMD_TO_MEM);
+                state.bm_cmd = val;
+                if old & BM_CMD_START == 0 && val & BM_CMD_START != 0 {
+                    state.bm_sts |= BM_STS_ACTIVE;
+                    self.try_dma(&mut state, mem);
+                } else if val & BM_CMD_START == 0 {
+                    state.bm_sts &= !BM_STS_ACTIVE;
+                }
+            }
+            BmReg::Status(_) => {
+                let val = wo.read_u8();
+                let sts = state.bm_sts & !(val & (BM_STS_ERR | BM_STS_INTR));
+                state.bm_sts = (sts & !BM_STS_DMA_CAP) | (val & BM_STS_DMA_CAP);
+            }
+            BmReg::Prdt(_) => state.bm_prdt = wo.read_u32() & !0x3,
+            BmReg::Reserved => {}
+        }
+    }
+}
+impl SelfArc for Channel {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
+}
+impl PioDev for Channel {
+    fn pio_rw(&self, _port: u16, ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        match (ident, rwo) {
+            (PIO_IDENT_CMD, RWOp::Read(ro)) => {
+                let reg = ro.offset();
+                self.cmd_read(&mut state, reg, ro)
+            }
+            (PIO_IDENT_CMD, RWOp::Write(wo)) => {
+                let reg = wo.offset();
+                self.cmd_write(&mut state, reg, wo, &ctx.mctx.memctx())
+            }
+            (_, RWOp::Read(ro)) => self.ctl_read(&state, ro),
+            (_, RWOp::Write(wo)) => self.ctl_write(&mut state, wo),
+        }
+    }
+}
+
+pub struct Piix3Ide {
+    channels: [Arc<Channel>; 2],
+    idetim: Mutex<[u8; IDETIM_LEN]>,
+}
+impl Piix3Ide {
+    /// Create a controller holding `drives`, in the order: primary master,
+    /// primary slave, secondary master, and secondary slave.
+    pub fn create(
+        pic: &LegacyPIC,
+        pio_bus: &PioBus,
+        drives: Vec<Option<IdeDrive>>,
+    ) -> Arc<pci::DeviceInst> {
+        assert!(drives.len() <= 4);
+        let mut drives = drives.into_iter();
+        let mut next = || drives.next().flatten();
+        let mut make_chan = |n: usize| {
+            let (_, _, irq) = CHAN_LEGACY[n];
+            Channel::new(n, pic.pin_handle(irq).unwrap(), [next(), next()])
+        };
+        let channels = [make_chan(0), make_chan(1)];
+
+        for (chan, (cmd, ctl, _)) in channels.iter().zip(CHAN_LEGACY.iter()) {
+            pio_bus
+                .register(
+                    *cmd,
+                    CMD_BLOCK_LEN,
+                    Arc::downgrade(chan) as Weak<dyn PioDev>,
+                    PIO_IDENT_CMD,
+                )
+                .unwrap();
+            pio_bus
+                .register(
+                    *ctl,
+                    CTL_BLOCK_LEN,
+                    Arc::downgrade(chan) as Weak<dyn PioDev>,
+                    PIO_IDENT_CTL,
+                )
+                .unwrap();
+        }
+
+        pci::Builder::new(pci::Ident {
+            vendor_id: VENDOR_INTEL,
+            device_id: DEV_PIIX3_IDE,
+            class: pci::bits::CLASS_STORAGE,
+            subclass: SUBCLASS_IDE,
+            prog_if: PROGIF_LEGACY_BM,
+            ..Default::default()
+        })
+        .add_bar_io(pci::BarN::BAR4, BM_LEN as u16)
+        .add_custom_cfg(IDETIM_OFFSET, IDETIM_LEN as u8)
+        .finish(Arc::new(Self { channels, idetim: Mutex::new(IDETIM_DEFAULT) }))
+    }
+}
+impl pci::Device for Piix3Ide {
+    fn reset(&self, _ctx: &DispCtx) {
+        for chan in self.channels.iter() {
+            chan.power_reset();
+        }
+        *self.idetim.lock().unwrap() = IDETIM_DEFAULT;
+    }
+    fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
+        assert_eq!(bar, pci::BarN::BAR4);
+        BM_MAP.process(&mut rwo, |id, rwo| {
+            let chan = match id {
+                BmReg::Cmd(n) | BmReg::Status(n) | BmReg::Prdt(n) => {
+                    &self.channels[*n]
+                }
+                BmReg::Reserved => &self.channels[0],
+            };
+            match rwo {
+                RWOp::Read(ro) => chan.bm_read(id, ro),
+                RWOp::Write(wo) => chan.bm_write(id, wo, &ctx.mctx.memctx()),
+            }
+        });
+    }
+    fn cfg_rw(&self, region: u8, rwo: RWOp) {
+        assert_eq!(region, IDETIM_OFFSET);
+        assert!(rwo.offset() + rwo.len() <= IDETIM_LEN);
+
+        let mut regs = self.idetim.lock().unwrap();
+        let off = rwo.offset();
+        match rwo {
+            RWOp::Read(ro) => ro.write_bytes(&regs[off..(off + ro.len())]),
+            RWOp::Write(wo) => wo.read_bytes(&mut regs[off..(off + wo.len())]),
+        }
+    }
+}
+
+/// Block request issued on behalf of the command in progress on a channel
+pub struct Request {
+    op: BlockOp,
+    off: usize,
+    bufs: Vec<GuestRegion>,
+    idx: usize,
+    chan: Arc<Channel>,
+    /// Sector data of a PIO transfer
+    pio: Option<Vec<u8>>,
+    gen: u64,
+}
+impl BlockReq for Request {
+    fn oper(&self) -> BlockOp {
+        self.op
+    }
+    fn offset(&self) -> usize {
+        self.off
+    }
+    fn next_buf(&mut self) -> Option<GuestRegion> {
+        let buf = self.bufs.get(self.idx)?;
+        self.idx += 1;
+        Some(GuestRegion(buf.0, buf.1))
+    }
+    fn host_buf(&mut self) -> Option<&mut [u8]> {
+        self.pio.as_deref_mut()
+    }
+    fn complete(self, res: BlockResult, _ctx: &DispCtx) {
+        self.chan.complete(self.gen, res, self.pio);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum BmReg {
+    Cmd(usize),
+    Status(usize),
+    Prdt(usize),
+    Reserved,
+}
+lazy_static! {
+    static ref BM_MAP: RegMap<BmReg> = {
+        let mut map = RegMap::new(BM_LEN);
+        for n in 0..2 {
+            let base = n * BM_CHAN_LEN;
+            map.define(base, 1, BmReg::Cmd(n));
+            map.define(base + 1, 1, BmReg::Reserved);
+            map.define(base + 2, 1, BmReg::Status(n));
+            map.define(base + 3, 1, BmReg::Reserved);
+            map.define(base + 4, 4, BmReg::Prdt(n));
+        }
+        map
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::vmm::TestMem;
+
+    /// Disk image in memory, whose requests are held until `process()`
+    struct MemBdev {
+        data: Mutex<Vec<u8>>,
+        reqs: Mutex<Vec<Request>>,
+    }
+    impl MemBdev {
+        fn new(sectors: usize) -> Arc<Self> {
+            let data = (0..sectors * SECTOR_LEN)
+                .map(|n| (n / SECTOR_LEN + n) as u8)
+                .collect();
+            Arc::new(Self {
+                data: Mutex::new(data),
+                reqs: Mutex::new(Vec::new()),
+            })
+        }
+        fn sector(&self, n: usize) -> Vec<u8> {
+            let data = self.data.lock().unwrap();
+            data[n * SECTOR_LEN..(n + 1) * SECTOR_LEN].to_vec()
+        }
+        /// Carry out the outstanding requests, returning how many there were
+        fn process(&self, mem: &MemCtx) -> usize {
+            let reqs = std::mem::take(&mut *self.reqs.lock().unwrap());
+            let count = reqs.len();
+            for mut req in reqs {
+                let mut data = self.data.lock().unwrap();
+                let (op, mut off) = (req.op, req.off);
+                if let Some(buf) = req.host_buf() {
+                    match op {
+                        BlockOp::Read => {
+                            buf.copy_from_slice(&data[off..(off + buf.len())])
+                        }
+                        _ => data[off..(off + buf.len())].copy_from_slice(buf),
+                    }
+                }
+                while let Some(buf) = req.next_buf() {
+                    let area = &mut data[off..(off + buf.1)];
+                    match op {
+                        BlockOp::Read => mem.write_from(buf.0, area, buf.1),
+                        _ => mem.read_into(buf.0, area, buf.1),
+                    };
+                    off += buf.1;
+                }
+                drop(data);
+                req.chan.complete(req.gen, BlockResult::Success, req.pio);
+            }
+            count
+        }
+    }
+    impl BlockDev<Request> for MemBdev {
+        fn enqueue(&self, req: Request) {
+            self.reqs.lock().unwrap().push(req);
+        }
+        fn inquire(&self) -> BlockInquiry {
+            let len = self.data.lock().unwrap().len();
+            BlockInquiry {
+                total_size: (len / SECTOR_LEN) as u64,
+                block_size: SECTOR_LEN as u32,
+                writable: true,
+            }
+        }
+    }
+
+    fn disk_chan(bdev: &Arc<MemBdev>) -> Arc<Channel> {
+        let drive = IdeDrive::Disk(Arc::clone(bdev) as Arc<dyn BlockDev<_>>);
+        Channel::new(0, LegacyPin::detached(14), [Some(drive), None])
+    }
+    fn outb(chan: &Channel, reg: usize, val: u8, mem: &MemCtx) {
+        let mut state = chan.state.lock().unwrap();
+        let buf = [val];
+        let mut wo = WriteOp::new_buf(reg, &buf);
+        chan.cmd_write(&mut state, reg, &mut wo, mem);
+    }
+    fn inb(chan: &Channel, reg: usize) -> u8 {
+        let mut buf = [0u8];
+        let mut state = chan.state.lock().unwrap();
+        chan.cmd_read(&mut state, reg, &mut ReadOp::new_buf(reg, &mut buf));
+        buf[0]
+    }
+    /// Issue `cmd` for `count` sectors at `lba`, using 28-bit addressing
+    fn issue(chan: &Channel, cmd: u8, lba: u32, count: u8, mem: &MemCtx) {
+        outb(chan, REG_COUNT, count, mem);
+        outb(chan, REG_LBA_LO, lba as u8, mem);
+        outb(chan, REG_LBA_MID, (lba >> 8) as u8, mem);
+        outb(chan, REG_LBA_HI, (lba >> 16) as u8, mem);
+        outb(chan, REG_DEVICE, DEV_LBA | (lba >> 24) as u8, mem);
+        outb(chan, REG_COMMAND, cmd, mem);
+    }
+    /// Read a sector through the data register, a word at a time
+    fn read_sector(chan: &Channel) -> Vec<u8> {
+        let mut data = vec![0u8; SECTOR_LEN];
+        for word in data.chunks_mut(2) {
+            let mut state = chan.state.lock().unwrap();
+            chan.data_read(&mut state, &mut ReadOp::new_buf(0, word));
+        }
+        data
+    }
+    fn write_sector(chan: &Channel, data: &[u8], mem: &MemCtx) {
+        for word in data.chunks(2) {
+            let mut state = chan.state.lock().unwrap();
+            chan.data_write(&mut state, &mut WriteOp::new_buf(0, word), mem);
+        }
+    }
+
+    #[test]
+    fn pio_sectors() {
+        let mem = TestMem::new(0x1000);
+        let mem = mem.memctx();
+        let bdev = MemBdev::new(16);
+        let chan = disk_chan(&bdev);
+
+        issue(&chan, ATA_CMD_READ_SECTORS, 5, 2, &mem);
+        assert_ne!(inb(&chan, REG_COMMAND) & ATA_SR_BSY, 0);
+        for n in 5..7 {
+            assert_eq!(bdev.process(&mem), 1);
+            assert!(chan.pin.is_asserted());
+            assert_eq!(inb(&chan, REG_COMMAND), STATUS_OK | ATA_SR_DRQ);
+            assert!(!chan.pin.is_asserted());
+            assert_eq!(read_sector(&chan), bdev.sector(n));
+        }
+        assert_eq!(bdev.process(&mem), 0);
+        assert_eq!(inb(&chan, REG_COMMAND), STATUS_OK);
+
+        let data = vec![0x5au8; SECTOR_LEN];
+        issue(&chan, ATA_CMD_WRITE_SECTORS, 9, 2, &mem);
+        for n in 9..11 {
+            assert_eq!(inb(&chan, REG_COMMAND), STATUS_OK | ATA_SR_DRQ);
+            write_sector(&chan, &data, &mem);
+            assert_eq!(bdev.process(&mem), 1);
+            assert_eq!(bdev.sector(n), data);
+        }
+        assert_eq!(inb(&chan, REG_COMMAND), STATUS_OK);
+        assert_eq!(bdev.sector(11), MemBdev::new(16).sector(11));
+    }
+
+    #[test]
+    fn dma_read() {
+        let tmem = TestMem::new(0x4000);
+        let mem = tmem.memctx();
+        let bdev = MemBdev::new(16);
+        let chan = disk_chan(&bdev);
+
+        // Two-entry PRD table at 0x1000, split across 0x2000 and 0x3000
+        let prdt: [u32; 4] = [0x2000, 0x100, 0x3000, 0x300 | PRD_EOT];
+        assert!(mem.write(GuestAddr(0x1000), &prdt));
+        let bm = |id: BmReg, val: u32| {
+            let buf = val.to_le_bytes();
+            let len = match id {
+                BmReg::Prdt(_) => 4,
+                _ => 1,
+            };
+            chan.bm_write(&id, &mut WriteOp::new_buf(0, &buf[..len]), &mem);
+        };
+        bm(BmReg::Prdt(0), 0x1000);
+
+        issue(&chan, ATA_CMD_READ_DMA, 3, 2, &mem);
+        bm(BmReg::Cmd(0), (BM_CMD_START | BM_CMD_TO_MEM) as u32);
+        assert_eq!(bdev.process(&mem), 1);
+        assert_eq!(inb(&chan, REG_COMMAND), STATUS_OK);
+
+        let mut buf = vec![0u8; 0x400];
+        mem.read_into(GuestAddr(0x2000), &mut buf[..0x100], 0x100);
+        mem.read_into(GuestAddr(0x3000), &mut buf[0x100..], 0x300);
+        assert_eq!(buf[..SECTOR_LEN], bdev.sector(3)[..]);
+        assert_eq!(buf[SECTOR_LEN..], bdev.sector(4)[..]);
+
+        let sts = chan.state.lock().unwrap().bm_sts;
+        assert_eq!(sts & (BM_STS_ACTIVE | BM_STS_INTR), BM_STS_INTR);
+    }
+
+    struct StubBdev;
+    impl BlockDev<Request> for StubBdev {
+        fn enqueue(&self, _req: Request) {}
+        fn inquire(&self) -> BlockInquiry {
+            BlockInquiry { total_size: 0x1000, block_size: 512, writable: true }
+        }
+    }
+
+    #[test]
+    fn signature() {
+        let disk = DriveState::new(IdeDrive::Disk(Arc::new(StubBdev)));
+        assert_eq!(disk.tf[REG_COUNT..=REG_LBA_HI], [1, 1, 0, 0]);
+        assert_eq!(disk.status, STATUS_OK);
+
+        let cd = DriveState::new(IdeDrive::Cdrom(Arc::new(StubBdev)));
+        assert_eq!(cd.tf[REG_COUNT..=REG_LBA_HI], [1, 1, 0x14, 0xeb]);
+        assert_eq!(cd.status, 0);
+    }
+
+    #[test]
+    fn addressing() {
+        let mut drive = DriveState::new(IdeDrive::Disk(Arc::new(StubBdev)));
+        drive.tf[REG_COUNT] = 0;
+        drive.tf[REG_LBA_LO] = 0x56;
+        drive.tf[REG_LBA_MID] = 0x34;
+        drive.tf[REG_LBA_HI] = 0x12;
+        assert_eq!(
+            drive.lba_count(DEV_LBA | 0x7, false),
+            Some((0x0712_3456, 256))
+        );
+
+        drive.hob[REG_COUNT] = 0x1;
+        drive.hob[REG_LBA_LO] = 0x9a;
+        drive.hob[REG_LBA_MID] = 0xbc;
+        drive.hob[REG_LBA_HI] = 0xde;
+        assert_eq!(
+            drive.lba_count(DEV_LBA, true),
+            Some((0xdebc_9a12_3456, 0x100))
+        );
+
+        // Cylinder 2, head 3, sector 4
+        drive.tf[REG_COUNT] = 1;
+        drive.tf[REG_LBA_LO] = 4;
+        drive.tf[REG_LBA_MID] = 2;
+        drive.tf[REG_LBA_HI] = 0;
+        assert_eq!(
+            drive.lba_count(0x3, false),
+            Some(((2 * CHS_HEADS + 3) * CHS_SECTORS + 3, 1))
+        );
+        drive.tf[REG_LBA_LO] = 0;
+        assert_eq!(drive.lba_count(0x3, false), None);
+    }
+}
@@ -1,7 +1,38 @@
+pub mod ahci;
+pub mod ata;
 pub mod chipset;
+pub mod i6300esb;
+pub mod ide;
 pub mod pci;
 pub mod ps2ctrl;
 pub mod qemu;
 pub mod rtc;
 pub mod uart;
 pub mod virtio;
+
+use crate::dispatch::DispCtx;
+
+/// Transitions in the state of an emulated device which are driven by the
+/// instance as a whole, rather than by the guest accessing the device.
+pub trait Lifecycle: Send + Sync {
+    /// Return the device to its power-on state, as part of an instance reset.
+    ///
+    /// This is called with the vCPUs stopped, after the in-kernel state of
+    /// the instance has been reinitialized.
+    #[allow(unused_variables)]
+    fn reset(&self, ctx: &DispCtx) {}
+    /// Note that the instance has woken from sleep (S3).
+    ///
+    /// As with [`Lifecycle::reset`], the in-kernel state of the instance has
+    /// been reinitialized, but the device is otherwise expected to retain its
+    /// state across the sleep.
+    #[allow(unused_variables)]
+    fn wake(&self, ctx: &DispCtx) {}
+    /// Stop any activity which proceeds independently of the guest, as the
+    /// instance has been paused (with its vCPUs stopped).
+    #[allow(unused_variables)]
+    fn pause(&self, ctx: &DispCtx) {}
+    /// Pick up where [`Lifecycle::pause`] left off, as the instance resumes.
+    #[allow(unused_variables)]
+    fn resume(&self, ctx: &DispCtx) {}
+}
@@ -7,6 +7,7 @@ use super::bits::*;
 use super::{Endpoint, INTxPinID};
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::hw::Lifecycle;
 use crate::intr_pins::IntrPin;
 use crate::mmio::MmioDev;
 use crate::pio::PioDev;
@@ -125,6 +126,10 @@ struct State {
     reg_intr_pin: u8,
 
     lintr_pin: Option<Arc<dyn IntrPin>>,
+    /// Level at which the device holds its INTx pin
+    intx_asserted: bool,
+    /// Deasserted-to-asserted transitions (and pulses) of the INTx pin
+    intx_count: u64,
 
     update_in_progress: bool,
 }
@@ -332,6 +337,16 @@ impl DeviceInst {
         }
     }
 
+    /// Snapshot of the interrupts raised by the device
+    pub fn intr_counts(&self) -> IntrCounts {
+        let intx = self.state.lock().unwrap().intx_count;
+        let msix = match self.msix_cfg.as_ref() {
+            Some(cfg) => cfg.counts(),
+            None => Vec::new(),
+        };
+        IntrCounts { intx, msix }
+    }
+
     /// State changes which result in a new interrupt mode for the device incur
     /// a notification which could trigger deadlock if normal lock-ordering was
     /// used.  In such cases, the process is done in two stages: the state
@@ -686,8 +701,8 @@ impl DeviceInst {
     fn notify_msi_update(&self, info: MsiUpdate, ctx: &DispCtx) {
         self.inner.msi_update(info, ctx);
     }
-    pub fn with_inner<T: 'static>(&self, f: impl FnOnce(&T)) {
-        f(Any::downcast_ref(self.inner_any.as_ref()).unwrap());
+    pub fn with_inner<T: 'static, R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(Any::downcast_ref(self.inner_any.as_ref()).unwrap())
     }
 }
 
@@ -736,6 +751,39 @@ impl Endpoint for DeviceInst {
     }
 }
 
+impl Lifecycle for DeviceInst {
+    fn reset(&self, ctx: &DispCtx) {
+        if let Some(msix) = self.msix_cfg.as_ref() {
+            msix.reset();
+        }
+
+        // With IO and MMIO decoding disabled, the BARs are unregistered, but
+        // keep their placement for the firmware to find (or reprogram).
+        let mut state = self.state.lock().unwrap();
+        let diff = state.reg_command ^ RegCmd::INTX_DIS;
+        self.update_bar_registration(diff, RegCmd::INTX_DIS, ctx);
+        if let Some(pin) = state.lintr_pin.as_ref() {
+            pin.deassert();
+        }
+        state.intx_asserted = false;
+        self.affects_intr_mode(state, |state| {
+            state.reg_command = RegCmd::INTX_DIS;
+            state.reg_intr_line = 0xff;
+        });
+
+        self.inner.reset(ctx);
+    }
+    fn wake(&self, ctx: &DispCtx) {
+        self.inner.wake(ctx);
+    }
+    fn pause(&self, ctx: &DispCtx) {
+        self.inner.pause(ctx);
+    }
+    fn resume(&self, ctx: &DispCtx) {
+        self.inner.resume(ctx);
+    }
+}
+
 impl PioDev for DeviceInst {
     fn pio_rw(&self, _port: u16, ident: usize, rwo: RWOp, ctx: &DispCtx) {
         self.bar_rw(ident, rwo, ctx);
@@ -762,18 +810,31 @@ impl INTxPin {
         Self { outer }
     }
     pub fn assert(&self) {
-        self.with_pin(|pin| pin.assert());
+        self.with_pin(|state| {
+            if !state.intx_asserted {
+                state.intx_asserted = true;
+                state.intx_count += 1;
+            }
+            state.lintr_pin.as_ref().unwrap().assert();
+        });
     }
     pub fn deassert(&self) {
-        self.with_pin(|pin| pin.deassert());
+        self.with_pin(|state| {
+            state.intx_asserted = false;
+            state.lintr_pin.as_ref().unwrap().deassert();
+        });
     }
     pub fn pulse(&self) {
-        self.with_pin(|pin| pin.pulse());
+        self.with_pin(|state| {
+            if !state.intx_asserted {
+                state.intx_count += 1;
+            }
+            state.lintr_pin.as_ref().unwrap().pulse();
+        });
     }
-    fn with_pin(&self, f: impl FnOnce(&dyn IntrPin)) {
+    fn with_pin(&self, f: impl FnOnce(&mut State)) {
         if let Some(dev) = Weak::upgrade(&self.outer) {
-            let state = dev.state.lock().unwrap();
-            f(state.lintr_pin.as_ref().unwrap().as_ref());
+            f(&mut dev.state.lock().unwrap());
         }
     }
 }
@@ -824,6 +885,19 @@ pub trait Device: Send + Sync + 'static {
     fn interrupt_mode_change(&self, mode: IntrMode) {}
     #[allow(unused_variables)]
     fn msi_update(&self, info: MsiUpdate, ctx: &DispCtx) {}
+    /// Reset device-specific state as part of an instance reset.  The standard
+    /// configuration space and MSI-X state have already been reset.
+    #[allow(unused_variables)]
+    fn reset(&self, ctx: &DispCtx) {}
+    /// Note that the instance has woken from sleep (S3)
+    #[allow(unused_variables)]
+    fn wake(&self, ctx: &DispCtx) {}
+    /// Stop any activity independent of the guest, as the instance is paused
+    #[allow(unused_variables)]
+    fn pause(&self, ctx: &DispCtx) {}
+    /// Resume activity stopped by [`Device::pause`]
+    #[allow(unused_variables)]
+    fn resume(&self, ctx: &DispCtx) {}
     // TODO
     // fn cap_read(&self);
     // fn cap_write(&self);
@@ -866,27 +940,36 @@ struct MsixEntry {
     mask_func: bool,
     enabled: bool,
     pending: bool,
+    counts: MsixCounts,
 }
 impl MsixEntry {
-    fn fire(&mut self, ctx: &DispCtx) {
+    /// Fire the vector, returning the message (address and data) to deliver,
+    /// unless it is disabled or its delivery is deferred by a mask.
+    fn fire(&mut self) -> Option<(u64, u32)> {
         if !self.enabled {
-            return;
+            return None;
         }
         if self.mask_func || self.mask_vec {
             self.pending = true;
-            return;
+            self.counts.deferred += 1;
+            return None;
         }
-        ctx.mctx.with_hdl(|hdl| {
-            hdl.lapic_msi(self.addr, self.data as u64).unwrap()
-        });
+        self.counts.delivered += 1;
+        Some((self.addr, self.data))
     }
-    fn check_mask(&mut self, ctx: &DispCtx) {
+    /// Return the message of a pending vector, if it is no longer masked
+    fn check_mask(&mut self) -> Option<(u64, u32)> {
         if !self.mask_vec && !self.mask_func && self.pending {
             self.pending = false;
-            ctx.mctx.with_hdl(|hdl| {
-                hdl.lapic_msi(self.addr, self.data as u64).unwrap()
-            });
+            self.counts.delivered += 1;
+            return Some((self.addr, self.data));
         }
+        None
+    }
+}
+fn send_msi(msg: Option<(u64, u32)>, ctx: &DispCtx) {
+    if let Some((addr, data)) = msg {
+        ctx.mctx.with_hdl(|hdl| hdl.lapic_msi(addr, data as u64).unwrap());
     }
 }
 
@@ -1009,7 +1092,7 @@ impl MsixCfg {
                         let mut ent = self.entries[*i as usize].lock().unwrap();
                         let val = wo.read_u32();
                         ent.mask_vec = val & MSIX_VEC_MASK != 0;
-                        ent.check_mask(ctx);
+                        send_msi(ent.check_mask(), ctx);
                         drop(ent);
                         updatef(MsiUpdate::Modify(*i));
                     }
@@ -1081,7 +1164,7 @@ impl MsixCfg {
                                 self.each_entry(|ent| {
                                     ent.mask_func = new_mask;
                                     ent.enabled = new_ena;
-                                    ent.check_mask(ctx);
+                                    send_msi(ent.check_mask(), ctx);
                                 });
                             }
                             state.enabled = new_ena;
@@ -1107,6 +1190,13 @@ impl MsixCfg {
             }
         });
     }
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = MsixCfgState::default();
+        self.each_entry(|ent| {
+            *ent = MsixEntry { counts: ent.counts, ..Default::default() };
+        });
+    }
     fn each_entry(&self, mut cb: impl FnMut(&mut MsixEntry)) {
         for ent in self.entries.iter() {
             let mut locked = ent.lock().unwrap();
@@ -1116,12 +1206,15 @@ impl MsixCfg {
     fn fire(&self, idx: u16, ctx: &DispCtx) {
         assert!(idx < self.count);
         let mut ent = self.entries[idx as usize].lock().unwrap();
-        ent.fire(ctx);
+        send_msi(ent.fire(), ctx);
     }
     fn is_enabled(&self) -> bool {
         let state = self.state.lock().unwrap();
         state.enabled
     }
+    fn counts(&self) -> Vec<MsixCounts> {
+        self.entries.iter().map(|ent| ent.lock().unwrap().counts).collect()
+    }
     fn read(&self, idx: u16) -> MsiEnt {
         assert!(idx < self.count);
         let ent = self.entries[idx as usize].lock().unwrap();
@@ -1134,6 +1227,24 @@ impl MsixCfg {
     }
 }
 
+/// Point-in-time counts of interrupts raised by a device
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct IntrCounts {
+    /// Assertions (or pulses) of the INTx pin
+    pub intx: u64,
+    /// Counts for each MSI-X vector, indexed by vector
+    pub msix: Vec<MsixCounts>,
+}
+
+/// Running count of messages for a single MSI-X vector
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MsixCounts {
+    /// Messages delivered to the guest
+    pub delivered: u64,
+    /// Messages held pending, as the vector (or function) was masked
+    pub deferred: u64,
+}
+
 // public struct for exposing MSI(-X) values
 pub struct MsiEnt {
     pub addr: u64,
@@ -1306,3 +1417,95 @@ impl<I: Device + 'static> Builder<I> {
         done
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intr_pins::LegacyPin;
+
+    struct TestDev;
+    impl Device for TestDev {
+        fn attach(&self, _: Option<INTxPin>, _: Option<MsixHdl>) {}
+    }
+
+    #[test]
+    fn intr_counts() {
+        let dev = Builder::new(Ident::default())
+            .add_lintr()
+            .add_cap_msix(BarN::BAR1, 2)
+            .finish(Arc::new(TestDev));
+        let pin = Arc::new(LegacyPin::detached(10));
+        dev.attach(&|| (INTxPinID::INTA, Arc::clone(&pin) as Arc<dyn IntrPin>));
+        assert_eq!(
+            dev.intr_counts(),
+            IntrCounts { intx: 0, msix: vec![MsixCounts::default(); 2] }
+        );
+
+        // Only assertions and pulses are counted
+        let intx = INTxPin::new(dev.self_weak());
+        intx.assert();
+        assert!(pin.is_asserted());
+        intx.deassert();
+        intx.pulse();
+        assert_eq!(dev.intr_counts().intx, 2);
+
+        // ... and only when the pin was not already held asserted
+        intx.assert();
+        intx.assert();
+        intx.pulse();
+        assert_eq!(dev.intr_counts().intx, 3);
+        intx.deassert();
+
+        let msix = dev.msix_cfg.as_ref().unwrap();
+        let mut ent = msix.entries[0].lock().unwrap();
+        // Disabled vectors are not counted
+        assert_eq!(ent.fire(), None);
+        ent.enabled = true;
+        ent.addr = 0xfee0_0000;
+        ent.data = 0x31;
+        assert_eq!(ent.fire(), Some((0xfee0_0000, 0x31)));
+        drop(ent);
+
+        // A masked vector is deferred, then delivered once unmasked
+        let mut ent = msix.entries[1].lock().unwrap();
+        ent.enabled = true;
+        ent.mask_vec = true;
+        assert_eq!(ent.fire(), None);
+        assert_eq!(ent.check_mask(), None);
+        ent.mask_vec = false;
+        assert_eq!(ent.check_mask(), Some((0, 0)));
+        drop(ent);
+
+        let counts = dev.intr_counts();
+        assert_eq!(counts.intx, 3);
+        assert_eq!(
+            counts.msix,
+            vec![
+                MsixCounts { delivered: 1, deferred: 0 },
+                MsixCounts { delivered: 1, deferred: 1 },
+            ]
+        );
+
+        // Counts persist across a reset of the MSI-X state
+        msix.reset();
+        assert_eq!(dev.intr_counts(), counts);
+    }
+
+    #[test]
+    fn intx_edges() {
+        let dev = Builder::new(Ident::default())
+            .add_lintr()
+            .finish(Arc::new(TestDev));
+        let pin = Arc::new(LegacyPin::detached(10));
+        dev.attach(&|| (INTxPinID::INTA, Arc::clone(&pin) as Arc<dyn IntrPin>));
+
+        // Re-asserting a pin which is already asserted is not a new interrupt
+        let intx = INTxPin::new(dev.self_weak());
+        intx.assert();
+        intx.assert();
+        assert_eq!(dev.intr_counts().intx, 1);
+        intx.deassert();
+        intx.assert();
+        assert_eq!(dev.intr_counts().intx, 2);
+    }
+}
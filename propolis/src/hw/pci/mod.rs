@@ -2,6 +2,7 @@ use std::sync::{Arc, Mutex};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::hw::Lifecycle;
 use crate::intr_pins::IntrPin;
 
 pub mod bits;
@@ -12,6 +13,10 @@ pub use device::*;
 pub const PORT_PCI_CONFIG_ADDR: u16 = 0xcf8;
 pub const PORT_PCI_CONFIG_DATA: u16 = 0xcfc;
 
+/// Size of an ECAM (PCIe enhanced configuration) region spanning 256 buses
+pub const LEN_ECAM: usize = 0x1000_0000;
+const LEN_ECAM_FUNC: usize = 0x1000;
+
 const MASK_FUNC: u8 = 0x07;
 const MASK_DEV: u8 = 0x1f;
 const MASK_BUS: u8 = 0xff;
@@ -48,7 +53,7 @@ impl BDF {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum INTxPinID {
     INTA = 1,
     INTB = 2,
@@ -56,7 +61,7 @@ pub enum INTxPinID {
     INTD = 4,
 }
 
-pub trait Endpoint: Send + Sync {
+pub trait Endpoint: Lifecycle {
     fn cfg_rw(&self, op: RWOp<'_, '_>, ctx: &DispCtx);
     fn attach(&self, get_lintr: &dyn Fn() -> (INTxPinID, Arc<dyn IntrPin>));
     fn bar_for_each(&self, cb: &mut dyn FnMut(BarN, &BarDefine));
@@ -157,6 +162,9 @@ impl PioCfgDecoder {
     pub fn new() -> Self {
         Self { addr: Mutex::new(0) }
     }
+    pub fn reset(&self) {
+        *self.addr.lock().unwrap() = 0;
+    }
     pub fn service_addr(&self, rwop: RWOp) {
         if rwop.len() != 4 || rwop.offset() != 0 {
             // XXX expect aligned/sized reads
@@ -194,3 +202,86 @@ impl PioCfgDecoder {
         }
     }
 }
+
+fn ecam_addr_parse(addr: usize) -> (BDF, usize) {
+    let func = (addr >> 12) as u8 & MASK_FUNC;
+    let device = (addr >> 15) as u8 & MASK_DEV;
+    let bus = (addr >> 20) as u8 & MASK_BUS;
+
+    (BDF::new(bus, device, func), addr & (LEN_ECAM_FUNC - 1))
+}
+
+/// Decode an access to the ECAM region, with `rwop` offset from its start, and
+/// pass it to `cb` if it falls within the conventional configuration space of
+/// a function.
+///
+/// The extended configuration space (beyond the first 256 bytes) of each
+/// function is empty: reads yield zeroes and writes are discarded.  Accesses
+/// to absent functions read as all-ones.
+pub fn ecam_service<F>(rwop: RWOp, mut cb: F)
+where
+    F: FnMut(&BDF, RWOp) -> Option<()>,
+{
+    let (bdf, off) = ecam_addr_parse(rwop.offset());
+    if off + rwop.len() > LEN_ECAM_FUNC {
+        // XXX expect accesses not to span functions
+        if let RWOp::Read(ro) = rwop {
+            ro.fill(0xff);
+        }
+        return;
+    }
+    let ext = off >= bits::LEN_CFG;
+    match rwop {
+        RWOp::Read(ro) if ext => ro.fill(0),
+        RWOp::Read(ro) => {
+            let mut cro = ReadOp::new_child(off, ro, ..);
+            if cb(&bdf, RWOp::Read(&mut cro)).is_none() {
+                cro.fill(0xff);
+            }
+        }
+        RWOp::Write(_) if ext => {}
+        RWOp::Write(wo) => {
+            let mut cwo = WriteOp::new_child(off, wo, ..);
+            let _ = cb(&bdf, RWOp::Write(&mut cwo));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ecam_decode() {
+        let read = |addr: usize, len: usize| {
+            let mut buf = vec![0u8; len];
+            let mut seen = None;
+            let mut ro = ReadOp::new_buf(addr, &mut buf);
+            ecam_service(RWOp::Read(&mut ro), |bdf, rwo| {
+                seen = Some((bdf.bus(), bdf.dev(), bdf.func(), rwo.offset()));
+                if bdf.dev() == 0 {
+                    if let RWOp::Read(ro) = rwo {
+                        ro.fill(0x5a);
+                    }
+                    Some(())
+                } else {
+                    None
+                }
+            });
+            (seen, buf)
+        };
+
+        let (seen, buf) = read(0, 4);
+        assert_eq!(seen, Some((0, 0, 0, 0)));
+        assert_eq!(buf, [0x5a; 4]);
+
+        let (seen, buf) = read((2 << 20) | (3 << 15) | (5 << 12) | 0x3c, 2);
+        assert_eq!(seen, Some((2, 3, 5, 0x3c)));
+        assert_eq!(buf, [0xff; 2]);
+
+        // Extended configuration space is empty
+        let (seen, buf) = read(0x100, 4);
+        assert_eq!(seen, None);
+        assert_eq!(buf, [0; 4]);
+    }
+}
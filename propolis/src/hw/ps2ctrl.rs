@@ -4,14 +4,15 @@ use std::sync::{Arc, Mutex, Weak};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::hw::Lifecycle;
 use crate::intr_pins::{LegacyPIC, LegacyPin};
 use crate::pio::{PioBus, PioDev};
 
-const PS2_PORT_DATA: u16 = 0x60;
-const PS2_PORT_CMD_STATUS: u16 = 0x64;
+pub(crate) const PS2_PORT_DATA: u16 = 0x60;
+pub(crate) const PS2_PORT_CMD_STATUS: u16 = 0x64;
 
-const PS2_IRQ_PRI: u8 = 1;
-const PS2_IRQ_AUX: u8 = 12;
+pub(crate) const PS2_IRQ_PRI: u8 = 1;
+pub(crate) const PS2_IRQ_AUX: u8 = 12;
 
 bitflags! {
     #[derive(Default)]
@@ -163,7 +164,7 @@ impl PS2Ctrl {
             0
         }
     }
-    fn cmd_write(&self, v: u8) {
+    fn cmd_write(&self, v: u8, ctx: &DispCtx) {
         let mut state = self.state.lock().unwrap();
         match v {
             PS2C_CMD_READ_CTRL_CFG => {
@@ -214,7 +215,13 @@ impl PS2Ctrl {
             PS2C_CMD_PULSE_START..=PS2C_CMD_PULSE_END => {
                 let to_pulse = v - PS2C_CMD_PULSE_START;
                 if to_pulse == 0xe {
-                    todo!("ps2-initiated reset");
+                    // Pulsing the reset line resets the whole instance
+                    let res = ctx.mctx.with_hdl(|hdl| {
+                        hdl.suspend(bhyve_api::vm_suspend_how::VM_SUSPEND_RESET)
+                    });
+                    if let Err(e) = res {
+                        println!("failed to reset instance: {}", e);
+                    }
                 }
             }
 
@@ -258,9 +265,33 @@ impl PS2Ctrl {
                 && state.aux_port.has_output(),
         );
     }
+
+    /// Queue scancodes (such as the make/break sequence for a key event) for
+    /// delivery to the guest via the primary port.
+    ///
+    /// Rather than stuffing the keyboard output buffer directly, the codes are
+    /// held in an input queue and fed to the guest one byte at a time as it
+    /// consumes them.  If the queue lacks room for the entire sequence, or the
+    /// guest has disabled scanning, none of it is queued and `false` is
+    /// returned.
+    pub fn key_input(&self, codes: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let queued = state.pri_port.key_input(codes);
+        self.update_intr(&mut state);
+        queued
+    }
+}
+impl Lifecycle for PS2Ctrl {
+    fn reset(&self, _ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        let pri_pin = state.pri_pin.take();
+        let aux_pin = state.aux_pin.take();
+        *state = PS2State { pri_pin, aux_pin, ..Default::default() };
+        self.update_intr(&mut state);
+    }
 }
 impl PioDev for PS2Ctrl {
-    fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, _ctx: &DispCtx) {
+    fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
         assert_eq!(rwo.len(), 1);
         match port {
             PS2_PORT_DATA => match rwo {
@@ -269,7 +300,7 @@ impl PioDev for PS2Ctrl {
             },
             PS2_PORT_CMD_STATUS => match rwo {
                 RWOp::Read(ro) => ro.write_u8(self.status_read()),
-                RWOp::Write(wo) => self.cmd_write(wo.read_u8()),
+                RWOp::Write(wo) => self.cmd_write(wo.read_u8(), ctx),
             },
             _ => {
                 panic!("unexpected pio in {:x}", port);
@@ -297,6 +328,7 @@ const PS2K_R_SELF_TEST_PASS: u8 = 0xaa;
 const PS2K_TYPEMATIC_MASK: u8 = 0x7f;
 
 const PS2_KBD_BUFSZ: usize = 16;
+const PS2_KBD_INPUT_BUFSZ: usize = 128;
 
 enum PS2ScanCodeSet {
     Set1,
@@ -314,6 +346,7 @@ impl PS2ScanCodeSet {
 
 struct PS2Kbd {
     buf: VecDeque<u8>,
+    input: VecDeque<u8>,
     cur_cmd: Option<u8>,
     enabled: bool,
     led_status: u8,
@@ -324,6 +357,7 @@ impl PS2Kbd {
     fn new() -> Self {
         Self {
             buf: VecDeque::with_capacity(PS2_KBD_BUFSZ),
+            input: VecDeque::with_capacity(PS2_KBD_INPUT_BUFSZ),
             cur_cmd: None,
             enabled: true,
             led_status: 0,
@@ -387,7 +421,9 @@ impl PS2Kbd {
                     self.resp(PS2K_R_ACK);
                 }
                 PS2K_CMD_SCAN_DIS => {
+                    // A disabled keyboard discards keys not yet delivered
                     self.enabled = false;
+                    self.input.clear();
                     self.resp(PS2K_R_ACK);
                 }
                 PS2K_CMD_SET_DEFAULT => {
@@ -432,12 +468,34 @@ impl PS2Kbd {
         self.typematic = 0;
         self.scan_code_set = PS2ScanCodeSet::Set1;
         self.buf.clear();
+        self.input.clear();
+    }
+    fn key_input(&mut self, codes: &[u8]) -> bool {
+        if !self.enabled || self.input.len() + codes.len() > PS2_KBD_INPUT_BUFSZ
+        {
+            return false;
+        }
+        self.input.extend(codes);
+        self.refill_output();
+        true
+    }
+    fn refill_output(&mut self) {
+        // Like the real controller, only expose a single byte of key input at
+        // a time, leaving the guest to pace delivery as it reads.  Any pending
+        // command responses are emitted first.
+        if self.buf.is_empty() {
+            if let Some(v) = self.input.pop_front() {
+                self.buf.push_back(v);
+            }
+        }
     }
     fn has_output(&self) -> bool {
         !self.buf.is_empty()
     }
     fn read_output(&mut self) -> Option<u8> {
-        self.buf.pop_front()
+        let res = self.buf.pop_front();
+        self.refill_output();
+        res
     }
     fn loopback(&mut self, v: u8) {
         self.resp(v);
@@ -627,3 +685,57 @@ impl Default for PS2Mouse {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kbd_input_fill() {
+        let mut kbd = PS2Kbd::new();
+
+        // make/break pairs up to the capacity of the input queue
+        let pairs = PS2_KBD_INPUT_BUFSZ / 2;
+        for i in 0..pairs {
+            let code = (i % 0x58) as u8 + 1;
+            assert!(kbd.key_input(&[code, code | 0x80]));
+        }
+        // no room remains for an additional event
+        assert!(!kbd.key_input(&[0x1e, 0x9e]));
+
+        let mut drained = Vec::new();
+        while let Some(v) = kbd.read_output() {
+            drained.push(v);
+        }
+        assert_eq!(drained.len(), PS2_KBD_INPUT_BUFSZ);
+        for (i, pair) in drained.chunks(2).enumerate() {
+            let code = (i % 0x58) as u8 + 1;
+            assert_eq!(pair, &[code, code | 0x80]);
+        }
+
+        // once drained, input is accepted again
+        assert!(kbd.key_input(&[0x1e, 0x9e]));
+    }
+
+    #[test]
+    fn kbd_input_disabled() {
+        let mut kbd = PS2Kbd::new();
+        assert!(kbd.key_input(&[0x1e, 0x9e, 0x30, 0xb0]));
+        assert_eq!(kbd.read_output(), Some(0x1e));
+
+        // Disabling scanning discards the keys still queued ...
+        kbd.cmd_input(PS2K_CMD_SCAN_DIS);
+        assert_eq!(kbd.read_output(), Some(0x9e));
+        assert_eq!(kbd.read_output(), Some(PS2K_R_ACK));
+        assert_eq!(kbd.read_output(), None);
+
+        // ... and drops any further input
+        assert!(!kbd.key_input(&[0x1e, 0x9e]));
+        assert_eq!(kbd.read_output(), None);
+
+        kbd.cmd_input(PS2K_CMD_SCAN_EN);
+        assert_eq!(kbd.read_output(), Some(PS2K_R_ACK));
+        assert!(kbd.key_input(&[0x1e, 0x9e]));
+        assert_eq!(kbd.read_output(), Some(0x1e));
+    }
+}
@@ -0,0 +1,441 @@
+//! Minimal encoder for the subset of AML needed to describe the machine in the
+//! DSDT.  Each function yields the encoded bytes of a single term, which are
+//! composed by passing them as the arguments (or body) of enclosing terms.
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const AND_OP: u8 = 0x7b;
+const OR_OP: u8 = 0x7d;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8a;
+const LLESS_OP: u8 = 0x95;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const RETURN_OP: u8 = 0xa4;
+
+// Extended (0x5b-prefixed) opcodes
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+
+/// Address space of an OperationRegion
+#[allow(unused)]
+#[derive(Copy, Clone)]
+pub enum RegionSpace {
+    SystemMemory = 0,
+    SystemIO = 1,
+    PciConfig = 2,
+}
+
+/// Encode a package length, which (as used for the extent of a term) includes
+/// the bytes of its own encoding.
+fn pkg_length(payload: usize) -> Vec<u8> {
+    if payload + 1 < 1 << 6 {
+        return vec![(payload + 1) as u8];
+    }
+    for nbytes in 2..=4 {
+        let total = payload + nbytes;
+        if total < 1 << (4 + 8 * (nbytes - 1)) {
+            let mut out = vec![((nbytes - 1) << 6 | (total & 0xf)) as u8];
+            for n in 1..nbytes {
+                out.push((total >> (4 + 8 * (n - 1))) as u8);
+            }
+            return out;
+        }
+    }
+    panic!("AML package too large: {}", payload);
+}
+
+/// A term with a package length, covering everything following its opcode
+fn pkg(op: &[u8], contents: Vec<u8>) -> Vec<u8> {
+    let mut out = op.to_vec();
+    out.extend(pkg_length(contents.len()));
+    out.extend(contents);
+    out
+}
+
+fn name_seg(seg: &str) -> [u8; 4] {
+    assert!(!seg.is_empty() && seg.len() <= 4, "bad name segment {}", seg);
+    let mut out = [b'_'; 4];
+    out[..seg.len()].copy_from_slice(seg.as_bytes());
+    out
+}
+
+/// Encode a name (or path), such as `LNKA`, `\_SB.PCI0` or `^ISA.P40C`
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = path;
+    if let Some(r) = rest.strip_prefix('\\') {
+        out.push(ROOT_CHAR);
+        rest = r;
+    }
+    while let Some(r) = rest.strip_prefix('^') {
+        out.push(PARENT_PREFIX_CHAR);
+        rest = r;
+    }
+    let segs: Vec<&str> = rest.split('.').filter(|s| !s.is_empty()).collect();
+    match segs.len() {
+        0 => out.push(ZERO_OP),
+        1 => {}
+        2 => out.push(DUAL_NAME_PREFIX),
+        n => {
+            out.push(MULTI_NAME_PREFIX);
+            out.push(n as u8);
+        }
+    }
+    for seg in segs {
+        out.extend_from_slice(&name_seg(seg));
+    }
+    out
+}
+
+pub fn int(val: u64) -> Vec<u8> {
+    match val {
+        0 => vec![ZERO_OP],
+        1 => vec![ONE_OP],
+        v if v <= u8::MAX as u64 => vec![BYTE_PREFIX, v as u8],
+        v if v <= u16::MAX as u64 => {
+            let mut out = vec![WORD_PREFIX];
+            out.extend_from_slice(&(v as u16).to_le_bytes());
+            out
+        }
+        v if v <= u32::MAX as u64 => {
+            let mut out = vec![DWORD_PREFIX];
+            out.extend_from_slice(&(v as u32).to_le_bytes());
+            out
+        }
+        v => {
+            let mut out = vec![QWORD_PREFIX];
+            out.extend_from_slice(&v.to_le_bytes());
+            out
+        }
+    }
+}
+
+pub fn string(val: &str) -> Vec<u8> {
+    let mut out = vec![STRING_PREFIX];
+    out.extend_from_slice(val.as_bytes());
+    out.push(0);
+    out
+}
+
+/// Compressed EISA ID (such as `PNP0A03`), as an integer
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let b = id.as_bytes();
+    assert_eq!(b.len(), 7, "bad EISA ID {}", id);
+    let c = |n: usize| (b[n] - 0x40) & 0x1f;
+    let h = |n: usize| (b[n] as char).to_digit(16).unwrap() as u8;
+    let bytes = [
+        c(0) << 2 | c(1) >> 3,
+        c(1) << 5 | c(2),
+        h(3) << 4 | h(4),
+        h(5) << 4 | h(6),
+    ];
+    int(u32::from_le_bytes(bytes) as u64)
+}
+
+pub fn arg(n: u8) -> Vec<u8> {
+    assert!(n < 7);
+    vec![ARG0_OP + n]
+}
+
+/// Invocation of the method at `path`
+pub fn call(path: &str, args: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut out = name_string(path);
+    out.extend(args.concat());
+    out
+}
+
+pub fn name(path: &str, val: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![NAME_OP];
+    out.extend(name_string(path));
+    out.extend(val);
+    out
+}
+
+pub fn scope(path: &str, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut contents = name_string(path);
+    contents.extend(body.concat());
+    pkg(&[SCOPE_OP], contents)
+}
+
+pub fn device(path: &str, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut contents = name_string(path);
+    contents.extend(body.concat());
+    pkg(&[EXT_OP_PREFIX, DEVICE_OP], contents)
+}
+
+pub fn method(
+    path: &str,
+    nargs: u8,
+    serialized: bool,
+    body: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    assert!(nargs < 8);
+    let mut contents = name_string(path);
+    contents.push(nargs | (serialized as u8) << 3);
+    contents.extend(body.concat());
+    pkg(&[METHOD_OP], contents)
+}
+
+pub fn buffer(data: &[u8]) -> Vec<u8> {
+    let mut contents = int(data.len() as u64);
+    contents.extend_from_slice(data);
+    pkg(&[BUFFER_OP], contents)
+}
+
+pub fn package(elems: Vec<Vec<u8>>) -> Vec<u8> {
+    assert!(elems.len() <= u8::MAX as usize);
+    let mut contents = vec![elems.len() as u8];
+    contents.extend(elems.concat());
+    pkg(&[PACKAGE_OP], contents)
+}
+
+pub fn op_region(
+    path: &str,
+    space: RegionSpace,
+    offset: u64,
+    len: u64,
+) -> Vec<u8> {
+    let mut out = vec![EXT_OP_PREFIX, OP_REGION_OP];
+    out.extend(name_string(path));
+    out.push(space as u8);
+    out.extend(int(offset));
+    out.extend(int(len));
+    out
+}
+
+/// Field with byte access, no locking, and the preserve update rule, listing
+/// the name and width (in bits) of each of its units.
+pub fn field(region: &str, units: &[(&str, u8)]) -> Vec<u8> {
+    const BYTE_ACC: u8 = 1;
+
+    let mut contents = name_string(region);
+    contents.push(BYTE_ACC);
+    for (unit, bits) in units {
+        // Widths are encoded as a package length, sans the self-inclusion
+        assert!(*bits < 1 << 6);
+        contents.extend_from_slice(&name_seg(unit));
+        contents.push(*bits);
+    }
+    pkg(&[EXT_OP_PREFIX, FIELD_OP], contents)
+}
+
+pub fn if_(pred: Vec<u8>, body: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut contents = pred;
+    contents.extend(body.concat());
+    pkg(&[IF_OP], contents)
+}
+pub fn else_(body: Vec<Vec<u8>>) -> Vec<u8> {
+    pkg(&[ELSE_OP], body.concat())
+}
+pub fn ret(val: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![RETURN_OP];
+    out.extend(val);
+    out
+}
+pub fn store(src: Vec<u8>, dst: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![STORE_OP];
+    out.extend(src);
+    out.extend(dst);
+    out
+}
+
+fn binop(op: u8, a: Vec<u8>, b: Vec<u8>, target: Option<Vec<u8>>) -> Vec<u8> {
+    let mut out = vec![op];
+    out.extend(a);
+    out.extend(b);
+    out.extend(target.unwrap_or_else(|| vec![ZERO_OP]));
+    out
+}
+pub fn and(a: Vec<u8>, b: Vec<u8>, target: Option<Vec<u8>>) -> Vec<u8> {
+    binop(AND_OP, a, b, target)
+}
+pub fn or(a: Vec<u8>, b: Vec<u8>, target: Option<Vec<u8>>) -> Vec<u8> {
+    binop(OR_OP, a, b, target)
+}
+pub fn lless(a: Vec<u8>, b: Vec<u8>) -> Vec<u8> {
+    let mut out = vec![LLESS_OP];
+    out.extend(a);
+    out.extend(b);
+    out
+}
+
+pub fn create_dword_field(buf: Vec<u8>, index: u64, path: &str) -> Vec<u8> {
+    let mut out = vec![CREATE_DWORD_FIELD_OP];
+    out.extend(buf);
+    out.extend(int(index));
+    out.extend(name_string(path));
+    out
+}
+
+/// Builder for a resource template: a buffer of resource descriptors
+#[derive(Default)]
+pub struct Resources {
+    data: Vec<u8>,
+}
+impl Resources {
+    // General flags for address space descriptors: a producer (bridge window)
+    // with both minimum and maximum fixed
+    const ADDR_WINDOW: u8 = 0x0c;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn large(&mut self, tag: u8, body: &[u8]) -> &mut Self {
+        self.data.push(tag);
+        self.data.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        self.data.extend_from_slice(body);
+        self
+    }
+
+    /// IO port range, with 16-bit decode
+    pub fn io(&mut self, base: u16, len: u8) -> &mut Self {
+        self.data.extend_from_slice(&[0x47, 0x01]);
+        self.data.extend_from_slice(&base.to_le_bytes());
+        self.data.extend_from_slice(&base.to_le_bytes());
+        self.data.extend_from_slice(&[0x01, len]);
+        self
+    }
+    /// ISA IRQ (edge triggered, active high)
+    pub fn irq_noflags(&mut self, irq: u8) -> &mut Self {
+        assert!(irq < 16);
+        self.data.push(0x22);
+        self.data.extend_from_slice(&(1u16 << irq).to_le_bytes());
+        self
+    }
+    /// Shareable, level-triggered, active-high interrupts
+    pub fn interrupt(&mut self, irqs: &[u32]) -> &mut Self {
+        // consumer, level, active high, shared
+        let mut body = vec![0x09, irqs.len() as u8];
+        for irq in irqs {
+            body.extend_from_slice(&irq.to_le_bytes());
+        }
+        self.large(0x89, &body)
+    }
+    pub fn memory32_fixed(&mut self, base: u32, len: u32) -> &mut Self {
+        let mut body = vec![0x01];
+        body.extend_from_slice(&base.to_le_bytes());
+        body.extend_from_slice(&len.to_le_bytes());
+        self.large(0x86, &body)
+    }
+    fn word_space(&mut self, kind: u8, tflags: u8, min: u16, max: u16) {
+        let mut body = vec![kind, Self::ADDR_WINDOW, tflags];
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            body.extend_from_slice(&v.to_le_bytes());
+        }
+        self.large(0x88, &body);
+    }
+    pub fn bus_numbers(&mut self, min: u16, max: u16) -> &mut Self {
+        self.word_space(2, 0, min, max);
+        self
+    }
+    /// Window of IO ports, inclusive of `max`
+    pub fn io_window(&mut self, min: u16, max: u16) -> &mut Self {
+        // entire (ISA and non-ISA) range
+        self.word_space(1, 0x03, min, max);
+        self
+    }
+    /// Window of (cacheable, read-write) 32-bit memory, inclusive of `max`
+    pub fn mem32_window(&mut self, min: u32, max: u32) -> &mut Self {
+        let mut body = vec![0, Self::ADDR_WINDOW, 0x03];
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            body.extend_from_slice(&v.to_le_bytes());
+        }
+        self.large(0x87, &body)
+    }
+    /// Window of (cacheable, read-write) 64-bit memory, inclusive of `max`
+    pub fn mem64_window(&mut self, min: u64, max: u64) -> &mut Self {
+        let mut body = vec![0, Self::ADDR_WINDOW, 0x03];
+        for v in [0, min, max, 0, max - min + 1].iter() {
+            body.extend_from_slice(&v.to_le_bytes());
+        }
+        self.large(0x8a, &body)
+    }
+    /// Resource template, as a buffer ending with the end tag
+    pub fn finish(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        // A zero checksum is treated as valid
+        data.extend_from_slice(&[0x79, 0]);
+        buffer(&data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(name_string("PCI0"), b"PCI0");
+        assert_eq!(name_string("_SB"), b"_SB_");
+        assert_eq!(name_string("\\_SB.PCI0"), b"\\\x2e_SB_PCI0");
+        assert_eq!(name_string("\\_SB.PCI0.ISA"), b"\\\x2f\x03_SB_PCI0ISA_");
+        assert_eq!(name_string("^ISA.P40C"), b"^\x2eISA_P40C");
+        assert_eq!(name_string("\\"), b"\\\x00");
+    }
+
+    #[test]
+    fn pkg_lengths() {
+        assert_eq!(pkg_length(0), [0x01]);
+        assert_eq!(pkg_length(0x3e), [0x3f]);
+        assert_eq!(pkg_length(0x3f), [0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), [0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), [0x81, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn data() {
+        assert_eq!(int(0), [ZERO_OP]);
+        assert_eq!(int(1), [ONE_OP]);
+        assert_eq!(int(0x80), [BYTE_PREFIX, 0x80]);
+        assert_eq!(int(0x1234), [WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(eisa_id("PNP0A03"), int(0x030a_d041));
+        assert_eq!(eisa_id("PNP0C0F"), int(0x0f0c_d041));
+        assert_eq!(string("ab"), [STRING_PREFIX, b'a', b'b', 0]);
+    }
+
+    #[test]
+    fn terms() {
+        // Name (_S5, Package () { Zero, Zero })
+        assert_eq!(
+            name("_S5", package(vec![int(0), int(0)])),
+            [0x08, b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x00]
+        );
+        // Method (_DIS) { Or (PRQ0, 0x80, PRQ0) }
+        assert_eq!(
+            method(
+                "_DIS",
+                0,
+                false,
+                vec![or(
+                    name_string("PRQ0"),
+                    int(0x80),
+                    Some(name_string("PRQ0"))
+                )]
+            ),
+            b"\x14\x11_DIS\x00\x7dPRQ0\x0a\x80PRQ0"
+        );
+        // ResourceTemplate () { IRQNoFlags () {4} }
+        assert_eq!(
+            Resources::new().irq_noflags(4).finish(),
+            [0x11, 0x08, 0x0a, 0x05, 0x22, 0x10, 0x00, 0x79, 0x00]
+        );
+    }
+}
@@ -0,0 +1,897 @@
+//! ACPI tables describing the machine (with an i440fx or Q35 chipset), as
+//! conveyed to firmware through fw_cfg.
+//!
+//! The tables are held in the `etc/acpi/tables` file, and the RSDP in
+//! `etc/acpi/rsdp`.  Commands in `etc/table-loader` direct the firmware to
+//! allocate those in guest memory, patch in the pointers between the tables
+//! (which are held as offsets into the files), and then compute checksums.
+//!
+//! For the Q35, an MCFG table describes its ECAM region for PCI configuration.
+//! The i440fx lacks one, so no MCFG table is provided there.
+
+use byteorder::{ByteOrder, LE};
+
+use super::fwcfg::{self, FixedItem, FwCfgBuilder};
+use super::pvpanic;
+use crate::hw::chipset::i440fx::{
+    PIR_OFFSET, PMBASE_DEFAULT, PMBASE_LEN, PM_TMR_OFF, PORT_RST_CTRL,
+    RST_CTRL_RESET, SCI_IRQ,
+};
+use crate::hw::chipset::q35::{
+    ECAM_BASE, ICH9_FIRST_SLOT, PIRQA_OFFSET, PIRQE_OFFSET, PIRQ_COUNT,
+    PIRQ_LEN,
+};
+use crate::hw::pci::LEN_ECAM;
+use crate::hw::ps2ctrl::{
+    PS2_IRQ_AUX, PS2_IRQ_PRI, PS2_PORT_CMD_STATUS, PS2_PORT_DATA,
+};
+use crate::vmm::{CpuTopology, MAX_PHYSMEM, MAX_SYSMEM};
+
+pub mod aml;
+
+const FILE_RSDP: &str = "etc/acpi/rsdp";
+const FILE_TABLES: &str = "etc/acpi/tables";
+const FILE_LOADER: &str = "etc/table-loader";
+const FILE_SYSTEM_STATES: &str = "etc/system-states";
+
+const OEM_ID: &[u8; 6] = b"PRPLIS";
+const OEM_TABLE_ID: &[u8; 8] = b"PROPOLIS";
+const CREATOR_ID: &[u8; 4] = b"PRPL";
+
+const HDR_LEN: usize = 36;
+const HDR_CHECKSUM: usize = 9;
+
+const LAPIC_ADDR: u32 = 0xfee0_0000;
+const IOAPIC_ADDR: u32 = 0xfec0_0000;
+const HPET_ADDR: u32 = 0xfed0_0000;
+
+// Registers within the PIIX4 PM block
+const PM1_EVT_OFF: u16 = 0x0;
+const PM1_CNT_OFF: u16 = 0x4;
+const GPE0_OFF: u16 = 0xc;
+
+/// ISA IRQs offered for routing of the PCI interrupt links
+const LNK_IRQS: [u32; 3] = [5, 10, 11];
+
+/// Chipset of the machine, which determines how its PCI host bridge and
+/// interrupt routing are described
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AcpiChipset {
+    #[default]
+    I440fx,
+    Q35,
+}
+
+/// Details of the machine reflected in the tables
+#[derive(Clone, Debug, Default)]
+pub struct AcpiParams {
+    pub chipset: AcpiChipset,
+    pub topology: CpuTopology,
+    /// UARTs to describe (as COM1 onward), by IO port and IRQ
+    pub uarts: Vec<(u16, u8)>,
+    /// Capabilities of the HPET, if one is to be described
+    pub hpet_cap: Option<u32>,
+    /// Describe the pvpanic device, so the guest can find it
+    pub pvpanic: bool,
+    /// Offer the S3 (suspend-to-RAM) sleep state to the guest
+    pub s3: bool,
+}
+
+/// Commands to the firmware table loader, each occupying 128 bytes
+enum LoaderCmd {
+    Allocate { file: &'static str, align: u32, fseg: bool },
+    AddPointer { dest: &'static str, src: &'static str, off: u32, size: u8 },
+    AddChecksum { file: &'static str, result: u32, start: u32, len: u32 },
+}
+impl LoaderCmd {
+    const SIZE: usize = 128;
+    const FILE_LEN: usize = 56;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut buf = [0u8; Self::SIZE];
+        let file = |buf: &mut [u8], name: &str| {
+            assert!(name.len() < Self::FILE_LEN);
+            buf[..name.len()].copy_from_slice(name.as_bytes());
+        };
+        match self {
+            LoaderCmd::Allocate { file: name, align, fseg } => {
+                LE::write_u32(&mut buf[0..4], 1);
+                file(&mut buf[4..60], name);
+                LE::write_u32(&mut buf[60..64], *align);
+                // zone: high memory (1) or the f-segment (2)
+                buf[64] = if *fseg { 2 } else { 1 };
+            }
+            LoaderCmd::AddPointer { dest, src, off, size } => {
+                LE::write_u32(&mut buf[0..4], 2);
+                file(&mut buf[4..60], dest);
+                file(&mut buf[60..116], src);
+                LE::write_u32(&mut buf[116..120], *off);
+                buf[120] = *size;
+            }
+            LoaderCmd::AddChecksum { file: name, result, start, len } => {
+                LE::write_u32(&mut buf[0..4], 3);
+                file(&mut buf[4..60], name);
+                LE::write_u32(&mut buf[60..64], *result);
+                LE::write_u32(&mut buf[64..68], *start);
+                LE::write_u32(&mut buf[68..72], *len);
+            }
+        }
+        out.extend_from_slice(&buf);
+    }
+}
+
+/// Standard table header, with the length and checksum yet to be filled
+fn header(sig: &[u8; 4], rev: u8, len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    out[0..4].copy_from_slice(sig);
+    LE::write_u32(&mut out[4..8], len as u32);
+    out[8] = rev;
+    out[10..16].copy_from_slice(OEM_ID);
+    out[16..24].copy_from_slice(OEM_TABLE_ID);
+    LE::write_u32(&mut out[24..28], 1);
+    out[28..32].copy_from_slice(CREATOR_ID);
+    LE::write_u32(&mut out[32..36], 1);
+    out
+}
+fn set_len(table: &mut [u8]) {
+    let len = table.len() as u32;
+    LE::write_u32(&mut table[4..8], len);
+}
+
+/// Generic Address Structure for a register in IO space
+fn gas_io(buf: &mut [u8], port: u16, len: u8) {
+    buf[0] = 1;
+    buf[1] = len * 8;
+    LE::write_u64(&mut buf[4..12], port as u64);
+}
+
+/// The `etc/acpi/tables` file under construction, and the loader commands
+/// needed to fix up the pointers and checksums within it.
+#[derive(Default)]
+struct Tables {
+    data: Vec<u8>,
+    pointers: Vec<LoaderCmd>,
+    checksums: Vec<LoaderCmd>,
+}
+impl Tables {
+    fn add(&mut self, table: Vec<u8>, align: usize, checksum: bool) -> u32 {
+        let pad = (align - self.data.len() % align) % align;
+        self.data.resize(self.data.len() + pad, 0);
+        let off = self.data.len() as u32;
+        if checksum {
+            self.checksums.push(LoaderCmd::AddChecksum {
+                file: FILE_TABLES,
+                result: off + HDR_CHECKSUM as u32,
+                start: off,
+                len: table.len() as u32,
+            });
+        }
+        self.data.extend(table);
+        off
+    }
+    /// Point the field at `off` (of `size` bytes) to the table at `target`
+    fn pointer(&mut self, off: u32, size: u8, target: u32) {
+        let field = &mut self.data[off as usize..][..size as usize];
+        match size {
+            4 => LE::write_u32(field, target),
+            8 => LE::write_u64(field, target as u64),
+            _ => panic!("bad pointer size {}", size),
+        }
+        self.pointers.push(LoaderCmd::AddPointer {
+            dest: FILE_TABLES,
+            src: FILE_TABLES,
+            off,
+            size,
+        });
+    }
+}
+
+fn facs() -> Vec<u8> {
+    let mut out = vec![0u8; 64];
+    out[0..4].copy_from_slice(b"FACS");
+    LE::write_u32(&mut out[4..8], 64);
+    // version
+    out[32] = 2;
+    out
+}
+
+/// FADT (revision 5), with pointers to the FACS and DSDT yet to be added
+fn fadt() -> Vec<u8> {
+    const FLAG_WBINVD: u32 = 1 << 0;
+    const FLAG_PROC_C1: u32 = 1 << 2;
+    const FLAG_SLP_BUTTON: u32 = 1 << 5;
+    const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+    // Legacy devices and an 8042 are present
+    const BOOT_ARCH: u16 = 0x3;
+
+    let mut out = header(b"FACP", 5, 268);
+    let pm = |off| PMBASE_DEFAULT + off;
+
+    LE::write_u16(&mut out[46..48], SCI_IRQ as u16);
+    LE::write_u32(&mut out[56..60], pm(PM1_EVT_OFF) as u32);
+    LE::write_u32(&mut out[64..68], pm(PM1_CNT_OFF) as u32);
+    LE::write_u32(&mut out[76..80], pm(PM_TMR_OFF) as u32);
+    LE::write_u32(&mut out[80..84], pm(GPE0_OFF) as u32);
+    out[88] = 4;
+    out[89] = 2;
+    out[91] = 4;
+    out[92] = 4;
+    // C2 and C3 are not supported
+    LE::write_u16(&mut out[96..98], 0x0fff);
+    LE::write_u16(&mut out[98..100], 0x0fff);
+    LE::write_u16(&mut out[109..111], BOOT_ARCH);
+    LE::write_u32(
+        &mut out[112..116],
+        FLAG_WBINVD | FLAG_PROC_C1 | FLAG_SLP_BUTTON | FLAG_RESET_REG_SUP,
+    );
+    gas_io(&mut out[116..128], PORT_RST_CTRL, 1);
+    out[128] = RST_CTRL_RESET;
+
+    gas_io(&mut out[148..160], pm(PM1_EVT_OFF), 4);
+    gas_io(&mut out[172..184], pm(PM1_CNT_OFF), 2);
+    gas_io(&mut out[208..220], pm(PM_TMR_OFF), 4);
+    gas_io(&mut out[220..232], pm(GPE0_OFF), 4);
+    out
+}
+
+fn madt(params: &AcpiParams) -> Vec<u8> {
+    const PCAT_COMPAT: u32 = 1;
+
+    let mut out = header(b"APIC", 3, HDR_LEN + 8);
+    LE::write_u32(&mut out[36..40], LAPIC_ADDR);
+    LE::write_u32(&mut out[40..44], PCAT_COMPAT);
+
+    // Processor UIDs and APIC IDs match the vCPU IDs.  The first thread of
+    // each core is listed ahead of any others, as the ACPI spec advises, so an
+    // OS limiting the processors it uses will favor distinct cores.
+    let topo = &params.topology;
+    let mut cpus: Vec<u8> = (0..topo.cpus()).collect();
+    cpus.sort_by_key(|cpu| topo.position(*cpu).2);
+    for cpu in cpus {
+        // processor UID and APIC ID, enabled
+        out.extend_from_slice(&[0, 8, cpu, cpu, 1, 0, 0, 0]);
+    }
+    // The IOAPIC is given ID 0, as bhyve has customarily done
+    out.extend_from_slice(&[1, 12, 0, 0]);
+    out.extend_from_slice(&IOAPIC_ADDR.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+
+    // The PIT is wired to IOAPIC pin 2, and the SCI is level-triggered
+    for (irq, gsi, flags) in
+        [(0u8, 2u32, 0u16), (SCI_IRQ, SCI_IRQ as u32, 0xd)].iter()
+    {
+        out.extend_from_slice(&[2, 10, 0, *irq]);
+        out.extend_from_slice(&gsi.to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+    }
+    // LINT1 of all processors is NMI
+    out.extend_from_slice(&[4, 6, 0xff, 0, 0, 1]);
+
+    set_len(&mut out);
+    out
+}
+
+fn hpet(cap: u32) -> Vec<u8> {
+    let mut out = header(b"HPET", 1, HDR_LEN + 20);
+    LE::write_u32(&mut out[36..40], cap);
+    // base address, in memory space
+    out[40] = 0;
+    out[41] = 64;
+    LE::write_u64(&mut out[44..52], HPET_ADDR as u64);
+    out
+}
+
+/// MCFG, describing the ECAM region of the Q35 for segment 0
+fn mcfg() -> Vec<u8> {
+    let mut out = header(b"MCFG", 1, HDR_LEN + 8 + 16);
+    let ent = &mut out[HDR_LEN + 8..];
+    LE::write_u64(&mut ent[0..8], ECAM_BASE as u64);
+    // segment 0, buses 0-255
+    ent[10] = 0;
+    ent[11] = ((LEN_ECAM >> 20) - 1) as u8;
+    out
+}
+
+fn dsdt(params: &AcpiParams) -> Vec<u8> {
+    use aml::*;
+
+    let q35 = params.chipset == AcpiChipset::Q35;
+    let links = if q35 { PIRQ_COUNT } else { 4 };
+    let lnk = |n: usize| format!("LNK{}", (b'A' + n as u8) as char);
+    let prq = |n: usize| format!("PRQ{}", n);
+
+    let isa_dev = |name: &str, hid: &str, res: &mut Resources| {
+        device(
+            name,
+            vec![
+                aml::name("_HID", eisa_id(hid)),
+                aml::name("_CRS", res.finish()),
+            ],
+        )
+    };
+    let uart = |n: usize, port: u16, irq: u8| {
+        device(
+            &format!("COM{}", n),
+            vec![
+                aml::name("_HID", eisa_id("PNP0501")),
+                aml::name("_UID", int(n as u64)),
+                aml::name(
+                    "_CRS",
+                    Resources::new().io(port, 8).irq_noflags(irq).finish(),
+                ),
+            ],
+        )
+    };
+    let mut isa_devs = match q35 {
+        false => vec![
+            aml::name("_ADR", int(0x0001_0000)),
+            op_region("P40C", RegionSpace::PciConfig, PIR_OFFSET as u64, 4),
+        ],
+        true => vec![
+            aml::name("_ADR", int(0x001f_0000)),
+            op_region(
+                "P60C",
+                RegionSpace::PciConfig,
+                PIRQA_OFFSET as u64,
+                PIRQ_LEN as u64,
+            ),
+            op_region(
+                "P68C",
+                RegionSpace::PciConfig,
+                PIRQE_OFFSET as u64,
+                PIRQ_LEN as u64,
+            ),
+        ],
+    };
+    isa_devs.extend(vec![
+        isa_dev(
+            "PIC",
+            "PNP0000",
+            Resources::new().io(0x20, 2).io(0xa0, 2).irq_noflags(2),
+        ),
+        isa_dev("TIMR", "PNP0100", Resources::new().io(0x40, 4).irq_noflags(0)),
+        isa_dev("RTC", "PNP0B00", Resources::new().io(0x70, 2).irq_noflags(8)),
+        isa_dev(
+            "KBD",
+            "PNP0303",
+            Resources::new()
+                .io(PS2_PORT_DATA, 1)
+                .io(PS2_PORT_CMD_STATUS, 1)
+                .irq_noflags(PS2_IRQ_PRI),
+        ),
+        isa_dev("MOU", "PNP0F13", Resources::new().irq_noflags(PS2_IRQ_AUX)),
+    ]);
+    for (n, (port, irq)) in params.uarts.iter().enumerate() {
+        isa_devs.push(uart(n + 1, *port, *irq));
+    }
+    if params.pvpanic {
+        isa_devs.push(device(
+            "PEVT",
+            vec![
+                aml::name("_HID", string("QEMU0001")),
+                aml::name(
+                    "_CRS",
+                    Resources::new()
+                        .io(pvpanic::QEMU_PVPANIC_IOPORT, 1)
+                        .finish(),
+                ),
+            ],
+        ));
+    }
+    let isa = device("ISA", isa_devs);
+
+    // Interrupt routing for INTA-D of each slot, rotated across the links.
+    // On the Q35, devices integrated in the ICH9 use PIRQA-D (LNKA-D), while
+    // all others use PIRQE-H (LNKE-H).
+    let mut prt = Vec::with_capacity(32 * 4);
+    for slot in 0..32u64 {
+        for pin in 0..4u64 {
+            let link = match q35 {
+                false => (slot + pin + 3) % 4,
+                true if slot >= ICH9_FIRST_SLOT as u64 => (slot + pin) % 4,
+                true => 4 + (slot + pin) % 4,
+            };
+            prt.push(package(vec![
+                int(slot << 16 | 0xffff),
+                int(pin),
+                name_string(&lnk(link as usize)),
+                int(0),
+            ]));
+        }
+    }
+
+    let mut crs = Resources::new();
+    crs.bus_numbers(0, 0xff)
+        .io(0xcf8, 8)
+        .io_window(0, 0xcf7)
+        .io_window(0xd00, 0xffff)
+        .mem32_window(0xa_0000, 0xb_ffff);
+    if q35 {
+        // Leave out the ECAM region
+        let ecam_end = (ECAM_BASE + LEN_ECAM) as u32;
+        crs.mem32_window(0xc000_0000, ECAM_BASE as u32 - 1)
+            .mem32_window(ecam_end, IOAPIC_ADDR - 1);
+    } else {
+        crs.mem32_window(0xc000_0000, IOAPIC_ADDR - 1);
+    }
+    crs.mem64_window(MAX_SYSMEM as u64, MAX_PHYSMEM as u64 - 1);
+    let host_bridge = match q35 {
+        false => vec![aml::name("_HID", eisa_id("PNP0A03"))],
+        true => vec![
+            aml::name("_HID", eisa_id("PNP0A08")),
+            aml::name("_CID", eisa_id("PNP0A03")),
+        ],
+    };
+
+    let mut pci0 = host_bridge;
+    pci0.extend(vec![
+        aml::name("_ADR", int(0)),
+        aml::name("_UID", int(0)),
+        aml::name("_BBN", int(0)),
+        aml::name("_CRS", crs.finish()),
+        aml::name("_PRT", package(prt)),
+        isa,
+    ]);
+    let pci0 = device("PCI0", pci0);
+
+    // Reserve the PM block (and the fw_cfg ports, and any ECAM region) from
+    // allocation
+    let mut res = Resources::new();
+    res.io(PMBASE_DEFAULT, PMBASE_LEN as u8).io(0x510, 0xc);
+    if q35 {
+        res.memory32_fixed(ECAM_BASE as u32, LEN_ECAM as u32);
+    }
+
+    let mut sb = vec![
+        pci0,
+        device(
+            "RES",
+            vec![
+                aml::name("_HID", eisa_id("PNP0C02")),
+                aml::name("_UID", int(1)),
+                aml::name("_CRS", res.finish()),
+            ],
+        ),
+        // Current resources of a link, given the value of its PIR register
+        method(
+            "IQCR",
+            1,
+            true,
+            vec![
+                aml::name("PRR0", Resources::new().interrupt(&[0]).finish()),
+                create_dword_field(name_string("PRR0"), 5, "PRRI"),
+                if_(
+                    lless(arg(0), int(0x80)),
+                    vec![store(arg(0), name_string("PRRI"))],
+                ),
+                ret(name_string("PRR0")),
+            ],
+        ),
+    ];
+    let regions: &[&str] = match q35 {
+        false => &["PCI0.ISA.P40C"],
+        true => &["PCI0.ISA.P60C", "PCI0.ISA.P68C"],
+    };
+    for (r, region) in regions.iter().enumerate() {
+        let names: Vec<String> = (r * 4..r * 4 + 4).map(prq).collect();
+        let units: Vec<(&str, u8)> =
+            names.iter().map(|n| (n.as_str(), 8)).collect();
+        sb.push(field(region, &units));
+    }
+    for n in 0..links {
+        let reg = || name_string(&prq(n));
+        sb.push(device(
+            &lnk(n),
+            vec![
+                aml::name("_HID", eisa_id("PNP0C0F")),
+                aml::name("_UID", int(n as u64)),
+                aml::name(
+                    "_PRS",
+                    Resources::new().interrupt(&LNK_IRQS).finish(),
+                ),
+                method(
+                    "_STA",
+                    0,
+                    false,
+                    vec![
+                        if_(and(reg(), int(0x80), None), vec![ret(int(0x09))]),
+                        else_(vec![ret(int(0x0b))]),
+                    ],
+                ),
+                method(
+                    "_DIS",
+                    0,
+                    false,
+                    vec![or(reg(), int(0x80), Some(reg()))],
+                ),
+                method("_CRS", 0, false, vec![ret(call("IQCR", vec![reg()]))]),
+                method(
+                    "_SRS",
+                    1,
+                    false,
+                    vec![
+                        create_dword_field(arg(0), 5, "PRRI"),
+                        store(name_string("PRRI"), reg()),
+                    ],
+                ),
+            ],
+        ));
+    }
+    if params.hpet_cap.is_some() {
+        sb.push(device(
+            "HPET",
+            vec![
+                aml::name("_HID", eisa_id("PNP0103")),
+                aml::name("_UID", int(0)),
+                aml::name(
+                    "_CRS",
+                    Resources::new().memory32_fixed(HPET_ADDR, 0x400).finish(),
+                ),
+            ],
+        ));
+    }
+    for cpu in 0..params.topology.cpus() {
+        sb.push(device(
+            &format!("C{:03X}", cpu),
+            vec![
+                aml::name("_HID", string("ACPI0007")),
+                aml::name("_UID", int(cpu as u64)),
+            ],
+        ));
+    }
+
+    let mut out = header(b"DSDT", 2, HDR_LEN);
+    out.extend(scope("\\_SB", sb));
+    // S5 (soft-off) is SUS_TYP 0 in the PM1 control register
+    out.extend(name("\\_S5", package(vec![int(0), int(0), int(0), int(0)])));
+    if params.s3 {
+        // S3 (suspend-to-RAM) is SUS_TYP 1
+        out.extend(name(
+            "\\_S3",
+            package(vec![int(1), int(1), int(0), int(0)]),
+        ));
+    }
+    set_len(&mut out);
+    out
+}
+
+pub struct Acpi {
+    rsdp: Vec<u8>,
+    tables: Vec<u8>,
+    loader: Vec<u8>,
+}
+impl Acpi {
+    pub fn new(params: &AcpiParams) -> Self {
+        let mut tables = Tables::default();
+
+        // The FACS requires 64-byte alignment, so place it first
+        let facs = tables.add(facs(), 64, false);
+        let dsdt = tables.add(dsdt(params), 8, true);
+
+        let mut entries = Vec::new();
+        let fadt_off = tables.add(fadt(), 8, true);
+        tables.pointer(fadt_off + 36, 4, facs);
+        tables.pointer(fadt_off + 40, 4, dsdt);
+        tables.pointer(fadt_off + 140, 8, dsdt);
+        entries.push(fadt_off);
+        entries.push(tables.add(madt(params), 8, true));
+        if let Some(cap) = params.hpet_cap {
+            entries.push(tables.add(hpet(cap), 8, true));
+        }
+        if params.chipset == AcpiChipset::Q35 {
+            entries.push(tables.add(mcfg(), 8, true));
+        }
+
+        let xsdt_len = HDR_LEN + entries.len() * 8;
+        let xsdt = tables.add(header(b"XSDT", 1, xsdt_len), 8, true);
+        for (n, entry) in entries.iter().enumerate() {
+            tables.pointer(xsdt + (HDR_LEN + n * 8) as u32, 8, *entry);
+        }
+
+        let mut rsdp = vec![0u8; 36];
+        rsdp[0..8].copy_from_slice(b"RSD PTR ");
+        rsdp[9..15].copy_from_slice(OEM_ID);
+        rsdp[15] = 2;
+        LE::write_u32(&mut rsdp[20..24], 36);
+        LE::write_u64(&mut rsdp[24..32], xsdt as u64);
+
+        let mut cmds = vec![
+            LoaderCmd::Allocate { file: FILE_RSDP, align: 16, fseg: true },
+            LoaderCmd::Allocate { file: FILE_TABLES, align: 64, fseg: false },
+            LoaderCmd::AddPointer {
+                dest: FILE_RSDP,
+                src: FILE_TABLES,
+                off: 24,
+                size: 8,
+            },
+        ];
+        cmds.append(&mut tables.pointers);
+        cmds.append(&mut tables.checksums);
+        // Checksums over the first 20 bytes (ACPI 1.0), and the entire RSDP
+        cmds.push(LoaderCmd::AddChecksum {
+            file: FILE_RSDP,
+            result: 8,
+            start: 0,
+            len: 20,
+        });
+        cmds.push(LoaderCmd::AddChecksum {
+            file: FILE_RSDP,
+            result: 32,
+            start: 0,
+            len: 36,
+        });
+
+        let mut loader = Vec::with_capacity(cmds.len() * LoaderCmd::SIZE);
+        for cmd in cmds.iter() {
+            cmd.encode(&mut loader);
+        }
+        Self { rsdp, tables: tables.data, loader }
+    }
+
+    pub fn attach(&self, builder: &mut FwCfgBuilder) -> fwcfg::Result {
+        builder.add_named(FILE_RSDP, FixedItem::new_raw(self.rsdp.clone()))?;
+        builder
+            .add_named(FILE_TABLES, FixedItem::new_raw(self.tables.clone()))?;
+        builder.add_named(FILE_LOADER, FixedItem::new_raw(self.loader.clone()))
+    }
+}
+
+/// Sleep states offered to the guest, in the form QEMU passes them to the
+/// firmware: a byte for each of S0-S5, with bit 7 marking the state as enabled.
+///
+/// Firmware consults this to decide whether to support resuming from S3.
+fn system_states(s3: bool) -> [u8; 6] {
+    const ENABLED: u8 = 1 << 7;
+    let mut states = [0u8; 6];
+    states[0] = ENABLED;
+    states[3] = if s3 { ENABLED | 1 } else { 1 };
+    states[4] = 2;
+    states[5] = ENABLED;
+    states
+}
+
+/// Attach the `etc/system-states` file, advertising (or not) S3 to firmware.
+pub fn attach_system_states(
+    builder: &mut FwCfgBuilder,
+    s3: bool,
+) -> fwcfg::Result {
+    builder.add_named(
+        FILE_SYSTEM_STATES,
+        FixedItem::new_raw(system_states(s3).to_vec()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn file_name(buf: &[u8]) -> String {
+        let end = buf.iter().position(|b| *b == 0).unwrap();
+        String::from_utf8(buf[..end].to_vec()).unwrap()
+    }
+    fn sum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |s, b| s.wrapping_add(*b))
+    }
+
+    /// Run the loader script as firmware would, placing the files in a flat
+    /// (guest physical) address space.
+    fn load(acpi: &Acpi) -> (Vec<u8>, BTreeMap<String, usize>) {
+        let mut files = BTreeMap::new();
+        files.insert(FILE_RSDP.to_string(), acpi.rsdp.clone());
+        files.insert(FILE_TABLES.to_string(), acpi.tables.clone());
+
+        let mut mem = vec![0u8; 0x1000];
+        let mut base = BTreeMap::new();
+        for cmd in acpi.loader.chunks(LoaderCmd::SIZE) {
+            match LE::read_u32(&cmd[0..4]) {
+                1 => {
+                    let name = file_name(&cmd[4..60]);
+                    let align = LE::read_u32(&cmd[60..64]) as usize;
+                    let addr = mem.len() + (align - mem.len() % align) % align;
+                    mem.resize(addr, 0);
+                    mem.extend_from_slice(&files[&name]);
+                    base.insert(name, addr);
+                }
+                2 => {
+                    let dest = base[&file_name(&cmd[4..60])];
+                    let src = base[&file_name(&cmd[60..116])];
+                    let off = dest + LE::read_u32(&cmd[116..120]) as usize;
+                    assert!(cmd[120] == 4 || cmd[120] == 8);
+                    let field = &mut mem[off..off + cmd[120] as usize];
+                    if cmd[120] == 4 {
+                        let val = LE::read_u32(field) + src as u32;
+                        LE::write_u32(field, val);
+                    } else {
+                        let val = LE::read_u64(field) + src as u64;
+                        LE::write_u64(field, val);
+                    }
+                }
+                3 => {
+                    let file = base[&file_name(&cmd[4..60])];
+                    let result = file + LE::read_u32(&cmd[60..64]) as usize;
+                    let start = file + LE::read_u32(&cmd[64..68]) as usize;
+                    let len = LE::read_u32(&cmd[68..72]) as usize;
+                    mem[result] = 0;
+                    mem[result] = sum(&mem[start..start + len]).wrapping_neg();
+                }
+                n => panic!("unexpected command {}", n),
+            }
+        }
+        (mem, base)
+    }
+
+    fn table_at(mem: &[u8], addr: usize) -> &[u8] {
+        let len = LE::read_u32(&mem[addr + 4..addr + 8]) as usize;
+        let table = &mem[addr..addr + len];
+        if &table[0..4] != b"FACS" {
+            assert_eq!(sum(table), 0, "bad checksum");
+        }
+        table
+    }
+
+    #[test]
+    fn loaded_tables() {
+        let acpi = Acpi::new(&AcpiParams {
+            chipset: AcpiChipset::I440fx,
+            topology: CpuTopology::flat(4),
+            uarts: Vec::new(),
+            hpet_cap: Some(0x8086a201),
+            pvpanic: true,
+            s3: true,
+        });
+        let (mem, base) = load(&acpi);
+
+        let rsdp = &mem[base[FILE_RSDP]..][..36];
+        assert_eq!(&rsdp[0..8], b"RSD PTR ");
+        assert_eq!(sum(&rsdp[..20]), 0);
+        assert_eq!(sum(rsdp), 0);
+
+        let xsdt = table_at(&mem, LE::read_u64(&rsdp[24..32]) as usize);
+        assert_eq!(&xsdt[0..4], b"XSDT");
+        let sigs: Vec<&[u8]> = xsdt[HDR_LEN..]
+            .chunks(8)
+            .map(|e| &table_at(&mem, LE::read_u64(e) as usize)[0..4])
+            .collect();
+        assert_eq!(sigs, [b"FACP", b"APIC", b"HPET"]);
+
+        let fadt = table_at(&mem, LE::read_u64(&xsdt[HDR_LEN..]) as usize);
+        let facs = LE::read_u32(&fadt[36..40]) as usize;
+        assert_eq!(facs % 64, 0);
+        assert_eq!(&table_at(&mem, facs)[0..4], b"FACS");
+        let dsdt = LE::read_u32(&fadt[40..44]) as usize;
+        assert_eq!(LE::read_u64(&fadt[140..148]), dsdt as u64);
+        assert_eq!(&table_at(&mem, dsdt)[0..4], b"DSDT");
+        // Reset via the Reset Control Register
+        assert_ne!(LE::read_u32(&fadt[112..116]) & (1 << 10), 0);
+        assert_eq!(LE::read_u64(&fadt[120..128]), 0xcf9);
+        assert_eq!(fadt[128], 0x6);
+        let dsdt = table_at(&mem, dsdt);
+        assert!(dsdt.windows(4).any(|w| w == b"\\_S3"));
+
+        // 4 LAPICs, the IOAPIC, 2 overrides, and LAPIC NMI
+        let madt = table_at(&mem, LE::read_u64(&xsdt[HDR_LEN + 8..]) as usize);
+        assert_eq!(madt.len(), HDR_LEN + 8 + 4 * 8 + 12 + 2 * 10 + 6);
+    }
+
+    #[test]
+    fn without_hpet() {
+        let acpi = Acpi::new(&AcpiParams {
+            chipset: AcpiChipset::I440fx,
+            topology: CpuTopology::flat(1),
+            uarts: Vec::new(),
+            hpet_cap: None,
+            pvpanic: false,
+            s3: false,
+        });
+        let (mem, base) = load(&acpi);
+        let rsdp = &mem[base[FILE_RSDP]..][..36];
+        let xsdt = table_at(&mem, LE::read_u64(&rsdp[24..32]) as usize);
+        assert_eq!(xsdt.len(), HDR_LEN + 2 * 8);
+
+        let dsdt = dsdt(&AcpiParams {
+            chipset: AcpiChipset::I440fx,
+            topology: CpuTopology::flat(1),
+            uarts: vec![(0x3f8, 4)],
+            hpet_cap: None,
+            pvpanic: false,
+            s3: false,
+        });
+        let has =
+            |needle: &[u8]| dsdt.windows(needle.len()).any(|w| w == needle);
+        assert!(has(b"PCI0"));
+        assert!(has(b"LNKD"));
+        assert!(has(b"C000"));
+        assert!(!has(b"C001"));
+        // Only the UARTs given are described
+        assert!(has(b"COM1"));
+        assert!(!has(b"COM2"));
+        assert!(!has(b"HPET"));
+        assert!(!has(b"PEVT"));
+        assert!(!has(b"_S3"));
+
+        let panic_dsdt = super::dsdt(&AcpiParams {
+            chipset: AcpiChipset::I440fx,
+            topology: CpuTopology::flat(1),
+            uarts: Vec::new(),
+            hpet_cap: None,
+            pvpanic: true,
+            s3: false,
+        });
+        assert!(panic_dsdt.windows(8).any(|w| w == b"QEMU0001"));
+    }
+
+    #[test]
+    fn q35_tables() {
+        let params = AcpiParams {
+            chipset: AcpiChipset::Q35,
+            topology: CpuTopology::flat(1),
+            ..Default::default()
+        };
+        let acpi = Acpi::new(&params);
+        let (mem, base) = load(&acpi);
+        let rsdp = &mem[base[FILE_RSDP]..][..36];
+        let xsdt = table_at(&mem, LE::read_u64(&rsdp[24..32]) as usize);
+        let mcfg = table_at(&mem, LE::read_u64(&xsdt[HDR_LEN + 16..]) as usize);
+        assert_eq!(&mcfg[0..4], b"MCFG");
+        assert_eq!(mcfg.len(), 60);
+        let ent = &mcfg[HDR_LEN + 8..];
+        assert_eq!(LE::read_u64(&ent[0..8]), 0xe000_0000);
+        assert_eq!(&ent[8..12], [0, 0, 0, 0xff]);
+
+        let dsdt = dsdt(&params);
+        let has =
+            |needle: &[u8]| dsdt.windows(needle.len()).any(|w| w == needle);
+        assert!(has(b"P60C"));
+        assert!(has(b"P68C"));
+        assert!(has(b"LNKH"));
+        assert!(has(b"PRQ7"));
+        assert!(!has(b"P40C"));
+        // PNP0A08 (PCI Express host bridge)
+        assert!(has(&aml::eisa_id("PNP0A08")));
+    }
+
+    #[test]
+    fn madt_topology() {
+        let lapics = |topology| {
+            let madt = madt(&AcpiParams { topology, ..Default::default() });
+            let mut ids = Vec::new();
+            let mut ents = &madt[HDR_LEN + 8..];
+            while !ents.is_empty() {
+                let (ent, rest) = ents.split_at(ents[1] as usize);
+                if ent[0] == 0 {
+                    // UID and APIC ID match, and the processor is enabled
+                    assert_eq!(ent[2], ent[3]);
+                    assert_eq!(LE::read_u32(&ent[4..8]), 1);
+                    ids.push(ent[3]);
+                }
+                ents = rest;
+            }
+            ids
+        };
+        assert_eq!(lapics(CpuTopology::flat(3)), [0, 1, 2]);
+        assert_eq!(lapics(CpuTopology::new(4, 1, 1).unwrap()), [0, 1, 2, 3]);
+        // 2 sockets of 2 cores, each with 2 threads
+        assert_eq!(
+            lapics(CpuTopology::new(2, 2, 2).unwrap()),
+            [0, 2, 4, 6, 1, 3, 5, 7]
+        );
+        assert_eq!(
+            lapics(CpuTopology::new(1, 2, 4).unwrap()),
+            [0, 4, 1, 5, 2, 6, 3, 7]
+        );
+
+        let dsdt = dsdt(&AcpiParams {
+            topology: CpuTopology::new(2, 2, 2).unwrap(),
+            ..Default::default()
+        });
+        assert!(dsdt.windows(4).any(|w| w == b"C007"));
+        assert!(!dsdt.windows(4).any(|w| w == b"C008"));
+    }
+
+    #[test]
+    fn sleep_states() {
+        assert_eq!(system_states(true), [0x80, 0, 0, 0x81, 2, 0x80]);
+        assert_eq!(system_states(false)[3] & 0x80, 0);
+    }
+}
@@ -0,0 +1,98 @@
+//! Boot device ordering, as conveyed to firmware (EDK2 or SeaBIOS) through the
+//! `bootorder` fw_cfg file in the form of OpenFirmware device paths.
+
+use crate::hw::pci::BDF;
+
+use super::fwcfg::{self, FixedItem, FwCfgBuilder};
+
+/// Root PCI bus, reached via the legacy config mechanism at port 0xcf8
+const PCI_ROOT: &str = "/pci@i0cf8";
+
+#[derive(Copy, Clone, Debug)]
+pub enum BootDevice {
+    VirtioBlock(BDF),
+}
+impl BootDevice {
+    /// OpenFirmware path to the device.
+    ///
+    /// Only devices on the root bus are addressable, as there is no support
+    /// for PCI bridges.
+    pub fn fw_path(&self) -> Option<String> {
+        match self {
+            BootDevice::VirtioBlock(bdf) => {
+                Some(format!("{}/scsi@{}/disk@0,0", PCI_ROOT, pci_unit(bdf)?))
+            }
+        }
+    }
+}
+
+/// Unit address of a PCI device: its slot, and function (if non-zero)
+fn pci_unit(bdf: &BDF) -> Option<String> {
+    if bdf.bus() != 0 {
+        return None;
+    }
+    if bdf.func() == 0 {
+        Some(format!("{:x}", bdf.dev()))
+    } else {
+        Some(format!("{:x},{:x}", bdf.dev(), bdf.func()))
+    }
+}
+
+#[derive(Default)]
+pub struct BootOrder {
+    paths: Vec<String>,
+}
+impl BootOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Append a device to the boot order, returning `None` if it cannot be
+    /// expressed as a firmware path.
+    pub fn push(&mut self, dev: &BootDevice) -> Option<()> {
+        self.paths.push(dev.fw_path()?);
+        Some(())
+    }
+    /// Contents of the `bootorder` file: newline-separated paths, with the
+    /// final newline replaced by a NUL terminator, as QEMU produces it.
+    fn contents(&self) -> Vec<u8> {
+        let mut data = self.paths.join("\n").into_bytes();
+        data.push(b'\0');
+        data
+    }
+    pub fn attach(&self, builder: &mut FwCfgBuilder) -> fwcfg::Result {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+        builder.add_named("bootorder", FixedItem::new_raw(self.contents()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fw_paths() {
+        let slot4 = BDF::new(0, 4, 0);
+        assert_eq!(
+            BootDevice::VirtioBlock(slot4).fw_path().unwrap(),
+            "/pci@i0cf8/scsi@4/disk@0,0"
+        );
+        assert_eq!(
+            BootDevice::VirtioBlock(BDF::new(0, 0x1a, 2)).fw_path().unwrap(),
+            "/pci@i0cf8/scsi@1a,2/disk@0,0"
+        );
+        assert!(BootDevice::VirtioBlock(BDF::new(1, 4, 0)).fw_path().is_none());
+    }
+
+    #[test]
+    fn file_contents() {
+        let mut order = BootOrder::new();
+        order.push(&BootDevice::VirtioBlock(BDF::new(0, 5, 0))).unwrap();
+        order.push(&BootDevice::VirtioBlock(BDF::new(0, 4, 0))).unwrap();
+        assert_eq!(
+            order.contents(),
+            b"/pci@i0cf8/scsi@5/disk@0,0\n/pci@i0cf8/scsi@4/disk@0,0\0"
+        );
+    }
+}
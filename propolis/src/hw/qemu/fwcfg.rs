@@ -3,6 +3,7 @@ use std::sync::{Arc, Mutex, MutexGuard, Weak};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::hw::Lifecycle;
 use crate::pio::{PioBus, PioDev};
 use bits::*;
 
@@ -197,6 +198,24 @@ mod test {
         assert_eq!(std::mem::size_of::<FwCfgFileEntry>(), 64);
         assert_eq!(std::mem::size_of::<FwCfgDmaReq>(), 16);
     }
+
+    #[test]
+    fn dma_features() {
+        let mut builder = FwCfgBuilder::new();
+        assert_eq!(builder.features(), FW_CFG_VER_BASE | FW_CFG_VER_DMA);
+        builder.set_dma(false);
+        assert_eq!(builder.features(), FW_CFG_VER_BASE);
+
+        let fwcfg = builder.finalize();
+        assert!(!fwcfg.dma);
+        assert_eq!(fwcfg.size(LegacyId::Signature as u16), 4);
+        assert_eq!(fwcfg.size(LegacyId::Id as u16), 4);
+        assert_eq!(&FW_CFG_DMA_SIGNATURE[..4], FW_CFG_SIGNATURE);
+
+        let fwcfg = FwCfgBuilder::new().finalize();
+        assert!(fwcfg.dma);
+        assert!(fwcfg.dir.is_present(LegacyId::Id as u16));
+    }
 }
 
 struct Entry {
@@ -277,6 +296,7 @@ pub struct FwCfgBuilder {
     entries: BTreeMap<u16, Entry>,
     name_to_sel: BTreeMap<String, u16>,
     next_sel: u16,
+    dma: bool,
 }
 
 impl FwCfgBuilder {
@@ -285,15 +305,11 @@ impl FwCfgBuilder {
             entries: BTreeMap::new(),
             name_to_sel: BTreeMap::new(),
             next_sel: ITEMS_FILE_START,
+            dma: true,
         };
         this.add_legacy(
             LegacyId::Signature,
-            FixedItem::new_raw(b"QEMU".to_vec()),
-        )
-        .unwrap();
-        this.add_legacy(
-            LegacyId::Id,
-            FixedItem::new_u32(FW_CFG_VER_BASE | FW_CFG_VER_DMA),
+            FixedItem::new_raw(FW_CFG_SIGNATURE.to_vec()),
         )
         .unwrap();
 
@@ -310,6 +326,22 @@ impl FwCfgBuilder {
         this
     }
 
+    /// Enable or disable the DMA interface (enabled by default).
+    ///
+    /// The feature bitmap in the `Id` entry advertises DMA support only when it
+    /// is enabled, and the DMA address register is not attached otherwise.
+    pub fn set_dma(&mut self, enabled: bool) {
+        self.dma = enabled;
+    }
+
+    fn features(&self) -> u32 {
+        if self.dma {
+            FW_CFG_VER_BASE | FW_CFG_VER_DMA
+        } else {
+            FW_CFG_VER_BASE
+        }
+    }
+
     pub fn add_legacy(
         &mut self,
         sel: LegacyId,
@@ -343,14 +375,18 @@ impl FwCfgBuilder {
         }
     }
 
-    pub fn finalize(self) -> Arc<FwCfg> {
+    pub fn finalize(mut self) -> Arc<FwCfg> {
+        let dma = self.dma;
+        self.add_legacy(LegacyId::Id, FixedItem::new_u32(self.features()))
+            .unwrap();
+
         let mut sorted_names: Vec<(String, u16)> =
             self.name_to_sel.into_iter().collect();
         // Should be sorted coming out of the btree, but be extra sure.
         sorted_names.sort();
         let dir = ItemDir { entries: self.entries, sorted_names };
 
-        Arc::new(FwCfg::new(dir))
+        Arc::new(FwCfg::new(dir, dma))
     }
 }
 
@@ -365,10 +401,11 @@ struct AccessState {
 pub struct FwCfg {
     dir: ItemDir,
     state: Mutex<AccessState>,
+    dma: bool,
 }
 impl FwCfg {
-    fn new(dir: ItemDir) -> Self {
-        Self { dir, state: Mutex::new(Default::default()) }
+    fn new(dir: ItemDir, dma: bool) -> Self {
+        Self { dir, state: Mutex::new(Default::default()), dma }
     }
 
     pub fn attach(self: &Arc<Self>, pio: &PioBus) {
@@ -378,7 +415,8 @@ impl FwCfg {
             (FW_CFG_IOP_DMA_HI, 4),
             (FW_CFG_IOP_DMA_LO, 4),
         ];
-        for (port, len) in ports.iter() {
+        let nports = if self.dma { ports.len() } else { 2 };
+        for (port, len) in ports[..nports].iter() {
             pio.register(
                 *port,
                 *len,
@@ -561,6 +599,13 @@ impl FwCfg {
     }
 }
 
+impl Lifecycle for FwCfg {
+    fn reset(&self, _ctx: &DispCtx) {
+        // Forget the selected item, and any half-written DMA address
+        *self.state.lock().unwrap() = AccessState::default();
+    }
+}
+
 impl PioDev for FwCfg {
     fn pio_rw(&self, port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
         let mut state = self.state.lock().unwrap();
@@ -603,16 +648,19 @@ impl PioDev for FwCfg {
                         state.offset = state.offset.saturating_add(1);
                     }
                     RWOp::Write(_wo) => {
-                        // XXX: ignore writes to data area
+                        // As in QEMU (since 2.4), writes to the data port are
+                        // ignored: items are only written through DMA.
                     }
                 }
             }
+            // Reads of the DMA address register yield a signature, by which
+            // firmware can detect the presence of the interface.
             FW_CFG_IOP_DMA_HI => match rwo {
                 RWOp::Read(ro) => {
                     if ro.len() != 4 {
                         ro.fill(0);
                     } else {
-                        ro.write_u32(state.addr_high.to_be());
+                        ro.write_bytes(&FW_CFG_DMA_SIGNATURE[..4]);
                     }
                 }
                 RWOp::Write(wo) => {
@@ -626,7 +674,7 @@ impl PioDev for FwCfg {
                     if ro.len() != 4 {
                         ro.fill(0);
                     } else {
-                        ro.write_u32(state.addr_low.to_be());
+                        ro.write_bytes(&FW_CFG_DMA_SIGNATURE[4..]);
                     }
                 }
                 RWOp::Write(wo) => {
@@ -657,6 +705,9 @@ mod bits {
     pub const ITEMS_ARCH_START: u16 = 0x8000;
     pub const ITEMS_ARCH_END: u16 = 0x9000;
 
+    pub const FW_CFG_SIGNATURE: &[u8; 4] = b"QEMU";
+    pub const FW_CFG_DMA_SIGNATURE: &[u8; 8] = b"QEMU CFG";
+
     pub const FW_CFG_VER_BASE: u32 = 1 << 0;
     pub const FW_CFG_VER_DMA: u32 = 1 << 1;
 
@@ -1,3 +1,7 @@
+pub mod acpi;
+pub mod bootorder;
 pub mod debug;
 pub mod fwcfg;
+pub mod pvpanic;
 pub mod ramfb;
+pub mod smbios;
@@ -0,0 +1,141 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::pio::{PioBus, PioDev};
+
+pub const QEMU_PVPANIC_IOPORT: u16 = 0x0505;
+
+bitflags! {
+    /// Events which the guest can report through the pvpanic device
+    pub struct PanicEvent: u8 {
+        /// Guest kernel has panicked
+        const PANICKED = 1 << 0;
+        /// Guest has loaded a crash kernel (kdump) following a panic
+        const CRASH_LOADED = 1 << 1;
+    }
+}
+
+/// Number of each event reported by the guest
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct PanicCounts {
+    pub panicked: u64,
+    pub crash_loaded: u64,
+}
+
+type PanicHandler = Box<dyn Fn(PanicEvent) + Send>;
+
+/// QEMU-compatible pvpanic device (ISA variant), through which the guest
+/// signals a kernel panic, allowing the host to distinguish it from a hang.
+pub struct QemuPvpanic {
+    counts: Mutex<PanicCounts>,
+    handler: Mutex<Option<PanicHandler>>,
+}
+impl QemuPvpanic {
+    pub fn create(pio: &PioBus) -> Arc<Self> {
+        let this = Arc::new(Self {
+            counts: Mutex::new(PanicCounts::default()),
+            handler: Mutex::new(None),
+        });
+        pio.register(
+            QEMU_PVPANIC_IOPORT,
+            1,
+            Arc::downgrade(&this) as Weak<dyn PioDev>,
+            0,
+        )
+        .unwrap();
+        this
+    }
+
+    /// Set a callback to be invoked, on the thread of the reporting vCPU, for
+    /// each event written by the guest.
+    pub fn on_event<F>(&self, handler: F)
+    where
+        F: Fn(PanicEvent) + Send + 'static,
+    {
+        *self.handler.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    pub fn counts(&self) -> PanicCounts {
+        *self.counts.lock().unwrap()
+    }
+
+    fn report(&self, events: PanicEvent) {
+        let mut counts = self.counts.lock().unwrap();
+        if events.contains(PanicEvent::PANICKED) {
+            counts.panicked += 1;
+        }
+        if events.contains(PanicEvent::CRASH_LOADED) {
+            counts.crash_loaded += 1;
+        }
+        drop(counts);
+
+        if let Some(handler) = self.handler.lock().unwrap().as_ref() {
+            handler(events);
+        }
+    }
+
+    fn pvpanic_rw(&self, rwo: RWOp) {
+        match rwo {
+            RWOp::Read(ro) => {
+                // Advertise the events we understand
+                ro.write_u8(PanicEvent::all().bits());
+            }
+            RWOp::Write(wo) => {
+                let events = PanicEvent::from_bits_truncate(wo.read_u8());
+                if !events.is_empty() {
+                    self.report(events);
+                }
+            }
+        }
+    }
+}
+
+impl PioDev for QemuPvpanic {
+    fn pio_rw(&self, _port: u16, _ident: usize, rwo: RWOp, _ctx: &DispCtx) {
+        self.pvpanic_rw(rwo)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(dev: &QemuPvpanic, val: u8) {
+        dev.pvpanic_rw(RWOp::Write(&mut WriteOp::new_buf(0, &[val])));
+    }
+
+    #[test]
+    fn events_reported() {
+        let dev = QemuPvpanic::create(&PioBus::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hseen = Arc::clone(&seen);
+        dev.on_event(move |events| hseen.lock().unwrap().push(events));
+
+        write(&dev, PanicEvent::PANICKED.bits());
+        assert_eq!(dev.counts(), PanicCounts { panicked: 1, crash_loaded: 0 });
+        write(&dev, PanicEvent::CRASH_LOADED.bits());
+        assert_eq!(dev.counts(), PanicCounts { panicked: 1, crash_loaded: 1 });
+
+        // Unknown bits are ignored, and an empty report is no event at all
+        write(&dev, 0x80);
+        write(&dev, PanicEvent::PANICKED.bits() | 0x80);
+        assert_eq!(dev.counts(), PanicCounts { panicked: 2, crash_loaded: 1 });
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                PanicEvent::PANICKED,
+                PanicEvent::CRASH_LOADED,
+                PanicEvent::PANICKED
+            ]
+        );
+    }
+
+    #[test]
+    fn events_advertised() {
+        let dev = QemuPvpanic::create(&PioBus::new());
+        let mut buf = [0u8; 1];
+        dev.pvpanic_rw(RWOp::Read(&mut ReadOp::new_buf(0, &mut buf)));
+        assert_eq!(buf[0], 0b11);
+    }
+}
@@ -1,9 +1,14 @@
-use std::sync::{Arc, Mutex};
+use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::qemu::fwcfg::{self, FwCfgBuilder, Item};
+use crate::hw::Lifecycle;
 use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
 
 use lazy_static::lazy_static;
 
@@ -40,6 +45,76 @@ fn fourcc_bytepp(fourcc: u32) -> Option<u32> {
     }
 }
 
+/// Largest width or height accepted for a framebuffer
+pub const FB_MAX_DIMENSION: u32 = 16384;
+
+/// Reasons a [`FramebufferSpec`] may be rejected
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SpecError {
+    /// Width and/or height is zero
+    ZeroDimension,
+    /// Pixel format is not one which is supported
+    UnsupportedFourcc(u32),
+    /// Dimensions exceed [`FB_MAX_DIMENSION`], or the resulting size cannot be
+    /// represented
+    TooLarge,
+    /// Contents provided are shorter than the framebuffer they describe
+    ShortData,
+}
+impl std::fmt::Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpecError::ZeroDimension => write!(f, "zero width or height"),
+            SpecError::UnsupportedFourcc(fourcc) => {
+                write!(f, "unsupported fourcc {:#x}", fourcc)
+            }
+            SpecError::TooLarge => write!(f, "framebuffer too large"),
+            SpecError::ShortData => write!(f, "framebuffer data too short"),
+        }
+    }
+}
+impl std::error::Error for SpecError {}
+
+/// Layout of a framebuffer, as programmed by the guest
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FramebufferSpec {
+    pub addr: u64,
+    pub fourcc: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per line, or 0 if lines are tightly packed
+    pub stride: u32,
+}
+impl FramebufferSpec {
+    /// Check that the framebuffer layout is one which can be displayed.
+    pub fn validate(&self) -> Result<(), SpecError> {
+        self.checked_len().map(|_| ())
+    }
+
+    /// Length in bytes of the framebuffer, from its start to the last pixel
+    pub fn byte_len(&self) -> Option<usize> {
+        self.checked_len().ok()
+    }
+
+    fn checked_len(&self) -> Result<usize, SpecError> {
+        if self.height == 0 || self.width == 0 {
+            return Err(SpecError::ZeroDimension);
+        }
+        let bypp = fourcc_bytepp(self.fourcc)
+            .ok_or(SpecError::UnsupportedFourcc(self.fourcc))?;
+        if self.width > FB_MAX_DIMENSION || self.height > FB_MAX_DIMENSION {
+            return Err(SpecError::TooLarge);
+        }
+        let line_len =
+            u32::checked_mul(self.width, bypp).ok_or(SpecError::TooLarge)?;
+        let stride = if self.stride == 0 { line_len } else { self.stride };
+        let total = u32::checked_mul(self.height - 1, stride)
+            .and_then(|v| v.checked_add(line_len))
+            .ok_or(SpecError::TooLarge)?;
+        Ok(total as usize)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Config {
     addr: u64,
@@ -50,48 +125,242 @@ pub struct Config {
     stride: u32,
 }
 impl Config {
-    fn verify(&self, ctx: &DispCtx) -> Option<()> {
-        if self.height == 0 || self.width == 0 {
-            return None;
+    fn spec(&self) -> FramebufferSpec {
+        FramebufferSpec {
+            addr: self.addr,
+            fourcc: self.fourcc,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
         }
+    }
+    /// Check that the programmed framebuffer is valid and resides entirely in
+    /// readable guest memory.
+    ///
+    /// A stride of 0 means lines are tightly packed (width * bytes-per-pixel).
+    /// Any other stride is taken as the distance in bytes between the starts
+    /// of consecutive lines.  The region checked runs from `addr` through the
+    /// last pixel of the final line, so no padding is required after it.
+    fn verify(&self, mem: &MemCtx) -> Option<()> {
+        let total_sz = self.spec().byte_len()?;
 
-        let bypp = fourcc_bytepp(self.fourcc)?;
-
-        let mem = ctx.mctx.memctx();
-        let line_sz = if self.stride == 0 { self.width } else { self.stride };
-        let total_sz = u32::checked_mul(self.height - 1, line_sz)?
-            .checked_add(self.width)?
-            .checked_mul(bypp)?;
-        let _ = mem.raw_readable(&GuestRegion(
-            GuestAddr(self.addr),
-            total_sz as usize,
-        ))?;
+        let _ =
+            mem.raw_readable(&GuestRegion(GuestAddr(self.addr), total_sz))?;
 
         Some(())
     }
 }
-#[derive(Default)]
 pub struct RamFb {
     config: Mutex<Config>,
+    fb_override: Mutex<Option<(FramebufferSpec, Vec<u8>)>>,
+    last_update: Mutex<Option<Instant>>,
+    update_cv: Condvar,
+    streaming_copy: AtomicBool,
+    /// Source of the current time, against which updates are timed
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
 }
 impl RamFb {
     pub fn create() -> Arc<Self> {
-        Arc::new(Self::default())
+        Self::with_clock(Instant::now)
+    }
+    fn with_clock(
+        clock: impl Fn() -> Instant + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config: Mutex::new(Config::default()),
+            fb_override: Mutex::new(None),
+            last_update: Mutex::new(None),
+            update_cv: Condvar::new(),
+            streaming_copy: AtomicBool::new(false),
+            clock: Box::new(clock),
+        })
     }
     pub fn attach(self: &Arc<Self>, builder: &mut FwCfgBuilder) {
         builder
             .add_named("etc/ramfb", Arc::clone(self) as Arc<dyn Item>)
             .unwrap();
     }
+
+    /// Get the layout of the framebuffer, if one is configured.
+    ///
+    /// While an override is active, its layout is reported instead of that
+    /// programmed by the guest.
+    pub fn read_spec(&self) -> Option<FramebufferSpec> {
+        if let Some((spec, _)) = self.fb_override.lock().unwrap().as_ref() {
+            return Some(*spec);
+        }
+        let spec = self.config.lock().unwrap().spec();
+        spec.byte_len().map(|_| spec)
+    }
+
+    /// Copy out the contents of the framebuffer, if one is configured.
+    pub fn read_framebuffer(&self, ctx: &DispCtx) -> Option<Vec<u8>> {
+        if let Some(data) = self.read_override() {
+            return Some(data);
+        }
+        let spec = self.config.lock().unwrap().spec();
+        let len = spec.byte_len()?;
+
+        let mut buf = vec![0u8; len];
+        let mem = ctx.mctx.memctx();
+        if self.streaming_copy.load(Ordering::Relaxed) {
+            let ptr =
+                mem.raw_readable(&GuestRegion(GuestAddr(spec.addr), len))?;
+            // Safety: the region is mapped for (at least) `len` bytes
+            unsafe { copy_streaming(ptr, &mut buf) };
+        } else {
+            let copied = mem.read_into(GuestAddr(spec.addr), &mut buf, len)?;
+            if copied != len {
+                return None;
+            }
+        }
+        Some(buf)
+    }
+
+    /// Use non-temporal stores when copying out the framebuffer, so the large
+    /// per-frame copy does not evict more useful data from the cache.
+    pub fn set_streaming_copy(&self, enable: bool) {
+        self.streaming_copy.store(enable, Ordering::Relaxed);
+    }
+
+    /// Serve `data` as the framebuffer contents, rather than reading from the
+    /// guest-configured location, until [`RamFb::clear_override`] is called.
+    ///
+    /// The override is rejected if `spec` is invalid, or if `data` is shorter
+    /// than the framebuffer it describes.
+    pub fn set_override(
+        &self,
+        spec: FramebufferSpec,
+        data: Vec<u8>,
+    ) -> Result<(), SpecError> {
+        let len = spec.checked_len()?;
+        if data.len() < len {
+            return Err(SpecError::ShortData);
+        }
+
+        let mut fb_override = self.fb_override.lock().unwrap();
+        *fb_override = Some((spec, data));
+        Ok(())
+    }
+
+    /// Resume serving framebuffer contents from guest memory
+    pub fn clear_override(&self) {
+        let mut fb_override = self.fb_override.lock().unwrap();
+        *fb_override = None;
+    }
+
+    /// Report whether the guest has updated the framebuffer configuration
+    /// since `since`, without reading any of its contents.
+    ///
+    /// Callers polling for changes should pass the time of their prior check.
+    pub fn take_updated(&self, since: Instant) -> bool {
+        let last_update = self.last_update.lock().unwrap();
+        last_update.is_some_and(|when| when > since)
+    }
+
+    /// Block until the guest updates the framebuffer configuration after
+    /// `since`, and then until `window` passes without any further update, so
+    /// that a burst of updates results in a single wakeup.
+    ///
+    /// Returns the time of the last update observed.
+    pub fn wait_updated(&self, since: Instant, window: Duration) -> Instant {
+        let mut last_update = self.last_update.lock().unwrap();
+        let mut when = loop {
+            match *last_update {
+                Some(when) if when > since => break when,
+                _ => {}
+            }
+            last_update = self.update_cv.wait(last_update).unwrap();
+        };
+        loop {
+            let now = (self.clock)();
+            let deadline = when + window;
+            if now >= deadline {
+                return when;
+            }
+            last_update = self
+                .update_cv
+                .wait_timeout(last_update, deadline - now)
+                .unwrap()
+                .0;
+            when = last_update.unwrap();
+        }
+    }
+
+    fn mark_updated(&self) {
+        let mut last_update = self.last_update.lock().unwrap();
+        *last_update = Some((self.clock)());
+        self.update_cv.notify_all();
+    }
+
+    fn read_override(&self) -> Option<Vec<u8>> {
+        let fb_override = self.fb_override.lock().unwrap();
+        fb_override.as_ref().map(|(_spec, data)| data.clone())
+    }
+}
+/// Copy `dst.len()` bytes from `src` into `dst`, using non-temporal stores
+/// where the platform supports them.
+///
+/// The source is guest memory, which the guest may modify concurrently, so it
+/// is only ever accessed through raw pointer reads, never as a slice.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `dst.len()` bytes.
+#[cfg(target_arch = "x86_64")]
+unsafe fn copy_streaming(src: *const u8, dst: &mut [u8]) {
+    use std::arch::x86_64::{
+        __m128i, _mm_loadu_si128, _mm_sfence, _mm_stream_si128,
+    };
+    let len = dst.len();
+    let dp = dst.as_mut_ptr();
+
+    // Streaming stores must be 16-byte aligned, so copy any unaligned head and
+    // tail normally.
+    let head = usize::min(dp.align_offset(16), len);
+    copy_nonoverlapping(src, dp, head);
+    let chunks = (len - head) / 16;
+    let sp = src.add(head) as *const __m128i;
+    let cp = dp.add(head) as *mut __m128i;
+    for i in 0..chunks {
+        _mm_stream_si128(cp.add(i), _mm_loadu_si128(sp.add(i)));
+    }
+    _mm_sfence();
+    let tail = head + chunks * 16;
+    copy_nonoverlapping(src.add(tail), dp.add(tail), len - tail);
 }
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn copy_streaming(src: *const u8, dst: &mut [u8]) {
+    copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len());
+}
+
+impl Lifecycle for RamFb {
+    fn reset(&self, _ctx: &DispCtx) {
+        // The firmware must program the framebuffer anew.  Any override is
+        // set from the host side, and is left in place.
+        let mut config = self.config.lock().unwrap();
+        let was_valid = config.spec().byte_len().is_some();
+        *config = Config::default();
+        if was_valid {
+            self.mark_updated();
+        }
+    }
+}
+
 impl Item for RamFb {
     fn size(&self) -> u32 {
         CFG_REGS_LEN as u32
     }
-    fn fwcfg_rw(&self, mut rwo: RWOp, ctx: &DispCtx) -> fwcfg::Result {
+    fn fwcfg_rw(&self, rwo: RWOp, ctx: &DispCtx) -> fwcfg::Result {
+        self.config_rw(rwo, &ctx.mctx.memctx());
+        Ok(())
+    }
+}
+impl RamFb {
+    fn config_rw(&self, mut rwo: RWOp, mem: &MemCtx) {
         let mut config = self.config.lock().unwrap();
         let valid_before =
-            if rwo.is_write() { config.verify(ctx).is_some() } else { false };
+            if rwo.is_write() { config.verify(mem).is_some() } else { false };
 
         CFG_REGS.process(&mut rwo, |id, rwo| match rwo {
             RWOp::Read(ro) => match id {
@@ -112,7 +381,7 @@ impl Item for RamFb {
             },
         });
         if rwo.is_write() {
-            let valid_after = config.verify(ctx).is_some();
+            let valid_after = config.verify(mem).is_some();
             if valid_after != valid_before {
                 println!(
                     "ramfb {} valid",
@@ -121,14 +390,240 @@ impl Item for RamFb {
             }
             if valid_after {
                 println!("ramfb config: {:x?}", config);
+            } else if valid_before {
+                if let Err(e) = config.spec().validate() {
+                    println!("ramfb config invalid: {}", e);
+                }
             }
             match (valid_before, valid_after) {
-                (true, _) | (false, true) => {
-                    //TODO: notify about update
-                }
+                (true, _) | (false, true) => self.mark_updated(),
                 _ => {}
             }
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vmm::TestMem;
+
+    const XRGB: u32 = 0x34325258;
+
+    /// Clock which only advances when told to
+    #[derive(Clone)]
+    struct TestClock(Arc<Mutex<Instant>>);
+    impl TestClock {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    fn test_ramfb() -> (Arc<RamFb>, TestClock) {
+        let clock = TestClock::new();
+        let now = clock.clone();
+        (RamFb::with_clock(move || now.now()), clock)
+    }
+
+    fn spec(width: u32, height: u32) -> FramebufferSpec {
+        FramebufferSpec { addr: 0, fourcc: XRGB, width, height, stride: 0 }
+    }
+
+    /// Contents of the etc/ramfb fw_cfg entry, as written by firmware
+    fn cfg_bytes(spec: &FramebufferSpec) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(CFG_REGS_LEN);
+        buf.extend_from_slice(&spec.addr.to_be_bytes());
+        buf.extend_from_slice(&spec.fourcc.to_be_bytes());
+        // flags
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&spec.width.to_be_bytes());
+        buf.extend_from_slice(&spec.height.to_be_bytes());
+        buf.extend_from_slice(&spec.stride.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn cfg_parse() {
+        let tmem = TestMem::new(0x10000);
+        let mem = tmem.memctx();
+        let (ramfb, clock) = test_ramfb();
+        assert_eq!(ramfb.size() as usize, CFG_REGS_LEN);
+
+        let fb = FramebufferSpec {
+            addr: 0x1000,
+            fourcc: XRGB,
+            width: 32,
+            height: 16,
+            stride: 0x100,
+        };
+        let buf = cfg_bytes(&fb);
+        assert_eq!(buf.len(), CFG_REGS_LEN);
+        let start = clock.now();
+        clock.advance(Duration::from_millis(1));
+        ramfb.config_rw(RWOp::Write(&mut WriteOp::new_buf(0, &buf)), &mem);
+        assert_eq!(ramfb.read_spec(), Some(fb));
+        assert!(ramfb.take_updated(start));
+
+        // The entry reads back as written
+        let mut readback = vec![0u8; CFG_REGS_LEN];
+        ramfb.config_rw(
+            RWOp::Read(&mut ReadOp::new_buf(0, &mut readback)),
+            &mem,
+        );
+        assert_eq!(readback, buf);
+
+        // A partial write, of only the height, is applied in place
+        let height = 8u32.to_be_bytes();
+        ramfb.config_rw(RWOp::Write(&mut WriteOp::new_buf(20, &height)), &mem);
+        assert_eq!(
+            ramfb.read_spec(),
+            Some(FramebufferSpec { height: 8, ..fb })
+        );
+
+        // A framebuffer extending beyond guest memory is not valid
+        let polled = clock.now();
+        clock.advance(Duration::from_millis(1));
+        let outside = FramebufferSpec { addr: 0xf800, ..fb };
+        let buf = cfg_bytes(&outside);
+        ramfb.config_rw(RWOp::Write(&mut WriteOp::new_buf(0, &buf)), &mem);
+        assert!(ramfb.take_updated(polled));
+        assert!(ramfb.config.lock().unwrap().verify(&mem).is_none());
+    }
+
+    #[test]
+    fn spec_validate() {
+        assert_eq!(spec(1024, 768).validate(), Ok(()));
+        assert_eq!(spec(1024, 768).byte_len(), Some(1024 * 768 * 4));
+        let max = spec(FB_MAX_DIMENSION, FB_MAX_DIMENSION);
+        assert_eq!(max.validate(), Ok(()));
+
+        assert_eq!(spec(0, 768).validate(), Err(SpecError::ZeroDimension));
+        assert_eq!(spec(1024, 0).validate(), Err(SpecError::ZeroDimension));
+
+        let bad_fmt = FramebufferSpec { fourcc: 0x1234, ..spec(1024, 768) };
+        assert_eq!(
+            bad_fmt.validate(),
+            Err(SpecError::UnsupportedFourcc(0x1234))
+        );
+        assert_eq!(bad_fmt.byte_len(), None);
+
+        assert_eq!(
+            spec(FB_MAX_DIMENSION + 1, 768).validate(),
+            Err(SpecError::TooLarge)
+        );
+        assert_eq!(
+            spec(1024, FB_MAX_DIMENSION + 1).validate(),
+            Err(SpecError::TooLarge)
+        );
+        let wide_stride = FramebufferSpec { stride: u32::MAX, ..spec(1, 2) };
+        assert_eq!(wide_stride.validate(), Err(SpecError::TooLarge));
+    }
+
+    #[test]
+    fn override_toggle() {
+        let ramfb = RamFb::create();
+        // Nothing has been programmed by the guest
+        assert_eq!(ramfb.read_spec(), None);
+        assert_eq!(ramfb.read_override(), None);
+
+        let spec = FramebufferSpec {
+            addr: 0,
+            fourcc: 0x34325258,
+            width: 4,
+            height: 2,
+            stride: 0,
+        };
+        assert_eq!(spec.byte_len(), Some(32));
+        let data: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            ramfb.set_override(spec, data[..31].to_vec()),
+            Err(SpecError::ShortData)
+        );
+        let bad = FramebufferSpec { width: 0, ..spec };
+        assert_eq!(
+            ramfb.set_override(bad, data.clone()),
+            Err(SpecError::ZeroDimension)
+        );
+        assert_eq!(ramfb.read_override(), None);
+
+        ramfb.set_override(spec, data.clone()).unwrap();
+        assert_eq!(ramfb.read_spec(), Some(spec));
+        assert_eq!(ramfb.read_override(), Some(data));
+
+        ramfb.clear_override();
+        assert_eq!(ramfb.read_spec(), None);
+        assert_eq!(ramfb.read_override(), None);
+    }
+
+    #[test]
+    fn updated_flag() {
+        let (ramfb, clock) = test_ramfb();
+        let start = clock.now();
+        assert!(!ramfb.take_updated(start));
+
+        // As done by a config write which makes the framebuffer valid
+        clock.advance(Duration::from_millis(1));
+        ramfb.mark_updated();
+        assert!(ramfb.take_updated(start));
+
+        // Polling again from after the update observes nothing new
+        let polled = clock.now();
+        assert!(!ramfb.take_updated(polled));
+        clock.advance(Duration::from_millis(1));
+        ramfb.mark_updated();
+        assert!(ramfb.take_updated(polled));
+    }
+
+    #[test]
+    fn streaming_copy_matches() {
+        let src: Vec<u8> = (0..4300u32).map(|n| (n * 7 % 256) as u8).collect();
+        for len in [0, 1, 15, 16, 17, 100, 4096, 4199].iter() {
+            for off in 0..16 {
+                let src = &src[off..(off + len)];
+                let mut plain = vec![0u8; len + 16];
+                let mut streamed = vec![0u8; len + 16];
+                plain[off..(off + len)].copy_from_slice(src);
+                unsafe {
+                    copy_streaming(
+                        src.as_ptr(),
+                        &mut streamed[off..(off + len)],
+                    )
+                };
+                assert_eq!(plain, streamed, "len {} off {}", len, off);
+            }
+        }
+    }
+
+    #[test]
+    fn updates_debounced() {
+        let (ramfb, clock) = test_ramfb();
+        let start = clock.now();
+        let window = Duration::from_millis(10);
+
+        clock.advance(Duration::from_millis(1));
+        ramfb.mark_updated();
+        let waiter = Arc::clone(&ramfb);
+        let hdl =
+            std::thread::spawn(move || waiter.wait_updated(start, window));
+
+        // However the waiter is scheduled, updates spaced within the window
+        // extend it, as time only passes when the clock is advanced.
+        for _ in 0..20 {
+            clock.advance(window / 2);
+            ramfb.mark_updated();
+        }
+        let last = clock.now();
+        clock.advance(window);
+        let woke = hdl.join().unwrap();
+
+        // The single wakeup covered the entire burst
+        assert_eq!(woke, last);
+        assert!(!ramfb.take_updated(woke));
     }
 }
@@ -0,0 +1,485 @@
+//! SMBIOS tables describing the instance, as conveyed to firmware through the
+//! `etc/smbios/smbios-tables` and `etc/smbios/smbios-anchor` fw_cfg files.
+//!
+//! Firmware (OVMF) installs the structures itself, taking the version from the
+//! anchor, so the structure table address in the anchor is left as zero.
+
+use byteorder::{ByteOrder, LE};
+
+use super::fwcfg::{self, FixedItem, FwCfgBuilder};
+use crate::vmm::CpuTopology;
+
+const SMBIOS_MAJOR: u8 = 2;
+const SMBIOS_MINOR: u8 = 8;
+const ANCHOR_LEN: usize = 0x1f;
+
+const MANUFACTURER: &str = "Propolis";
+
+const HANDLE_NONE: u16 = 0xffff;
+/// Memory error information not provided
+const HANDLE_NO_ERR: u16 = 0xfffe;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEM_ARRAY: u8 = 16;
+const TYPE_MEM_DEVICE: u8 = 17;
+const TYPE_BOOT_INFO: u8 = 32;
+const TYPE_END: u8 = 127;
+
+// Processor characteristics
+const PROC_UNKNOWN: u16 = 1 << 1;
+const PROC_MULTI_CORE: u16 = 1 << 3;
+const PROC_HW_THREAD: u16 = 1 << 4;
+
+/// Details of the instance reflected in the tables
+#[derive(Clone, Debug, Default)]
+pub struct SmbiosParams {
+    /// System UUID, in its RFC 4122 (big-endian) byte order
+    pub uuid: [u8; 16],
+    pub serial: Option<String>,
+    /// Size of guest memory, in bytes
+    pub mem_size: u64,
+    pub topology: CpuTopology,
+}
+
+/// A single structure: its formatted area followed by the string-set
+struct Table {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    nstrings: u8,
+}
+impl Table {
+    fn new(kind: u8, handle: u16) -> Self {
+        let mut data = vec![kind, 0];
+        data.extend_from_slice(&handle.to_le_bytes());
+        Self { data, strings: Vec::new(), nstrings: 0 }
+    }
+    fn u8(&mut self, val: u8) -> &mut Self {
+        self.data.push(val);
+        self
+    }
+    fn u16(&mut self, val: u16) -> &mut Self {
+        self.data.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+    fn u32(&mut self, val: u32) -> &mut Self {
+        self.data.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+    fn u64(&mut self, val: u64) -> &mut Self {
+        self.data.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+    fn bytes(&mut self, val: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(val);
+        self
+    }
+    /// Reference a string, stored in the string-set.  Empty strings are
+    /// represented by the index 0, rather than occupying an entry.
+    fn string(&mut self, val: &str) -> &mut Self {
+        let val: Vec<u8> = val.bytes().filter(|b| *b != 0).collect();
+        if val.is_empty() {
+            return self.u8(0);
+        }
+        self.strings.extend_from_slice(&val);
+        self.strings.push(0);
+        self.nstrings += 1;
+        let idx = self.nstrings;
+        self.u8(idx)
+    }
+    fn finish(&mut self, out: &mut Vec<u8>) -> usize {
+        assert!(self.data.len() <= u8::MAX as usize);
+        self.data[1] = self.data.len() as u8;
+        let start = out.len();
+        out.extend_from_slice(&self.data);
+        if self.strings.is_empty() {
+            // An empty string-set is still terminated by a double NUL
+            out.extend_from_slice(&[0, 0]);
+        } else {
+            out.extend_from_slice(&self.strings);
+            out.push(0);
+        }
+        out.len() - start
+    }
+}
+
+/// Encode a UUID as SMBIOS expects: with its first three fields little-endian
+fn smbios_uuid(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut out = *uuid;
+    out[0..4].reverse();
+    out[4..6].reverse();
+    out[6..8].reverse();
+    out
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+pub struct Smbios {
+    tables: Vec<u8>,
+    count: u16,
+    max_size: u16,
+}
+impl Smbios {
+    pub fn new(params: &SmbiosParams) -> Self {
+        let mut this = Self { tables: Vec::new(), count: 0, max_size: 0 };
+        let serial = params.serial.as_deref().unwrap_or("");
+
+        this.add(
+            Table::new(TYPE_BIOS, 0)
+                .string(MANUFACTURER)
+                .string(env!("CARGO_PKG_VERSION"))
+                // starting segment
+                .u16(0xe800)
+                // release date
+                .string("")
+                // ROM size
+                .u8(0)
+                // characteristics: not supported
+                .u64(1 << 3)
+                // extension bytes: describes a virtual machine
+                .u8(0)
+                .u8(1 << 4)
+                // BIOS and EC release
+                .u8(0)
+                .u8(0)
+                .u8(0xff)
+                .u8(0xff),
+        );
+        this.add(
+            Table::new(TYPE_SYSTEM, TYPE_SYSTEM as u16 * 0x100)
+                .string(MANUFACTURER)
+                .string("Propolis VM")
+                .string("")
+                .string(serial)
+                .bytes(&smbios_uuid(&params.uuid))
+                // wake-up type: power switch
+                .u8(0x06)
+                .string("")
+                .string(""),
+        );
+        this.add(
+            Table::new(TYPE_BASEBOARD, TYPE_BASEBOARD as u16 * 0x100)
+                .string(MANUFACTURER)
+                .string("")
+                .string("")
+                .string("")
+                .string("")
+                // feature flags: hosting board
+                .u8(1 << 0)
+                .string("")
+                .u16(TYPE_CHASSIS as u16 * 0x100)
+                // board type: motherboard
+                .u8(0x0a)
+                // contained object handles
+                .u8(0),
+        );
+        this.add(
+            Table::new(TYPE_CHASSIS, TYPE_CHASSIS as u16 * 0x100)
+                .string(MANUFACTURER)
+                // type: other
+                .u8(0x01)
+                .string("")
+                .string("")
+                .string("")
+                // boot-up, power supply, and thermal state: safe
+                .u8(0x03)
+                .u8(0x03)
+                .u8(0x03)
+                // security status: unknown
+                .u8(0x02)
+                // OEM-defined
+                .u32(0)
+                // height, power cords
+                .u8(0)
+                .u8(0)
+                // contained elements (count and record length)
+                .u8(0)
+                .u8(0)
+                .string(""),
+        );
+        // A processor structure for each socket
+        let topo = &params.topology;
+        let threads = topo.cores() * topo.threads();
+        let mut characteristics = 0;
+        if topo.cores() > 1 {
+            characteristics |= PROC_MULTI_CORE;
+        }
+        if topo.threads() > 1 {
+            characteristics |= PROC_HW_THREAD;
+        }
+        if characteristics == 0 {
+            characteristics = PROC_UNKNOWN;
+        }
+        for socket in 0..topo.sockets() {
+            this.add(
+                Table::new(
+                    TYPE_PROCESSOR,
+                    TYPE_PROCESSOR as u16 * 0x100 + socket as u16,
+                )
+                .string(&format!("CPU {}", socket))
+                // type: central processor, family: other
+                .u8(0x03)
+                .u8(0x01)
+                .string(MANUFACTURER)
+                // processor ID
+                .u64(0)
+                .string("")
+                // voltage, external clock
+                .u8(0)
+                .u16(0)
+                // max and current speed (MHz)
+                .u16(2000)
+                .u16(2000)
+                // status: populated, enabled
+                .u8(0x41)
+                // upgrade: other
+                .u8(0x01)
+                // L1-L3 cache handles
+                .u16(HANDLE_NONE)
+                .u16(HANDLE_NONE)
+                .u16(HANDLE_NONE)
+                .string("")
+                .string("")
+                .string("")
+                // cores, cores enabled, threads
+                .u8(topo.cores())
+                .u8(topo.cores())
+                .u8(threads)
+                .u16(characteristics)
+                .u16(0x01),
+            );
+        }
+
+        let mem_kib = params.mem_size / 1024;
+        this.add(
+            Table::new(TYPE_MEM_ARRAY, TYPE_MEM_ARRAY as u16 * 0x100)
+                // location: other, use: system memory, ECC: none
+                .u8(0x01)
+                .u8(0x03)
+                .u8(0x03)
+                .u32(if mem_kib < 0x8000_0000 {
+                    mem_kib as u32
+                } else {
+                    0x8000_0000
+                })
+                .u16(HANDLE_NO_ERR)
+                // devices
+                .u16(1)
+                .u64(if mem_kib < 0x8000_0000 { 0 } else { params.mem_size }),
+        );
+        let mem_mib = params.mem_size / (1024 * 1024);
+        this.add(
+            Table::new(TYPE_MEM_DEVICE, TYPE_MEM_DEVICE as u16 * 0x100)
+                .u16(TYPE_MEM_ARRAY as u16 * 0x100)
+                .u16(HANDLE_NO_ERR)
+                // total and data width
+                .u16(64)
+                .u16(64)
+                .u16(if mem_mib < 0x7fff { mem_mib as u16 } else { 0x7fff })
+                // form factor: DIMM
+                .u8(0x09)
+                // device set
+                .u8(0)
+                .string("DIMM 0")
+                .string("")
+                // type: RAM, detail: other
+                .u8(0x07)
+                .u16(1 << 1)
+                // speed
+                .u16(0)
+                .string(MANUFACTURER)
+                .string("")
+                .string("")
+                .string("")
+                // attributes
+                .u8(0)
+                .u32(if mem_mib < 0x7fff { 0 } else { mem_mib as u32 })
+                // configured speed, and minimum, maximum, configured voltage
+                .u16(0)
+                .u16(0)
+                .u16(0)
+                .u16(0),
+        );
+        this.add(
+            Table::new(TYPE_BOOT_INFO, TYPE_BOOT_INFO as u16 * 0x100)
+                .bytes(&[0; 6])
+                // status: no errors detected
+                .u8(0),
+        );
+        this.add(&mut Table::new(TYPE_END, TYPE_END as u16 * 0x100));
+        this
+    }
+
+    fn add(&mut self, table: &mut Table) {
+        let size = table.finish(&mut self.tables);
+        self.count += 1;
+        self.max_size = self.max_size.max(size as u16);
+    }
+
+    /// Contents of the `etc/smbios/smbios-anchor` file: a 32-bit (2.x) entry
+    /// point structure
+    fn anchor(&self) -> [u8; ANCHOR_LEN] {
+        let mut buf = [0u8; ANCHOR_LEN];
+        buf[0..4].copy_from_slice(b"_SM_");
+        buf[5] = ANCHOR_LEN as u8;
+        buf[6] = SMBIOS_MAJOR;
+        buf[7] = SMBIOS_MINOR;
+        LE::write_u16(&mut buf[8..10], self.max_size);
+        buf[16..21].copy_from_slice(b"_DMI_");
+        LE::write_u16(&mut buf[22..24], self.tables.len() as u16);
+        LE::write_u16(&mut buf[28..30], self.count);
+        buf[30] = SMBIOS_MAJOR << 4 | SMBIOS_MINOR;
+
+        buf[21] = checksum(&buf[16..]);
+        buf[4] = checksum(&buf);
+        buf
+    }
+
+    pub fn attach(&self, builder: &mut FwCfgBuilder) -> fwcfg::Result {
+        builder.add_named(
+            "etc/smbios/smbios-tables",
+            FixedItem::new_raw(self.tables.clone()),
+        )?;
+        builder.add_named(
+            "etc/smbios/smbios-anchor",
+            FixedItem::new_raw(self.anchor().to_vec()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A structure as (type, handle, formatted area, strings)
+    type Parsed<'a> = (u8, u16, &'a [u8], Vec<&'a [u8]>);
+
+    fn walk(mut data: &[u8]) -> Vec<Parsed> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let len = data[1] as usize;
+            let (area, rest) = data.split_at(len);
+            let end = rest.windows(2).position(|w| w == [0, 0]).unwrap();
+            let strings = rest[..end]
+                .split(|b| *b == 0)
+                .filter(|s| !s.is_empty())
+                .collect();
+            out.push((area[0], LE::read_u16(&area[2..4]), area, strings));
+            data = &rest[end + 2..];
+        }
+        out
+    }
+
+    fn params() -> SmbiosParams {
+        SmbiosParams {
+            uuid: [
+                0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99,
+                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+            ],
+            serial: Some("abc123".to_string()),
+            mem_size: 1024 * 1024 * 1024,
+            topology: CpuTopology::flat(2),
+        }
+    }
+
+    #[test]
+    fn structures() {
+        let smbios = Smbios::new(&params());
+        let tables = walk(&smbios.tables);
+        let types: Vec<u8> = tables.iter().map(|t| t.0).collect();
+        assert_eq!(types, [0, 1, 2, 3, 4, 4, 16, 17, 32, 127]);
+        assert_eq!(tables.len(), smbios.count as usize);
+
+        let mut handles: Vec<u16> = tables.iter().map(|t| t.1).collect();
+        handles.dedup();
+        assert_eq!(handles.len(), tables.len());
+
+        // Formatted area lengths, per SMBIOS 2.8
+        let lens: Vec<usize> = tables.iter().map(|t| t.2.len()).collect();
+        assert_eq!(lens, [24, 27, 15, 22, 42, 42, 23, 40, 11, 4]);
+
+        let (_, _, system, strings) = &tables[1];
+        // The empty version string is omitted from the string-set
+        assert_eq!(system[7], 3);
+        assert_eq!(strings[2], b"abc123");
+        assert_eq!(
+            &system[8..24],
+            &[
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99,
+                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff
+            ]
+        );
+        assert_eq!(tables[5].3[0], b"CPU 1");
+
+        // 1GiB of memory, in KiB and MiB
+        assert_eq!(LE::read_u32(&tables[6].2[7..11]), 1024 * 1024);
+        assert_eq!(LE::read_u16(&tables[7].2[12..14]), 1024);
+    }
+
+    #[test]
+    fn processor_topology() {
+        let procs = |topology| {
+            let smbios = Smbios::new(&SmbiosParams { topology, ..params() });
+            walk(&smbios.tables)
+                .iter()
+                .filter(|t| t.0 == TYPE_PROCESSOR)
+                .map(|t| {
+                    // cores, cores enabled, threads, and characteristics
+                    let area = t.2;
+                    (t.3[0].to_vec(), area[35], area[36], area[37], area[38])
+                })
+                .collect::<Vec<_>>()
+        };
+        let cpu = |n: u8| format!("CPU {}", n).into_bytes();
+
+        assert_eq!(
+            procs(CpuTopology::flat(2)),
+            [(cpu(0), 1, 1, 1, 0x02), (cpu(1), 1, 1, 1, 0x02)]
+        );
+        // 2 sockets of 4 cores, each with 2 threads
+        assert_eq!(
+            procs(CpuTopology::new(2, 4, 2).unwrap()),
+            [(cpu(0), 4, 4, 8, 0x18), (cpu(1), 4, 4, 8, 0x18)]
+        );
+        assert_eq!(
+            procs(CpuTopology::new(1, 8, 1).unwrap()),
+            [(cpu(0), 8, 8, 8, 0x08)]
+        );
+        assert_eq!(
+            procs(CpuTopology::new(1, 1, 2).unwrap()),
+            [(cpu(0), 1, 1, 2, 0x10)]
+        );
+    }
+
+    #[test]
+    fn large_memory() {
+        let mut p = params();
+        p.mem_size = 4 << 40;
+        let smbios = Smbios::new(&p);
+        let tables = walk(&smbios.tables);
+
+        let array = tables[6].2;
+        assert_eq!(LE::read_u32(&array[7..11]), 0x8000_0000);
+        assert_eq!(LE::read_u64(&array[15..23]), 4 << 40);
+        let device = tables[7].2;
+        assert_eq!(LE::read_u16(&device[12..14]), 0x7fff);
+        assert_eq!(LE::read_u32(&device[28..32]), 4 << 20);
+    }
+
+    #[test]
+    fn anchor() {
+        let smbios = Smbios::new(&params());
+        let anchor = smbios.anchor();
+        assert_eq!(&anchor[0..4], b"_SM_");
+        assert_eq!(checksum(&anchor), 0);
+        assert_eq!(checksum(&anchor[16..]), 0);
+        assert_eq!(LE::read_u16(&anchor[22..24]) as usize, smbios.tables.len());
+        assert_eq!(LE::read_u16(&anchor[28..30]), 10);
+        assert_eq!(anchor[30], 0x28);
+    }
+}
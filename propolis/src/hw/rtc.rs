@@ -1,5 +1,5 @@
-use std::io::Result;
-use std::time::SystemTime;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, SystemTime};
 
 use crate::vmm::VmmHdl;
 
@@ -9,16 +9,210 @@ const MEM_BASE: usize = 16 * 1024 * 1024;
 const MEM_OFF_LOW: u8 = 0x34;
 const MEM_OFF_HIGH: u8 = 0x5b;
 
+/// Shutdown status byte, through which firmware learns why it was started
+const CMOS_OFF_SHUTDOWN: u8 = 0x0f;
+const SHUTDOWN_S3_RESUME: u8 = 0xfe;
+
+const CMOS_OFF_ALARM_SEC: u8 = 0x01;
+const CMOS_OFF_ALARM_MIN: u8 = 0x03;
+const CMOS_OFF_ALARM_HOUR: u8 = 0x05;
+const CMOS_OFF_REG_B: u8 = 0x0b;
+const REG_B_24H: u8 = 1 << 1;
+/// Time fields are in binary, rather than BCD
+const REG_B_DM: u8 = 1 << 2;
+const REG_B_AIE: u8 = 1 << 5;
+/// Alarm fields with the top two bits set match any value
+const ALARM_DONT_CARE: u8 = 0xc0;
+/// Hour field flag for PM, in 12-hour mode
+const HOUR_PM: u8 = 1 << 7;
+const SECS_PER_DAY: u32 = 86400;
+
+/// Does the CMOS time (seconds, minutes, hours) match that of the alarm?
+///
+/// Both are in the encoding (BCD or binary, 12 or 24 hour) chosen by the
+/// guest, so they can be compared directly.
+fn alarm_matches(time: [u8; 3], alarm: [u8; 3]) -> bool {
+    time.iter()
+        .zip(alarm.iter())
+        .all(|(t, a)| a & ALARM_DONT_CARE == ALARM_DONT_CARE || t == a)
+}
+
+/// Time of day (in seconds) represented by the CMOS seconds, minutes and
+/// hours fields, in the encoding indicated by `reg_b`.
+fn decode_time(time: [u8; 3], reg_b: u8) -> u32 {
+    let field = |v: u8| match reg_b & REG_B_DM {
+        0 => (v >> 4) as u32 * 10 + (v & 0xf) as u32,
+        _ => v as u32,
+    };
+    let hour = match reg_b & REG_B_24H {
+        0 => {
+            let pm = time[2] & HOUR_PM != 0;
+            field(time[2] & !HOUR_PM) % 12 + if pm { 12 } else { 0 }
+        }
+        _ => field(time[2]),
+    };
+    field(time[0]) + field(time[1]) * 60 + hour * 3600
+}
+
+/// Encode a CMOS field value, in BCD or binary as indicated by `reg_b`
+fn encode_field(v: u32, reg_b: u8) -> u8 {
+    match reg_b & REG_B_DM {
+        0 => ((v / 10) << 4 | (v % 10)) as u8,
+        _ => v as u8,
+    }
+}
+
+/// Inverse of [`decode_time`]
+fn encode_time(secs: u32, reg_b: u8) -> [u8; 3] {
+    let field = |v: u32| encode_field(v, reg_b);
+    let hour = secs / 3600;
+    let hour = match reg_b & REG_B_24H {
+        0 => {
+            let pm = if hour >= 12 { HOUR_PM } else { 0 };
+            field(match hour % 12 {
+                0 => 12,
+                h => h,
+            }) | pm
+        }
+        _ => field(hour),
+    };
+    [field(secs % 60), field(secs / 60 % 60), hour]
+}
+
+/// Seconds from `time` until the alarm next matches, if it does so within a
+/// day.  An alarm which matches `time` itself is due immediately.
+fn secs_until_alarm(time: [u8; 3], alarm: [u8; 3], reg_b: u8) -> Option<u32> {
+    let now = decode_time(time, reg_b);
+    (0..SECS_PER_DAY).find(|delta| {
+        let then = (now + delta) % SECS_PER_DAY;
+        alarm_matches(encode_time(then, reg_b), alarm)
+    })
+}
+
+/// Seconds since the Unix epoch, which the RTC cannot represent times before
+fn unix_secs(time: SystemTime) -> Result<u64> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "time precedes epoch"))
+}
+
+/// Date and time, broken down into the fields held in the RTC CMOS
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CmosTime {
+    pub sec: u8,
+    pub min: u8,
+    pub hour: u8,
+    /// Day of week, 1 (Sunday) through 7
+    pub wday: u8,
+    pub mday: u8,
+    pub month: u8,
+    pub year: u8,
+    pub century: u8,
+}
+impl CmosTime {
+    /// Break down a time (in UTC) given as seconds since the Unix epoch.
+    pub fn from_unix(secs: u64) -> Self {
+        let days = secs / SECS_PER_DAY as u64;
+        let rem = secs % SECS_PER_DAY as u64;
+
+        // Civil date from day count, per Howard Hinnant's days_from_civil
+        // inverse, with eras of 400 years starting on March 1st.
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let mday = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            sec: (rem % 60) as u8,
+            min: (rem / 60 % 60) as u8,
+            hour: (rem / 3600) as u8,
+            // 1970-01-01 was a Thursday
+            wday: ((days + 4) % 7 + 1) as u8,
+            mday: mday as u8,
+            month: month as u8,
+            year: (year % 100) as u8,
+            century: (year / 100) as u8,
+        }
+    }
+
+    /// Contents of the CMOS time registers (offsets 0x00 - 0x09, with the
+    /// alarm fields zeroed) and century register (0x32), in the encoding (BCD
+    /// or binary, 12 or 24 hour) indicated by `reg_b`.
+    pub fn regs(&self, reg_b: u8) -> ([u8; 10], u8) {
+        let field = |v: u8| encode_field(v as u32, reg_b);
+        let secs = self.sec as u32 + self.min as u32 * 60;
+        let time = encode_time(secs + self.hour as u32 * 3600, reg_b);
+        let regs = [
+            time[0],
+            0,
+            time[1],
+            0,
+            time[2],
+            0,
+            field(self.wday),
+            field(self.mday),
+            field(self.month),
+            field(self.year),
+        ];
+        (regs, field(self.century))
+    }
+}
+
 pub struct Rtc {}
 
 impl Rtc {
     pub fn set_time(hdl: &VmmHdl) -> Result<()> {
-        hdl.rtc_settime(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-        )
+        Self::set_time_to(hdl, SystemTime::now())?;
+        Ok(())
+    }
+
+    /// Set the RTC time base to `time`, yielding its breakdown into the CMOS
+    /// fields.
+    ///
+    /// The in-kernel RTC derives its CMOS time registers from the time base
+    /// (and does not permit them to be written directly), so the guest observes
+    /// those fields, encoded per [`CmosTime::regs`], on its next read.
+    pub fn set_time_to(hdl: &VmmHdl, time: SystemTime) -> Result<CmosTime> {
+        let secs = unix_secs(time)?;
+        hdl.rtc_settime(secs)?;
+        Ok(CmosTime::from_unix(secs))
+    }
+
+    /// Flag the next firmware start as a resume from S3, via the CMOS shutdown
+    /// status byte.  Firmware clears the flag once it has acted upon it.
+    pub fn set_s3_resume(hdl: &VmmHdl) -> Result<()> {
+        hdl.rtc_write(CMOS_OFF_SHUTDOWN, SHUTDOWN_S3_RESUME)?;
+        Ok(())
+    }
+
+    /// If the guest has armed the RTC alarm, how long until it is due?
+    ///
+    /// The in-kernel RTC cannot deliver its alarm interrupt to a sleeping
+    /// instance, so this is used to time the alarm as a wake event.  The RTC
+    /// time is read at a granularity of seconds, so the alarm may in fact be
+    /// due up to a second sooner.  An alarm which can never match (such as
+    /// one for an hour beyond 23) is treated as disarmed.
+    pub fn alarm_delay(hdl: &VmmHdl) -> Result<Option<Duration>> {
+        let reg_b = hdl.rtc_read(CMOS_OFF_REG_B)?;
+        if reg_b & REG_B_AIE == 0 {
+            return Ok(None);
+        }
+        let mut time = [0u8; 3];
+        let mut alarm = [0u8; 3];
+        let offs =
+            [CMOS_OFF_ALARM_SEC, CMOS_OFF_ALARM_MIN, CMOS_OFF_ALARM_HOUR];
+        for (i, off) in offs.iter().enumerate() {
+            // Each time field immediately precedes its alarm counterpart
+            time[i] = hdl.rtc_read(off - 1)?;
+            alarm[i] = hdl.rtc_read(*off)?;
+        }
+        let secs = secs_until_alarm(time, alarm, reg_b);
+        Ok(secs.map(|s| Duration::from_secs(s as u64)))
     }
 
     pub fn store_memory_sizing(
@@ -49,3 +243,111 @@ impl Rtc {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alarm_match() {
+        // 12:30:15 (BCD)
+        let time = [0x15, 0x30, 0x12];
+        assert!(alarm_matches(time, [0x15, 0x30, 0x12]));
+        assert!(!alarm_matches(time, [0x16, 0x30, 0x12]));
+        // Don't-care fields match anything
+        assert!(alarm_matches(time, [0x15, 0xff, 0xc0]));
+        assert!(alarm_matches(time, [0xc5, 0xc0, 0xc0]));
+        assert!(!alarm_matches(time, [0x15, 0xc0, 0x11]));
+    }
+
+    #[test]
+    fn alarm_delay() {
+        let bcd_24h = REG_B_24H;
+        // 23:59:50, with the alarm at midnight
+        let time = [0x50, 0x59, 0x23];
+        assert_eq!(
+            secs_until_alarm(time, [0x00, 0x00, 0x00], bcd_24h),
+            Some(10)
+        );
+        assert_eq!(
+            secs_until_alarm(time, [0x50, 0x59, 0x23], bcd_24h),
+            Some(0)
+        );
+        // Every minute, on the half-minute
+        assert_eq!(
+            secs_until_alarm(time, [0x30, 0xc0, 0xc0], bcd_24h),
+            Some(40)
+        );
+        assert_eq!(secs_until_alarm(time, [0x00, 0x00, 0x24], bcd_24h), None);
+
+        // 11:59:59 PM in binary, 12-hour mode, with the alarm at 12:00:01 AM
+        let bin_12h = REG_B_DM;
+        let time = [59, 59, 11 | HOUR_PM];
+        assert_eq!(decode_time(time, bin_12h), SECS_PER_DAY - 1);
+        assert_eq!(secs_until_alarm(time, [1, 0, 12], bin_12h), Some(2));
+        assert_eq!(encode_time(12 * 3600, bin_12h), [0, 0, 12 | HOUR_PM]);
+    }
+
+    #[test]
+    fn cmos_encoding() {
+        // Thursday, 1970-01-01 00:00:00
+        let epoch = CmosTime::from_unix(0);
+        assert_eq!(
+            epoch.regs(REG_B_24H),
+            ([0x00, 0, 0x00, 0, 0x00, 0, 0x05, 0x01, 0x01, 0x70], 0x19)
+        );
+        // Midnight is 12 AM in 12-hour mode
+        assert_eq!(epoch.regs(0).0[4], 0x12);
+
+        // Saturday, 2020-02-29 13:45:56
+        let leap = CmosTime::from_unix(1582983956);
+        assert_eq!(
+            leap,
+            CmosTime {
+                sec: 56,
+                min: 45,
+                hour: 13,
+                wday: 7,
+                mday: 29,
+                month: 2,
+                year: 20,
+                century: 20
+            }
+        );
+        assert_eq!(
+            leap.regs(REG_B_24H),
+            ([0x56, 0, 0x45, 0, 0x13, 0, 0x07, 0x29, 0x02, 0x20], 0x20)
+        );
+        assert_eq!(
+            leap.regs(0),
+            (
+                [0x56, 0, 0x45, 0, 0x01 | HOUR_PM, 0, 0x07, 0x29, 0x02, 0x20],
+                0x20
+            )
+        );
+        assert_eq!(
+            leap.regs(REG_B_DM | REG_B_24H),
+            ([56, 0, 45, 0, 13, 0, 7, 29, 2, 20], 20)
+        );
+        assert_eq!(
+            leap.regs(REG_B_DM),
+            ([56, 0, 45, 0, 1 | HOUR_PM, 0, 7, 29, 2, 20], 20)
+        );
+
+        // Sunday, 2000-01-02 23:59:59
+        let (regs, century) =
+            CmosTime::from_unix(946857599).regs(REG_B_DM | REG_B_24H);
+        assert_eq!(regs, [59, 0, 59, 0, 23, 0, 1, 2, 1, 0]);
+        assert_eq!(century, 20);
+    }
+
+    #[test]
+    fn pre_epoch() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(unix_secs(epoch).unwrap(), 0);
+        assert_eq!(unix_secs(epoch + Duration::from_millis(1500)).unwrap(), 1);
+
+        let err = unix_secs(epoch - Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -4,6 +4,7 @@ use super::base::Uart;
 use crate::chardev::*;
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::hw::Lifecycle;
 use crate::intr_pins::{IntrPin, LegacyPin};
 use crate::pio::PioDev;
 
@@ -92,6 +93,24 @@ impl Source for LpcUart {
     }
 }
 
+impl Lifecycle for LpcUart {
+    fn reset(&self, ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        let writable_before = state.uart.is_writable();
+        state.uart = Uart::new();
+        state.sync_intr_pin();
+        let write_notify = !writable_before && state.uart.is_writable();
+        drop(state);
+
+        if write_notify {
+            let notifiers = self.notifiers.lock().unwrap();
+            if let Some(cb) = notifiers.notify_writable.as_ref() {
+                cb(ctx);
+            }
+        }
+    }
+}
+
 impl PioDev for LpcUart {
     fn pio_rw(&self, _port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
         assert!(rwo.offset() < REGISTER_LEN);
@@ -1,5 +1,10 @@
 pub const VIRTIO_DEV_NET: u16 = 0x1000;
 pub const VIRTIO_DEV_BLOCK: u16 = 0x1001;
+pub const VIRTIO_DEV_RNG: u16 = 0x1005;
+pub const VIRTIO_DEV_9P: u16 = 0x1009;
+// No transitional ID is defined for vsock, but this yields its subsystem ID
+// (19) in the range a legacy driver will bind to.
+pub const VIRTIO_DEV_SOCK: u16 = 0x1012;
 
 // Legacy interface feature bits
 pub const VIRTIO_F_NOTIFY_ON_EMPTY: usize = 1 << 24;
@@ -39,9 +44,13 @@ pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
 pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
 pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 1 << 10;
 pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
+pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
 pub const VIRTIO_BLK_F_DISCARD: u32 = 1 << 13;
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 1 << 14;
 
+// virtio-9p feature bits
+pub const VIRTIO_9P_F_MOUNT_TAG: u32 = 1 << 0;
+
 // virtqueue descriptor bits
 pub const VIRTQ_DESC_F_NEXT: u16 = 1;
 pub const VIRTQ_DESC_F_WRITE: u16 = 2;
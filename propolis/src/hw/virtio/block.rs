@@ -5,6 +5,7 @@ use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::hw::pci;
 use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
 
 use super::bits::*;
 use super::pci::PciVirtio;
@@ -28,27 +29,99 @@ const VIRTIO_BLK_CFG_SIZE: usize = 0x3c;
 /// Sizing for virtio-block is specified in 512B sectors
 const SECTOR_SZ: usize = 512;
 
+/// Limit on the size of a single WRITE_ZEROES request (in sectors)
+const MAX_ZERO_SECTORS: u32 = 0x40_0000;
+const VIRTIO_BLK_WZ_F_UNMAP: u32 = 1 << 0;
+
+/// Upper bound on request queues, keeping one MSI-X vector apiece (and one for
+/// config changes) within the 2048 entries permitted in a table
+pub const MAX_QUEUES: u16 = 2047;
+
+/// Logical and physical block sizes advertised to the guest
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlockTopology {
+    logical: u32,
+    physical: u32,
+}
+impl BlockTopology {
+    /// Both sizes must be powers of two, with the logical size at least one
+    /// sector, and no larger than the physical size.
+    pub fn new(logical: u32, physical: u32) -> Result<Self, &'static str> {
+        if !logical.is_power_of_two() || !physical.is_power_of_two() {
+            return Err("block sizes must be powers of two");
+        }
+        if (logical as usize) < SECTOR_SZ {
+            return Err("logical block size must be at least 512 bytes");
+        }
+        if logical > physical {
+            return Err("logical block size exceeds physical block size");
+        }
+        if physical / logical > u16::MAX as u32 {
+            return Err("physical block size too large");
+        }
+        Ok(Self { logical, physical })
+    }
+    /// Physical blocks per logical block, as a power of two
+    fn phys_exp(&self) -> u8 {
+        (self.physical / self.logical).trailing_zeros() as u8
+    }
+    /// Is an access of `len` bytes at `sector` aligned to logical blocks?
+    fn aligned(&self, sector: u64, len: usize) -> bool {
+        let lsize = self.logical as u64;
+        sector
+            .checked_mul(SECTOR_SZ as u64)
+            .map(|off| off.is_multiple_of(lsize))
+            .unwrap_or(false)
+            && (len as u64).is_multiple_of(lsize)
+    }
+}
+
 pub struct VirtioBlock {
     bdev: Arc<dyn BlockDev<Request>>,
+    topo: Option<BlockTopology>,
+    num_queues: u16,
 }
 impl VirtioBlock {
     pub fn create(
         queue_size: u16,
         bdev: Arc<dyn BlockDev<Request>>,
     ) -> Arc<pci::DeviceInst> {
-        // virtio-block only needs two MSI-X entries for its interrupt needs:
-        // - device config changes
-        // - queue 0 notification
-        let msix_count = Some(2);
+        Self::create_multiqueue(queue_size, 1, bdev, None)
+    }
+    /// Create a device which advertises the block sizes in `topo` to the
+    /// guest, rejecting requests not aligned to its logical block size.
+    pub fn create_with_topology(
+        queue_size: u16,
+        bdev: Arc<dyn BlockDev<Request>>,
+        topo: BlockTopology,
+    ) -> Arc<pci::DeviceInst> {
+        Self::create_multiqueue(queue_size, 1, bdev, Some(topo))
+    }
+    /// Create a device with `num_queues` request queues, offered to the guest
+    /// (via VIRTIO_BLK_F_MQ) when more than one.
+    ///
+    /// Requests from all of the queues are submitted to `bdev`, which should
+    /// have a worker for each queue if they are to be processed in parallel.
+    pub fn create_multiqueue(
+        queue_size: u16,
+        num_queues: u16,
+        bdev: Arc<dyn BlockDev<Request>>,
+        topo: Option<BlockTopology>,
+    ) -> Arc<pci::DeviceInst> {
+        assert!(num_queues > 0 && num_queues <= MAX_QUEUES);
+
+        // virtio-block needs an MSI-X entry for device config changes, and one
+        // for each of its queues
+        let msix_count = Some(num_queues + 1);
 
         PciVirtio::create(
             queue_size,
-            1,
+            num_queues,
             msix_count,
             VIRTIO_DEV_BLOCK,
             pci::bits::CLASS_STORAGE,
             VIRTIO_BLK_CFG_SIZE,
-            Arc::new(Self { bdev }),
+            Arc::new(Self { bdev, topo, num_queues }),
         )
     }
 
@@ -63,7 +136,20 @@ impl VirtioBlock {
                 // XXX: Copy the static limit from qemu for now
                 ro.write_u32(128 - 2);
             }
-            BlockReg::BlockSize => ro.write_u32(info.block_size),
+            BlockReg::BlockSize => match self.topo.as_ref() {
+                Some(topo) => ro.write_u32(topo.logical),
+                None => ro.write_u32(info.block_size),
+            },
+            BlockReg::TopoPhysExp => {
+                ro.write_u8(self.topo.map(|t| t.phys_exp()).unwrap_or(0))
+            }
+            BlockReg::TopoMinIoSz => ro.write_u16(
+                self.topo.map(|t| (t.physical / t.logical) as u16).unwrap_or(0),
+            ),
+            BlockReg::NumQueues => ro.write_u16(self.num_queues),
+            BlockReg::MaxZeroSectors => ro.write_u32(MAX_ZERO_SECTORS),
+            BlockReg::MaxZeroSeg => ro.write_u32(1),
+            BlockReg::ZeroMayUnmap => ro.write_u8(0),
             BlockReg::Unused => {
                 ro.fill(0);
             }
@@ -73,6 +159,39 @@ impl VirtioBlock {
             }
         }
     }
+    fn req_aligned(&self, sector: u64, len: usize) -> bool {
+        match self.topo.as_ref() {
+            Some(topo) => topo.aligned(sector, len),
+            None => true,
+        }
+    }
+    fn fail_chain(
+        vq: &Arc<VirtQueue>,
+        mut chain: Chain,
+        status: u8,
+        mem: &MemCtx,
+        ctx: &DispCtx,
+    ) {
+        // try to set the status byte
+        let remain = chain.remain_write_bytes();
+        if remain >= 1 {
+            chain.write_skip(remain - 1);
+            chain.write(&status, mem);
+        }
+        vq.push_used(&mut chain, mem, ctx);
+    }
+    fn zero_seg_valid(&self, seg: &VbZeroSeg) -> bool {
+        let info = self.bdev.inquire();
+        let capacity =
+            info.total_size * info.block_size as u64 / SECTOR_SZ as u64;
+        seg.num_sectors != 0
+            && seg.num_sectors <= MAX_ZERO_SECTORS
+            && seg
+                .sector
+                .checked_add(seg.num_sectors as u64)
+                .map(|end| end <= capacity)
+                .unwrap_or(false)
+    }
 }
 impl VirtioDevice for VirtioBlock {
     fn device_cfg_rw(&self, mut rwo: RWOp) {
@@ -86,10 +205,18 @@ impl VirtioDevice for VirtioBlock {
     fn device_get_features(&self) -> u32 {
         let mut feat = VIRTIO_BLK_F_BLK_SIZE;
         feat |= VIRTIO_BLK_F_SEG_MAX;
+        if self.topo.is_some() {
+            feat |= VIRTIO_BLK_F_TOPOLOGY;
+        }
+        if self.num_queues > 1 {
+            feat |= VIRTIO_BLK_F_MQ;
+        }
 
         let dev_data = self.bdev.inquire();
         if !dev_data.writable {
             feat |= VIRTIO_BLK_F_RO;
+        } else {
+            feat |= VIRTIO_BLK_F_WRITE_ZEROES;
         }
         feat
     }
@@ -116,6 +243,16 @@ impl VirtioDevice for VirtioBlock {
                     // should be (blocksize * 512) + 1 remaining write bytes
                     let remain = chain.remain_write_bytes();
                     let blocks = (remain - 1) / SECTOR_SZ;
+                    if !self.req_aligned(breq.sector, blocks * SECTOR_SZ) {
+                        Self::fail_chain(
+                            vq,
+                            chain,
+                            VIRTIO_BLK_S_IOERR,
+                            mem,
+                            ctx,
+                        );
+                        continue;
+                    }
 
                     self.bdev.enqueue(Request::new_read(
                         chain,
@@ -127,6 +264,16 @@ impl VirtioDevice for VirtioBlock {
                 VIRTIO_BLK_T_OUT => {
                     // should be (blocksize * 512) remaining read bytes
                     let blocks = chain.remain_read_bytes() / SECTOR_SZ;
+                    if !self.req_aligned(breq.sector, blocks * SECTOR_SZ) {
+                        Self::fail_chain(
+                            vq,
+                            chain,
+                            VIRTIO_BLK_S_IOERR,
+                            mem,
+                            ctx,
+                        );
+                        continue;
+                    }
                     self.bdev.enqueue(Request::new_write(
                         chain,
                         Arc::clone(vq),
@@ -134,14 +281,37 @@ impl VirtioDevice for VirtioBlock {
                         blocks * SECTOR_SZ,
                     ));
                 }
-                _ => {
-                    // try to set the status byte to failed
-                    let remain = chain.remain_write_bytes();
-                    if remain >= 1 {
-                        chain.write_skip(remain - 1);
-                        chain.write(&VIRTIO_BLK_S_UNSUPP, mem);
+                VIRTIO_BLK_T_WRITE_ZEROES => {
+                    let mut seg = VbZeroSeg::default();
+                    let valid = chain.remain_read_bytes()
+                        == std::mem::size_of::<VbZeroSeg>()
+                        && chain.remain_write_bytes() == 1
+                        && chain.read(&mut seg, mem)
+                        && self.zero_seg_valid(&seg)
+                        && self.req_aligned(
+                            seg.sector,
+                            seg.num_sectors as usize * SECTOR_SZ,
+                        );
+                    if !valid {
+                        Self::fail_chain(
+                            vq,
+                            chain,
+                            VIRTIO_BLK_S_IOERR,
+                            mem,
+                            ctx,
+                        );
+                        continue;
                     }
-                    vq.push_used(&mut chain, mem, ctx);
+                    self.bdev.enqueue(Request::new_write_zeroes(
+                        chain,
+                        Arc::clone(vq),
+                        seg.sector as usize * SECTOR_SZ,
+                        seg.num_sectors as usize * SECTOR_SZ,
+                        seg.flags & VIRTIO_BLK_WZ_F_UNMAP != 0,
+                    ));
+                }
+                _ => {
+                    Self::fail_chain(vq, chain, VIRTIO_BLK_S_UNSUPP, mem, ctx);
                 }
             }
         }
@@ -190,6 +360,24 @@ impl Request {
             vq,
         }
     }
+    fn new_write_zeroes(
+        chain: Chain,
+        vq: Arc<VirtQueue>,
+        off: usize,
+        size: usize,
+        may_unmap: bool,
+    ) -> Self {
+        assert_eq!(chain.remain_read_bytes(), 0);
+        assert_eq!(chain.remain_write_bytes(), 1);
+        Self {
+            op: BlockOp::WriteZeroes { len: size, may_unmap },
+            off,
+            xfer_size: 0,
+            xfer_left: 0,
+            chain,
+            vq,
+        }
+    }
 }
 impl BlockReq for Request {
     fn oper(&self) -> BlockOp {
@@ -219,6 +407,7 @@ impl BlockReq for Request {
         let res = match self.op {
             BlockOp::Read => self.chain.writable_buf(self.xfer_left),
             BlockOp::Write => self.chain.readable_buf(self.xfer_left),
+            BlockOp::WriteZeroes { .. } => None,
         };
         if let Some(region) = res.as_ref() {
             assert!(self.xfer_left >= region.1);
@@ -236,6 +425,14 @@ struct VbReq {
     sector: u64,
 }
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VbZeroSeg {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum BlockReg {
     Capacity,
@@ -250,6 +447,7 @@ enum BlockReg {
     TopoMinIoSz,
     TopoOptIoSz,
     Writeback,
+    NumQueues,
     Unused,
     MaxDiscardSectors,
     MaxDiscardSeg,
@@ -273,7 +471,8 @@ lazy_static! {
             (BlockReg::TopoMinIoSz, 2),
             (BlockReg::TopoOptIoSz, 4),
             (BlockReg::Writeback, 1),
-            (BlockReg::Unused, 3),
+            (BlockReg::Unused, 1),
+            (BlockReg::NumQueues, 2),
             (BlockReg::MaxDiscardSectors, 4),
             (BlockReg::MaxDiscardSeg, 4),
             (BlockReg::DiscardSectorAlign, 4),
@@ -289,3 +488,87 @@ lazy_static! {
         )
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct StubBdev;
+    impl BlockDev<Request> for StubBdev {
+        fn enqueue(&self, _req: Request) {}
+        fn inquire(&self) -> BlockInquiry {
+            BlockInquiry { total_size: 0x1000, block_size: 512, writable: true }
+        }
+    }
+
+    fn cfg_bytes(dev: &VirtioBlock) -> Vec<u8> {
+        let mut buf = vec![0u8; VIRTIO_BLK_CFG_SIZE];
+        let mut ro = ReadOp::new_buf(0, &mut buf);
+        dev.device_cfg_rw(RWOp::Read(&mut ro));
+        buf
+    }
+
+    #[test]
+    fn topology_validate() {
+        assert!(BlockTopology::new(512, 512).is_ok());
+        assert!(BlockTopology::new(512, 4096).is_ok());
+        assert!(BlockTopology::new(4096, 4096).is_ok());
+
+        assert!(BlockTopology::new(256, 4096).is_err());
+        assert!(BlockTopology::new(4096, 512).is_err());
+        assert!(BlockTopology::new(1000, 4096).is_err());
+        assert!(BlockTopology::new(512, 3000).is_err());
+        assert!(BlockTopology::new(0, 512).is_err());
+
+        let topo = BlockTopology::new(4096, 4096).unwrap();
+        assert!(topo.aligned(8, 4096));
+        assert!(topo.aligned(16, 8192));
+        assert!(!topo.aligned(1, 4096));
+        assert!(!topo.aligned(8, 512));
+        assert!(!topo.aligned(u64::MAX, 4096));
+    }
+
+    #[test]
+    fn topology_config() {
+        let bdev = Arc::new(StubBdev) as Arc<dyn BlockDev<Request>>;
+
+        let plain =
+            VirtioBlock { bdev: Arc::clone(&bdev), topo: None, num_queues: 1 };
+        let cfg = cfg_bytes(&plain);
+        assert_eq!(&cfg[20..24], &512u32.to_le_bytes());
+        assert_eq!(&cfg[24..28], &[0, 0, 0, 0]);
+        assert_eq!(plain.device_get_features() & VIRTIO_BLK_F_TOPOLOGY, 0);
+        assert!(plain.req_aligned(1, 512));
+
+        let topo = BlockTopology::new(512, 4096).unwrap();
+        let dev = VirtioBlock { bdev, topo: Some(topo), num_queues: 1 };
+        let cfg = cfg_bytes(&dev);
+        // blk_size, physical_block_exp, alignment_offset, min_io_size
+        assert_eq!(&cfg[20..24], &512u32.to_le_bytes());
+        assert_eq!(cfg[24], 3);
+        assert_eq!(cfg[25], 0);
+        assert_eq!(&cfg[26..28], &8u16.to_le_bytes());
+        assert_ne!(dev.device_get_features() & VIRTIO_BLK_F_TOPOLOGY, 0);
+        // Capacity remains in 512B sectors
+        assert_eq!(&cfg[0..8], &0x1000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn multiqueue_config() {
+        let bdev = Arc::new(StubBdev) as Arc<dyn BlockDev<Request>>;
+
+        let single =
+            VirtioBlock { bdev: Arc::clone(&bdev), topo: None, num_queues: 1 };
+        assert_eq!(single.device_get_features() & VIRTIO_BLK_F_MQ, 0);
+        assert_eq!(&cfg_bytes(&single)[34..36], &1u16.to_le_bytes());
+
+        let multi = VirtioBlock { bdev, topo: None, num_queues: 4 };
+        assert_ne!(multi.device_get_features() & VIRTIO_BLK_F_MQ, 0);
+        let cfg = cfg_bytes(&multi);
+        assert_eq!(&cfg[34..36], &4u16.to_le_bytes());
+        // Neighboring fields are unaffected
+        assert_eq!(cfg[33], 0);
+        assert_eq!(&cfg[36..40], &[0, 0, 0, 0]);
+        assert_eq!(&cfg[48..52], &MAX_ZERO_SECTORS.to_le_bytes());
+    }
+}
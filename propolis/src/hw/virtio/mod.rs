@@ -4,15 +4,20 @@ use std::sync::Arc;
 mod bits;
 
 pub mod block;
+pub mod net;
+pub mod p9fs;
 mod pci;
 mod queue;
+pub mod rng;
 pub mod viona;
+pub mod vsock;
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
 use queue::VirtQueue;
 
 pub use block::VirtioBlock;
+pub use pci::PciVirtio;
 
 pub trait VirtioDevice: Send + Sync + 'static {
     fn device_cfg_rw(&self, ro: RWOp);
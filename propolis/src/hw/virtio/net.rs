@@ -0,0 +1,462 @@
+//! Userspace virtio-net device, exchanging frames with a tap device on the
+//! host, for when the in-kernel viona acceleration is unavailable.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::common::*;
+use crate::dispatch::events::{Event, EventTarget, FdEvents, Resource, Token};
+use crate::dispatch::DispCtx;
+use crate::hw::pci;
+use crate::util::self_arc::*;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::PciVirtio;
+use super::queue::{Chain, VirtQueue};
+use super::viona::{
+    NetReg, ETHERADDRL, NET_DEV_REGS, VIRTIO_NET_CFG_SIZE, VIRTIO_NET_S_LINK_UP,
+};
+use super::VirtioDevice;
+
+const RX_QUEUE: u16 = 0;
+
+/// Largest frame which will be read from the tap device, or accepted from the
+/// guest for transmission
+const MAX_FRAME_LEN: usize = 0x10000;
+
+const ETHERMTU: u16 = 1500;
+
+/// Header preceding each frame in the queues.  With no offloads offered, it is
+/// always zeroed on receive and ignored on transmit.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+/// Host tap device, from which each read yields a single frame, and to which
+/// each write sends one.
+pub struct TapDev {
+    fp: File,
+}
+impl TapDev {
+    pub fn open(path: &str) -> Result<Self> {
+        let fp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        Ok(Self { fp })
+    }
+    fn fd(&self) -> RawFd {
+        self.fp.as_raw_fd()
+    }
+    fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        (&self.fp).read(buf)
+    }
+    fn send(&self, frame: &[u8]) -> Result<()> {
+        let n = (&self.fp).write(frame)?;
+        if n != frame.len() {
+            return Err(Error::new(ErrorKind::WriteZero, "short frame write"));
+        }
+        Ok(())
+    }
+}
+
+/// Reason for which the movement of received frames to the guest stopped
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RxStop {
+    /// No more frames are waiting on the tap device
+    Drained,
+    /// The guest has no more receive buffers available
+    NoBuffers,
+    /// Reading from the tap device failed
+    Failed,
+}
+
+struct Inner {
+    rx_vq: Option<Arc<VirtQueue>>,
+    event_token: Option<Token>,
+    rx_buf: Vec<u8>,
+    /// Length of a frame (in `rx_buf`) awaiting a guest receive buffer
+    rx_pending: Option<usize>,
+}
+
+pub struct VirtioNet {
+    mac_addr: [u8; ETHERADDRL],
+    tap: TapDev,
+    inner: Mutex<Inner>,
+
+    sa_cell: SelfArcCell<Self>,
+}
+impl VirtioNet {
+    pub fn create(
+        tap: TapDev,
+        mac_addr: [u8; ETHERADDRL],
+        queue_size: u16,
+    ) -> Arc<pci::DeviceInst> {
+        let mut this = Arc::new(Self {
+            mac_addr,
+            tap,
+            inner: Mutex::new(Inner {
+                rx_vq: None,
+                event_token: None,
+                rx_buf: vec![0; MAX_FRAME_LEN],
+                rx_pending: None,
+            }),
+            sa_cell: SelfArcCell::new(),
+        });
+        SelfArc::self_arc_init(&mut this);
+
+        // RX and TX
+        let queue_count = 2;
+        // interrupts for RX, TX, and device config
+        let msix_count = Some(3);
+
+        PciVirtio::create(
+            queue_size,
+            queue_count,
+            msix_count,
+            VIRTIO_DEV_NET,
+            pci::bits::CLASS_NETWORK,
+            VIRTIO_NET_CFG_SIZE,
+            this,
+        )
+    }
+
+    fn net_cfg_read(&self, id: &NetReg, ro: &mut ReadOp) {
+        match id {
+            NetReg::Mac => ro.write_bytes(&self.mac_addr),
+            NetReg::Status => ro.write_u16(VIRTIO_NET_S_LINK_UP),
+            NetReg::MaxVqPairs => ro.write_u16(1),
+            NetReg::Mtu => ro.write_u16(ETHERMTU),
+        }
+    }
+
+    /// Poll the tap device for frames, if not already doing so
+    fn watch_tap(&self, inner: &mut Inner, ctx: &DispCtx) {
+        if inner.event_token.is_none() {
+            let token = ctx.event.fd_register(
+                self.tap.fd(),
+                FdEvents::POLLIN,
+                self.self_weak() as Weak<dyn EventTarget>,
+            );
+            inner.event_token = Some(token);
+        }
+    }
+
+    /// Move frames from the tap device into guest receive buffers, until
+    /// either is exhausted.
+    fn process_rx(&self, inner: &mut Inner, ctx: &DispCtx) {
+        let vq = match inner.rx_vq.as_ref() {
+            Some(vq) => Arc::clone(vq),
+            None => return,
+        };
+        let (stop, notify) = self.rx_frames(inner, &vq, &ctx.mctx.memctx());
+        if notify {
+            vq.notify(ctx);
+        }
+        if stop != RxStop::Drained {
+            // Stop polling the tap until the guest provides more buffers, or
+            // for good if it has failed.
+            if let Some(token) = inner.event_token.take() {
+                ctx.event.fd_deregister(token);
+            }
+        }
+    }
+
+    /// Receive frames into the buffers of `vq`, returning why it stopped and
+    /// whether the guest is to be notified of the buffers used.
+    fn rx_frames(
+        &self,
+        inner: &mut Inner,
+        vq: &VirtQueue,
+        mem: &MemCtx,
+    ) -> (RxStop, bool) {
+        let mut notify = false;
+        loop {
+            let len = match inner.rx_pending.take() {
+                Some(len) => len,
+                None => match self.tap.recv(&mut inner.rx_buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        return (RxStop::Drained, notify)
+                    }
+                    Err(e) => {
+                        println!("virtio-net tap read failed: {}", e);
+                        return (RxStop::Failed, notify);
+                    }
+                },
+            };
+
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, mem).is_none() {
+                inner.rx_pending = Some(len);
+                return (RxStop::NoBuffers, notify);
+            }
+            // A frame too large for the buffers is truncated
+            write_frame(&mut chain, &inner.rx_buf[..len], mem);
+            notify |= vq.put_used(&mut chain, mem);
+        }
+    }
+
+    fn process_tx(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) {
+        if self.tx_frames(vq, &ctx.mctx.memctx()) {
+            vq.notify(ctx);
+        }
+    }
+
+    /// Send the frames queued in `vq`, returning whether the guest is to be
+    /// notified of the buffers used.
+    fn tx_frames(&self, vq: &VirtQueue, mem: &MemCtx) -> bool {
+        let mut frame = Vec::with_capacity(MAX_FRAME_LEN);
+        let mut notify = false;
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, mem).is_none() {
+                return notify;
+            }
+            if read_frame(&mut chain, &mut frame, mem) {
+                // Like a physical NIC, drop the frame if it cannot be sent
+                let _ = self.tap.send(&frame);
+            }
+            notify |= vq.put_used(&mut chain, mem);
+        }
+    }
+}
+impl VirtioDevice for VirtioNet {
+    fn device_cfg_rw(&self, mut rwo: RWOp) {
+        NET_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => self.net_cfg_read(id, ro),
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn device_get_features(&self) -> u32 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU
+    }
+    fn device_set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) {
+        if vq.id == RX_QUEUE {
+            // More receive buffers are available
+            let mut inner = self.inner.lock().unwrap();
+            self.watch_tap(&mut inner, ctx);
+            self.process_rx(&mut inner, ctx);
+        } else {
+            self.process_tx(vq, ctx);
+        }
+    }
+
+    fn attach(&self, queues: &[Arc<VirtQueue>]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rx_vq = Some(Arc::clone(&queues[RX_QUEUE as usize]));
+    }
+
+    fn device_reset(&self, ctx: &DispCtx) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rx_pending = None;
+        self.watch_tap(&mut inner, ctx);
+    }
+}
+impl EventTarget for VirtioNet {
+    fn event_process(&self, event: &Event, ctx: &DispCtx) {
+        match event.res {
+            Resource::Fd(fd, _) => {
+                assert_eq!(fd, self.tap.fd());
+                let mut inner = self.inner.lock().unwrap();
+                self.process_rx(&mut inner, ctx);
+            }
+        }
+    }
+}
+impl SelfArc for VirtioNet {
+    fn self_arc_cell(&self) -> &SelfArcCell<Self> {
+        &self.sa_cell
+    }
+}
+
+/// Write a received `frame`, behind its header, into the buffers of `chain`.
+fn write_frame(chain: &mut Chain, frame: &[u8], mem: &MemCtx) -> bool {
+    if !chain.write(&VirtioNetHdr::default(), mem) {
+        return false;
+    }
+    let mut done = 0;
+    while done < frame.len() {
+        let region = match chain.writable_buf(frame.len() - done) {
+            Some(r) => r,
+            None => return false,
+        };
+        match mem.write_from(region.0, &frame[done..], region.1) {
+            Some(n) => done += n,
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Read a frame to be transmitted, skipping its header, from `chain`.
+///
+/// Frames larger than [`MAX_FRAME_LEN`] are rejected, rather than truncated.
+fn read_frame(chain: &mut Chain, frame: &mut Vec<u8>, mem: &MemCtx) -> bool {
+    let mut hdr = VirtioNetHdr::default();
+    if !chain.read(&mut hdr, mem) {
+        return false;
+    }
+    frame.clear();
+    while let Some(region) = chain.readable_buf(MAX_FRAME_LEN - frame.len()) {
+        let start = frame.len();
+        frame.resize(start + region.1, 0);
+        if mem.read_into(region.0, &mut frame[start..], region.1).is_none() {
+            return false;
+        }
+    }
+    !frame.is_empty() && chain.remain_read_bytes() == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::virtio::queue::TestQueue;
+    use crate::vmm::TestMem;
+
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixDatagram;
+
+    const HDR_LEN: u32 = 10;
+
+    /// Device backed by a datagram socket, standing in for the tap device,
+    /// the other end of which is returned.
+    fn device() -> (VirtioNet, UnixDatagram) {
+        let (tap, host) = UnixDatagram::pair().unwrap();
+        tap.set_nonblocking(true).unwrap();
+        host.set_nonblocking(true).unwrap();
+        let fp = unsafe { File::from_raw_fd(tap.into_raw_fd()) };
+        let dev = VirtioNet {
+            mac_addr: [0x02, 0x08, 0x20, 0, 0, 1],
+            tap: TapDev { fp },
+            inner: Mutex::new(Inner {
+                rx_vq: None,
+                event_token: None,
+                rx_buf: vec![0; MAX_FRAME_LEN],
+                rx_pending: None,
+            }),
+            sa_cell: SelfArcCell::new(),
+        };
+        (dev, host)
+    }
+
+    fn read_mem(mem: &MemCtx, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        assert_eq!(mem.read_into(GuestAddr(addr), &mut buf, len), Some(len));
+        buf
+    }
+
+    #[test]
+    fn rx() {
+        let tmem = TestMem::new(0x10000);
+        let mem = tmem.memctx();
+        let (dev, host) = device();
+        let mut rxq = TestQueue::new(RX_QUEUE, 16, 0);
+        let mut inner = dev.inner.lock().unwrap();
+
+        let first: Vec<u8> = (0..100).collect();
+        let second = vec![0xa5u8; 60];
+        host.send(&first).unwrap();
+        host.send(&second).unwrap();
+
+        // The frame is held until the guest offers a buffer
+        assert_eq!(
+            dev.rx_frames(&mut inner, &rxq.vq, &mem),
+            (RxStop::NoBuffers, false)
+        );
+        assert_eq!(inner.rx_pending, Some(first.len()));
+
+        let head = rxq
+            .add_chain(&[(0x4000, HDR_LEN, true), (0x5000, 0x800, true)], &mem);
+        assert_eq!(
+            dev.rx_frames(&mut inner, &rxq.vq, &mem),
+            (RxStop::NoBuffers, true)
+        );
+        assert_eq!(rxq.used(&mem), [(head as u32, HDR_LEN + 100)]);
+        assert_eq!(read_mem(&mem, 0x4000, HDR_LEN as usize), [0u8; 10]);
+        assert_eq!(read_mem(&mem, 0x5000, first.len()), first);
+
+        // Header and frame may also share a buffer
+        let head2 = rxq.add_chain(&[(0x6000, 0x800, true)], &mem);
+        assert_eq!(
+            dev.rx_frames(&mut inner, &rxq.vq, &mem),
+            (RxStop::Drained, true)
+        );
+        assert_eq!(rxq.used(&mem)[1], (head2 as u32, HDR_LEN + 60));
+        assert_eq!(read_mem(&mem, 0x6000 + HDR_LEN as u64, 60), second);
+        assert_eq!(inner.rx_pending, None);
+    }
+
+    #[test]
+    fn tx() {
+        let tmem = TestMem::new(0x20000);
+        let mem = tmem.memctx();
+        let (dev, host) = device();
+        let mut txq = TestQueue::new(1, 16, 0);
+
+        let frame: Vec<u8> = (0..60).collect();
+        assert_eq!(
+            mem.write_from(GuestAddr(0x5000), &frame, frame.len()),
+            Some(60)
+        );
+        let head = txq
+            .add_chain(&[(0x4000, HDR_LEN, false), (0x5000, 60, false)], &mem);
+        assert!(dev.tx_frames(&txq.vq, &mem));
+        let mut buf = vec![0u8; MAX_FRAME_LEN + 1];
+        assert_eq!(host.recv(&mut buf).unwrap(), 60);
+        assert_eq!(&buf[..60], &frame[..]);
+        assert_eq!(txq.used(&mem), [(head as u32, 0)]);
+
+        // An oversized frame is dropped, rather than sent truncated
+        let big = MAX_FRAME_LEN as u32 + 1;
+        let head = txq
+            .add_chain(&[(0x4000, HDR_LEN, false), (0x8000, big, false)], &mem);
+        assert!(dev.tx_frames(&txq.vq, &mem));
+        assert_eq!(
+            host.recv(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(txq.used(&mem)[1], (head as u32, 0));
+
+        // ... as is one lacking even a header
+        txq.add_chain(&[(0x4000, HDR_LEN - 1, false)], &mem);
+        assert!(dev.tx_frames(&txq.vq, &mem));
+        assert_eq!(
+            host.recv(&mut buf).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(txq.used(&mem).len(), 3);
+    }
+
+    #[test]
+    fn mtu_offered() {
+        let (dev, _host) = device();
+        assert_ne!(dev.device_get_features() & VIRTIO_NET_F_MTU, 0);
+        let mut buf = [0u8; 2];
+        let mut ro = ReadOp::new_buf(0, &mut buf);
+        dev.net_cfg_read(&NetReg::Mtu, &mut ro);
+        assert_eq!(u16::from_le_bytes(buf), ETHERMTU);
+    }
+
+    #[test]
+    fn hdr_layout() {
+        // Legacy header, without the num_buffers field of MRG_RXBUF
+        assert_eq!(std::mem::size_of::<VirtioNetHdr>(), 10);
+    }
+}
@@ -0,0 +1,1226 @@
+//! virtio-9p device, sharing a host directory with the guest via 9P2000.L.
+//!
+//! Requests are handled synchronously as the queue is notified.  Walks do not
+//! traverse symlinks, nor ascend past the root of the share.  Operations on a
+//! fid never follow a symlink it refers to, and those acting within a
+//! directory fid require it to be a real directory.  The guest is thereby
+//! confined to the host directory, barring concurrent changes to it on the
+//! host side.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt,
+};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::dispatch::DispCtx;
+use crate::hw::pci;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::PciVirtio;
+use super::queue::{Chain, VirtQueue};
+use super::VirtioDevice;
+
+/// Longest mount tag accepted for a share
+pub const MAX_TAG_LEN: usize = 255;
+
+/// Largest message size which will be negotiated with the guest
+const MAX_MSIZE: u32 = 0x2_0000;
+
+const P9_VERSION: &str = "9P2000.L";
+const P9_NOTAG: u16 = 0xffff;
+/// Header: size[4] type[1] tag[2]
+const HDR_LEN: u32 = 7;
+/// Walks are limited to this many path elements per request
+const MAXWELEM: u16 = 16;
+
+const TLERROR: u8 = 6;
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TMKDIR: u8 = 72;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+const P9_SETATTR_MODE: u32 = 0x1;
+const P9_SETATTR_SIZE: u32 = 0x8;
+
+/// Magic reported by statfs for a 9P filesystem
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+// Open flags and directory entry types, as the (Linux) guest defines them
+const L_O_ACCMODE: u32 = 0o3;
+const L_O_RDONLY: u32 = 0o0;
+const L_O_WRONLY: u32 = 0o1;
+const L_O_TRUNC: u32 = 0o1000;
+const L_AT_REMOVEDIR: u32 = 0x200;
+
+const DT_UNKNOWN: u8 = 0;
+const DT_FIFO: u8 = 1;
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+const DT_SOCK: u8 = 12;
+
+/// Error numbers returned in Rlerror, which are those of the Linux guest
+/// rather than the host.
+mod errno {
+    pub const EPERM: u32 = 1;
+    pub const ENOENT: u32 = 2;
+    pub const EIO: u32 = 5;
+    pub const EBADF: u32 = 9;
+    pub const EAGAIN: u32 = 11;
+    pub const ENOMEM: u32 = 12;
+    pub const EACCES: u32 = 13;
+    pub const EEXIST: u32 = 17;
+    pub const EXDEV: u32 = 18;
+    pub const ENOTDIR: u32 = 20;
+    pub const EISDIR: u32 = 21;
+    pub const EINVAL: u32 = 22;
+    pub const EMFILE: u32 = 24;
+    pub const EFBIG: u32 = 27;
+    pub const ENOSPC: u32 = 28;
+    pub const EROFS: u32 = 30;
+    pub const ENAMETOOLONG: u32 = 36;
+    pub const ENOTEMPTY: u32 = 39;
+    pub const ELOOP: u32 = 40;
+    pub const EPROTO: u32 = 71;
+    pub const EOPNOTSUPP: u32 = 95;
+}
+
+/// Translate a host error into the guest error number
+fn guest_errno(e: &Error) -> u32 {
+    match e.raw_os_error() {
+        Some(libc::EPERM) => errno::EPERM,
+        Some(libc::ENOENT) => errno::ENOENT,
+        Some(libc::EBADF) => errno::EBADF,
+        Some(libc::EAGAIN) => errno::EAGAIN,
+        Some(libc::ENOMEM) => errno::ENOMEM,
+        Some(libc::EACCES) => errno::EACCES,
+        Some(libc::EEXIST) => errno::EEXIST,
+        Some(libc::EXDEV) => errno::EXDEV,
+        Some(libc::ENOTDIR) => errno::ENOTDIR,
+        Some(libc::EISDIR) => errno::EISDIR,
+        Some(libc::EINVAL) => errno::EINVAL,
+        Some(libc::EMFILE) => errno::EMFILE,
+        Some(libc::EFBIG) => errno::EFBIG,
+        Some(libc::ENOSPC) => errno::ENOSPC,
+        Some(libc::EROFS) => errno::EROFS,
+        Some(libc::ENAMETOOLONG) => errno::ENAMETOOLONG,
+        Some(libc::ENOTEMPTY) => errno::ENOTEMPTY,
+        Some(libc::ELOOP) => errno::ELOOP,
+        Some(_) => errno::EIO,
+        None => match e.kind() {
+            ErrorKind::NotFound => errno::ENOENT,
+            ErrorKind::PermissionDenied => errno::EACCES,
+            ErrorKind::AlreadyExists => errno::EEXIST,
+            ErrorKind::InvalidInput => errno::EINVAL,
+            _ => errno::EIO,
+        },
+    }
+}
+
+type OpResult = std::result::Result<(), u32>;
+
+/// Cursor over the body of a request
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+    fn bytes(&mut self, len: usize) -> std::result::Result<&'a [u8], u32> {
+        let end = self.pos.checked_add(len).ok_or(errno::EPROTO)?;
+        let res = self.buf.get(self.pos..end).ok_or(errno::EPROTO)?;
+        self.pos = end;
+        Ok(res)
+    }
+    fn u8(&mut self) -> std::result::Result<u8, u32> {
+        Ok(self.bytes(1)?[0])
+    }
+    fn u16(&mut self) -> std::result::Result<u16, u32> {
+        let mut raw = [0u8; 2];
+        raw.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(raw))
+    }
+    fn u32(&mut self) -> std::result::Result<u32, u32> {
+        let mut raw = [0u8; 4];
+        raw.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(raw))
+    }
+    fn u64(&mut self) -> std::result::Result<u64, u32> {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(raw))
+    }
+    fn string(&mut self) -> std::result::Result<String, u32> {
+        let len = self.u16()? as usize;
+        let raw = self.bytes(len)?;
+        String::from_utf8(raw.to_vec()).map_err(|_| errno::EINVAL)
+    }
+}
+
+/// Body of a response under construction
+struct Writer(Vec<u8>);
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn string(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+    fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.ty);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
+
+/// Server's unique identification for a file
+struct Qid {
+    ty: u8,
+    version: u32,
+    path: u64,
+}
+impl Qid {
+    const LEN: usize = 13;
+
+    fn from_meta(meta: &Metadata) -> Self {
+        let ft = meta.file_type();
+        let ty = if ft.is_dir() {
+            QTDIR
+        } else if ft.is_symlink() {
+            QTSYMLINK
+        } else {
+            QTFILE
+        };
+        Self { ty, version: 0, path: meta.ino() }
+    }
+}
+
+fn dirent_type(meta: &Metadata) -> u8 {
+    let ft = meta.file_type();
+    if ft.is_dir() {
+        DT_DIR
+    } else if ft.is_file() {
+        DT_REG
+    } else if ft.is_symlink() {
+        DT_LNK
+    } else if ft.is_char_device() {
+        DT_CHR
+    } else if ft.is_block_device() {
+        DT_BLK
+    } else if ft.is_fifo() {
+        DT_FIFO
+    } else if ft.is_socket() {
+        DT_SOCK
+    } else {
+        DT_UNKNOWN
+    }
+}
+
+/// Is `name` a single, ordinary, path component?
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains('/')
+        && !name.contains('\0')
+}
+
+enum Opened {
+    File(File),
+    /// Directory, with its entries (in name order) as of the last readdir from
+    /// offset zero
+    Dir(Vec<String>),
+}
+
+struct Fid {
+    /// Path relative to the root of the share
+    rel: PathBuf,
+    open: Option<Opened>,
+}
+
+/// 9P2000.L file server for a host directory
+pub struct P9Server {
+    root: PathBuf,
+    read_only: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+impl P9Server {
+    pub fn new(root: impl AsRef<Path>, read_only: bool) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        if !fs::metadata(&root)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self { root, read_only, msize: MAX_MSIZE, fids: HashMap::new() })
+    }
+
+    /// Drop all fids, as when the device is reset
+    fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = MAX_MSIZE;
+    }
+
+    /// Process the request message in `req`, returning the response message.
+    pub fn handle(&mut self, req: &[u8]) -> Vec<u8> {
+        let mut rd = Reader::new(req);
+        let (mtype, tag) = match (rd.u32(), rd.u8(), rd.u16()) {
+            (Ok(size), Ok(mtype), Ok(tag)) if size as usize <= req.len() => {
+                rd.buf = &req[..size as usize];
+                (mtype, tag)
+            }
+            _ => (TLERROR, P9_NOTAG),
+        };
+
+        let mut body = Writer(Vec::new());
+        let res = match mtype {
+            TVERSION => self.version(&mut rd, &mut body),
+            TATTACH => self.attach(&mut rd, &mut body),
+            TFLUSH => rd.u16().map(|_oldtag| ()),
+            TWALK => self.walk(&mut rd, &mut body),
+            TCLUNK => self.clunk(&mut rd),
+            TGETATTR => self.getattr(&mut rd, &mut body),
+            TSETATTR => self.setattr(&mut rd),
+            TSTATFS => self.statfs(&mut rd, &mut body),
+            TLOPEN => self.lopen(&mut rd, &mut body),
+            TLCREATE => self.lcreate(&mut rd, &mut body),
+            TREAD => self.read(&mut rd, &mut body),
+            TWRITE => self.write(&mut rd, &mut body),
+            TREADDIR => self.readdir(&mut rd, &mut body),
+            TFSYNC => self.fsync(&mut rd),
+            TMKDIR => self.mkdir(&mut rd, &mut body),
+            TUNLINKAT => self.unlinkat(&mut rd),
+            TLERROR => Err(errno::EPROTO),
+            _ => Err(errno::EOPNOTSUPP),
+        };
+
+        let rtype = match res {
+            Ok(()) => mtype + 1,
+            Err(ecode) => {
+                body.0.clear();
+                body.u32(ecode);
+                RLERROR
+            }
+        };
+        let mut resp = Vec::with_capacity(HDR_LEN as usize + body.0.len());
+        resp.extend_from_slice(&(HDR_LEN + body.0.len() as u32).to_le_bytes());
+        resp.push(rtype);
+        resp.extend_from_slice(&tag.to_le_bytes());
+        resp.extend_from_slice(&body.0);
+        resp
+    }
+
+    fn host_path(&self, rel: &Path) -> PathBuf {
+        self.root.join(rel)
+    }
+    fn fid(&self, fid: u32) -> std::result::Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(errno::EBADF)
+    }
+    fn fid_meta(&self, fid: u32) -> std::result::Result<Metadata, u32> {
+        let path = self.host_path(&self.fid(fid)?.rel);
+        fs::symlink_metadata(path).map_err(|e| guest_errno(&e))
+    }
+    /// Host path of `fid`, which must refer to a directory (not a symlink)
+    fn dir_path(&self, fid: u32) -> std::result::Result<PathBuf, u32> {
+        let path = self.host_path(&self.fid(fid)?.rel);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => Ok(path),
+            Ok(_) => Err(errno::ENOTDIR),
+            Err(e) => Err(guest_errno(&e)),
+        }
+    }
+    fn check_writable(&self) -> OpResult {
+        match self.read_only {
+            true => Err(errno::EROFS),
+            false => Ok(()),
+        }
+    }
+    /// Largest payload which fits in a response with `hdr` bytes of fields
+    fn max_payload(&self, hdr: u32) -> u32 {
+        self.msize.saturating_sub(HDR_LEN + hdr)
+    }
+
+    fn version(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let msize = rd.u32()?;
+        let version = rd.string()?;
+        if msize <= HDR_LEN + 4 {
+            return Err(errno::EINVAL);
+        }
+        self.fids.clear();
+        self.msize = u32::min(msize, MAX_MSIZE);
+        w.u32(self.msize);
+        if version.starts_with(P9_VERSION) {
+            w.string(P9_VERSION);
+        } else {
+            w.string("unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let _afid = rd.u32()?;
+        let _uname = rd.string()?;
+        let _aname = rd.string()?;
+        if self.fids.contains_key(&fid) {
+            return Err(errno::EBADF);
+        }
+        let meta = fs::metadata(&self.root).map_err(|e| guest_errno(&e))?;
+        self.fids.insert(fid, Fid { rel: PathBuf::new(), open: None });
+        w.qid(&Qid::from_meta(&meta));
+        Ok(())
+    }
+
+    fn walk(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let newfid = rd.u32()?;
+        let nwname = rd.u16()?;
+        if nwname > MAXWELEM {
+            return Err(errno::EINVAL);
+        }
+        let mut names = Vec::with_capacity(nwname as usize);
+        for _ in 0..nwname {
+            names.push(rd.string()?);
+        }
+        let mut rel = self.fid(fid)?.rel.clone();
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(errno::EBADF);
+        }
+
+        let mut qids = Vec::with_capacity(names.len());
+        for name in names.iter() {
+            let res = if name == ".." {
+                // Ascending from the root leaves one at the root
+                rel.pop();
+                fs::symlink_metadata(self.host_path(&rel))
+            } else if !valid_name(name) {
+                Err(Error::from_raw_os_error(libc::EINVAL))
+            } else {
+                // Only descend through directories, and never symlinks
+                match fs::symlink_metadata(self.host_path(&rel)) {
+                    Ok(m) if m.is_dir() => {
+                        rel.push(name);
+                        fs::symlink_metadata(self.host_path(&rel))
+                    }
+                    Ok(_) => Err(Error::from_raw_os_error(libc::ENOTDIR)),
+                    Err(e) => Err(e),
+                }
+            };
+            match res {
+                Ok(meta) => qids.push(Qid::from_meta(&meta)),
+                Err(e) if qids.is_empty() => return Err(guest_errno(&e)),
+                Err(_) => break,
+            }
+        }
+
+        // The new fid is only established if the full walk succeeded
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid { rel, open: None });
+        }
+        w.u16(qids.len() as u16);
+        for qid in qids.iter() {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn clunk(&mut self, rd: &mut Reader) -> OpResult {
+        let fid = rd.u32()?;
+        self.fids.remove(&fid).map(|_| ()).ok_or(errno::EBADF)
+    }
+
+    fn getattr(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let _mask = rd.u64()?;
+        let meta = self.fid_meta(fid)?;
+
+        w.u64(P9_GETATTR_BASIC);
+        w.qid(&Qid::from_meta(&meta));
+        w.u32(meta.mode());
+        w.u32(meta.uid());
+        w.u32(meta.gid());
+        w.u64(meta.nlink());
+        w.u64(meta.rdev());
+        w.u64(meta.size());
+        w.u64(meta.blksize());
+        w.u64(meta.blocks());
+        w.u64(meta.atime() as u64);
+        w.u64(meta.atime_nsec() as u64);
+        w.u64(meta.mtime() as u64);
+        w.u64(meta.mtime_nsec() as u64);
+        w.u64(meta.ctime() as u64);
+        w.u64(meta.ctime_nsec() as u64);
+        // btime, gen, and data_version are not reported
+        for _ in 0..4 {
+            w.u64(0);
+        }
+        Ok(())
+    }
+
+    /// Only the mode and size can be altered.  Requests to change ownership
+    /// or timestamps are ignored.
+    fn setattr(&mut self, rd: &mut Reader) -> OpResult {
+        let fid = rd.u32()?;
+        let valid = rd.u32()?;
+        let mode = rd.u32()?;
+        let _uid = rd.u32()?;
+        let _gid = rd.u32()?;
+        let size = rd.u64()?;
+        let path = self.host_path(&self.fid(fid)?.rel);
+        if valid & (P9_SETATTR_MODE | P9_SETATTR_SIZE) == 0 {
+            return Ok(());
+        }
+        self.check_writable()?;
+
+        if valid & P9_SETATTR_MODE != 0 {
+            let cpath = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| errno::EINVAL)?;
+            // Symlinks are not followed, lest the guest alter files outside
+            // of the share
+            let res = unsafe {
+                libc::fchmodat(
+                    libc::AT_FDCWD,
+                    cpath.as_ptr(),
+                    (mode & 0o7777) as libc::mode_t,
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if res != 0 {
+                return Err(guest_errno(&Error::last_os_error()));
+            }
+        }
+        if valid & P9_SETATTR_SIZE != 0 {
+            OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .and_then(|fp| fp.set_len(size))
+                .map_err(|e| guest_errno(&e))?;
+        }
+        Ok(())
+    }
+
+    fn statfs(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let path = self.host_path(&self.fid(fid)?.rel);
+        // Query through a handle which does not follow symlinks
+        let fp = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(&path)
+            .map_err(|e| guest_errno(&e))?;
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatvfs(fp.as_raw_fd(), &mut st) } != 0 {
+            return Err(guest_errno(&Error::last_os_error()));
+        }
+        w.u32(V9FS_MAGIC);
+        w.u32(st.f_bsize as u32);
+        w.u64(st.f_blocks as u64);
+        w.u64(st.f_bfree as u64);
+        w.u64(st.f_bavail as u64);
+        w.u64(st.f_files as u64);
+        w.u64(st.f_ffree as u64);
+        w.u64(st.f_fsid as u64);
+        w.u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    fn open_opts(&self, flags: u32) -> std::result::Result<OpenOptions, u32> {
+        let mut opts = OpenOptions::new();
+        match flags & L_O_ACCMODE {
+            L_O_RDONLY => opts.read(true),
+            L_O_WRONLY => opts.write(true),
+            _ => opts.read(true).write(true),
+        };
+        if flags & L_O_ACCMODE != L_O_RDONLY {
+            self.check_writable()?;
+            if flags & L_O_TRUNC != 0 {
+                opts.truncate(true);
+            }
+        }
+        opts.custom_flags(libc::O_NOFOLLOW);
+        Ok(opts)
+    }
+
+    fn lopen(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let flags = rd.u32()?;
+        let meta = self.fid_meta(fid)?;
+        if self.fid(fid)?.open.is_some() {
+            return Err(errno::EINVAL);
+        }
+
+        let opened = if meta.is_dir() {
+            if flags & L_O_ACCMODE != L_O_RDONLY {
+                return Err(errno::EISDIR);
+            }
+            Opened::Dir(list_dir(&self.host_path(&self.fid(fid)?.rel))?)
+        } else {
+            let path = self.host_path(&self.fid(fid)?.rel);
+            let fp = self
+                .open_opts(flags)?
+                .open(path)
+                .map_err(|e| guest_errno(&e))?;
+            Opened::File(fp)
+        };
+        self.fids.get_mut(&fid).unwrap().open = Some(opened);
+
+        w.qid(&Qid::from_meta(&meta));
+        // No iounit preference beyond the msize
+        w.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let name = rd.string()?;
+        let flags = rd.u32()?;
+        let mode = rd.u32()?;
+        let _gid = rd.u32()?;
+        self.check_writable()?;
+        if !valid_name(&name) {
+            return Err(errno::EINVAL);
+        }
+        let dir = self.fid(fid)?;
+        if dir.open.is_some() {
+            return Err(errno::EINVAL);
+        }
+        let rel = dir.rel.join(&name);
+        self.dir_path(fid)?;
+
+        let mut opts = self.open_opts(flags)?;
+        let fp = opts
+            .write(true)
+            .create_new(true)
+            .mode(mode & 0o7777)
+            .open(self.host_path(&rel))
+            .map_err(|e| guest_errno(&e))?;
+        let meta = fp.metadata().map_err(|e| guest_errno(&e))?;
+
+        // The fid now refers to the newly created (and opened) file
+        let ent = self.fids.get_mut(&fid).unwrap();
+        ent.rel = rel;
+        ent.open = Some(Opened::File(fp));
+
+        w.qid(&Qid::from_meta(&meta));
+        w.u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let offset = rd.u64()?;
+        let count = u32::min(rd.u32()?, self.max_payload(4));
+        let fp = match self.fid(fid)?.open.as_ref() {
+            Some(Opened::File(fp)) => fp,
+            Some(Opened::Dir(_)) => return Err(errno::EISDIR),
+            None => return Err(errno::EBADF),
+        };
+        let mut buf = vec![0u8; count as usize];
+        let nread =
+            fp.read_at(&mut buf, offset).map_err(|e| guest_errno(&e))?;
+        w.u32(nread as u32);
+        w.0.extend_from_slice(&buf[..nread]);
+        Ok(())
+    }
+
+    fn write(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let offset = rd.u64()?;
+        let count = rd.u32()?;
+        let data = rd.bytes(count as usize)?;
+        self.check_writable()?;
+        let fp = match self.fid(fid)?.open.as_ref() {
+            Some(Opened::File(fp)) => fp,
+            Some(Opened::Dir(_)) => return Err(errno::EISDIR),
+            None => return Err(errno::EBADF),
+        };
+        let nwritten =
+            fp.write_at(data, offset).map_err(|e| guest_errno(&e))?;
+        w.u32(nwritten as u32);
+        Ok(())
+    }
+
+    /// Directory entries are reported in name order, with the offset of each
+    /// being its position in that order.  The listing is read when the guest
+    /// (re)starts from offset zero, and held for the reads which follow.
+    fn readdir(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let fid = rd.u32()?;
+        let offset = rd.u64()?;
+        let count = u32::min(rd.u32()?, self.max_payload(4)) as usize;
+        let dir = self.host_path(&self.fid(fid)?.rel);
+        let names = match self.fids.get_mut(&fid).unwrap().open.as_mut() {
+            Some(Opened::Dir(names)) => names,
+            Some(Opened::File(_)) => return Err(errno::ENOTDIR),
+            None => return Err(errno::EBADF),
+        };
+        if offset == 0 {
+            *names = list_dir(&dir)?;
+        }
+
+        let mut entries = Writer(Vec::new());
+        let start = usize::min(offset as usize, names.len());
+        for (idx, name) in names.iter().enumerate().skip(start) {
+            let meta = match fs::symlink_metadata(dir.join(name)) {
+                Ok(m) => m,
+                // Removed since the listing was read
+                Err(_) => continue,
+            };
+            let len = Qid::LEN + 8 + 1 + 2 + name.len();
+            if entries.0.len() + len > count {
+                break;
+            }
+            entries.qid(&Qid::from_meta(&meta));
+            entries.u64(idx as u64 + 1);
+            entries.u8(dirent_type(&meta));
+            entries.string(name);
+        }
+        w.u32(entries.0.len() as u32);
+        w.0.extend_from_slice(&entries.0);
+        Ok(())
+    }
+
+    fn fsync(&mut self, rd: &mut Reader) -> OpResult {
+        let fid = rd.u32()?;
+        let datasync = rd.u32()?;
+        match self.fid(fid)?.open.as_ref() {
+            Some(Opened::File(fp)) => {
+                let res = match datasync {
+                    0 => fp.sync_all(),
+                    _ => fp.sync_data(),
+                };
+                res.map_err(|e| guest_errno(&e))
+            }
+            Some(Opened::Dir(_)) => Ok(()),
+            None => Err(errno::EBADF),
+        }
+    }
+
+    fn mkdir(&mut self, rd: &mut Reader, w: &mut Writer) -> OpResult {
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        let mode = rd.u32()?;
+        let _gid = rd.u32()?;
+        self.check_writable()?;
+        if !valid_name(&name) {
+            return Err(errno::EINVAL);
+        }
+        let path = self.dir_path(dfid)?.join(&name);
+        DirBuilder::new()
+            .mode(mode & 0o7777)
+            .create(&path)
+            .map_err(|e| guest_errno(&e))?;
+        let meta = fs::symlink_metadata(&path).map_err(|e| guest_errno(&e))?;
+        w.qid(&Qid::from_meta(&meta));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, rd: &mut Reader) -> OpResult {
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        let flags = rd.u32()?;
+        self.check_writable()?;
+        if !valid_name(&name) {
+            return Err(errno::EINVAL);
+        }
+        let path = self.dir_path(dfid)?.join(&name);
+        let res = match flags & L_AT_REMOVEDIR {
+            0 => fs::remove_file(path),
+            _ => fs::remove_dir(path),
+        };
+        res.map_err(|e| guest_errno(&e))
+    }
+}
+
+/// Names of the entries in the directory at `path`, in sorted order
+fn list_dir(path: &Path) -> std::result::Result<Vec<String>, u32> {
+    let mut names = fs::read_dir(path)
+        .map_err(|e| guest_errno(&e))?
+        .filter_map(|ent| ent.ok()?.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum P9Reg {
+    TagLen,
+    Tag,
+}
+
+pub struct VirtioP9 {
+    cfg_map: RegMap<P9Reg>,
+    tag: Vec<u8>,
+    server: Mutex<P9Server>,
+}
+impl VirtioP9 {
+    /// Create a device sharing the host directory at `path`, which the guest
+    /// can mount using `tag`.
+    pub fn create(
+        tag: &str,
+        path: impl AsRef<Path>,
+        read_only: bool,
+        queue_size: u16,
+    ) -> Result<Arc<pci::DeviceInst>> {
+        if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("mount tag must be 1-{} bytes", MAX_TAG_LEN),
+            ));
+        }
+        let server = P9Server::new(path, read_only)?;
+
+        let cfg_size = 2 + tag.len();
+        let layout = [(P9Reg::TagLen, 2), (P9Reg::Tag, tag.len())];
+        let this = Self {
+            cfg_map: RegMap::create_packed(cfg_size, &layout, None),
+            tag: tag.as_bytes().to_vec(),
+            server: Mutex::new(server),
+        };
+
+        // virtio-9p needs an MSI-X entry for device config changes, and one
+        // for its single request queue
+        let msix_count = Some(2);
+
+        Ok(PciVirtio::create(
+            queue_size,
+            1,
+            msix_count,
+            VIRTIO_DEV_9P,
+            pci::bits::CLASS_STORAGE,
+            cfg_size,
+            Arc::new(this),
+        ))
+    }
+}
+impl VirtioDevice for VirtioP9 {
+    fn device_cfg_rw(&self, mut rwo: RWOp) {
+        self.cfg_map.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                P9Reg::TagLen => ro.write_u16(self.tag.len() as u16),
+                P9Reg::Tag => ro.write_bytes(&self.tag),
+            },
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn device_get_features(&self) -> u32 {
+        VIRTIO_9P_F_MOUNT_TAG
+    }
+    fn device_set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) {
+        let mem = &ctx.mctx.memctx();
+        let mut server = self.server.lock().unwrap();
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, mem).is_none() {
+                break;
+            }
+            let req = read_chain(&mut chain, mem);
+            let resp = server.handle(&req);
+            write_chain(&mut chain, &resp, mem);
+            vq.push_used(&mut chain, mem, ctx);
+        }
+    }
+
+    fn device_reset(&self, _ctx: &DispCtx) {
+        self.server.lock().unwrap().reset();
+    }
+}
+
+/// Gather the readable portion of `chain` (the request message)
+fn read_chain(chain: &mut Chain, mem: &MemCtx) -> Vec<u8> {
+    let len = usize::min(chain.remain_read_bytes(), MAX_MSIZE as usize);
+    let mut buf = vec![0u8; len];
+    let mut done = 0;
+    while let Some(region) = chain.readable_buf(len - done) {
+        match mem.read_into(region.0, &mut buf[done..], region.1) {
+            Some(n) => done += n,
+            None => break,
+        }
+    }
+    buf.truncate(done);
+    buf
+}
+
+/// Write as much of the response `msg` as fits into the buffers of `chain`
+fn write_chain(chain: &mut Chain, msg: &[u8], mem: &MemCtx) {
+    let mut done = 0;
+    while let Some(region) = chain.writable_buf(msg.len() - done) {
+        match mem.write_from(region.0, &msg[done..], region.1) {
+            Some(n) => done += n,
+            None => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::util::tempfs::TempDir;
+
+    fn msg(mtype: u8, f: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut body = Writer(Vec::new());
+        f(&mut body);
+        let mut m = (HDR_LEN + body.0.len() as u32).to_le_bytes().to_vec();
+        m.push(mtype);
+        m.extend_from_slice(&1u16.to_le_bytes());
+        m.extend_from_slice(&body.0);
+        m
+    }
+
+    /// Issue a request, checking the response type, and returning its body
+    fn call(
+        srv: &mut P9Server,
+        mtype: u8,
+        f: impl FnOnce(&mut Writer),
+    ) -> std::result::Result<Vec<u8>, u32> {
+        let resp = srv.handle(&msg(mtype, f));
+        let mut rd = Reader::new(&resp);
+        assert_eq!(rd.u32().unwrap() as usize, resp.len());
+        let rtype = rd.u8().unwrap();
+        assert_eq!(rd.u16().unwrap(), 1);
+        if rtype == RLERROR {
+            Err(rd.u32().unwrap())
+        } else {
+            assert_eq!(rtype, mtype + 1);
+            Ok(resp[HDR_LEN as usize..].to_vec())
+        }
+    }
+
+    fn attach(srv: &mut P9Server) {
+        call(srv, TVERSION, |w| {
+            w.u32(8192);
+            w.string(P9_VERSION);
+        })
+        .unwrap();
+        call(srv, TATTACH, |w| {
+            w.u32(0);
+            w.u32(!0);
+            w.string("root");
+            w.string("");
+            w.u32(0);
+        })
+        .unwrap();
+    }
+
+    fn walk(
+        srv: &mut P9Server,
+        fid: u32,
+        newfid: u32,
+        names: &[&str],
+    ) -> std::result::Result<u16, u32> {
+        let body = call(srv, TWALK, |w| {
+            w.u32(fid);
+            w.u32(newfid);
+            w.u16(names.len() as u16);
+            for n in names {
+                w.string(n);
+            }
+        })?;
+        Ok(Reader::new(&body).u16().unwrap())
+    }
+
+    fn lopen(srv: &mut P9Server, fid: u32, flags: u32) -> OpResult {
+        call(srv, TLOPEN, |w| {
+            w.u32(fid);
+            w.u32(flags);
+        })
+        .map(|_| ())
+    }
+
+    #[test]
+    fn version_negotiate() {
+        let dir = TempDir::new("p9-version");
+        let mut srv = P9Server::new(&dir.path(), true).unwrap();
+
+        let body = call(&mut srv, TVERSION, |w| {
+            w.u32(0x10_0000);
+            w.string(P9_VERSION);
+        })
+        .unwrap();
+        let mut rd = Reader::new(&body);
+        assert_eq!(rd.u32().unwrap(), MAX_MSIZE);
+        assert_eq!(rd.string().unwrap(), P9_VERSION);
+
+        let body = call(&mut srv, TVERSION, |w| {
+            w.u32(8192);
+            w.string("9P2000.u");
+        })
+        .unwrap();
+        let mut rd = Reader::new(&body);
+        assert_eq!(rd.u32().unwrap(), 8192);
+        assert_eq!(rd.string().unwrap(), "unknown");
+
+        // Truncated and unsupported requests result in errors
+        assert_eq!(
+            call(&mut srv, TVERSION, |w| w.u32(8192)),
+            Err(errno::EPROTO)
+        );
+        assert_eq!(call(&mut srv, 22, |w| w.u32(0)), Err(errno::EOPNOTSUPP));
+    }
+
+    #[test]
+    fn walk_read() {
+        let dir = TempDir::new("p9-read");
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/file"), b"hello world").unwrap();
+        std::os::unix::fs::symlink("/etc", dir.path().join("link")).unwrap();
+        let mut srv = P9Server::new(&dir.path(), true).unwrap();
+        attach(&mut srv);
+
+        assert_eq!(walk(&mut srv, 0, 1, &["sub", "file"]), Ok(2));
+        lopen(&mut srv, 1, L_O_RDONLY).unwrap();
+        let body = call(&mut srv, TREAD, |w| {
+            w.u32(1);
+            w.u64(6);
+            w.u32(100);
+        })
+        .unwrap();
+        assert_eq!(&body[..4], &5u32.to_le_bytes());
+        assert_eq!(&body[4..], b"world");
+
+        // Partial walks do not establish the new fid
+        assert_eq!(walk(&mut srv, 0, 2, &["sub", "missing"]), Ok(1));
+        assert_eq!(lopen(&mut srv, 2, L_O_RDONLY), Err(errno::EBADF));
+        assert_eq!(walk(&mut srv, 0, 2, &["missing"]), Err(errno::ENOENT));
+
+        // Nor can the walk escape the share, through ".." or symlinks
+        assert_eq!(walk(&mut srv, 0, 2, &["..", "..", "sub"]), Ok(3));
+        assert_eq!(walk(&mut srv, 0, 3, &["link", "passwd"]), Ok(1));
+        assert_eq!(walk(&mut srv, 0, 3, &["sub/file"]), Err(errno::EINVAL));
+        assert_eq!(lopen(&mut srv, 3, L_O_RDONLY), Err(errno::EBADF));
+
+        // Writes are refused on a read-only share
+        assert_eq!(walk(&mut srv, 0, 4, &["sub", "file"]), Ok(2));
+        assert_eq!(lopen(&mut srv, 4, 2), Err(errno::EROFS));
+
+        call(&mut srv, TCLUNK, |w| w.u32(1)).unwrap();
+        assert_eq!(call(&mut srv, TCLUNK, |w| w.u32(1)), Err(errno::EBADF));
+    }
+
+    #[test]
+    fn readdir_entries() {
+        let dir = TempDir::new("p9-readdir");
+        for name in ["b", "a", "c"].iter() {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        fs::create_dir(dir.path().join("d")).unwrap();
+        let mut srv = P9Server::new(&dir.path(), true).unwrap();
+        attach(&mut srv);
+        assert_eq!(walk(&mut srv, 0, 1, &[]), Ok(0));
+        lopen(&mut srv, 1, L_O_RDONLY).unwrap();
+
+        let readdir = |srv: &mut P9Server, offset: u64| {
+            let body = call(srv, TREADDIR, |w| {
+                w.u32(1);
+                w.u64(offset);
+                w.u32(4096);
+            })
+            .unwrap();
+            let mut rd = Reader::new(&body);
+            let len = rd.u32().unwrap() as usize;
+            assert_eq!(len, body.len() - 4);
+            let mut ents = Vec::new();
+            while rd.pos < body.len() {
+                rd.bytes(Qid::LEN).unwrap();
+                let off = rd.u64().unwrap();
+                let ty = rd.u8().unwrap();
+                ents.push((off, ty, rd.string().unwrap()));
+            }
+            ents
+        };
+        let ents = readdir(&mut srv, 0);
+        assert_eq!(
+            ents,
+            vec![
+                (1, DT_REG, "a".to_string()),
+                (2, DT_REG, "b".to_string()),
+                (3, DT_REG, "c".to_string()),
+                (4, DT_DIR, "d".to_string()),
+            ]
+        );
+        assert_eq!(readdir(&mut srv, 3), vec![(4, DT_DIR, "d".to_string())]);
+        // Entries added since are only seen once the guest rewinds
+        fs::write(dir.path().join("e"), b"").unwrap();
+        assert!(readdir(&mut srv, 4).is_empty());
+        assert_eq!(readdir(&mut srv, 0).len(), 5);
+        fs::remove_file(dir.path().join("e")).unwrap();
+        readdir(&mut srv, 0);
+        assert!(readdir(&mut srv, 4).is_empty());
+    }
+
+    #[test]
+    fn create_write() {
+        let dir = TempDir::new("p9-write");
+        let mut srv = P9Server::new(&dir.path(), false).unwrap();
+        attach(&mut srv);
+
+        assert_eq!(walk(&mut srv, 0, 1, &[]), Ok(0));
+        call(&mut srv, TLCREATE, |w| {
+            w.u32(1);
+            w.string("new");
+            w.u32(2);
+            w.u32(0o644);
+            w.u32(0);
+        })
+        .unwrap();
+        let body = call(&mut srv, TWRITE, |w| {
+            w.u32(1);
+            w.u64(0);
+            w.u32(4);
+            w.0.extend_from_slice(b"data");
+        })
+        .unwrap();
+        assert_eq!(body, 4u32.to_le_bytes());
+        assert_eq!(fs::read(dir.path().join("new")).unwrap(), b"data");
+        call(&mut srv, TSETATTR, |w| {
+            w.u32(1);
+            w.u32(P9_SETATTR_MODE);
+            w.u32(0o600);
+            w.u32(0);
+            w.u32(0);
+            w.u64(0);
+            w.0.extend_from_slice(&[0u8; 32]);
+        })
+        .unwrap();
+        let meta = fs::metadata(dir.path().join("new")).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o600);
+
+        call(&mut srv, TMKDIR, |w| {
+            w.u32(0);
+            w.string("sub");
+            w.u32(0o755);
+            w.u32(0);
+        })
+        .unwrap();
+        assert!(dir.path().join("sub").is_dir());
+        let unlink = |srv: &mut P9Server, name: &str, flags: u32| {
+            call(srv, TUNLINKAT, |w| {
+                w.u32(0);
+                w.string(name);
+                w.u32(flags);
+            })
+            .map(|_| ())
+        };
+        assert!(unlink(&mut srv, "sub", 0).is_err());
+        unlink(&mut srv, "sub", L_AT_REMOVEDIR).unwrap();
+        unlink(&mut srv, "new", 0).unwrap();
+        assert_eq!(unlink(&mut srv, "new", 0), Err(errno::ENOENT));
+        assert_eq!(unlink(&mut srv, "..", 0), Err(errno::EINVAL));
+        assert!(dir.path().exists());
+    }
+
+    #[test]
+    fn symlink_confined() {
+        let dir = TempDir::new("p9-confine");
+        let outside = TempDir::new("p9-outside");
+        fs::write(outside.path().join("victim"), b"").unwrap();
+        fs::set_permissions(
+            outside.path().join("victim"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(&outside.path(), dir.path().join("out"))
+            .unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("victim"),
+            dir.path().join("victim"),
+        )
+        .unwrap();
+        let mut srv = P9Server::new(&dir.path(), false).unwrap();
+        attach(&mut srv);
+
+        // The symlink itself can be walked to, but not operated through
+        assert_eq!(walk(&mut srv, 0, 1, &["out"]), Ok(1));
+        let res = call(&mut srv, TLCREATE, |w| {
+            w.u32(1);
+            w.string("new");
+            w.u32(2);
+            w.u32(0o644);
+            w.u32(0);
+        });
+        assert_eq!(res, Err(errno::ENOTDIR));
+        let res = call(&mut srv, TMKDIR, |w| {
+            w.u32(1);
+            w.string("sub");
+            w.u32(0o755);
+            w.u32(0);
+        });
+        assert_eq!(res, Err(errno::ENOTDIR));
+        let res = call(&mut srv, TUNLINKAT, |w| {
+            w.u32(1);
+            w.string("victim");
+            w.u32(0);
+        });
+        assert_eq!(res, Err(errno::ENOTDIR));
+        assert!(call(&mut srv, TSTATFS, |w| w.u32(1)).is_err());
+
+        assert_eq!(walk(&mut srv, 0, 2, &["victim"]), Ok(1));
+        let res = call(&mut srv, TSETATTR, |w| {
+            w.u32(2);
+            w.u32(P9_SETATTR_MODE);
+            w.u32(0o777);
+            w.u32(0);
+            w.u32(0);
+            w.u64(0);
+            w.0.extend_from_slice(&[0u8; 32]);
+        });
+        assert!(res.is_err());
+
+        let names: Vec<_> = fs::read_dir(&outside.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec![std::ffi::OsString::from("victim")]);
+        let meta = fs::metadata(outside.path().join("victim")).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o600);
+    }
+}
@@ -40,6 +40,8 @@ struct VirtioState {
     status: Status,
     queue_sel: u16,
     nego_feat: u32,
+    /// Features latched once the driver has accepted them
+    feat_final: Option<u32>,
     isr_status: u8,
     intr_mode: IntrMode,
     intr_mode_updating: bool,
@@ -57,6 +59,7 @@ impl VirtioState {
             status: Status::RESET,
             queue_sel: 0,
             nego_feat: 0,
+            feat_final: None,
             isr_status: 0,
             intr_mode: IntrMode::IsrOnly,
             intr_mode_updating: false,
@@ -70,12 +73,44 @@ impl VirtioState {
         self.status = Status::RESET;
         self.queue_sel = 0;
         self.nego_feat = 0;
+        self.feat_final = None;
         self.isr_status = 0;
         if let Some(pin) = self.lintr_pin.as_ref() {
             pin.deassert();
         }
         self.msix_cfg_vec = VIRTIO_MSI_NO_VECTOR;
     }
+    /// Record the features written by the driver, limited to those which are
+    /// supported by the device.
+    ///
+    /// Once features are final, further writes are ignored (returning `None`)
+    /// until the device is reset.
+    fn set_driver_features(
+        &mut self,
+        feat: u32,
+        supported: u32,
+    ) -> Option<u32> {
+        if self.feat_final.is_some() {
+            return None;
+        }
+        self.nego_feat = feat & supported;
+        Some(self.nego_feat)
+    }
+    /// Update the device status, returning the negotiated features if they
+    /// became final with this update.
+    ///
+    /// Features are considered final when the driver sets FEATURES_OK, or
+    /// DRIVER_OK for legacy drivers which predate FEATURES_OK.
+    fn set_status(&mut self, val: Status) -> Option<u32> {
+        self.status = val;
+        if self.feat_final.is_none()
+            && val.intersects(Status::FEATURES_OK | Status::DRIVER_OK)
+        {
+            self.feat_final = Some(self.nego_feat);
+            return self.feat_final;
+        }
+        None
+    }
 }
 
 pub struct PciVirtio {
@@ -111,14 +146,15 @@ impl PciVirtio {
             queues.push(Arc::new(VirtQueue::new(id, queue_size)));
         }
 
-        let layout = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
-        let layout_nomsix = [
-            (VirtioTop::LegacyConfig, LEGACY_REG_SZ_NO_MSIX),
-            (VirtioTop::DeviceConfig, cfg_sz),
-        ];
+        // Devices without any config space (such as virtio-rng) omit that
+        // region from the layout entirely.
+        let mut layout = vec![(VirtioTop::LegacyConfig, LEGACY_REG_SZ)];
+        let mut layout_nomsix =
+            vec![(VirtioTop::LegacyConfig, LEGACY_REG_SZ_NO_MSIX)];
+        if cfg_sz != 0 {
+            layout.push((VirtioTop::DeviceConfig, cfg_sz));
+            layout_nomsix.push((VirtioTop::DeviceConfig, cfg_sz));
+        }
 
         let mut this = Arc::new(Self {
             map: RegMap::create_packed_passthru(
@@ -225,10 +261,13 @@ impl PciVirtio {
     fn legacy_write(&self, id: &LegacyReg, wo: &mut WriteOp, ctx: &DispCtx) {
         match id {
             LegacyReg::FeatDriver => {
-                let nego = wo.read_u32() & self.features_supported();
                 let mut state = self.state.lock().unwrap();
-                state.nego_feat = nego;
-                self.dev.device_set_features(nego);
+                if let Some(nego) = state.set_driver_features(
+                    wo.read_u32(),
+                    self.features_supported(),
+                ) {
+                    self.dev.device_set_features(nego);
+                }
             }
             LegacyReg::QueuePfn => {
                 let mut state = self.state.lock().unwrap();
@@ -305,9 +344,14 @@ impl PciVirtio {
             self.device_reset(state, ctx)
         } else {
             // XXX: better device status FSM
-            state.status = val;
+            let _ = state.set_status(val);
         }
     }
+
+    /// Features negotiated with the guest driver, once it has accepted them.
+    pub fn negotiated_features(&self) -> Option<u32> {
+        self.state.lock().unwrap().feat_final
+    }
     fn queue_notify(&self, queue: u16, ctx: &DispCtx) {
         if let Some(vq) = self.queues.get(queue as usize) {
             self.dev.queue_notify(vq, ctx);
@@ -405,6 +449,9 @@ impl SelfArc for PciVirtio {
 }
 
 impl pci::Device for PciVirtio {
+    fn reset(&self, ctx: &DispCtx) {
+        self.device_reset(self.state.lock().unwrap(), ctx);
+    }
     fn bar_rw(&self, bar: pci::BarN, mut rwo: RWOp, ctx: &DispCtx) {
         assert_eq!(bar, pci::BarN::BAR0);
         let map = match self.map_which.load(Ordering::SeqCst) {
@@ -558,3 +605,39 @@ lazy_static! {
         RegMap::create_packed(LEGACY_REG_SZ, &layout, None)
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn feature_negotiation() {
+        let supported = VIRTIO_F_RING_INDIRECT_DESC as u32 | 0x3;
+        let mut state = VirtioState::new(1);
+
+        assert_eq!(state.set_status(Status::ACK), None);
+        assert_eq!(state.set_status(Status::ACK | Status::DRIVER), None);
+        // Unsupported bits requested by the driver are dropped
+        assert_eq!(
+            state.set_driver_features(0xffff_ffff, supported),
+            Some(supported)
+        );
+        let status = Status::ACK | Status::DRIVER | Status::FEATURES_OK;
+        assert_eq!(state.set_status(status), Some(supported));
+        assert_eq!(state.feat_final, Some(supported));
+
+        // Once final, features are not renegotiated without a reset
+        assert_eq!(state.set_driver_features(0x1, supported), None);
+        assert_eq!(state.nego_feat, supported);
+        assert_eq!(state.set_status(status | Status::DRIVER_OK), None);
+        assert_eq!(state.feat_final, Some(supported));
+
+        state.reset();
+        assert_eq!(state.feat_final, None);
+
+        // Legacy drivers go straight to DRIVER_OK
+        assert_eq!(state.set_driver_features(0x2, supported), Some(0x2));
+        let status = Status::ACK | Status::DRIVER | Status::DRIVER_OK;
+        assert_eq!(state.set_status(status), Some(0x2));
+    }
+}
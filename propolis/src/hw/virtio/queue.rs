@@ -294,18 +294,28 @@ impl VirtQueue {
         Some(len)
     }
     pub fn push_used(&self, chain: &mut Chain, mem: &MemCtx, ctx: &DispCtx) {
+        if self.put_used(chain, mem) {
+            self.notify(ctx);
+        }
+    }
+    /// Place `chain` in the used ring, without notifying the guest.  Returns
+    /// whether the guest expects to be notified, via [`VirtQueue::notify`].
+    pub fn put_used(&self, chain: &mut Chain, mem: &MemCtx) -> bool {
         assert!(chain.idx.is_some());
         let mut used = self.used.lock().unwrap();
         let id = mem::replace(&mut chain.idx, None).unwrap();
         // XXX: for now, just go off of the write stats
         let len = chain.write_stat.bytes - chain.write_stat.bytes_remain;
         used.write_used(id, len, self.size, mem);
-        if !used.suppress_intr(mem) {
-            if let Some(i) = used.interrupt.as_ref() {
-                i.notify(ctx)
-            }
-        }
         chain.reset();
+        !used.suppress_intr(mem)
+    }
+    /// Notify the guest of entries placed in the used ring
+    pub fn notify(&self, ctx: &DispCtx) {
+        let used = self.used.lock().unwrap();
+        if let Some(i) = used.interrupt.as_ref() {
+            i.notify(ctx)
+        }
     }
 
     pub(super) fn set_interrupt(&self, intr: Box<dyn VirtioIntr>) {
@@ -565,3 +575,82 @@ pub struct MapInfo {
     pub avail_addr: u64,
     pub used_addr: u64,
 }
+
+/// Virtqueue laid out (in the legacy format) in test memory, through which a
+/// test can act as the guest driver.
+#[cfg(test)]
+pub struct TestQueue {
+    pub vq: std::sync::Arc<VirtQueue>,
+    used_addr: u64,
+    next_desc: u16,
+    avail_idx: u16,
+}
+#[cfg(test)]
+impl TestQueue {
+    /// Create queue `id`, of `size` entries, with its rings at `base`
+    pub fn new(id: u16, size: u16, base: u64) -> Self {
+        let vq = VirtQueue::new(id, size);
+        assert!(vq.map_legacy(base));
+        let used_addr = vq.map_info().unwrap().used_addr;
+        Self {
+            vq: std::sync::Arc::new(vq),
+            used_addr,
+            next_desc: 0,
+            avail_idx: 0,
+        }
+    }
+    fn desc_addr(&self, id: u16) -> GuestAddr {
+        let base = self.vq.map_info().unwrap().desc_addr;
+        GuestAddr(base + (id as usize * mem::size_of::<VqdDesc>()) as u64)
+    }
+    /// Make a chain of buffers, each given as (address, length, writable by
+    /// the device), available to the device.  Returns the head descriptor.
+    pub fn add_chain(
+        &mut self,
+        bufs: &[(u64, u32, bool)],
+        mem: &MemCtx,
+    ) -> u16 {
+        let size = self.vq.size;
+        let head = self.next_desc;
+        for (i, (addr, len, writable)) in bufs.iter().enumerate() {
+            let id = self.next_desc;
+            self.next_desc = (id + 1) % size;
+            let mut flags = DescFlag::empty();
+            if *writable {
+                flags |= DescFlag::WRITE;
+            }
+            if i + 1 < bufs.len() {
+                flags |= DescFlag::NEXT;
+            }
+            let desc = VqdDesc {
+                addr: *addr,
+                len: *len,
+                flags: flags.bits(),
+                next: self.next_desc,
+            };
+            assert!(mem.write(self.desc_addr(id), &desc));
+        }
+
+        let avail = self.vq.map_info().unwrap().avail_addr;
+        let slot = (self.avail_idx % size) as u64;
+        assert!(mem.write(GuestAddr(avail + 4 + slot * 2), &head));
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        assert!(mem.write(GuestAddr(avail + 2), &self.avail_idx));
+        head
+    }
+    /// Entries, as (head descriptor, length written), which the device has
+    /// placed in the used ring
+    pub fn used(&self, mem: &MemCtx) -> Vec<(u32, u32)> {
+        let idx: u16 = mem.read(GuestAddr(self.used_addr + 2)).unwrap();
+        (0..idx)
+            .map(|n| {
+                let slot = (n % self.vq.size) as usize;
+                let addr = self.used_addr as usize
+                    + 4
+                    + slot * mem::size_of::<VqdUsed>();
+                let ent: VqdUsed = mem.read(GuestAddr(addr as u64)).unwrap();
+                (ent.id, ent.len)
+            })
+            .collect()
+    }
+}
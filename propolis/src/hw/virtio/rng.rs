@@ -0,0 +1,451 @@
+use std::cmp::min;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::*;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::hw::pci;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::PciVirtio;
+use super::queue::{Chain, VirtQueue};
+use super::VirtioDevice;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+/// Delay before retrying a source which yielded no entropy
+const STALL_DELAY: Duration = Duration::from_millis(100);
+
+/// Host source of entropy for a virtio-rng device
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RngSource {
+    /// Character device (or file) to read from, such as `/dev/random`
+    Path(PathBuf),
+    /// The `getrandom(2)` syscall
+    GetRandom,
+}
+
+enum Source {
+    File(File),
+    GetRandom,
+}
+impl Source {
+    fn open(src: &RngSource) -> Result<Self> {
+        match src {
+            RngSource::Path(p) => Ok(Source::File(File::open(p)?)),
+            RngSource::GetRandom => Ok(Source::GetRandom),
+        }
+    }
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Source::File(fp) => {
+                let mut fp: &File = fp;
+                fp.read(buf)
+            }
+            Source::GetRandom => {
+                let res = unsafe {
+                    libc::getrandom(
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        0,
+                    )
+                };
+                if res < 0 {
+                    Err(Error::last_os_error())
+                } else {
+                    Ok(res as usize)
+                }
+            }
+        }
+    }
+}
+
+/// Token bucket limiting the bytes handed to the guest, permitting a burst of
+/// up to one second worth of the configured rate.
+struct Bucket {
+    rate: u64,
+    tokens: u64,
+    last: Instant,
+}
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        assert!(rate > 0);
+        Self { rate, tokens: rate, last: now }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_nanos();
+        let add = elapsed * self.rate as u128 / NANOS_PER_SEC;
+        if add == 0 {
+            // Leave `last` untouched so short intervals still accumulate
+            return;
+        }
+        self.tokens = min(self.rate as u128, self.tokens as u128 + add) as u64;
+        self.last = now;
+    }
+    /// Take up to `want` tokens, returning how many were granted.
+    fn take(&mut self, want: usize, now: Instant) -> usize {
+        self.refill(now);
+        let granted = min(want as u64, self.tokens);
+        self.tokens -= granted;
+        granted as usize
+    }
+    /// Return `unused` tokens of those taken
+    fn give_back(&mut self, unused: usize) {
+        self.tokens = min(self.rate, self.tokens + unused as u64);
+    }
+    /// How long until at least one token is available again
+    fn until_next(&self) -> Duration {
+        let nanos = NANOS_PER_SEC / self.rate as u128;
+        Duration::from_nanos(nanos.max(1) as u64)
+    }
+}
+
+/// Why [`VirtioRng::fill_bufs`] stopped filling buffers
+#[derive(Debug, Eq, PartialEq)]
+enum FillStop {
+    /// No buffers remain available
+    Empty,
+    /// The rate limit is exhausted
+    Limited,
+    /// The source yielded nothing, so the buffers were left pending
+    Stalled,
+}
+
+struct State {
+    vq: Option<Arc<VirtQueue>>,
+    notified: bool,
+    limit: Option<Bucket>,
+    /// Buffers popped from the queue, but not yet filled
+    pending: Option<Chain>,
+}
+
+/// virtio-rng device, supplying the guest with entropy from the host.
+///
+/// Reads from the entropy source may block (as `/dev/random` can), so the
+/// requests are serviced by a worker spawned on the dispatcher, rather than in
+/// the context of the vCPU which notified the queue.
+pub struct VirtioRng {
+    source: Source,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+impl VirtioRng {
+    /// Create a device reading from `source`, optionally limited to `rate`
+    /// bytes per second.
+    pub fn create(
+        source: &RngSource,
+        rate: Option<u64>,
+        queue_size: u16,
+        disp: &Dispatcher,
+    ) -> Result<Arc<pci::DeviceInst>> {
+        if rate == Some(0) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "rate limit must be non-zero",
+            ));
+        }
+        let this = Arc::new(Self {
+            source: Source::open(source)?,
+            state: Mutex::new(State {
+                vq: None,
+                notified: false,
+                limit: rate.map(|r| Bucket::new(r, Instant::now())),
+                pending: None,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let qthis = Arc::clone(&this);
+        disp.on_quiesce(move || qthis.wake());
+        disp.spawn("virtio-rng".to_string(), Arc::clone(&this), Self::run)?;
+
+        // One MSI-X entry for (unused) config changes, and one for the queue
+        let msix_count = Some(2);
+
+        Ok(PciVirtio::create(
+            queue_size,
+            1,
+            msix_count,
+            VIRTIO_DEV_RNG,
+            pci::bits::CLASS_UNCLASSIFIED,
+            0,
+            this,
+        ))
+    }
+
+    fn wake(&self) {
+        // Take the lock so the wake-up cannot slip in between the worker
+        // checking its exit condition and waiting on the condvar.
+        let _guard = self.state.lock().unwrap();
+        self.cond.notify_all();
+    }
+
+    fn run(ctx: DispCtx, this: Arc<Self>) {
+        loop {
+            let vq = {
+                let state = this.state.lock().unwrap();
+                let mut state = this
+                    .cond
+                    .wait_while(state, |s| !s.notified && !ctx.should_exit())
+                    .unwrap();
+                if ctx.should_exit() {
+                    return;
+                }
+                state.notified = false;
+                match state.vq.as_ref() {
+                    Some(vq) => Arc::clone(vq),
+                    None => continue,
+                }
+            };
+            if !this.process(&vq, &ctx) {
+                return;
+            }
+        }
+    }
+
+    /// Fill all available buffers in `vq`, returning `false` if the worker was
+    /// asked to exit while waiting on the rate limit.
+    fn process(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) -> bool {
+        let mem = &ctx.mctx.memctx();
+        loop {
+            if !self.wait_tokens(ctx) {
+                return false;
+            }
+            let _active = ctx.device_activity();
+            let (stop, notify) = self.fill_bufs(vq, mem);
+            if notify {
+                vq.notify(ctx);
+            }
+            match stop {
+                FillStop::Empty => return true,
+                FillStop::Limited => {}
+                FillStop::Stalled => {
+                    let state = self.state.lock().unwrap();
+                    let _ = self.cond.wait_timeout(state, STALL_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Fill buffers of `vq` from the source, returning why it stopped and
+    /// whether the guest is to be notified of the buffers used.
+    ///
+    /// Should the source yield nothing, the buffers are held for a later
+    /// attempt, rather than being handed back to the guest empty.
+    fn fill_bufs(&self, vq: &VirtQueue, mem: &MemCtx) -> (FillStop, bool) {
+        let mut notify = false;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            if let Some(bucket) = state.limit.as_mut() {
+                bucket.refill(now);
+                if bucket.tokens == 0 {
+                    return (FillStop::Limited, notify);
+                }
+            }
+            let mut chain = match state.pending.take() {
+                Some(chain) => chain,
+                None => {
+                    let mut chain = Chain::with_capacity(4);
+                    if vq.pop_avail(&mut chain, mem).is_none() {
+                        return (FillStop::Empty, notify);
+                    }
+                    chain
+                }
+            };
+            let want = chain.remain_write_bytes();
+            let allowed = match state.limit.as_mut() {
+                Some(bucket) => bucket.take(want, now),
+                None => want,
+            };
+            // The source may block, so do not hold the lock while reading
+            drop(state);
+
+            let mut buf = vec![0u8; allowed];
+            let mut filled = 0;
+            while filled < allowed {
+                match self.source.read(&mut buf[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+
+            if filled < allowed {
+                let mut state = self.state.lock().unwrap();
+                if let Some(bucket) = state.limit.as_mut() {
+                    bucket.give_back(allowed - filled);
+                }
+                if filled == 0 {
+                    state.pending = Some(chain);
+                    return (FillStop::Stalled, notify);
+                }
+            }
+
+            let mut done = 0;
+            while let Some(region) = chain.writable_buf(filled - done) {
+                match mem.write_from(region.0, &buf[done..], region.1) {
+                    Some(n) => done += n,
+                    None => break,
+                }
+            }
+            notify |= vq.put_used(&mut chain, mem);
+        }
+    }
+
+    /// Block until the rate limit (if any) permits at least one byte to be
+    /// handed to the guest.  Returns `false` if asked to exit while waiting.
+    fn wait_tokens(&self, ctx: &DispCtx) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if ctx.should_exit() {
+                return false;
+            }
+            let wait = match state.limit.as_mut() {
+                None => return true,
+                Some(bucket) => {
+                    bucket.refill(Instant::now());
+                    if bucket.tokens > 0 {
+                        return true;
+                    }
+                    bucket.until_next()
+                }
+            };
+            state = self.cond.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+}
+impl VirtioDevice for VirtioRng {
+    fn device_cfg_rw(&self, mut ro: RWOp) {
+        // virtio-rng has no device-specific config space
+        if let RWOp::Read(ro) = &mut ro {
+            ro.fill(0);
+        }
+    }
+    fn device_get_features(&self) -> u32 {
+        0
+    }
+    fn device_set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, _vq: &Arc<VirtQueue>, _ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        state.notified = true;
+        self.cond.notify_all();
+    }
+    fn device_reset(&self, _ctx: &DispCtx) {
+        let mut state = self.state.lock().unwrap();
+        state.notified = false;
+        state.pending = None;
+    }
+    fn attach(&self, queues: &[Arc<VirtQueue>]) {
+        self.state.lock().unwrap().vq = Some(Arc::clone(&queues[0]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::virtio::queue::TestQueue;
+    use crate::vmm::TestMem;
+
+    use std::io::Write;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    /// Device reading from a socket, standing in for the entropy source, the
+    /// other end of which is returned.
+    fn device(rate: Option<u64>) -> (VirtioRng, UnixStream) {
+        let (src, host) = UnixStream::pair().unwrap();
+        src.set_nonblocking(true).unwrap();
+        let fp = unsafe { File::from_raw_fd(src.into_raw_fd()) };
+        let dev = VirtioRng {
+            source: Source::File(fp),
+            state: Mutex::new(State {
+                vq: None,
+                notified: false,
+                limit: rate.map(|r| Bucket::new(r, Instant::now())),
+                pending: None,
+            }),
+            cond: Condvar::new(),
+        };
+        (dev, host)
+    }
+
+    fn read_mem(mem: &MemCtx, addr: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        assert_eq!(mem.read_into(GuestAddr(addr), &mut buf, len), Some(len));
+        buf
+    }
+
+    #[test]
+    fn fill() {
+        let tmem = TestMem::new(0x10000);
+        let mem = tmem.memctx();
+        let (dev, mut host) = device(None);
+        let mut q = TestQueue::new(0, 16, 0);
+
+        let data: Vec<u8> = (0..16).collect();
+        host.write_all(&data).unwrap();
+        let head = q.add_chain(&[(0x4000, 16, true)], &mem);
+        assert_eq!(dev.fill_bufs(&q.vq, &mem), (FillStop::Empty, true));
+        assert_eq!(q.used(&mem), [(head as u32, 16)]);
+        assert_eq!(read_mem(&mem, 0x4000, 16), data);
+
+        // A source with nothing to offer leaves the buffers pending ...
+        let head = q.add_chain(&[(0x5000, 16, true)], &mem);
+        assert_eq!(dev.fill_bufs(&q.vq, &mem), (FillStop::Stalled, false));
+        assert_eq!(q.used(&mem).len(), 1);
+
+        // ... until it can be read again
+        host.write_all(&data[..8]).unwrap();
+        assert_eq!(dev.fill_bufs(&q.vq, &mem), (FillStop::Empty, true));
+        assert_eq!(q.used(&mem)[1], (head as u32, 8));
+        assert_eq!(read_mem(&mem, 0x5000, 8), data[..8]);
+    }
+
+    #[test]
+    fn fill_limited() {
+        let tmem = TestMem::new(0x10000);
+        let mem = tmem.memctx();
+        let (dev, mut host) = device(Some(4));
+        let mut q = TestQueue::new(0, 16, 0);
+
+        // Tokens are not consumed by a stalled read
+        let head = q.add_chain(&[(0x4000, 16, true)], &mem);
+        assert_eq!(dev.fill_bufs(&q.vq, &mem), (FillStop::Stalled, false));
+        host.write_all(&[0xa5; 16]).unwrap();
+        assert_eq!(dev.fill_bufs(&q.vq, &mem), (FillStop::Limited, true));
+        assert_eq!(q.used(&mem), [(head as u32, 4)]);
+    }
+
+    #[test]
+    fn bucket_limit() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1000, start);
+
+        // Full burst is available up front, but no more
+        assert_eq!(bucket.take(600, start), 600);
+        assert_eq!(bucket.take(600, start), 400);
+        assert_eq!(bucket.take(1, start), 0);
+        assert_eq!(bucket.until_next(), Duration::from_millis(1));
+
+        // Tokens accrue at the configured rate
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(1000, later), 250);
+
+        // ... and are capped at one second worth
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(5000, much_later), 1000);
+
+        // Sub-token intervals are not lost
+        let mut slow = Bucket::new(1, start);
+        assert_eq!(slow.take(1, start), 1);
+        let half = start + Duration::from_millis(500);
+        assert_eq!(slow.take(1, half), 0);
+        assert_eq!(slow.take(1, half + Duration::from_millis(500)), 1);
+    }
+}
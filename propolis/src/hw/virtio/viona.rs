@@ -19,12 +19,68 @@ use super::{VirtioDevice, VqChange, VqIntr};
 
 use lazy_static::lazy_static;
 
-const VIRTIO_NET_S_LINK_UP: u16 = 1 << 0;
+pub(super) const VIRTIO_NET_S_LINK_UP: u16 = 1 << 0;
 const VIRTIO_NET_S_ANNOUNCE: u16 = 1 << 1;
 
-const VIRTIO_NET_CFG_SIZE: usize = 0xc;
+pub(super) const VIRTIO_NET_CFG_SIZE: usize = 0xc;
 
-const ETHERADDRL: usize = 6;
+pub(super) const ETHERADDRL: usize = 6;
+
+/// Smallest MTU which will be advertised to the guest (the IPv4 minimum)
+pub const MIN_MTU: u16 = 68;
+
+const OFFLOAD_CSUM: u32 = VIRTIO_NET_F_CSUM | VIRTIO_NET_F_GUEST_CSUM;
+const OFFLOAD_TSO: u32 = VIRTIO_NET_F_HOST_TSO4
+    | VIRTIO_NET_F_HOST_TSO6
+    | VIRTIO_NET_F_HOST_ECN
+    | VIRTIO_NET_F_GUEST_TSO4
+    | VIRTIO_NET_F_GUEST_TSO6
+    | VIRTIO_NET_F_GUEST_ECN;
+
+/// Tunables for what a viona device advertises to the guest.
+///
+/// Offloads are only offered when also supported by the underlying vnic.
+#[derive(Copy, Clone, Debug)]
+pub struct VionaConfig {
+    /// MTU to advertise, rather than that of the vnic.  It is limited to the
+    /// vnic MTU, and should be no less than [`MIN_MTU`].
+    pub mtu: Option<u16>,
+    /// Checksum offload, in both directions
+    pub csum: bool,
+    /// TCP segmentation offload, in both directions.  This requires `csum`.
+    pub tso: bool,
+    /// Mergeable receive buffers
+    pub mrg_rxbuf: bool,
+}
+impl VionaConfig {
+    /// Feature bits to offer, given those available from the vnic
+    fn features(&self, avail: u32) -> u32 {
+        let mut mask = !0;
+        if !self.csum {
+            mask &= !(OFFLOAD_CSUM | OFFLOAD_TSO);
+        }
+        if !self.tso {
+            mask &= !OFFLOAD_TSO;
+        }
+        if !self.mrg_rxbuf {
+            mask &= !VIRTIO_NET_F_MGR_RXBUF;
+        }
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU | (avail & mask)
+    }
+
+    /// MTU to advertise, given that of the vnic
+    fn mtu(&self, vnic_mtu: u16) -> u16 {
+        match self.mtu {
+            Some(mtu) => mtu.max(MIN_MTU).min(vnic_mtu),
+            None => vnic_mtu,
+        }
+    }
+}
+impl Default for VionaConfig {
+    fn default() -> Self {
+        Self { mtu: None, csum: true, tso: true, mrg_rxbuf: true }
+    }
+}
 
 struct Inner {
     queues: Vec<Arc<VirtQueue>>,
@@ -37,6 +93,7 @@ impl Inner {
 }
 
 pub struct VirtioViona {
+    /// Features offered to the guest
     dev_features: u32,
     link_id: u32,
     mac_addr: [u8; ETHERADDRL],
@@ -51,16 +108,31 @@ impl VirtioViona {
         vnic_name: &str,
         queue_size: u16,
         vm: &VmmHdl,
+    ) -> Result<Arc<pci::DeviceInst>> {
+        Self::create_with_config(
+            vnic_name,
+            queue_size,
+            vm,
+            VionaConfig::default(),
+        )
+    }
+
+    /// Create a device advertising the MTU and offloads selected by `config`
+    pub fn create_with_config(
+        vnic_name: &str,
+        queue_size: u16,
+        vm: &VmmHdl,
+        config: VionaConfig,
     ) -> Result<Arc<pci::DeviceInst>> {
         let dlhdl = dladm::Handle::new()?;
         let info = dlhdl.query_vnic(vnic_name)?;
         let hdl = VionaHdl::new(info.link_id, vm.fd())?;
 
         let mut this = VirtioViona {
-            dev_features: hdl.get_avail_features()?,
+            dev_features: config.features(hdl.get_avail_features()?),
             link_id: info.link_id,
             mac_addr: [0; ETHERADDRL],
-            mtu: info.mtu,
+            mtu: config.mtu(info.mtu),
             hdl,
             inner: Mutex::new(Inner::new()),
             sa_cell: SelfArcCell::new(),
@@ -130,11 +202,7 @@ impl VirtioDevice for VirtioViona {
         });
     }
     fn device_get_features(&self) -> u32 {
-        let mut feat = VIRTIO_NET_F_MAC;
-        feat |= VIRTIO_NET_F_MTU;
-        feat |= self.dev_features;
-
-        feat
+        self.dev_features
     }
     fn device_set_features(&self, feat: u32) {
         self.hdl.set_features(feat);
@@ -220,14 +288,14 @@ impl SelfArc for VirtioViona {
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-enum NetReg {
+pub(super) enum NetReg {
     Mac,
     Status,
     MaxVqPairs,
     Mtu,
 }
 lazy_static! {
-    static ref NET_DEV_REGS: RegMap<NetReg> = {
+    pub(super) static ref NET_DEV_REGS: RegMap<NetReg> = {
         let layout = [
             (NetReg::Mac, 6),
             (NetReg::Status, 2),
@@ -331,3 +399,55 @@ impl VionaHdl {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn advertised_config() {
+        let avail = VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_GUEST_CSUM
+            | VIRTIO_NET_F_HOST_TSO4
+            | VIRTIO_NET_F_GUEST_TSO4
+            | VIRTIO_NET_F_MGR_RXBUF;
+
+        let cfg = VionaConfig::default();
+        assert_eq!(
+            cfg.features(avail),
+            avail | VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU
+        );
+        assert_eq!(cfg.mtu(1500), 1500);
+
+        // Offloads missing from the vnic are not offered
+        assert_eq!(
+            cfg.features(VIRTIO_NET_F_CSUM),
+            VIRTIO_NET_F_CSUM | VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU
+        );
+
+        // TSO goes with checksum offload
+        let cfg = VionaConfig { csum: false, ..Default::default() };
+        assert_eq!(
+            cfg.features(avail),
+            VIRTIO_NET_F_MGR_RXBUF | VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU
+        );
+        let cfg = VionaConfig {
+            mtu: Some(9000),
+            tso: false,
+            mrg_rxbuf: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            cfg.features(avail),
+            OFFLOAD_CSUM | VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU
+        );
+
+        // MTU is limited by the vnic
+        assert_eq!(cfg.mtu(1500), 1500);
+        assert_eq!(cfg.mtu(9000), 9000);
+        let cfg = VionaConfig { mtu: Some(1400), ..Default::default() };
+        assert_eq!(cfg.mtu(1500), 1400);
+        let cfg = VionaConfig { mtu: Some(10), ..Default::default() };
+        assert_eq!(cfg.mtu(1500), MIN_MTU);
+    }
+}
@@ -0,0 +1,989 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::common::*;
+use crate::dispatch::{DispCtx, Dispatcher};
+use crate::hw::pci;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::PciVirtio;
+use super::queue::{Chain, VirtQueue};
+use super::VirtioDevice;
+
+const VSOCK_HOST_CID: u64 = 2;
+const VSOCK_TYPE_STREAM: u16 = 1;
+
+const VSOCK_OP_REQUEST: u16 = 1;
+const VSOCK_OP_RESPONSE: u16 = 2;
+const VSOCK_OP_RST: u16 = 3;
+const VSOCK_OP_SHUTDOWN: u16 = 4;
+const VSOCK_OP_RW: u16 = 5;
+const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+const VSOCK_SHUTDOWN_RCV: u32 = 1 << 0;
+const VSOCK_SHUTDOWN_SEND: u32 = 1 << 1;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const NUM_QUEUES: u16 = 3;
+
+const VSOCK_CFG_SIZE: usize = 8;
+
+/// Buffer space advertised to the guest for each connection
+const BUF_ALLOC: u32 = 64 * 1024;
+/// Limit on the payload of a single packet sent to the guest
+const MAX_PKT_LEN: usize = 4096;
+/// Host-initiated connections are given source ports starting here
+const EPHEMERAL_PORT_BASE: u32 = 0x4000_0000;
+/// Limit on the packets awaiting delivery to the guest.  Data is limited by
+/// the credit offered by the guest, but the control packets it provokes are
+/// not, so past this point they are dropped (or new connections refused).
+const MAX_PENDING: usize = 256;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone, Default)]
+struct VsockHdr {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    typ: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+const HDR_SZ: usize = std::mem::size_of::<VsockHdr>();
+
+/// Configuration for a virtio-vsock device
+#[derive(Clone, Debug, Default)]
+pub struct VsockConfig {
+    /// Context ID assigned to the guest (3 or greater)
+    pub guest_cid: u64,
+    /// Guest connections to each of these host ports are proxied to the Unix
+    /// socket at the associated path.
+    pub host_ports: BTreeMap<u32, PathBuf>,
+    /// A Unix socket is bound at each of these paths, with connections to it
+    /// proxied to the associated port in the guest.
+    pub guest_ports: BTreeMap<u32, PathBuf>,
+}
+
+/// Connection, identified by its host and guest ports
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct ConnKey {
+    host_port: u32,
+    guest_port: u32,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ConnState {
+    /// Host-initiated, awaiting a response from the guest
+    Connecting,
+    /// Guest-initiated, awaiting completion of the connection to the host
+    /// socket
+    HostConnecting,
+    Connected,
+}
+
+struct Conn {
+    stream: UnixStream,
+    state: ConnState,
+    /// Data from the guest, yet to be written to the host socket
+    tx_buf: Vec<u8>,
+    /// Bytes from the guest which have been written to the host socket
+    fwd_cnt: u32,
+    /// Value of `fwd_cnt` last reported to the guest via a credit update
+    fwd_reported: u32,
+    /// Bytes of payload sent to the guest
+    rx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Guest will send no further data
+    peer_shut_send: bool,
+    /// Guest will receive no further data
+    peer_shut_rcv: bool,
+    /// Host socket has reached EOF
+    host_eof: bool,
+}
+impl Conn {
+    fn new(stream: UnixStream, state: ConnState) -> Self {
+        Self {
+            stream,
+            state,
+            tx_buf: Vec::new(),
+            fwd_cnt: 0,
+            fwd_reported: 0,
+            rx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            peer_shut_send: false,
+            peer_shut_rcv: false,
+            host_eof: false,
+        }
+    }
+    /// Bytes which can be sent to the guest without overrunning its buffer
+    fn peer_credit(&self) -> u32 {
+        let in_flight = self.rx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight)
+    }
+    fn interest(&self) -> libc::c_short {
+        if self.state == ConnState::HostConnecting {
+            return libc::POLLOUT;
+        }
+        let mut events = 0;
+        if self.state == ConnState::Connected
+            && !self.host_eof
+            && !self.peer_shut_rcv
+            && self.peer_credit() > 0
+        {
+            events |= libc::POLLIN;
+        }
+        if !self.tx_buf.is_empty() {
+            events |= libc::POLLOUT;
+        }
+        events
+    }
+    /// Write as much of the pending guest data to the host socket as it will
+    /// accept without blocking.
+    fn drain_tx(&mut self) -> Result<()> {
+        while !self.tx_buf.is_empty() {
+            match self.stream.write(&self.tx_buf) {
+                Ok(n) => {
+                    self.tx_buf.drain(..n);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(n as u32);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.tx_buf.is_empty() && self.peer_shut_send {
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+    /// Does the guest need to hear of the buffer space freed up since it was
+    /// last told of our progress?
+    fn needs_credit_update(&self) -> bool {
+        self.fwd_cnt.wrapping_sub(self.fwd_reported) >= BUF_ALLOC / 2
+    }
+}
+
+/// Packet awaiting delivery to the guest
+struct Pkt {
+    key: ConnKey,
+    op: u16,
+    flags: u32,
+    data: Vec<u8>,
+}
+
+struct Inner {
+    rxq: Option<Arc<VirtQueue>>,
+    txq: Option<Arc<VirtQueue>>,
+    conns: BTreeMap<ConnKey, Conn>,
+    /// Sockets of closed connections.  They are dropped by the worker, so the
+    /// descriptors are not closed (and potentially reused) while it is polling
+    /// them.
+    closing: Vec<UnixStream>,
+    pending: VecDeque<Pkt>,
+    next_port: u32,
+}
+impl Inner {
+    fn queue(&mut self, key: ConnKey, op: u16, flags: u32, data: Vec<u8>) {
+        if data.is_empty() {
+            // A control packet identical to one already pending would tell
+            // the guest nothing new.
+            let dup = self.pending.iter().any(|p| {
+                p.key == key
+                    && p.op == op
+                    && p.flags == flags
+                    && p.data.is_empty()
+            });
+            if dup {
+                return;
+            }
+        }
+        self.pending.push_back(Pkt { key, op, flags, data });
+    }
+    fn is_backlogged(&self) -> bool {
+        self.pending.len() >= MAX_PENDING
+    }
+    /// Reset a connection of which no state is held.  This is dropped if the
+    /// guest is not keeping up with the packets already pending.
+    fn queue_rst(&mut self, key: ConnKey) {
+        if !self.is_backlogged() {
+            self.queue(key, VSOCK_OP_RST, 0, Vec::new());
+        }
+    }
+    fn close(&mut self, key: ConnKey, rst: bool) {
+        if let Some(conn) = self.conns.remove(&key) {
+            let _ = conn.stream.shutdown(Shutdown::Both);
+            self.closing.push(conn.stream);
+        }
+        if rst {
+            self.queue(key, VSOCK_OP_RST, 0, Vec::new());
+        }
+    }
+    fn alloc_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_port;
+            self.next_port = match port.checked_add(1) {
+                Some(p) => p,
+                None => EPHEMERAL_PORT_BASE,
+            };
+            if !self.conns.keys().any(|k| k.host_port == port) {
+                return port;
+            }
+        }
+    }
+    fn reset(&mut self) {
+        let keys: Vec<ConnKey> = self.conns.keys().copied().collect();
+        for key in keys {
+            self.close(key, false);
+        }
+        self.pending.clear();
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum VsockReg {
+    GuestCid,
+}
+
+/// Source of readiness reported by the worker's poll
+#[derive(Copy, Clone)]
+enum Target {
+    Wake,
+    Listener(usize),
+    Conn(ConnKey),
+}
+
+/// virtio-vsock device, proxying guest stream sockets to Unix sockets on the
+/// host.
+///
+/// Guest packets are processed in the context of the notifying vCPU, with
+/// writes to the host sockets made without blocking.  A worker spawned on the
+/// dispatcher polls the host sockets, accepting connections to the listeners
+/// and forwarding data (within the credit offered by the guest) to it.
+pub struct VirtioVsock {
+    cfg: VsockConfig,
+    cfg_map: RegMap<VsockReg>,
+    listeners: Vec<(u32, UnixListener)>,
+    wake_tx: UnixStream,
+    wake_rx: UnixStream,
+    inner: Mutex<Inner>,
+}
+impl VirtioVsock {
+    pub fn create(
+        cfg: VsockConfig,
+        queue_size: u16,
+        disp: &Dispatcher,
+    ) -> Result<Arc<pci::DeviceInst>> {
+        let this = Arc::new(Self::new(cfg)?);
+
+        let qthis = Arc::clone(&this);
+        disp.on_quiesce(move || qthis.wake());
+        disp.spawn("virtio-vsock".to_string(), Arc::clone(&this), Self::run)?;
+
+        // One MSI-X entry for config changes, and one for each queue
+        let msix_count = Some(1 + NUM_QUEUES);
+
+        Ok(PciVirtio::create(
+            queue_size,
+            NUM_QUEUES,
+            msix_count,
+            VIRTIO_DEV_SOCK,
+            pci::bits::CLASS_UNCLASSIFIED,
+            VSOCK_CFG_SIZE,
+            this,
+        ))
+    }
+    fn new(cfg: VsockConfig) -> Result<Self> {
+        if cfg.guest_cid <= VSOCK_HOST_CID || cfg.guest_cid >= u32::MAX as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid guest CID {}", cfg.guest_cid),
+            ));
+        }
+        let mut listeners = Vec::new();
+        for (port, path) in cfg.guest_ports.iter() {
+            let sock = match UnixListener::bind(path) {
+                Ok(sock) => sock,
+                Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                    std::fs::remove_file(path)?;
+                    UnixListener::bind(path)?
+                }
+                Err(e) => return Err(e),
+            };
+            sock.set_nonblocking(true)?;
+            listeners.push((*port, sock));
+        }
+        let (wake_tx, wake_rx) = UnixStream::pair()?;
+        wake_tx.set_nonblocking(true)?;
+        wake_rx.set_nonblocking(true)?;
+
+        Ok(Self {
+            cfg,
+            cfg_map: RegMap::create_packed(
+                VSOCK_CFG_SIZE,
+                &[(VsockReg::GuestCid, 8)],
+                None,
+            ),
+            listeners,
+            wake_tx,
+            wake_rx,
+            inner: Mutex::new(Inner {
+                rxq: None,
+                txq: None,
+                conns: BTreeMap::new(),
+                closing: Vec::new(),
+                pending: VecDeque::new(),
+                next_port: EPHEMERAL_PORT_BASE,
+            }),
+        })
+    }
+
+    fn wake(&self) {
+        // A full socket buffer means the worker has a wake-up pending already
+        let _ = (&self.wake_tx).write(&[0u8]);
+    }
+
+    fn run(ctx: DispCtx, this: Arc<Self>) {
+        let mut fds: Vec<libc::pollfd> = Vec::new();
+        let mut targets: Vec<Target> = Vec::new();
+        loop {
+            fds.clear();
+            targets.clear();
+            let mut add = |fd: RawFd, events, target| {
+                fds.push(libc::pollfd { fd, events, revents: 0 });
+                targets.push(target);
+            };
+            add(this.wake_rx.as_raw_fd(), libc::POLLIN, Target::Wake);
+            {
+                let mut inner = this.inner.lock().unwrap();
+                // Only now can the sockets of closed connections be dropped
+                inner.closing.clear();
+                if !inner.is_backlogged() {
+                    for (idx, (_port, sock)) in
+                        this.listeners.iter().enumerate()
+                    {
+                        add(
+                            sock.as_raw_fd(),
+                            libc::POLLIN,
+                            Target::Listener(idx),
+                        );
+                    }
+                }
+                for (key, conn) in inner.conns.iter() {
+                    let events = conn.interest();
+                    if events != 0 {
+                        add(
+                            conn.stream.as_raw_fd(),
+                            events,
+                            Target::Conn(*key),
+                        );
+                    }
+                }
+            }
+
+            let res = unsafe {
+                libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1)
+            };
+            if ctx.should_exit() {
+                return;
+            }
+            if res < 0 {
+                continue;
+            }
+
+            let _active = ctx.device_activity();
+            let mut inner = this.inner.lock().unwrap();
+            for (pfd, target) in fds.iter().zip(targets.iter()) {
+                if pfd.revents == 0 {
+                    continue;
+                }
+                match target {
+                    Target::Wake => {
+                        let mut buf = [0u8; 64];
+                        while let Ok(n) = (&this.wake_rx).read(&mut buf) {
+                            if n == 0 {
+                                break;
+                            }
+                        }
+                    }
+                    Target::Listener(idx) => {
+                        this.accept(&mut inner, *idx);
+                    }
+                    Target::Conn(key) => {
+                        this.conn_ready(&mut inner, *key, pfd);
+                    }
+                }
+            }
+            this.flush_rx(&mut inner, &ctx);
+        }
+    }
+
+    /// Accept a host connection to a listener, asking the guest to connect
+    fn accept(&self, inner: &mut Inner, idx: usize) {
+        let (guest_port, listener) = &self.listeners[idx];
+        // Connections beyond what the guest is keeping up with are left in
+        // the listen backlog.
+        while !inner.is_backlogged() {
+            let stream = match listener.accept() {
+                Ok((stream, _addr)) => stream,
+                Err(_) => break,
+            };
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let key = ConnKey {
+                host_port: inner.alloc_port(),
+                guest_port: *guest_port,
+            };
+            inner.conns.insert(key, Conn::new(stream, ConnState::Connecting));
+            inner.queue(key, VSOCK_OP_REQUEST, 0, Vec::new());
+        }
+    }
+
+    /// Service a host socket which the worker found to be ready
+    fn conn_ready(&self, inner: &mut Inner, key: ConnKey, pfd: &libc::pollfd) {
+        match inner.conns.get(&key) {
+            // The connection may have been closed (and its key reused) since
+            // the socket was polled.
+            Some(conn) if conn.stream.as_raw_fd() == pfd.fd => {}
+            _ => return,
+        }
+        let hup = libc::POLLHUP | libc::POLLERR;
+        let conn = inner.conns.get_mut(&key).unwrap();
+        if conn.state == ConnState::HostConnecting {
+            if pfd.revents & (libc::POLLOUT | hup) == 0 {
+                return;
+            }
+            match conn.stream.take_error() {
+                Ok(None) if pfd.revents & hup == 0 => {
+                    conn.state = ConnState::Connected;
+                    inner.queue(key, VSOCK_OP_RESPONSE, 0, Vec::new());
+                }
+                _ => inner.close(key, true),
+            }
+            return;
+        }
+        if pfd.revents & (libc::POLLOUT | hup) != 0
+            && pfd.events & libc::POLLOUT != 0
+        {
+            let conn = inner.conns.get_mut(&key).unwrap();
+            if conn.drain_tx().is_err() {
+                inner.close(key, true);
+                return;
+            }
+            if conn.needs_credit_update() {
+                conn.fwd_reported = conn.fwd_cnt;
+                inner.queue(key, VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+            }
+        }
+        if pfd.revents & (libc::POLLIN | hup) != 0
+            && pfd.events & libc::POLLIN != 0
+        {
+            let conn = inner.conns.get_mut(&key).unwrap();
+            let len = usize::min(conn.peer_credit() as usize, MAX_PKT_LEN);
+            let mut buf = vec![0u8; len];
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    conn.host_eof = true;
+                    inner.queue(
+                        key,
+                        VSOCK_OP_SHUTDOWN,
+                        VSOCK_SHUTDOWN_SEND,
+                        Vec::new(),
+                    );
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    conn.rx_cnt = conn.rx_cnt.wrapping_add(n as u32);
+                    inner.queue(key, VSOCK_OP_RW, 0, buf);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(_) => inner.close(key, true),
+            }
+        }
+    }
+
+    /// Process the packets sent by the guest on the TX queue
+    fn process_tx(&self, inner: &mut Inner, ctx: &DispCtx) {
+        let txq = match inner.txq.as_ref() {
+            Some(vq) => Arc::clone(vq),
+            None => return,
+        };
+        let mem = &ctx.mctx.memctx();
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if txq.pop_avail(&mut chain, mem).is_none() {
+                break;
+            }
+            let mut hdr = VsockHdr::default();
+            let valid = chain.read(&mut hdr, mem);
+            let len = usize::min(hdr.len as usize, chain.remain_read_bytes());
+            let mut data = vec![0u8; len];
+            let mut done = 0;
+            while let Some(region) = chain.readable_buf(len - done) {
+                match mem.read_into(region.0, &mut data[done..], region.1) {
+                    Some(n) => done += n,
+                    None => break,
+                }
+            }
+            data.truncate(done);
+            txq.push_used(&mut chain, mem, ctx);
+            if valid {
+                self.handle_pkt(inner, &hdr, data);
+            }
+        }
+    }
+
+    fn handle_pkt(&self, inner: &mut Inner, hdr: &VsockHdr, data: Vec<u8>) {
+        let (op, flags) = (hdr.op, hdr.flags);
+        let key = ConnKey { host_port: hdr.dst_port, guest_port: hdr.src_port };
+        if hdr.src_cid != self.cfg.guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.typ != VSOCK_TYPE_STREAM
+        {
+            if op != VSOCK_OP_RST {
+                inner.queue_rst(key);
+            }
+            return;
+        }
+
+        if op == VSOCK_OP_REQUEST {
+            if inner.conns.contains_key(&key) {
+                inner.close(key, true);
+                return;
+            }
+            if inner.is_backlogged() {
+                // Refused without a word, leaving the guest to time out
+                return;
+            }
+            let res = match self.cfg.host_ports.get(&key.host_port) {
+                Some(path) => connect_nonblocking(path),
+                None => Err(Error::from(ErrorKind::ConnectionRefused)),
+            };
+            match res {
+                Ok((stream, in_progress)) => {
+                    let state = match in_progress {
+                        true => ConnState::HostConnecting,
+                        false => ConnState::Connected,
+                    };
+                    let mut conn = Conn::new(stream, state);
+                    conn.peer_buf_alloc = hdr.buf_alloc;
+                    conn.peer_fwd_cnt = hdr.fwd_cnt;
+                    inner.conns.insert(key, conn);
+                    if !in_progress {
+                        inner.queue(key, VSOCK_OP_RESPONSE, 0, Vec::new());
+                    }
+                }
+                Err(_) => inner.queue_rst(key),
+            }
+            return;
+        }
+
+        let conn = match inner.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                if op != VSOCK_OP_RST {
+                    inner.queue_rst(key);
+                }
+                return;
+            }
+        };
+        conn.peer_buf_alloc = hdr.buf_alloc;
+        conn.peer_fwd_cnt = hdr.fwd_cnt;
+
+        match op {
+            VSOCK_OP_RESPONSE if conn.state == ConnState::Connecting => {
+                conn.state = ConnState::Connected;
+            }
+            VSOCK_OP_RW if conn.state == ConnState::Connected => {
+                if conn.peer_shut_send
+                    || conn.tx_buf.len() + data.len() > BUF_ALLOC as usize
+                {
+                    // Guest has overrun the credit it was offered
+                    inner.close(key, true);
+                    return;
+                }
+                conn.tx_buf.extend_from_slice(&data);
+                if conn.drain_tx().is_err() {
+                    inner.close(key, true);
+                    return;
+                }
+                if conn.needs_credit_update() {
+                    conn.fwd_reported = conn.fwd_cnt;
+                    inner.queue(key, VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+                }
+            }
+            VSOCK_OP_SHUTDOWN => {
+                if flags & VSOCK_SHUTDOWN_SEND != 0 {
+                    conn.peer_shut_send = true;
+                    if conn.drain_tx().is_err() {
+                        inner.close(key, true);
+                        return;
+                    }
+                }
+                if flags & VSOCK_SHUTDOWN_RCV != 0 {
+                    conn.peer_shut_rcv = true;
+                    let _ = conn.stream.shutdown(Shutdown::Read);
+                }
+                if conn.peer_shut_send && conn.peer_shut_rcv {
+                    // Guest awaits a reset to complete its close
+                    inner.close(key, true);
+                }
+            }
+            VSOCK_OP_RST => {
+                inner.close(key, false);
+            }
+            VSOCK_OP_CREDIT_UPDATE => {}
+            VSOCK_OP_CREDIT_REQUEST => {
+                conn.fwd_reported = conn.fwd_cnt;
+                inner.queue(key, VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+            }
+            _ => inner.close(key, true),
+        }
+    }
+
+    /// Header for (the first `len` bytes of) a pending packet
+    fn pkt_hdr(&self, pkt: &Pkt, len: usize, fwd_cnt: u32) -> VsockHdr {
+        VsockHdr {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.cfg.guest_cid,
+            src_port: pkt.key.host_port,
+            dst_port: pkt.key.guest_port,
+            len: len as u32,
+            typ: VSOCK_TYPE_STREAM,
+            op: pkt.op,
+            flags: pkt.flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt,
+        }
+    }
+
+    /// Deliver pending packets into the buffers available on the RX queue
+    fn flush_rx(&self, inner: &mut Inner, ctx: &DispCtx) {
+        let rxq = match inner.rxq.as_ref() {
+            Some(vq) => Arc::clone(vq),
+            None => return,
+        };
+        let mem = &ctx.mctx.memctx();
+        while let Some(pkt) = inner.pending.front_mut() {
+            let mut chain = Chain::with_capacity(4);
+            if rxq.pop_avail(&mut chain, mem).is_none() {
+                break;
+            }
+            let room = chain.remain_write_bytes().saturating_sub(HDR_SZ);
+            let len = usize::min(pkt.data.len(), room);
+            let fwd_cnt = inner.conns.get(&pkt.key).map_or(0, |c| c.fwd_cnt);
+            let hdr = self.pkt_hdr(pkt, len, fwd_cnt);
+            if chain.write(&hdr, mem) {
+                let mut done = 0;
+                while let Some(region) = chain.writable_buf(len - done) {
+                    match mem.write_from(region.0, &pkt.data[done..], region.1)
+                    {
+                        Some(n) => done += n,
+                        None => break,
+                    }
+                }
+            }
+            rxq.push_used(&mut chain, mem, ctx);
+
+            // A payload too large for the buffers is split across packets
+            if len < pkt.data.len() {
+                pkt.data.drain(..len);
+            } else {
+                inner.pending.pop_front();
+            }
+        }
+    }
+}
+/// Begin connecting to the Unix socket at `path`, without blocking should its
+/// listen backlog be full.  Returns the stream, and whether the connection is
+/// still in progress (for which the stream will poll as writable once done).
+fn connect_nonblocking(path: &Path) -> Result<(UnixStream, bool)> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let raw = path.as_os_str().as_bytes();
+    if raw.len() >= addr.sun_path.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "socket path too long",
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, src) in addr.sun_path.iter_mut().zip(raw.iter()) {
+        *dst = *src as libc::c_char;
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Safety: the descriptor was just created, and is owned by nothing else
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    let res = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        return Ok((stream, false));
+    }
+    let err = Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EINPROGRESS) => Ok((stream, true)),
+        _ => Err(err),
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_cfg_rw(&self, mut rwo: RWOp) {
+        self.cfg_map.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                VsockReg::GuestCid => ro.write_u64(self.cfg.guest_cid),
+            },
+            RWOp::Write(_) => {
+                //ignore writes
+            }
+        });
+    }
+    fn device_get_features(&self) -> u32 {
+        0
+    }
+    fn device_set_features(&self, _feat: u32) {}
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>, ctx: &DispCtx) {
+        let mut inner = self.inner.lock().unwrap();
+        if vq.id == TX_QUEUE {
+            self.process_tx(&mut inner, ctx);
+        }
+        let was_backlogged = inner.is_backlogged();
+        self.flush_rx(&mut inner, ctx);
+        let unblocked = was_backlogged && !inner.is_backlogged();
+        drop(inner);
+        if vq.id != RX_QUEUE || unblocked {
+            // Credit or connection state (or room for further packets to the
+            // guest) may have changed what the worker should be polling for.
+            self.wake();
+        }
+    }
+    fn device_reset(&self, _ctx: &DispCtx) {
+        self.inner.lock().unwrap().reset();
+        self.wake();
+    }
+    fn attach(&self, queues: &[Arc<VirtQueue>]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rxq = Some(Arc::clone(&queues[RX_QUEUE as usize]));
+        inner.txq = Some(Arc::clone(&queues[TX_QUEUE as usize]));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::util::tempfs::TempDir;
+
+    #[test]
+    fn conn_credit() {
+        let (host, _peer) = UnixStream::pair().unwrap();
+        let mut conn = Conn::new(host, ConnState::Connected);
+
+        // No credit until the guest advertises its buffer
+        assert_eq!(conn.peer_credit(), 0);
+        assert_eq!(conn.interest(), 0);
+
+        conn.peer_buf_alloc = 0x1000;
+        assert_eq!(conn.interest(), libc::POLLIN);
+        conn.rx_cnt = 0x1000;
+        assert_eq!(conn.peer_credit(), 0);
+        conn.peer_fwd_cnt = 0x800;
+        assert_eq!(conn.peer_credit(), 0x800);
+
+        // Counters are free-running and may wrap
+        conn.rx_cnt = 0x10;
+        conn.peer_fwd_cnt = u32::MAX - 0xf;
+        assert_eq!(conn.peer_credit(), 0x1000 - 0x20);
+
+        conn.host_eof = true;
+        assert_eq!(conn.interest(), 0);
+    }
+
+    #[test]
+    fn hdr_layout() {
+        assert_eq!(HDR_SZ, 44);
+    }
+
+    const GUEST_CID: u64 = 3;
+    const HOST_PORT: u32 = 1234;
+    const KEY: ConnKey = ConnKey { host_port: HOST_PORT, guest_port: 5000 };
+
+    /// Device proxying HOST_PORT to a listener in `dir`
+    fn device(dir: &TempDir) -> (VirtioVsock, UnixListener) {
+        let path = dir.path().join("host.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut cfg =
+            VsockConfig { guest_cid: GUEST_CID, ..Default::default() };
+        cfg.host_ports.insert(HOST_PORT, path);
+        (VirtioVsock::new(cfg).unwrap(), listener)
+    }
+
+    fn send(dev: &VirtioVsock, key: ConnKey, op: u16, flags: u32, data: &[u8]) {
+        let hdr = VsockHdr {
+            src_cid: GUEST_CID,
+            dst_cid: VSOCK_HOST_CID,
+            src_port: key.guest_port,
+            dst_port: key.host_port,
+            len: data.len() as u32,
+            typ: VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc: BUF_ALLOC,
+            fwd_cnt: 0,
+        };
+        let mut inner = dev.inner.lock().unwrap();
+        dev.handle_pkt(&mut inner, &hdr, data.to_vec());
+    }
+
+    /// Take the (op, flags) of the packets pending delivery to the guest
+    fn take_pending(dev: &VirtioVsock) -> Vec<(u16, u32)> {
+        let mut inner = dev.inner.lock().unwrap();
+        inner.pending.drain(..).map(|p| (p.op, p.flags)).collect()
+    }
+
+    fn connect(dev: &VirtioVsock, listener: &UnixListener) -> UnixStream {
+        send(dev, KEY, VSOCK_OP_REQUEST, 0, &[]);
+        assert_eq!(take_pending(dev), [(VSOCK_OP_RESPONSE, 0)]);
+        let state = dev.inner.lock().unwrap().conns.get(&KEY).unwrap().state;
+        assert_eq!(state, ConnState::Connected);
+        listener.accept().unwrap().0
+    }
+
+    #[test]
+    fn request_response() {
+        let dir = TempDir::new("vsock-request");
+        let (dev, listener) = device(&dir);
+        let _peer = connect(&dev, &listener);
+
+        // Nothing listens on other ports
+        let other = ConnKey { host_port: HOST_PORT + 1, ..KEY };
+        send(&dev, other, VSOCK_OP_REQUEST, 0, &[]);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RST, 0)]);
+        assert!(!dev.inner.lock().unwrap().conns.contains_key(&other));
+
+        // Nor is a second connection with the same ports accepted
+        send(&dev, KEY, VSOCK_OP_REQUEST, 0, &[]);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RST, 0)]);
+        assert!(dev.inner.lock().unwrap().conns.is_empty());
+    }
+
+    #[test]
+    fn rw_credit() {
+        let dir = TempDir::new("vsock-rw");
+        let (dev, listener) = device(&dir);
+        let mut peer = connect(&dev, &listener);
+
+        send(&dev, KEY, VSOCK_OP_RW, 0, b"hello");
+        let mut buf = [0u8; 5];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(take_pending(&dev).is_empty());
+
+        // Credit is reported once half of the buffer has been forwarded
+        let data = vec![0x5a; BUF_ALLOC as usize / 2];
+        send(&dev, KEY, VSOCK_OP_RW, 0, &data);
+        let mut buf = vec![0u8; data.len()];
+        peer.read_exact(&mut buf).unwrap();
+        {
+            let inner = dev.inner.lock().unwrap();
+            let pkt = inner.pending.front().unwrap();
+            assert_eq!(pkt.op, VSOCK_OP_CREDIT_UPDATE);
+            let fwd_cnt = inner.conns.get(&KEY).unwrap().fwd_cnt;
+            let hdr = dev.pkt_hdr(pkt, 0, fwd_cnt);
+            assert_eq!({ hdr.fwd_cnt }, 5 + data.len() as u32);
+            assert_eq!({ hdr.dst_cid }, GUEST_CID);
+            assert_eq!({ hdr.src_port }, HOST_PORT);
+        }
+
+        // Requests for credit are coalesced with any update pending
+        send(&dev, KEY, VSOCK_OP_CREDIT_REQUEST, 0, &[]);
+        send(&dev, KEY, VSOCK_OP_CREDIT_REQUEST, 0, &[]);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_CREDIT_UPDATE, 0)]);
+
+        // Overrunning the offered buffer resets the connection
+        let data = vec![0u8; BUF_ALLOC as usize + 1];
+        send(&dev, KEY, VSOCK_OP_RW, 0, &data);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RST, 0)]);
+        assert!(dev.inner.lock().unwrap().conns.is_empty());
+    }
+
+    #[test]
+    fn shutdown() {
+        let dir = TempDir::new("vsock-shutdown");
+        let (dev, listener) = device(&dir);
+        let mut peer = connect(&dev, &listener);
+
+        send(&dev, KEY, VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_SEND, &[]);
+        let mut buf = [0u8; 1];
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+        assert!(take_pending(&dev).is_empty());
+        assert!(dev.inner.lock().unwrap().conns.contains_key(&KEY));
+
+        // Once shut down in both directions, the guest awaits a reset
+        send(&dev, KEY, VSOCK_OP_SHUTDOWN, VSOCK_SHUTDOWN_RCV, &[]);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RST, 0)]);
+        assert!(dev.inner.lock().unwrap().conns.is_empty());
+    }
+
+    #[test]
+    fn rst() {
+        let dir = TempDir::new("vsock-rst");
+        let (dev, listener) = device(&dir);
+        let mut peer = connect(&dev, &listener);
+
+        send(&dev, KEY, VSOCK_OP_RST, 0, &[]);
+        assert!(take_pending(&dev).is_empty());
+        assert!(dev.inner.lock().unwrap().conns.is_empty());
+        let mut buf = [0u8; 1];
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+
+        // A reset is not answered, but other packets for unknown connections
+        // are answered with one.
+        send(&dev, KEY, VSOCK_OP_RST, 0, &[]);
+        assert!(take_pending(&dev).is_empty());
+        send(&dev, KEY, VSOCK_OP_RW, 0, b"data");
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RST, 0)]);
+    }
+
+    #[test]
+    fn pending_bounded() {
+        let dir = TempDir::new("vsock-backlog");
+        let (dev, _listener) = device(&dir);
+
+        for port in 0..(2 * MAX_PENDING as u32) {
+            let key = ConnKey { host_port: HOST_PORT, guest_port: port };
+            send(&dev, key, VSOCK_OP_RW, 0, b"data");
+        }
+        assert_eq!(dev.inner.lock().unwrap().pending.len(), MAX_PENDING);
+
+        // New connections are refused until the guest catches up
+        send(&dev, KEY, VSOCK_OP_REQUEST, 0, &[]);
+        assert!(dev.inner.lock().unwrap().conns.is_empty());
+        assert_eq!(take_pending(&dev).len(), MAX_PENDING);
+        send(&dev, KEY, VSOCK_OP_REQUEST, 0, &[]);
+        assert_eq!(take_pending(&dev), [(VSOCK_OP_RESPONSE, 0)]);
+    }
+}
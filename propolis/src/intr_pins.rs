@@ -111,6 +111,11 @@ impl LegacyPin {
     fn new(irq: u8, pic: Weak<LegacyPIC>) -> Self {
         Self { irq, asserted: Mutex::new(false), pic }
     }
+    /// Pin attached to no PIC, for devices under test
+    #[cfg(test)]
+    pub(crate) fn detached(irq: u8) -> Self {
+        Self::new(irq, Weak::new())
+    }
     pub fn set_state(&self, is_asserted: bool) {
         if is_asserted {
             self.assert();
@@ -27,8 +27,9 @@ use vcpu::VcpuHdl;
 pub fn vcpu_run_loop(dctx: DispCtx, mut vcpu: VcpuHdl) {
     let mctx = &dctx.mctx;
     let mut next_entry = VmEntry::Run;
-    loop {
+    while !dctx.should_exit() {
         let exit = vcpu.run(&next_entry).unwrap();
+        vcpu.exit_stats().record(ExitReason::from(&exit.kind));
         //println!("rip:{:x} exit: {:?}", exit.rip, exit.kind);
         match exit.kind {
             VmExitKind::Bogus => {
@@ -87,6 +88,23 @@ pub fn vcpu_run_loop(dctx: DispCtx, mut vcpu: VcpuHdl) {
                 println!("wrmsr({:x}, {:x})", msr, val);
                 next_entry = VmEntry::Run
             }
+            VmExitKind::Hlt => {
+                // The kernel parks the vCPU until it has an interrupt to
+                // deliver, so there is nothing further to do here.
+                next_entry = VmEntry::Run
+            }
+            VmExitKind::Suspended(reason) => {
+                // The instance is halted, so there is nothing more to run.
+                // Whoever is waiting on the vCPUs decides if it is reset.
+                dctx.report_suspend(reason);
+                return;
+            }
+            VmExitKind::Debug => {
+                // The instance is being paused, so wait out the pause before
+                // returning to the guest.
+                dctx.park();
+                next_entry = VmEntry::Run
+            }
             _ => panic!("unrecognized exit: {:?}", exit.kind),
         }
     }
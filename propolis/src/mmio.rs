@@ -2,22 +2,28 @@ use std::sync::{Arc, Mutex, Weak};
 
 use crate::common::*;
 use crate::dispatch::DispCtx;
+use crate::pio::PioDev;
 use crate::util::aspace::ASpace;
 pub use crate::util::aspace::{Error, Result};
+use crate::util::regmap::RegMap;
+use crate::util::trace::{AccessKind, AccessTrace};
 
 use byteorder::{ByteOrder, LE};
 
+type RegHandler<R> = dyn Fn(&R, RWOp, &DispCtx) + Send + Sync + 'static;
+
 pub trait MmioDev: Send + Sync {
     fn mmio_rw(&self, addr: usize, ident: usize, rwop: RWOp, ctx: &DispCtx);
 }
 
 pub struct MmioBus {
     map: Mutex<ASpace<(Weak<dyn MmioDev>, usize)>>,
+    trace: AccessTrace,
 }
 impl MmioBus {
     pub fn new(max: usize) -> Self {
         assert!(max != 0);
-        Self { map: Mutex::new(ASpace::new(0, max)) }
+        Self { map: Mutex::new(ASpace::new(0, max)), trace: AccessTrace::new() }
     }
 
     pub fn register(
@@ -33,9 +39,27 @@ impl MmioBus {
         &self,
         addr: usize,
     ) -> Result<(Weak<dyn MmioDev>, usize)> {
+        self.trace.set(addr, None);
         self.map.lock().unwrap().unregister(addr)
     }
 
+    /// Log every access to the region registered at `start`, under `name`, or
+    /// cease doing so if `name` is `None`.
+    pub fn set_trace(&self, start: usize, name: Option<&str>) -> Result<()> {
+        let map = self.map.lock().unwrap();
+        match map.region_at(start) {
+            Ok((rstart, _len, _ent)) if rstart == start => {
+                self.trace.set(rstart, name);
+                Ok(())
+            }
+            _ => Err(Error::NotFound),
+        }
+    }
+    /// Access trace state for the bus, such as for redirecting its output
+    pub fn trace(&self) -> &AccessTrace {
+        &self.trace
+    }
+
     pub fn handle_write(
         &self,
         addr: usize,
@@ -55,8 +79,13 @@ impl MmioBus {
             let mut wo = WriteOp::new_buf(o as usize, data);
             dev.mmio_rw(a, ident, RWOp::Write(&mut wo), ctx)
         });
-        if !handled {
-            println!("unhandled MMIO write - addr:{:x} len:{}", addr, bytes);
+        match handled {
+            Some(start) => {
+                self.trace.access(start, addr - start, AccessKind::Write, data)
+            }
+            None => {
+                println!("unhandled MMIO write - addr:{:x} len:{}", addr, bytes)
+            }
         }
     }
     pub fn handle_read(&self, addr: usize, bytes: u8, ctx: &DispCtx) -> u64 {
@@ -72,14 +101,21 @@ impl MmioBus {
             let mut ro = ReadOp::new_buf(o as usize, data);
             dev.mmio_rw(a, ident, RWOp::Read(&mut ro), ctx)
         });
-        if !handled {
-            println!("unhandled MMIO read - addr:{:x} len:{}", addr, bytes);
+        match handled {
+            Some(start) => {
+                self.trace.access(start, addr - start, AccessKind::Read, data)
+            }
+            None => {
+                println!("unhandled MMIO read - addr:{:x} len:{}", addr, bytes)
+            }
         }
 
         LE::read_u64(&buf)
     }
 
-    fn do_mmio<F>(&self, addr: usize, f: F) -> bool
+    /// Dispatch an access at `addr` to the device registered there, returning
+    /// the start of its region, if any.
+    fn do_mmio<F>(&self, addr: usize, f: F) -> Option<usize>
     where
         F: FnOnce(usize, usize, &Arc<dyn MmioDev>, usize),
     {
@@ -90,9 +126,117 @@ impl MmioBus {
             // unlock map before entering handler
             drop(map);
             f(start, addr - start, &dev, identv);
-            true
+            Some(start)
         } else {
-            false
+            None
+        }
+    }
+}
+
+/// Device whose accesses are decoded through a [`RegMap`] before being passed
+/// to a handler for the individual register.
+///
+/// Accesses which fall outside the bounds of the map are rejected: reads
+/// return all-ones and writes are discarded.  While intended for MMIO, the
+/// decoding is not specific to that bus, so it can be registered on the port
+/// IO bus as well.
+pub struct MmioDevice<R: Send + Sync + 'static> {
+    regs: &'static RegMap<R>,
+    handler: Box<RegHandler<R>>,
+}
+impl<R: Send + Sync + 'static> MmioDevice<R> {
+    pub fn new<F>(regs: &'static RegMap<R>, handler: F) -> Arc<Self>
+    where
+        F: Fn(&R, RWOp, &DispCtx) + Send + Sync + 'static,
+    {
+        Arc::new(Self { regs, handler: Box::new(handler) })
+    }
+
+    fn access_valid(&self, rwo: &RWOp) -> bool {
+        self.regs.covers(rwo.offset(), rwo.len())
+    }
+
+    fn access(&self, mut rwo: RWOp, ctx: &DispCtx) {
+        if !self.access_valid(&rwo) {
+            match rwo {
+                RWOp::Read(ro) => {
+                    println!(
+                        "rejected out-of-bounds read - off:{:x} len:{}",
+                        ro.offset(),
+                        ro.len()
+                    );
+                    ro.fill(0xff);
+                }
+                RWOp::Write(wo) => {
+                    println!(
+                        "rejected out-of-bounds write - off:{:x} len:{}",
+                        wo.offset(),
+                        wo.len()
+                    );
+                }
+            }
+            return;
         }
+        self.regs.process(&mut rwo, |id, rwo| (self.handler)(id, rwo, ctx));
+    }
+}
+impl<R: Send + Sync + 'static> MmioDev for MmioDevice<R> {
+    fn mmio_rw(&self, _addr: usize, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        self.access(rwo, ctx)
+    }
+}
+impl<R: Send + Sync + 'static> PioDev for MmioDevice<R> {
+    fn pio_rw(&self, _port: u16, _ident: usize, rwo: RWOp, ctx: &DispCtx) {
+        self.access(rwo, ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use lazy_static::lazy_static;
+
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    enum Reg {
+        A,
+        B,
+    }
+    lazy_static! {
+        static ref REGS: RegMap<Reg> =
+            RegMap::create_packed(8, &[(Reg::A, 4), (Reg::B, 4)], None);
+    }
+
+    #[test]
+    fn bounds() {
+        let dev = MmioDevice::new(&REGS, |_id, _rwo, _ctx| {});
+
+        let mut buf = [0u8; 8];
+        let valid = [(0, 1), (0, 4), (4, 4), (0, 8), (7, 1)];
+        for (off, len) in valid.iter() {
+            let mut ro = ReadOp::new_buf(*off, &mut buf[..*len]);
+            assert!(dev.access_valid(&RWOp::Read(&mut ro)));
+        }
+        let invalid = [(8, 1), (4, 8), (7, 2), (usize::MAX, 1)];
+        for (off, len) in invalid.iter() {
+            let mut ro = ReadOp::new_buf(*off, &mut buf[..*len]);
+            assert!(!dev.access_valid(&RWOp::Read(&mut ro)));
+            let mut wo = WriteOp::new_buf(*off, &buf[..*len]);
+            assert!(!dev.access_valid(&RWOp::Write(&mut wo)));
+        }
+    }
+
+    #[test]
+    fn trace_regions() {
+        let dev = MmioDevice::new(&REGS, |_id, _rwo, _ctx| {});
+        let bus = MmioBus::new(0xffff);
+        bus.register(0x1000, 8, Arc::downgrade(&dev) as Weak<dyn MmioDev>, 0)
+            .unwrap();
+
+        assert!(bus.set_trace(0x1000, Some("stub")).is_ok());
+        assert!(bus.set_trace(0x1000, None).is_ok());
+        // Only the start of a registered region is accepted
+        assert_eq!(bus.set_trace(0x1004, Some("stub")), Err(Error::NotFound));
+        assert_eq!(bus.set_trace(0x2000, Some("stub")), Err(Error::NotFound));
     }
 }
@@ -4,6 +4,7 @@ use crate::common::*;
 use crate::dispatch::DispCtx;
 use crate::util::aspace::ASpace;
 pub use crate::util::aspace::{Error, Result};
+use crate::util::trace::{AccessKind, AccessTrace};
 
 use byteorder::{ByteOrder, LE};
 
@@ -13,11 +14,15 @@ pub trait PioDev: Send + Sync {
 
 pub struct PioBus {
     map: Mutex<ASpace<(Weak<dyn PioDev>, usize)>>,
+    trace: AccessTrace,
 }
 
 impl PioBus {
     pub fn new() -> Self {
-        Self { map: Mutex::new(ASpace::new(0, u16::MAX as usize)) }
+        Self {
+            map: Mutex::new(ASpace::new(0, u16::MAX as usize)),
+            trace: AccessTrace::new(),
+        }
     }
 
     pub fn register(
@@ -34,9 +39,27 @@ impl PioBus {
         )
     }
     pub fn unregister(&self, start: u16) -> Result<(Weak<dyn PioDev>, usize)> {
+        self.trace.set(start as usize, None);
         self.map.lock().unwrap().unregister(start as usize)
     }
 
+    /// Log every access to the region registered at `start`, under `name`, or
+    /// cease doing so if `name` is `None`.
+    pub fn set_trace(&self, start: u16, name: Option<&str>) -> Result<()> {
+        let map = self.map.lock().unwrap();
+        match map.region_at(start as usize) {
+            Ok((rstart, _len, _ent)) if rstart == start as usize => {
+                self.trace.set(rstart, name);
+                Ok(())
+            }
+            _ => Err(Error::NotFound),
+        }
+    }
+    /// Access trace state for the bus, such as for redirecting its output
+    pub fn trace(&self) -> &AccessTrace {
+        &self.trace
+    }
+
     pub fn handle_out(&self, port: u16, bytes: u8, val: u32, ctx: &DispCtx) {
         let buf = val.to_le_bytes();
         let data = match bytes {
@@ -49,8 +72,16 @@ impl PioBus {
             let mut wo = WriteOp::new_buf(o as usize, data);
             dev.pio_rw(p, ident, RWOp::Write(&mut wo), ctx)
         });
-        if !handled {
-            println!("unhandled IO out - port:{:x} len:{}", port, bytes);
+        match handled {
+            Some(start) => self.trace.access(
+                start as usize,
+                (port - start) as usize,
+                AccessKind::Write,
+                data,
+            ),
+            None => {
+                println!("unhandled IO out - port:{:x} len:{}", port, bytes)
+            }
         }
     }
 
@@ -66,14 +97,24 @@ impl PioBus {
             let mut ro = ReadOp::new_buf(o as usize, data);
             dev.pio_rw(p, ident, RWOp::Read(&mut ro), ctx)
         });
-        if !handled {
-            println!("unhandled IO in - port:{:x} len:{}", port, bytes);
+        match handled {
+            Some(start) => self.trace.access(
+                start as usize,
+                (port - start) as usize,
+                AccessKind::Read,
+                data,
+            ),
+            None => {
+                println!("unhandled IO in - port:{:x} len:{}", port, bytes)
+            }
         }
 
         LE::read_u32(&buf)
     }
 
-    fn do_pio<F>(&self, port: u16, f: F) -> bool
+    /// Dispatch an access at `port` to the device registered there, returning
+    /// the start of its region, if any.
+    fn do_pio<F>(&self, port: u16, f: F) -> Option<u16>
     where
         F: FnOnce(u16, u16, &Arc<dyn PioDev>, usize),
     {
@@ -84,9 +125,9 @@ impl PioBus {
             // unlock map before entering handler
             drop(map);
             f(start as u16, port - start as u16, &dev, identv);
-            true
+            Some(start as u16)
         } else {
-            false
+            None
         }
     }
 }
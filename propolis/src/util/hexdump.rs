@@ -0,0 +1,58 @@
+use std::fmt::Write;
+
+const BYTES_PER_LINE: usize = 16;
+const HEX_WIDTH: usize = 40;
+
+/// Format `data` as an `xxd`-style hexdump, with offsets starting at `base`.
+///
+/// Each line holds 16 bytes in 2-byte groups, followed by their printable
+/// ASCII representation (with `.` standing in for anything non-printable).
+pub fn hexdump(base: u64, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (n, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let addr = base + (n * BYTES_PER_LINE) as u64;
+        let mut hex = String::with_capacity(HEX_WIDTH);
+        for pair in chunk.chunks(2) {
+            for b in pair {
+                write!(hex, "{:02x}", b).unwrap();
+            }
+            hex.push(' ');
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|b| {
+                if b.is_ascii_graphic() || *b == b' ' {
+                    *b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(
+            out,
+            "{:08x}: {:width$} {}",
+            addr,
+            hex,
+            ascii,
+            width = HEX_WIDTH
+        )
+        .unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format() {
+        let data = b"Hello, propolis!\x00\x01\xffbhyve";
+        let expect = "\
+00001000: 4865 6c6c 6f2c 2070 726f 706f 6c69 7321  Hello, propolis!
+00001010: 0001 ff62 6879 7665                      ...bhyve
+";
+        assert_eq!(hexdump(0x1000, data), expect);
+        assert_eq!(hexdump(0, &[]), "");
+    }
+}
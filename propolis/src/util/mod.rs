@@ -1,4 +1,8 @@
 pub mod aspace;
+pub mod hexdump;
 pub mod regmap;
 pub mod self_arc;
 pub mod sys;
+pub mod tempfs;
+pub mod testimg;
+pub mod trace;
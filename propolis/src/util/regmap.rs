@@ -53,6 +53,22 @@ impl<ID> RegMap<ID> {
         self.space.register(start, len, RegDef { id, flags }).unwrap();
     }
 
+    /// Total length of the registers defined in the map.
+    ///
+    /// For a fully-populated map, this is equal to the size it was created with.
+    pub fn total_len(&self) -> usize {
+        self.space.iter().map(|(_start, len, _reg)| len).sum()
+    }
+
+    /// Does an access of `len` bytes at `offset` fall entirely within the map?
+    pub fn covers(&self, offset: usize, len: usize) -> bool {
+        len != 0
+            && offset
+                .checked_add(len)
+                .map(|end| end <= self.len)
+                .unwrap_or(false)
+    }
+
     pub fn process<F>(&self, op: &mut RWOp<'_, '_>, mut f: F)
     where
         F: FnMut(&ID, RWOp),
@@ -363,4 +379,15 @@ mod test {
         let res = drive_reads(&reads, &map);
         assert_eq!(res, expected);
     }
+    #[test]
+    fn total_len() {
+        let map = RegMap::create_packed(0x10, &[('a', 8), ('b', 8)], None);
+        assert_eq!(map.total_len(), 0x10);
+
+        // Sparsely populated
+        let mut map = RegMap::new(0x10);
+        map.define(0, 4, 'a');
+        map.define(8, 2, 'b');
+        assert_eq!(map.total_len(), 6);
+    }
 }
@@ -0,0 +1,54 @@
+//! Temporary files and directories, removed when dropped, for use by tests.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn temp_path(name: &str) -> PathBuf {
+    let mut path = std::env::temp_dir();
+    path.push(format!("propolis-{}-{}", name, std::process::id()));
+    path
+}
+
+/// File in the temporary directory, with its name derived from `name` and the
+/// ID of the running process.
+pub struct TempFile(PathBuf);
+impl TempFile {
+    /// Create the file, holding `contents`.
+    pub fn new(name: &str, contents: &[u8]) -> Self {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        Self(path)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    /// Read back the current contents of the file.
+    pub fn contents(&self) -> Vec<u8> {
+        fs::read(&self.0).unwrap()
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Empty directory in the temporary directory, named as for [`TempFile`].
+/// It is removed, along with anything created within it, when dropped.
+pub struct TempDir(PathBuf);
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = temp_path(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        Self(path)
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
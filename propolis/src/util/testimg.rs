@@ -0,0 +1,138 @@
+//! Tiny real-mode programs, placed at the reset vector of a ROM, for running a
+//! vCPU in tests without any real firmware.
+
+use std::io::Result;
+
+use crate::vcpu::VcpuHdl;
+use crate::vmm::Machine;
+
+use bhyve_api::vm_reg_name;
+
+/// Space between the reset vector and the top of the 4GiB address space
+const RESET_VECTOR_LEN: usize = 0x10;
+
+const OP_HLT: u8 = 0xf4;
+
+/// Guest program image to be executed from the reset vector
+pub struct ResetImage {
+    code: Vec<u8>,
+}
+impl ResetImage {
+    /// Halt, looping back to the HLT if ever woken
+    pub fn hlt_loop() -> Self {
+        Self { code: vec![OP_HLT, 0xeb, 0xfd] }
+    }
+
+    /// Write `val` to the QEMU debug port (0x402), then halt in a loop
+    pub fn debug_out(val: u8) -> Self {
+        let code = vec![
+            0xba, 0x02, 0x04, // mov $0x402, %dx
+            0xb0, val,  // mov $val, %al
+            0xee, // out %al, (%dx)
+            OP_HLT, 0xeb, 0xfd, // hlt; jmp .-3
+        ];
+        Self { code }
+    }
+
+    /// Write the program into `rom`, a buffer for the ROM mapped up to the 4GiB
+    /// boundary.  The rest of the ROM is filled with HLT instructions.
+    pub fn write_rom(&self, rom: &mut [u8]) {
+        assert!(rom.len() >= RESET_VECTOR_LEN);
+        assert!(self.code.len() <= RESET_VECTOR_LEN);
+
+        for b in rom.iter_mut() {
+            *b = OP_HLT;
+        }
+        let start = rom.len() - RESET_VECTOR_LEN;
+        rom[start..(start + self.code.len())].copy_from_slice(&self.code);
+    }
+
+    /// Populate the ROM region named `name` in `machine` with the program.
+    pub fn populate(&self, machine: &Machine, name: &str) -> Result<()> {
+        machine.populate_rom(name, |ptr, len| {
+            let buf =
+                unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), len) };
+            self.write_rom(buf);
+            Ok(())
+        })
+    }
+}
+
+/// Ready `vcpu` to begin execution at the reset vector.
+pub fn setup_vcpu(vcpu: &mut VcpuHdl) -> Result<()> {
+    vcpu.set_default_capabs()?;
+    vcpu.reboot_state()?;
+    vcpu.activate()?;
+    vcpu.set_run_state(bhyve_api::VRS_RUN)?;
+    vcpu.set_reg(vm_reg_name::VM_REG_GUEST_RIP, 0xfff0)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rom_layout() {
+        let mut rom = vec![0u8; 0x1000];
+        ResetImage::debug_out(0x5a).write_rom(&mut rom);
+
+        let vector = &rom[0x1000 - RESET_VECTOR_LEN..];
+        assert_eq!(
+            &vector[..9],
+            &[0xba, 0x02, 0x04, 0xb0, 0x5a, 0xee, 0xf4, 0xeb, 0xfd]
+        );
+        assert!(vector[9..].iter().all(|b| *b == OP_HLT));
+        assert!(rom[..0x1000 - RESET_VECTOR_LEN].iter().all(|b| *b == OP_HLT));
+    }
+
+    /// Run a single vCPU through the debug port write to its HLT.
+    ///
+    /// This requires access to the vmm driver, so must be run explicitly.
+    #[cfg(target_os = "illumos")]
+    #[test]
+    #[ignore]
+    fn run_to_hlt() {
+        use crate::exits::*;
+        use crate::vmm::{Builder, Prot};
+
+        let name = format!("propolis-testimg-{}", std::process::id());
+        let rom_len = 0x1000;
+        let vm = Builder::new(&name, true)
+            .unwrap()
+            .max_cpus(1)
+            .unwrap()
+            .add_mem_region(0, 0x20_0000, Prot::ALL, "lowmem")
+            .unwrap()
+            .add_rom_region(
+                0x1_0000_0000 - rom_len,
+                rom_len,
+                Prot::READ | Prot::EXEC,
+                "bootrom",
+            )
+            .unwrap()
+            .finalize()
+            .unwrap();
+        ResetImage::debug_out(0x5a).populate(&vm, "bootrom").unwrap();
+
+        let mut vcpu = vm.vcpu(0);
+        setup_vcpu(&mut vcpu).unwrap();
+
+        let mut entry = VmEntry::Run;
+        let mut written = None;
+        loop {
+            let exit = vcpu.run(&entry).unwrap();
+            match exit.kind {
+                VmExitKind::Bogus => entry = VmEntry::Run,
+                VmExitKind::Inout(InoutReq::Out(io, val)) => {
+                    assert_eq!(io.port, 0x402);
+                    written = Some(val as u8);
+                    entry = VmEntry::InoutFulfill(InoutRes::Out(io));
+                }
+                VmExitKind::Hlt => break,
+                kind => panic!("unexpected exit: {:?}", kind),
+            }
+        }
+        assert_eq!(written, Some(0x5a));
+    }
+}
@@ -0,0 +1,144 @@
+//! Opt-in tracing of guest accesses to regions of the PIO and MMIO buses.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// A single guest access to a traced region
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessEvent {
+    pub region: String,
+    /// Offset of the access from the start of the region
+    pub offset: usize,
+    pub size: usize,
+    pub value: u64,
+    pub kind: AccessKind,
+}
+
+type Sink = dyn Fn(&AccessEvent) + Send + Sync + 'static;
+
+fn print_event(ev: &AccessEvent) {
+    println!(
+        "trace {} {:?} - off:{:x} len:{} val:{:x}",
+        ev.region, ev.kind, ev.offset, ev.size, ev.value
+    );
+}
+
+/// Set of traced regions (keyed by their start address) for a bus, and where
+/// events for accesses to them are emitted.
+pub struct AccessTrace {
+    regions: Mutex<BTreeMap<usize, String>>,
+    /// Whether any region is traced, so untraced buses can skip the lock
+    enabled: AtomicBool,
+    sink: Mutex<Box<Sink>>,
+}
+impl AccessTrace {
+    pub fn new() -> Self {
+        Self {
+            regions: Mutex::new(BTreeMap::new()),
+            enabled: AtomicBool::new(false),
+            sink: Mutex::new(Box::new(print_event)),
+        }
+    }
+
+    /// Enable tracing, under `name`, of the region starting at `start`, or
+    /// disable it if `name` is `None`.
+    pub fn set(&self, start: usize, name: Option<&str>) {
+        let mut regions = self.regions.lock().unwrap();
+        match name {
+            Some(name) => {
+                regions.insert(start, name.to_string());
+            }
+            None => {
+                regions.remove(&start);
+            }
+        }
+        self.enabled.store(!regions.is_empty(), Ordering::Release);
+    }
+
+    /// Direct events somewhere other than stdout
+    pub fn set_sink<F>(&self, sink: F)
+    where
+        F: Fn(&AccessEvent) + Send + Sync + 'static,
+    {
+        *self.sink.lock().unwrap() = Box::new(sink);
+    }
+
+    /// Note an access of `data` at `offset` within the region starting at
+    /// `start`, emitting an event if that region is traced.
+    pub fn access(
+        &self,
+        start: usize,
+        offset: usize,
+        kind: AccessKind,
+        data: &[u8],
+    ) {
+        if !self.enabled.load(Ordering::Acquire) {
+            return;
+        }
+        let regions = self.regions.lock().unwrap();
+        if let Some(name) = regions.get(&start) {
+            let mut buf = [0u8; 8];
+            let size = usize::min(data.len(), buf.len());
+            buf[..size].copy_from_slice(&data[..size]);
+            let ev = AccessEvent {
+                region: name.clone(),
+                offset,
+                size,
+                value: u64::from_le_bytes(buf),
+                kind,
+            };
+            drop(regions);
+            (self.sink.lock().unwrap())(&ev);
+        }
+    }
+}
+impl Default for AccessTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn capture() {
+        let trace = AccessTrace::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let captured = Arc::clone(&events);
+        trace.set_sink(move |ev| captured.lock().unwrap().push(ev.clone()));
+
+        // Nothing traced yet
+        trace.access(0x60, 0, AccessKind::Read, &[0xfa]);
+        assert!(events.lock().unwrap().is_empty());
+
+        trace.set(0x3f8, Some("com1"));
+        trace.access(0x3f8, 5, AccessKind::Read, &[0x60]);
+        trace.access(0x3f8, 0, AccessKind::Write, &[0x41]);
+        trace.access(0x60, 0, AccessKind::Write, &[0xff]);
+        trace.set(0x3f8, None);
+        trace.access(0x3f8, 0, AccessKind::Write, &[0x42]);
+        assert!(!trace.enabled.load(Ordering::Acquire));
+
+        let ev = |offset, kind, value| AccessEvent {
+            region: "com1".to_string(),
+            offset,
+            size: 1,
+            value,
+            kind,
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![ev(5, AccessKind::Read, 0x60), ev(0, AccessKind::Write, 0x41)]
+        );
+    }
+}
@@ -1,23 +1,139 @@
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::sync::Arc;
 
-use crate::exits::{VmEntry, VmExit};
-use crate::vmm::VmmHdl;
+use crate::common::{GuestAddr, PAGE_SIZE};
+use crate::exits::{ExitStats, VmEntry, VmExit};
+use crate::vmm::{MemCtx, VmmHdl};
+
+/// Maximum number of bytes returned by [`VcpuHdl::read_insn`], enough to cover
+/// the longest x86 instruction.
+pub const INSN_FETCH_LEN: usize = 16;
+
+/// Segment registers reported by [`VcpuHdl::segments`], with their names
+pub const SEGMENT_REGS: [(bhyve_api::vm_reg_name, &str); 8] = [
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_CS, "cs"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_DS, "ds"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_ES, "es"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_FS, "fs"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_GS, "gs"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_SS, "ss"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_TR, "tr"),
+    (bhyve_api::vm_reg_name::VM_REG_GUEST_LDTR, "ldtr"),
+];
+
+// Segment access rights, in the VMCS format used by bhyve
+const SEG_ACC_S: u32 = 1 << 4;
+const SEG_ACC_P: u32 = 1 << 7;
+const SEG_ACC_L: u32 = 1 << 13;
+const SEG_ACC_DB: u32 = 1 << 14;
+const SEG_ACC_G: u32 = 1 << 15;
+const SEG_ACC_UNUSABLE: u32 = 1 << 16;
+
+const CR0_PE: u64 = 1 << 0;
+const EFER_LMA: u64 = 1 << 10;
+
+/// Execution mode of a vCPU
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CpuMode {
+    Real,
+    Protected,
+    /// 32-bit code under long mode
+    Compat,
+    Long,
+}
+impl CpuMode {
+    fn from_state(cr0: u64, efer: u64, cs: &bhyve_api::seg_desc) -> Self {
+        if cr0 & CR0_PE == 0 {
+            CpuMode::Real
+        } else if efer & EFER_LMA == 0 {
+            CpuMode::Protected
+        } else if cs.access & SEG_ACC_L != 0 {
+            CpuMode::Long
+        } else {
+            CpuMode::Compat
+        }
+    }
+}
+
+/// Format a segment descriptor for display: its base, limit, and the decoded
+/// access rights.
+pub fn fmt_segment(desc: &bhyve_api::seg_desc) -> String {
+    let acc = desc.access;
+    if acc & SEG_ACC_UNUSABLE != 0 {
+        return format!(
+            "base:{:016x} limit:{:08x} unusable",
+            desc.base, desc.limit
+        );
+    }
+    let mut flags = vec![format!("type={:x}", acc & 0xf)];
+    flags.push(format!("dpl={}", (acc >> 5) & 0x3));
+    for (bit, name) in [
+        (SEG_ACC_S, "S"),
+        (SEG_ACC_P, "P"),
+        (SEG_ACC_L, "L"),
+        (SEG_ACC_DB, "D/B"),
+        (SEG_ACC_G, "G"),
+    ]
+    .iter()
+    {
+        if acc & bit != 0 {
+            flags.push(name.to_string());
+        }
+    }
+    format!(
+        "base:{:016x} limit:{:08x} access:{:05x} {}",
+        desc.base,
+        desc.limit,
+        acc,
+        flags.join(" ")
+    )
+}
+
+/// Determine the address and length of the instruction bytes to fetch for
+/// `rip` within a code segment based at `cs_base`.
+///
+/// The fetch is truncated at the end of the page if `full` is false, so that
+/// an instruction at the very end of a memory region can still be read.
+fn insn_fetch_range(
+    cs_base: u64,
+    rip: u64,
+    full: bool,
+) -> Option<(u64, usize)> {
+    let addr = cs_base.checked_add(rip)?;
+    let page_left = PAGE_SIZE - (addr as usize & (PAGE_SIZE - 1));
+    let len = if full {
+        INSN_FETCH_LEN
+    } else {
+        usize::min(INSN_FETCH_LEN, page_left)
+    };
+    addr.checked_add(len as u64 - 1)?;
+    Some((addr, len))
+}
 
 pub struct VcpuHdl {
     hdl: Arc<VmmHdl>,
     id: i32,
+    stats: Arc<ExitStats>,
 }
 
 impl VcpuHdl {
-    pub fn from_vmhdl(hdl: Arc<VmmHdl>, id: i32) -> Self {
-        Self { hdl, id }
+    pub fn from_vmhdl(
+        hdl: Arc<VmmHdl>,
+        id: i32,
+        stats: Arc<ExitStats>,
+    ) -> Self {
+        Self { hdl, id, stats }
     }
 
     pub fn cpuid(&self) -> i32 {
         self.id
     }
 
+    /// Counters of VM exits taken by this vCPU
+    pub fn exit_stats(&self) -> &ExitStats {
+        &self.stats
+    }
+
     pub fn set_default_capabs(&mut self) -> Result<()> {
         // Enable exit-on-HLT so the host CPU does not spin in VM context when
         // the guest enters a HLT instruction.
@@ -27,7 +143,8 @@ impl VcpuHdl {
             capval: 1,
             allcpus: 0,
         };
-        self.hdl.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap)
+        self.hdl.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap)?;
+        Ok(())
     }
 
     pub fn set_reg(
@@ -44,6 +161,70 @@ impl VcpuHdl {
         self.hdl.ioctl(bhyve_api::VM_SET_REGISTER, &mut regcmd)?;
         Ok(())
     }
+    pub fn get_reg(&self, reg: bhyve_api::vm_reg_name) -> Result<u64> {
+        let mut regcmd = bhyve_api::vm_register {
+            cpuid: self.id,
+            regnum: reg as i32,
+            regval: 0,
+        };
+
+        self.hdl.ioctl(bhyve_api::VM_GET_REGISTER, &mut regcmd)?;
+        Ok(regcmd.regval)
+    }
+    pub fn get_segreg(
+        &self,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<bhyve_api::seg_desc> {
+        let mut desc = bhyve_api::vm_seg_desc {
+            cpuid: self.id,
+            regnum: reg as i32,
+            desc: bhyve_api::seg_desc { base: 0, limit: 0, access: 0 },
+        };
+
+        self.hdl.ioctl(bhyve_api::VM_GET_SEGMENT_DESCRIPTOR, &mut desc)?;
+        Ok(desc.desc)
+    }
+    /// Get the descriptors for each of the [`SEGMENT_REGS`]
+    pub fn segments(&self) -> Result<Vec<(&'static str, bhyve_api::seg_desc)>> {
+        SEGMENT_REGS
+            .iter()
+            .map(|(reg, name)| Ok((*name, self.get_segreg(*reg)?)))
+            .collect()
+    }
+    /// Determine the current execution mode from CR0, EFER, and CS
+    pub fn cpu_mode(&self) -> Result<CpuMode> {
+        let cr0 = self.get_reg(bhyve_api::vm_reg_name::VM_REG_GUEST_CR0)?;
+        let efer = self.get_reg(bhyve_api::vm_reg_name::VM_REG_GUEST_EFER)?;
+        let cs = self.get_segreg(bhyve_api::vm_reg_name::VM_REG_GUEST_CS)?;
+        Ok(CpuMode::from_state(cr0, efer, &cs))
+    }
+    /// Read the bytes of the instruction at the current %rip, for consumption
+    /// by disassembly tooling.
+    ///
+    /// The address is formed from %rip and the CS base, and is treated as a
+    /// guest-physical address.  This is correct for real mode and for guests
+    /// running with identity-mapped paging; paged translation is not performed.
+    /// Fewer than [`INSN_FETCH_LEN`] bytes are returned if the instruction lies
+    /// at the end of a page which is not followed by readable memory.
+    pub fn read_insn(&self, mem: &MemCtx) -> Result<Vec<u8>> {
+        let rip = self.get_reg(bhyve_api::vm_reg_name::VM_REG_GUEST_RIP)?;
+        let cs = self.get_segreg(bhyve_api::vm_reg_name::VM_REG_GUEST_CS)?;
+
+        for full in [true, false].iter() {
+            let (addr, len) = match insn_fetch_range(cs.base, rip, *full) {
+                Some(range) => range,
+                None => continue,
+            };
+            let mut buf = vec![0u8; len];
+            if mem.read_into(GuestAddr(addr), &mut buf, len) == Some(len) {
+                return Ok(buf);
+            }
+        }
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("instruction at {:x}:{:x} not readable", cs.base, rip),
+        ))
+    }
     pub fn set_segreg(
         &mut self,
         reg: bhyve_api::vm_reg_name,
@@ -91,3 +272,71 @@ impl VcpuHdl {
         Ok(VmExit::from(&exit))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn seg(base: u64, limit: u32, access: u32) -> bhyve_api::seg_desc {
+        bhyve_api::seg_desc { base, limit, access }
+    }
+
+    #[test]
+    fn segment_decode() {
+        // As established by reset, and by the direct kernel boot path
+        let reset_cs = seg(0xffff_0000, 0xffff, 0x93);
+        let long_cs = seg(0, 0xffff_ffff, 0xa09b);
+        let prot_cs = seg(0, 0xffff_ffff, 0xc09b);
+
+        assert_eq!(CpuMode::from_state(0x10, 0, &reset_cs), CpuMode::Real);
+        assert_eq!(CpuMode::from_state(0x11, 0, &prot_cs), CpuMode::Protected);
+        assert_eq!(
+            CpuMode::from_state(0x8000_0011, 0x500, &long_cs),
+            CpuMode::Long
+        );
+        assert_eq!(
+            CpuMode::from_state(0x8000_0011, 0x500, &prot_cs),
+            CpuMode::Compat
+        );
+
+        assert_eq!(
+            fmt_segment(&long_cs),
+            "base:0000000000000000 limit:ffffffff access:0a09b \
+            type=b dpl=0 S P L G"
+        );
+        assert_eq!(
+            fmt_segment(&reset_cs),
+            "base:00000000ffff0000 limit:0000ffff access:00093 type=3 dpl=0 S P"
+        );
+        assert_eq!(
+            fmt_segment(&seg(0, 0, 0x1_0000)),
+            "base:0000000000000000 limit:00000000 unusable"
+        );
+    }
+
+    #[test]
+    fn fetch_range() {
+        // Real mode, at the reset vector
+        assert_eq!(
+            insn_fetch_range(0xffff_0000, 0xfff0, true),
+            Some((0xffff_fff0, INSN_FETCH_LEN))
+        );
+        assert_eq!(
+            insn_fetch_range(0xffff_0000, 0xfff0, false),
+            Some((0xffff_fff0, INSN_FETCH_LEN))
+        );
+        // Truncated at the end of the page
+        assert_eq!(insn_fetch_range(0, 0x1ffa, false), Some((0x1ffa, 6)));
+        assert_eq!(
+            insn_fetch_range(0, 0x1ffa, true),
+            Some((0x1ffa, INSN_FETCH_LEN))
+        );
+        // Address space overflow
+        assert_eq!(insn_fetch_range(u64::MAX, 1, true), None);
+        assert_eq!(insn_fetch_range(0, u64::MAX - 4, true), None);
+        assert_eq!(
+            insn_fetch_range(0, u64::MAX - 4, false),
+            Some((u64::MAX - 4, 5))
+        );
+    }
+}
@@ -7,10 +7,39 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::ptr::NonNull;
 
+use crate::common::PAGE_SIZE;
 use crate::util::sys::ioctl;
 
+/// Check that `name` is acceptable as the name of a VM instance.
+///
+/// The kernel limits names to [`bhyve_api::VM_MAX_NAMELEN`] (less one for the
+/// NUL terminator) bytes.  Since each instance appears as a device node under
+/// [`bhyve_api::VMM_PATH_PREFIX`], names are further limited here to ASCII
+/// alphanumerics, `-`, `_`, and `.`, and cannot be `.` or `..`.
+pub fn validate_name(name: &str) -> Result<()> {
+    let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidInput, msg));
+    if name.is_empty() {
+        return invalid("VM name cannot be empty".to_string());
+    }
+    if name.len() >= bhyve_api::VM_MAX_NAMELEN {
+        return invalid(format!(
+            "VM name exceeds {} bytes",
+            bhyve_api::VM_MAX_NAMELEN - 1
+        ));
+    }
+    if name == "." || name == ".." {
+        return invalid(format!("VM name cannot be \"{}\"", name));
+    }
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if let Some(c) = name.chars().find(|c| !allowed(*c)) {
+        return invalid(format!("VM name contains illegal character {:?}", c));
+    }
+    Ok(())
+}
+
 #[cfg(target_os = "illumos")]
 pub fn create_vm(name: &str, force: bool) -> Result<VmmHdl> {
+    validate_name(name)?;
     let ctl = OpenOptions::new()
         .write(true)
         .custom_flags(libc::O_EXCL)
@@ -52,7 +81,8 @@ pub fn create_vm(name: &str, force: bool) -> Result<VmmHdl> {
     Ok(VmmHdl { inner: fp, name: name.to_string() })
 }
 #[cfg(not(target_os = "illumos"))]
-pub fn create_vm(_name: &str, _force: bool) -> Result<VmmHdl> {
+pub fn create_vm(name: &str, _force: bool) -> Result<VmmHdl> {
+    validate_name(name)?;
     {
         // suppress unused warnings
         let mut _oo = OpenOptions::new();
@@ -90,6 +120,36 @@ pub fn destroy_vm(_name: &str) -> Result<()> {
     Ok(())
 }
 
+/// Open a handle to an existing VM instance.
+pub fn open_vm(name: &str) -> Result<VmmHdl> {
+    validate_name(name)?;
+    let mut vmpath = PathBuf::from(bhyve_api::VMM_PATH_PREFIX);
+    vmpath.push(name);
+
+    let fp = OpenOptions::new().write(true).read(true).open(vmpath)?;
+    Ok(VmmHdl { inner: fp, name: name.to_string() })
+}
+
+/// Check that the host vmm driver is present and able to run VMs.
+///
+/// The bhyve interface does not (yet) expose a version to query, so this is
+/// the extent of the runtime compatibility check available.
+#[cfg(target_os = "illumos")]
+pub fn check_api() -> Result<()> {
+    let ctl = OpenOptions::new().write(true).open(bhyve_api::VMM_CTL_PATH)?;
+    let ctlfd = ctl.as_raw_fd();
+
+    let res = unsafe { libc::ioctl(ctlfd, bhyve_api::VMM_VM_SUPPORTED, 0) };
+    if res != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+#[cfg(not(target_os = "illumos"))]
+pub fn check_api() -> Result<()> {
+    Err(Error::new(ErrorKind::Other, "illumos required"))
+}
+
 bitflags! {
     pub struct Prot: u8 {
         const NONE = 0;
@@ -102,6 +162,121 @@ bitflags! {
     }
 }
 
+/// Errors resulting from operations against a [`VmmHdl`].
+///
+/// The errno values commonly returned by the vmm driver are classified into
+/// distinct variants so that callers can act upon them without picking through
+/// raw OS errors.  Each variant retains the errno it was classified from (as
+/// several map to the same variant), so that [`VmmError::errno`] and the
+/// conversion back to an [`Error`] are faithful.  Anything else is preserved
+/// as-is in [`VmmError::Other`].
+#[derive(Debug)]
+pub enum VmmError {
+    /// The VM (or a resource within it) does not exist
+    NotFound(i32),
+    /// Insufficient privilege for the requested operation
+    PermissionDenied(i32),
+    /// The operation is not supported by the host vmm driver
+    NotSupported(i32),
+    /// The resource being created already exists
+    AlreadyExists(i32),
+    /// The request was malformed or out of range
+    InvalidArgument(i32),
+    /// The resource is in use and cannot be acted upon
+    Busy(i32),
+    /// Any other error, with the raw errno (if any) available from the inner
+    /// [`Error`]
+    Other(Error),
+}
+impl VmmError {
+    /// The errno corresponding to this error, if one is known.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            VmmError::NotFound(errno)
+            | VmmError::PermissionDenied(errno)
+            | VmmError::NotSupported(errno)
+            | VmmError::AlreadyExists(errno)
+            | VmmError::InvalidArgument(errno)
+            | VmmError::Busy(errno) => Some(*errno),
+            VmmError::Other(e) => e.raw_os_error(),
+        }
+    }
+}
+impl From<Error> for VmmError {
+    fn from(e: Error) -> Self {
+        match e.raw_os_error() {
+            Some(n @ libc::ENOENT) | Some(n @ libc::ENXIO) => {
+                VmmError::NotFound(n)
+            }
+            Some(n @ libc::EPERM) | Some(n @ libc::EACCES) => {
+                VmmError::PermissionDenied(n)
+            }
+            Some(n @ libc::ENOTSUP) | Some(n @ libc::ENOTTY) => {
+                VmmError::NotSupported(n)
+            }
+            Some(n @ libc::EEXIST) => VmmError::AlreadyExists(n),
+            Some(n @ libc::EINVAL) => VmmError::InvalidArgument(n),
+            Some(n @ libc::EBUSY) => VmmError::Busy(n),
+            _ => VmmError::Other(e),
+        }
+    }
+}
+impl From<VmmError> for Error {
+    fn from(e: VmmError) -> Self {
+        match e {
+            VmmError::Other(inner) => inner,
+            classified => Error::from_raw_os_error(classified.errno().unwrap()),
+        }
+    }
+}
+impl std::fmt::Display for VmmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let desc = match self {
+            VmmError::NotFound(_) => "vmm resource not found",
+            VmmError::PermissionDenied(_) => "vmm permission denied",
+            VmmError::NotSupported(_) => "vmm operation not supported",
+            VmmError::AlreadyExists(_) => "vmm resource already exists",
+            VmmError::InvalidArgument(_) => "invalid vmm argument",
+            VmmError::Busy(_) => "vmm resource busy",
+            VmmError::Other(e) => return write!(f, "vmm error: {}", e),
+        };
+        write!(
+            f,
+            "{}: {}",
+            desc,
+            Error::from_raw_os_error(self.errno().unwrap())
+        )
+    }
+}
+impl std::error::Error for VmmError {}
+
+pub type VmmResult<T> = std::result::Result<T, VmmError>;
+
+/// Check that `[gpa, gpa + len)` is entirely covered by readable mappings.
+///
+/// The mappings are expected to be sorted by address, as returned by
+/// [`VmmHdl::memmaps`].
+fn range_mapped(maps: &[bhyve_api::vm_memmap], gpa: u64, len: usize) -> bool {
+    let end = match gpa.checked_add(len as u64) {
+        Some(end) if len != 0 => end,
+        _ => return false,
+    };
+    let mut cur = gpa;
+    for map in maps.iter() {
+        let map_end = map.gpa + map.len as u64;
+        if map.gpa <= cur && cur < map_end {
+            if map.prot & bhyve_api::PROT_READ as i32 == 0 {
+                return false;
+            }
+            cur = map_end;
+            if cur >= end {
+                return true;
+            }
+        }
+    }
+    false
+}
+
 pub struct VmmHdl {
     inner: File,
     name: String,
@@ -110,7 +285,7 @@ impl VmmHdl {
     pub fn fd(&self) -> RawFd {
         self.inner.as_raw_fd()
     }
-    pub fn ioctl<T>(&self, cmd: i32, data: *mut T) -> Result<()> {
+    pub fn ioctl<T>(&self, cmd: i32, data: *mut T) -> VmmResult<()> {
         ioctl(self.fd(), cmd, data)?;
         Ok(())
     }
@@ -120,7 +295,7 @@ impl VmmHdl {
         segid: i32,
         size: usize,
         segname: Option<&str>,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         let mut seg = bhyve_api::vm_memseg {
             segid,
             len: size,
@@ -142,7 +317,7 @@ impl VmmHdl {
         len: usize,
         segoff: usize,
         prot: Prot,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         assert!(segoff <= i64::MAX as usize);
 
         let mut map = bhyve_api::vm_memmap {
@@ -156,7 +331,75 @@ impl VmmHdl {
         self.ioctl(bhyve_api::VM_MMAP_MEMSEG, &mut map)
     }
 
-    pub fn devmem_offset(&self, segid: i32, offset: usize) -> Result<usize> {
+    pub fn get_memseg(&self, segid: i32) -> VmmResult<bhyve_api::vm_memseg> {
+        let mut seg = bhyve_api::vm_memseg {
+            segid,
+            len: 0,
+            name: [0u8; bhyve_api::SEG_NAME_LEN],
+        };
+        self.ioctl(bhyve_api::VM_GET_MEMSEG, &mut seg)?;
+        Ok(seg)
+    }
+
+    /// Query the first guest memory mapping at or above `gpa`.
+    pub fn memmap_next(
+        &self,
+        gpa: u64,
+    ) -> VmmResult<Option<bhyve_api::vm_memmap>> {
+        let mut map = bhyve_api::vm_memmap {
+            gpa,
+            segid: 0,
+            segoff: 0,
+            len: 0,
+            prot: 0,
+            flags: 0,
+        };
+        match self.ioctl(bhyve_api::VM_MMAP_GETNEXT, &mut map) {
+            Ok(()) => Ok(Some(map)),
+            Err(VmmError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// List all guest memory mappings, in ascending order of address.
+    pub fn memmaps(&self) -> VmmResult<Vec<bhyve_api::vm_memmap>> {
+        let mut maps = Vec::new();
+        let mut gpa = 0;
+        while let Some(map) = self.memmap_next(gpa)? {
+            gpa = map.gpa + map.len as u64;
+            maps.push(map);
+        }
+        Ok(maps)
+    }
+
+    /// Read a range of guest physical memory.
+    ///
+    /// The range must be entirely covered by readable guest memory mappings,
+    /// otherwise the request is rejected with [`VmmError::InvalidArgument`].
+    pub fn read_guest(&self, gpa: u64, len: usize) -> VmmResult<Vec<u8>> {
+        let maps = self.memmaps()?;
+        if !range_mapped(&maps, gpa, len) {
+            return Err(VmmError::InvalidArgument(libc::EINVAL));
+        }
+
+        let page_off = gpa as usize & (PAGE_SIZE - 1);
+        let map_base = gpa as usize - page_off;
+        let map_len = (page_off + len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut buf = vec![0u8; len];
+        unsafe {
+            let ptr =
+                self.mmap_guest_mem(map_base, map_len, Prot::READ, None)?;
+            ptr::copy_nonoverlapping(
+                ptr.as_ptr().add(page_off),
+                buf.as_mut_ptr(),
+                len,
+            );
+            libc::munmap(ptr.as_ptr() as *mut libc::c_void, map_len);
+        }
+        Ok(buf)
+    }
+
+    pub fn devmem_offset(&self, segid: i32, offset: usize) -> VmmResult<usize> {
         assert!(offset <= i64::MAX as usize);
 
         let mut devoff = bhyve_api::vm_devmem_offset { segid, offset: 0 };
@@ -166,7 +409,11 @@ impl VmmHdl {
         Ok(devoff.offset as usize)
     }
 
-    pub unsafe fn mmap_seg(&self, segid: i32, size: usize) -> Result<*mut u8> {
+    pub unsafe fn mmap_seg(
+        &self,
+        segid: i32,
+        size: usize,
+    ) -> VmmResult<*mut u8> {
         let devoff = self.devmem_offset(segid, 0)?;
         let ptr = libc::mmap(
             ptr::null_mut(),
@@ -177,7 +424,7 @@ impl VmmHdl {
             devoff as i64,
         ) as *mut u8;
         if ptr.is_null() {
-            return Err(Error::last_os_error());
+            return Err(Error::last_os_error().into());
         }
         Ok(ptr)
     }
@@ -187,7 +434,7 @@ impl VmmHdl {
         size: usize,
         prot: Prot,
         map_at: Option<NonNull<u8>>,
-    ) -> Result<NonNull<u8>> {
+    ) -> VmmResult<NonNull<u8>> {
         let (map_addr, add_flags) = if let Some(addr) = map_at {
             (addr.as_ptr() as *mut libc::c_void, libc::MAP_FIXED)
         } else {
@@ -201,14 +448,26 @@ impl VmmHdl {
             self.fd(),
             offset as i64,
         ) as *mut u8;
-        NonNull::new(ptr).ok_or_else(Error::last_os_error)
+        NonNull::new(ptr).ok_or_else(|| Error::last_os_error().into())
     }
 
-    pub fn rtc_settime(&self, unix_time: u64) -> Result<()> {
+    pub fn rtc_settime(&self, unix_time: u64) -> VmmResult<()> {
         let mut time: u64 = unix_time;
         self.ioctl(bhyve_api::VM_RTC_SETTIME, &mut time)
     }
-    pub fn rtc_write(&self, offset: u8, value: u8) -> Result<()> {
+    /// Get the RTC time, in seconds since the Unix epoch
+    pub fn rtc_gettime(&self) -> VmmResult<u64> {
+        let mut time: u64 = 0;
+        self.ioctl(bhyve_api::VM_RTC_GETTIME, &mut time)?;
+        Ok(time)
+    }
+    pub fn rtc_read(&self, offset: u8) -> VmmResult<u8> {
+        let mut data =
+            bhyve_api::vm_rtc_data { offset: offset as i32, value: 0 };
+        self.ioctl(bhyve_api::VM_RTC_READ, &mut data)?;
+        Ok(data.value)
+    }
+    pub fn rtc_write(&self, offset: u8, value: u8) -> VmmResult<()> {
         let mut data = bhyve_api::vm_rtc_data { offset: offset as i32, value };
         self.ioctl(bhyve_api::VM_RTC_WRITE, &mut data)
     }
@@ -217,7 +476,7 @@ impl VmmHdl {
         &self,
         pic_irq: u8,
         ioapic_irq: Option<u8>,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         let mut data = bhyve_api::vm_isa_irq {
             atpic_irq: pic_irq as i32,
             ioapic_irq: ioapic_irq.map(|x| x as i32).unwrap_or(-1),
@@ -228,7 +487,7 @@ impl VmmHdl {
         &self,
         pic_irq: u8,
         ioapic_irq: Option<u8>,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         let mut data = bhyve_api::vm_isa_irq {
             atpic_irq: pic_irq as i32,
             ioapic_irq: ioapic_irq.map(|x| x as i32).unwrap_or(-1),
@@ -239,7 +498,7 @@ impl VmmHdl {
         &self,
         pic_irq: u8,
         ioapic_irq: Option<u8>,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         let mut data = bhyve_api::vm_isa_irq {
             atpic_irq: pic_irq as i32,
             ioapic_irq: ioapic_irq.map(|x| x as i32).unwrap_or(-1),
@@ -251,7 +510,7 @@ impl VmmHdl {
         &self,
         vec: u8,
         level_mode: bool,
-    ) -> Result<()> {
+    ) -> VmmResult<()> {
         let mut data = bhyve_api::vm_isa_irq_trigger {
             atpic_irq: vec as i32,
             trigger: if level_mode { 1 } else { 0 },
@@ -260,37 +519,193 @@ impl VmmHdl {
     }
 
     #[allow(unused)]
-    pub fn ioapic_assert_irq(&self, irq: u8) -> Result<()> {
+    pub fn ioapic_assert_irq(&self, irq: u8) -> VmmResult<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         self.ioctl(bhyve_api::VM_IOAPIC_ASSERT_IRQ, &mut data)
     }
     #[allow(unused)]
-    pub fn ioapic_deassert_irq(&self, irq: u8) -> Result<()> {
+    pub fn ioapic_deassert_irq(&self, irq: u8) -> VmmResult<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         self.ioctl(bhyve_api::VM_IOAPIC_DEASSERT_IRQ, &mut data)
     }
     #[allow(unused)]
-    pub fn ioapic_pulse_irq(&self, irq: u8) -> Result<()> {
+    pub fn ioapic_pulse_irq(&self, irq: u8) -> VmmResult<()> {
         let mut data = bhyve_api::vm_ioapic_irq { irq: irq as i32 };
         self.ioctl(bhyve_api::VM_IOAPIC_PULSE_IRQ, &mut data)
     }
     #[allow(unused)]
-    pub fn ioapic_pin_count(&self) -> Result<u8> {
+    pub fn ioapic_pin_count(&self) -> VmmResult<u8> {
         let mut data = 0u32;
         self.ioctl(bhyve_api::VM_IOAPIC_PINCOUNT, &mut data)?;
         Ok(data as u8)
     }
 
-    pub fn lapic_msi(&self, addr: u64, msg: u64) -> Result<()> {
+    pub fn lapic_msi(&self, addr: u64, msg: u64) -> VmmResult<()> {
         let mut data = bhyve_api::vm_lapic_msi { addr, msg };
         self.ioctl(bhyve_api::VM_LAPIC_MSI, &mut data)
     }
 
-    pub fn pmtmr_locate(&self, port: u16) -> Result<()> {
+    pub fn pmtmr_locate(&self, port: u16) -> VmmResult<()> {
         self.ioctl(bhyve_api::VM_PMTMR_LOCATE, port as *mut usize)
     }
+    /// Capabilities (and ID) of the in-kernel HPET, as a guest would read them
+    /// from its General Capabilities register.
+    pub fn hpet_capabilities(&self) -> VmmResult<u32> {
+        let mut cap = bhyve_api::vm_hpet_cap::default();
+        self.ioctl(bhyve_api::VM_GET_HPET_CAPABILITIES, &mut cap)?;
+        Ok(cap.capabilities)
+    }
+
+    /// Set the topology the vCPUs are presented with, through the CPUID
+    /// leaves emulated in the kernel.  The product of the counts cannot exceed
+    /// the maximum vCPU count of the instance.
+    pub fn set_topology(
+        &self,
+        sockets: u16,
+        cores: u16,
+        threads: u16,
+    ) -> VmmResult<()> {
+        let mut topo =
+            bhyve_api::vm_cpu_topology { sockets, cores, threads, maxcpus: 0 };
+        self.ioctl(bhyve_api::VM_SET_TOPOLOGY, &mut topo)
+    }
+
+    /// Suspend the instance, halting all of its vCPUs for the given reason.
+    ///
+    /// This is terminal, short of a reinitialization of the instance.  If the
+    /// instance is already suspended, the request is treated as successful.
+    pub fn suspend(&self, how: bhyve_api::vm_suspend_how) -> VmmResult<()> {
+        let mut data = bhyve_api::vm_suspend { how: how as i32 };
+        match self.ioctl(bhyve_api::VM_SUSPEND, &mut data) {
+            Err(e) if e.errno() == Some(libc::EALREADY) => Ok(()),
+            res => res,
+        }
+    }
+    /// Reinitialize a suspended instance, returning the in-kernel device and
+    /// vCPU state to its power-on condition.  Guest memory is left intact.
+    ///
+    /// All vCPUs must have observed the suspension (exiting from `VM_RUN`)
+    /// before the instance can be reinitialized.  Once done, the vCPUs must be
+    /// activated again before they will run.
+    pub fn reinit(&self) -> VmmResult<()> {
+        self.ioctl(bhyve_api::VM_REINIT, ptr::null_mut::<libc::c_void>())
+    }
+    /// Stop all vCPUs from entering the guest until [`VmmHdl::resume`]
+    ///
+    /// Pausing already-paused vCPUs is not an error.
+    pub fn pause(&self) -> VmmResult<()> {
+        let mut data = bhyve_api::vm_activate_cpu { vcpuid: -1 };
+        self.ioctl(bhyve_api::VM_SUSPEND_CPU, &mut data)
+    }
+    /// Allow vCPUs stopped by [`VmmHdl::pause`] to run again
+    pub fn resume(&self) -> VmmResult<()> {
+        let mut data = bhyve_api::vm_activate_cpu { vcpuid: -1 };
+        self.ioctl(bhyve_api::VM_RESUME_CPU, &mut data)
+    }
+
+    pub fn destroy(&mut self) -> VmmResult<()> {
+        destroy_vm(&self.name)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn name_validation() {
+        assert!(validate_name("testvm").is_ok());
+        assert!(validate_name("vm-0_1.a").is_ok());
+        let longest = "a".repeat(bhyve_api::VM_MAX_NAMELEN - 1);
+        assert!(validate_name(&longest).is_ok());
+
+        let too_long = "a".repeat(bhyve_api::VM_MAX_NAMELEN);
+        for bad in
+            ["", &too_long, ".", "..", "a/b", "vm 0", "vm\0", "vm\u{e9}"].iter()
+        {
+            let err = validate_name(bad).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn errno_classify() {
+        let cases = [
+            libc::ENOENT,
+            libc::ENXIO,
+            libc::EPERM,
+            libc::EACCES,
+            libc::ENOTSUP,
+            libc::ENOTTY,
+            libc::EEXIST,
+            libc::EINVAL,
+            libc::EBUSY,
+        ];
+        for raw in cases.iter() {
+            let err: VmmError = Error::from_raw_os_error(*raw).into();
+            assert!(!matches!(err, VmmError::Other(_)), "errno {}", raw);
+            // The original errno survives classification
+            assert_eq!(err.errno(), Some(*raw));
+            assert_eq!(Error::from(err).raw_os_error(), Some(*raw));
+        }
+        assert!(matches!(
+            VmmError::from(Error::from_raw_os_error(libc::ENXIO)),
+            VmmError::NotFound(libc::ENXIO)
+        ));
+        assert!(matches!(
+            VmmError::from(Error::from_raw_os_error(libc::EBUSY)),
+            VmmError::Busy(libc::EBUSY)
+        ));
+        assert!(matches!(
+            VmmError::from(Error::from_raw_os_error(libc::EIO)),
+            VmmError::Other(_)
+        ));
+    }
+
+    fn map(gpa: u64, len: usize, prot: u8) -> bhyve_api::vm_memmap {
+        bhyve_api::vm_memmap {
+            gpa,
+            segid: 0,
+            segoff: 0,
+            len,
+            prot: prot as i32,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn read_bounds() {
+        let maps = [
+            map(0, 0x1000, bhyve_api::PROT_ALL),
+            map(0x1000, 0x1000, bhyve_api::PROT_READ),
+            map(0x4000, 0x1000, bhyve_api::PROT_ALL),
+            map(0x5000, 0x1000, bhyve_api::PROT_WRITE),
+        ];
+
+        assert!(range_mapped(&maps, 0, 0x10));
+        assert!(range_mapped(&maps, 0xff0, 0x20), "spanning adjacent maps");
+        assert!(range_mapped(&maps, 0x4000, 0x1000));
+
+        assert!(!range_mapped(&maps, 0x10, 0), "empty read");
+        assert!(!range_mapped(&maps, 0x1ff0, 0x20), "runs into hole");
+        assert!(!range_mapped(&maps, 0x2000, 0x10), "within hole");
+        assert!(!range_mapped(&maps, 0x4ff0, 0x20), "unreadable map");
+        assert!(!range_mapped(&maps, 0x6000, 0x10), "beyond all maps");
+        assert!(!range_mapped(&maps, u64::MAX, 2), "overflow");
+    }
+
+    #[test]
+    fn errno_other() {
+        let err: VmmError = Error::from_raw_os_error(libc::EALREADY).into();
+        assert!(matches!(err, VmmError::Other(_)));
+        assert_eq!(err.errno(), Some(libc::EALREADY));
+
+        let err: VmmError = Error::new(ErrorKind::Other, "no errno").into();
+        assert_eq!(err.errno(), None);
 
-    pub fn destroy(&mut self) -> Result<()> {
-        destroy_vm(&self.name)
+        // Round-trip back into io::Error retains the errno
+        let ioerr: Error = VmmError::Busy(libc::EBUSY).into();
+        assert_eq!(ioerr.raw_os_error(), Some(libc::EBUSY));
     }
 }
@@ -1,11 +1,14 @@
+use std::convert::TryFrom;
 use std::io::{Error, ErrorKind, Result};
 use std::marker::PhantomData;
 use std::mem::size_of;
 use std::ptr::{copy_nonoverlapping, NonNull};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use crate::common::{GuestAddr, GuestRegion};
-use crate::hw::rtc::Rtc;
+use crate::exits::{ExitCounts, ExitStats};
+use crate::hw::rtc::{CmosTime, Rtc};
 use crate::mmio::MmioBus;
 use crate::pio::PioBus;
 use crate::util::aspace::ASpace;
@@ -34,6 +37,124 @@ enum MapKind {
     MmioReserve,
 }
 
+/// Kind of guest-physical region described by a [`MemRegionDesc`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemRegionKind {
+    Ram,
+    Rom,
+    Mmio,
+}
+
+/// Description of a guest-physical region the [`Machine`] was built with
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemRegionDesc {
+    pub base: usize,
+    pub len: usize,
+    pub prot: Prot,
+    pub kind: MemRegionKind,
+    pub name: String,
+}
+
+/// Arrangement of the vCPUs into sockets, cores, and threads
+///
+/// bhyve gives each vCPU the APIC ID matching its vCPU ID, while CPUID
+/// describes the thread and core fields of the APIC ID as a whole number of
+/// bits.  The core and thread counts must then be powers of two, for the
+/// vCPUs to be numbered consecutively through the threads of each core, then
+/// the cores of each socket.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CpuTopology {
+    sockets: u8,
+    cores: u8,
+    threads: u8,
+}
+impl CpuTopology {
+    /// Topology with `cores` cores in each socket, and `threads` threads in
+    /// each core.
+    pub fn new(sockets: u8, cores: u8, threads: u8) -> Result<Self> {
+        if sockets == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "no sockets"));
+        }
+        if !cores.is_power_of_two() || !threads.is_power_of_two() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "cores and threads must be powers of two",
+            ));
+        }
+        let total = sockets as usize * cores as usize * threads as usize;
+        if total > bhyve_api::VM_MAXCPU {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "topology exceeds maximum vCPU count",
+            ));
+        }
+        Ok(Self { sockets, cores, threads })
+    }
+    /// Each of `cpus` vCPUs in a socket of its own
+    pub fn flat(cpus: u8) -> Self {
+        Self { sockets: cpus, cores: 1, threads: 1 }
+    }
+    pub fn sockets(&self) -> u8 {
+        self.sockets
+    }
+    pub fn cores(&self) -> u8 {
+        self.cores
+    }
+    pub fn threads(&self) -> u8 {
+        self.threads
+    }
+    /// Total number of vCPUs
+    pub fn cpus(&self) -> u8 {
+        self.sockets * self.cores * self.threads
+    }
+    /// Socket, core (within the socket), and thread (within the core) of the
+    /// given vCPU
+    pub fn position(&self, vcpu: u8) -> (u8, u8, u8) {
+        let core = vcpu / self.threads;
+        (core / self.cores, core % self.cores, vcpu % self.threads)
+    }
+}
+impl Default for CpuTopology {
+    fn default() -> Self {
+        Self::flat(1)
+    }
+}
+
+/// Guest-physical layout of an instance with `lowmem` bytes of RAM, and a boot
+/// ROM of `rom_len` bytes ending at 4GiB, for use with [`Builder::add_layout`].
+///
+/// The 32-bit MMIO window and the PCI ECAM region sit below the ROM, with the
+/// 64-bit MMIO window above all possible system memory.
+pub fn standard_layout(lowmem: usize, rom_len: usize) -> Vec<MemRegionDesc> {
+    let region = |base, len, prot, kind, name: &str| MemRegionDesc {
+        base,
+        len,
+        prot,
+        kind,
+        name: name.to_string(),
+    };
+    let mmio = Prot::READ | Prot::WRITE;
+    vec![
+        region(0, lowmem, Prot::ALL, MemRegionKind::Ram, "lowmem"),
+        region(
+            0x1_0000_0000 - rom_len,
+            rom_len,
+            Prot::READ | Prot::EXEC,
+            MemRegionKind::Rom,
+            "bootrom",
+        ),
+        region(0xc000_0000, 0x2000_0000, mmio, MemRegionKind::Mmio, "dev32"),
+        region(0xe000_0000, 0x1000_0000, mmio, MemRegionKind::Mmio, "pcicfg"),
+        region(
+            MAX_SYSMEM,
+            MAX_PHYSMEM - MAX_SYSMEM,
+            mmio,
+            MemRegionKind::Mmio,
+            "dev64",
+        ),
+    ]
+}
+
 struct MapEnt {
     kind: MapKind,
     name: String,
@@ -49,9 +170,11 @@ pub struct Machine {
     hdl: Arc<VmmHdl>,
     max_cpu: u8,
     cpus: Mutex<Vec<Option<VcpuHdl>>>,
+    exit_stats: Vec<Arc<ExitStats>>,
     state_lock: Mutex<()>,
 
     map_physmem: ASpace<MapEnt>,
+    mem_regions: Vec<MemRegionDesc>,
     bus_mmio: MmioBus,
     bus_pio: PioBus,
 }
@@ -91,9 +214,35 @@ impl Machine {
         Ok(())
     }
 
+    /// Push a new host time into the guest RTC, as when the host clock has
+    /// been stepped, yielding the date and time the guest will observe.
+    pub fn update_guest_rtc(&self, time: SystemTime) -> Result<CmosTime> {
+        let _lock = self.state_lock.lock().unwrap();
+        Rtc::set_time_to(&self.hdl, time)
+    }
+
     pub fn get_hdl(&self) -> Arc<VmmHdl> {
         Arc::clone(&self.hdl)
     }
+
+    /// Snapshot of the VM exit counts for a given vCPU
+    pub fn exit_stats(&self, id: i32) -> Option<ExitCounts> {
+        let stats = self.exit_stats.get(usize::try_from(id).ok()?)?;
+        Some(stats.snapshot())
+    }
+    /// VM exit counts aggregated across all vCPUs
+    pub fn exit_stats_total(&self) -> ExitCounts {
+        let mut total = ExitCounts::default();
+        for stats in self.exit_stats.iter() {
+            total.merge(&stats.snapshot());
+        }
+        total
+    }
+    /// Regions of guest-physical space (RAM, ROM, and MMIO reservations) the
+    /// machine was built with, sorted by base address.
+    pub fn mem_regions(&self) -> &[MemRegionDesc] {
+        &self.mem_regions
+    }
 }
 
 #[derive(Clone)]
@@ -286,6 +435,33 @@ impl<T: Copy> Iterator for MemMany<T> {
     }
 }
 
+/// Guest memory backed by a heap allocation rather than a VM, mapped at
+/// guest-physical address 0, so devices which access memory through a
+/// [`MemCtx`] can be exercised in tests.
+#[cfg(test)]
+pub struct TestMem {
+    map: ASpace<MapEnt>,
+    _buf: Box<[u8]>,
+}
+#[cfg(test)]
+impl TestMem {
+    pub fn new(len: usize) -> Self {
+        let mut buf = vec![0u8; len].into_boxed_slice();
+        let mut map = ASpace::new(0, MAX_PHYSMEM - 1);
+        let ent = MapEnt {
+            kind: MapKind::SysMem(-1, Prot::ALL),
+            name: "test".to_string(),
+            guest_map: NonNull::new(buf.as_mut_ptr()),
+            dev_map: None,
+        };
+        map.register(0, len, ent).unwrap();
+        Self { map, _buf: buf }
+    }
+    pub fn memctx(&self) -> MemCtx<'_> {
+        MemCtx { map: &self.map }
+    }
+}
+
 pub struct Builder {
     inner_hdl: Option<VmmHdl>,
     max_cpu: u8,
@@ -367,6 +543,40 @@ impl Builder {
             })?;
         Ok(self)
     }
+    /// Add each of the regions in `layout`, such as that from
+    /// [`standard_layout`].  The protection of MMIO regions is not used.
+    pub fn add_layout(mut self, layout: &[MemRegionDesc]) -> Result<Self> {
+        for r in layout.iter() {
+            self = match r.kind {
+                MemRegionKind::Ram => {
+                    self.add_mem_region(r.base, r.len, r.prot, &r.name)?
+                }
+                MemRegionKind::Rom => {
+                    self.add_rom_region(r.base, r.len, r.prot, &r.name)?
+                }
+                MemRegionKind::Mmio => {
+                    self.add_mmio_region(r.base, r.len, &r.name)?
+                }
+            };
+        }
+        Ok(self)
+    }
+    /// Present the vCPUs per `topo`, which must account for all of them.  This
+    /// is reflected in the CPUID leaves emulated by the kernel.
+    pub fn cpu_topology(self, topo: CpuTopology) -> Result<Self> {
+        if topo.cpus() != self.max_cpu {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "topology does not match maxcpu",
+            ));
+        }
+        self.hdl().set_topology(
+            topo.sockets as u16,
+            topo.cores as u16,
+            topo.threads as u16,
+        )?;
+        Ok(self)
+    }
     pub fn max_cpus(mut self, max: u8) -> Result<Self> {
         if max == 0 || max > bhyve_api::VM_MAXCPU as u8 {
             Err(Error::new(ErrorKind::InvalidInput, "maxcpu out of range"))
@@ -391,6 +601,22 @@ impl Builder {
         }
     }
 
+    fn region_descs(&self) -> Vec<MemRegionDesc> {
+        self.memmap
+            .iter()
+            .map(|(base, len, (ent, name))| {
+                let (kind, prot) = match *ent {
+                    MapKind::SysMem(_, prot) => (MemRegionKind::Ram, prot),
+                    MapKind::Rom(_, prot) => (MemRegionKind::Rom, prot),
+                    MapKind::MmioReserve => {
+                        (MemRegionKind::Mmio, Prot::READ | Prot::WRITE)
+                    }
+                };
+                MemRegionDesc { base, len, prot, kind, name: name.clone() }
+            })
+            .collect()
+    }
+
     fn prep_mem_map(&self, hdl: &VmmHdl) -> Result<ASpace<MapEnt>> {
         let last_sysmem = self.last_sysmem_addr()?;
 
@@ -462,24 +688,31 @@ impl Builder {
         let hdl = std::mem::replace(&mut self.inner_hdl, None).unwrap();
 
         let map = self.prep_mem_map(&hdl)?;
+        let mem_regions = self.region_descs();
 
         let arc_hdl = Arc::new(hdl);
 
         let mut cpus = Vec::with_capacity(self.max_cpu as usize);
+        let mut exit_stats = Vec::with_capacity(self.max_cpu as usize);
         for n in 0..self.max_cpu {
+            let stats = Arc::new(ExitStats::new());
             cpus.push(Some(VcpuHdl::from_vmhdl(
                 Arc::clone(&arc_hdl),
                 n as i32,
+                Arc::clone(&stats),
             )));
+            exit_stats.push(stats);
         }
 
         let machine = Arc::new(Machine {
             hdl: arc_hdl,
             max_cpu: self.max_cpu,
             cpus: Mutex::new(cpus),
+            exit_stats,
             state_lock: Mutex::new(()),
 
             map_physmem: map,
+            mem_regions,
             bus_mmio: MmioBus::new(MAX_PHYSMEM),
             bus_pio: PioBus::new(),
         });
@@ -494,3 +727,74 @@ impl Drop for Builder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn region_enumeration() {
+        // Register the layout used by propolis-cli, without requiring a real
+        // vmm instance to back it.
+        let lowmem = 0x4000_0000;
+        let rom_len = 0x20_0000;
+        let mut memmap = ASpace::new(0, MAX_PHYSMEM - 1);
+        for (segid, r) in standard_layout(lowmem, rom_len).iter().enumerate() {
+            let kind = match r.kind {
+                MemRegionKind::Ram => MapKind::SysMem(segid as i32, r.prot),
+                MemRegionKind::Rom => MapKind::Rom(segid as i32, r.prot),
+                MemRegionKind::Mmio => MapKind::MmioReserve,
+            };
+            memmap.register(r.base, r.len, (kind, r.name.clone())).unwrap();
+        }
+        let builder =
+            Builder { inner_hdl: None, max_cpu: 1, cur_segid: 2, memmap };
+
+        let descs = builder.region_descs();
+        let summary: Vec<(usize, usize, MemRegionKind, &str)> = descs
+            .iter()
+            .map(|d| (d.base, d.len, d.kind, d.name.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, lowmem, MemRegionKind::Ram, "lowmem"),
+                (0xc000_0000, 0x2000_0000, MemRegionKind::Mmio, "dev32"),
+                (0xe000_0000, 0x1000_0000, MemRegionKind::Mmio, "pcicfg"),
+                (
+                    0x1_0000_0000 - rom_len,
+                    rom_len,
+                    MemRegionKind::Rom,
+                    "bootrom"
+                ),
+                (
+                    MAX_SYSMEM,
+                    MAX_PHYSMEM - MAX_SYSMEM,
+                    MemRegionKind::Mmio,
+                    "dev64"
+                ),
+            ]
+        );
+        assert_eq!(descs[0].prot, Prot::ALL);
+        assert_eq!(descs[3].prot, Prot::READ | Prot::EXEC);
+    }
+
+    #[test]
+    fn cpu_topology() {
+        let topo = CpuTopology::new(2, 4, 2).unwrap();
+        assert_eq!(topo.cpus(), 16);
+        assert_eq!(topo.position(0), (0, 0, 0));
+        assert_eq!(topo.position(1), (0, 0, 1));
+        assert_eq!(topo.position(2), (0, 1, 0));
+        assert_eq!(topo.position(7), (0, 3, 1));
+        assert_eq!(topo.position(8), (1, 0, 0));
+        assert_eq!(topo.position(15), (1, 3, 1));
+
+        assert_eq!(CpuTopology::flat(3).position(2), (2, 0, 0));
+        assert!(CpuTopology::new(0, 1, 1).is_err());
+        assert!(CpuTopology::new(1, 3, 1).is_err());
+        assert!(CpuTopology::new(1, 1, 0).is_err());
+        assert!(CpuTopology::new(2, 8, 2).is_ok());
+        assert!(CpuTopology::new(3, 8, 2).is_err());
+    }
+}